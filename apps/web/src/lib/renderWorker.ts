@@ -7,7 +7,13 @@ import {
   createGetManyBytes,
   concatenateChunks,
 } from "./utils/workerHelpers";
-import { AudioOptions, convert_bms_to_wav, SampleFormat, ResampleMethod } from "@bmxtract/lib";
+import {
+  AudioOptions,
+  ChartMode,
+  convert_bms_to_wav,
+  SampleFormat,
+  ResampleMethod,
+} from "@bmxtract/lib";
 
 log.debug("Started worker.");
 
@@ -85,7 +91,7 @@ sw.onmessage = async (ev: MessageEvent<Message>) => {
 
     case MessageType.RENDER:
       {
-        const { id, bmsText, audioOptions } = ev.data;
+        const { id, bmsBytes, audioOptions } = ev.data;
 
         if (!renderFn) {
           return postMessage({
@@ -119,15 +125,22 @@ sw.onmessage = async (ev: MessageEvent<Message>) => {
             });
           };
 
+          const options = new AudioOptions(
+            audioOptions.channels,
+            audioOptions.sampleRate,
+            audioOptions.bitsPerSample,
+            audioOptions.sampleFormat === "float" ? SampleFormat.Float : SampleFormat.Int,
+            audioOptions.resampleQuality === "sinc" ? ResampleMethod.Sinc : ResampleMethod.Linear,
+          );
+          options.chart_mode = {
+            bms: ChartMode.Bms,
+            pms: ChartMode.Pms,
+            dtx: ChartMode.Dtx,
+          }[audioOptions.chartMode];
+
           await renderFn(
-            bmsText,
-            new AudioOptions(
-              audioOptions.channels,
-              audioOptions.sampleRate,
-              audioOptions.bitsPerSample,
-              audioOptions.sampleFormat === "float" ? SampleFormat.Float : SampleFormat.Int,
-              audioOptions.resampleQuality === "sinc" ? ResampleMethod.Sinc : ResampleMethod.Linear,
-            ),
+            bmsBytes,
+            options,
             onProgress,
             onChunk,
             getManyBytes,
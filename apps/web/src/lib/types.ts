@@ -26,13 +26,14 @@ type MessagePayloadMap = {
   // C2S
   [MessageType.RENDER]: {
     id: string;
-    bmsText: string;
+    bmsBytes: Uint8Array;
     audioOptions: {
       channels: number;
       sampleRate: number;
       bitsPerSample: number;
       sampleFormat: "int" | "float";
       resampleQuality: "linear" | "sinc";
+      chartMode: "bms" | "pms" | "dtx";
     };
   };
   [MessageType.READ_FILES_RESPONSE]: {
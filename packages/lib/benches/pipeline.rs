@@ -0,0 +1,300 @@
+//! Benchmarks for the decode, timeline and mix stages of a render.
+//!
+//! Charts and keysounds are generated so the numbers do not depend on files
+//! outside the repository. Run with `cargo bench -p bmxtract`, or pass a
+//! filter such as `cargo bench -p bmxtract -- mix` to run one group.
+
+use bmxtract::audio::{ResampleMethod, decode_audio};
+use bmxtract::bms::Bms;
+use bmxtract::mixer::{
+    EventRef, Sample, bucketize_events, default_chunk_frames, mix_chunk, precompute_overlaps,
+    prepare_events, to_storage,
+};
+use bmxtract::timeline::{
+    SoundEvent, TempoMap, build_tempo_map, extract_sound_events, index_audio_files,
+};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
+use std::sync::Arc;
+
+/// Sample rate of the generated keysound files.
+const SOURCE_RATE: u32 = 44100;
+
+/// Output sample rate of the benchmarked renders.
+const SAMPLE_RATE: u32 = 44100;
+
+/// Output channel count of the benchmarked renders.
+const CHANNELS: usize = 2;
+
+/// A generated chart and the size of its keysound set.
+struct ChartCase {
+    name: &'static str,
+    /// Number of `#WAV` definitions.
+    keysounds: usize,
+    /// Number of measures.
+    measures: usize,
+    /// Objects per measure on each lane.
+    density: usize,
+    /// Number of key lanes in use besides one BGM lane.
+    lanes: usize,
+}
+
+/// Charts from a sparse single-play chart to a keysound-dense convert.
+const CASES: [ChartCase; 3] = [
+    ChartCase {
+        name: "sparse",
+        keysounds: 64,
+        measures: 64,
+        density: 8,
+        lanes: 4,
+    },
+    ChartCase {
+        name: "typical",
+        keysounds: 400,
+        measures: 128,
+        density: 16,
+        lanes: 7,
+    },
+    ChartCase {
+        name: "dense",
+        keysounds: 1200,
+        measures: 128,
+        density: 24,
+        lanes: 14,
+    },
+];
+
+/// Key lane channels, 1P then 2P.
+const LANES: [&str; 14] = [
+    "11", "12", "13", "14", "15", "18", "19", "21", "22", "23", "24", "25", "28", "29",
+];
+
+/// Base-36 object id.
+fn object_id(id: usize) -> String {
+    let digit = |d: usize| char::from_digit(d as u32, 36).unwrap().to_ascii_uppercase();
+    format!("{}{}", digit(id / 36), digit(id % 36))
+}
+
+/// Write a chart for a case, cycling through the keysounds so each is reused.
+fn chart_text(case: &ChartCase) -> String {
+    let mut text = String::from("#BPM 150\n#BPM01 75\n#BPM02 300\n#STOP01 48\n");
+    for i in 1..=case.keysounds {
+        text.push_str(&format!("#WAV{} k{i}.wav\n", object_id(i)));
+    }
+    let mut next = 0;
+    for m in 0..case.measures {
+        if m % 16 == 15 {
+            text.push_str(&format!("#{m:03}08:0102\n#{m:03}09:0001\n"));
+        }
+        for lane in std::iter::once("01").chain(LANES[..case.lanes].iter().copied()) {
+            let mut line = format!("#{m:03}{lane}:");
+            for slot in 0..case.density {
+                // Leave gaps so lanes are not uniformly full.
+                if (slot + m) % 3 == 2 {
+                    line.push_str("00");
+                } else {
+                    line.push_str(&object_id(next % case.keysounds + 1));
+                    next += 1;
+                }
+            }
+            text.push_str(&line);
+            text.push('\n');
+        }
+    }
+    text
+}
+
+/// Generate a 16-bit stereo WAV file of a decaying tone.
+fn wave_file(frames: usize, freq: f32) -> Vec<u8> {
+    let data_len = frames * 4;
+    let mut wav = Vec::with_capacity(44 + data_len);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&SOURCE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SOURCE_RATE * 4).to_le_bytes());
+    wav.extend_from_slice(&4u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(data_len as u32).to_le_bytes());
+    for i in 0..frames {
+        let t = i as f32 / SOURCE_RATE as f32;
+        let envelope = 1.0 - i as f32 / frames as f32;
+        let value = (std::f32::consts::TAU * freq * t).sin() * envelope * 0.5;
+        let sample = (value * 32767.0) as i16;
+        wav.extend_from_slice(&sample.to_le_bytes());
+        wav.extend_from_slice(&(sample / 2).to_le_bytes());
+    }
+    wav
+}
+
+/// Decoded keysounds for a chart, of lengths between 0.1 s and 1.3 s.
+fn keysounds(count: usize) -> Vec<(Vec<f32>, usize)> {
+    (0..count)
+        .map(|i| {
+            let frames = SAMPLE_RATE as usize / 10 + (i * 7919) % (SAMPLE_RATE as usize * 6 / 5);
+            let freq = 110.0 * (1 + i % 24) as f32;
+            let samples = (0..frames)
+                .flat_map(|f| {
+                    let t = f as f32 / SAMPLE_RATE as f32;
+                    let v = (std::f32::consts::TAU * freq * t).sin() * 0.3;
+                    [v, v * 0.8]
+                })
+                .collect();
+            (samples, frames)
+        })
+        .collect()
+}
+
+/// Everything the timeline stages produce for a case.
+struct Timeline {
+    bms: Bms,
+    tempo_map: TempoMap,
+    filename_to_id: ahash::AHashMap<String, usize>,
+    sound_events: Vec<SoundEvent>,
+}
+
+fn timeline(case: &ChartCase) -> Timeline {
+    let bms = Bms::parse(&chart_text(case)).unwrap();
+    let tempo_map = build_tempo_map(&bms);
+    let (_, filename_to_id) = index_audio_files(&bms);
+    let sound_events =
+        extract_sound_events(&bms, &tempo_map, &filename_to_id, SAMPLE_RATE, CHANNELS);
+    Timeline {
+        bms,
+        tempo_map,
+        filename_to_id,
+        sound_events,
+    }
+}
+
+/// Inputs to the mixing stages with keysounds stored as `S`.
+struct MixInput<S> {
+    events: Vec<EventRef>,
+    decoded: Vec<(Vec<S>, usize)>,
+    total_len: usize,
+}
+
+fn mix_input<S: Sample>(case: &ChartCase, sound_events: &[SoundEvent]) -> MixInput<S> {
+    let decoded: Vec<(Vec<S>, usize)> = keysounds(case.keysounds)
+        .into_iter()
+        .map(|(samples, frames)| (to_storage(samples), frames))
+        .collect();
+    let prepared = prepare_events(sound_events, &decoded, CHANNELS);
+    MixInput {
+        events: prepared.events,
+        decoded,
+        total_len: prepared.total_len,
+    }
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    let file: Arc<[u8]> = wave_file(SOURCE_RATE as usize / 2, 440.0).into();
+    group.throughput(Throughput::Bytes(file.len() as u64));
+    let targets = [
+        ("passthrough", SOURCE_RATE, ResampleMethod::Linear),
+        ("linear_48k", 48000, ResampleMethod::Linear),
+        ("sinc_48k", 48000, ResampleMethod::Sinc),
+    ];
+    for (name, rate, quality) in targets {
+        group.bench_function(name, |b| {
+            b.iter(|| decode_audio(black_box(file.clone()), rate, CHANNELS, quality).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn timeline_stages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("timeline");
+    for case in &CASES {
+        let text = chart_text(case);
+        let t = timeline(case);
+        group.throughput(Throughput::Elements(t.sound_events.len() as u64));
+        group.bench_with_input(BenchmarkId::new("parse", case.name), &text, |b, text| {
+            b.iter(|| Bms::parse(black_box(text)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("tempo_map", case.name), &t, |b, t| {
+            b.iter(|| build_tempo_map(black_box(&t.bms)))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("extract_sound_events", case.name),
+            &t,
+            |b, t| {
+                b.iter(|| {
+                    extract_sound_events(
+                        black_box(&t.bms),
+                        &t.tempo_map,
+                        &t.filename_to_id,
+                        SAMPLE_RATE,
+                        CHANNELS,
+                    )
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Benchmark `precompute_overlaps` and a full pass of `mix_chunk` at several chunk sizes.
+fn mix_stages<S: Sample>(c: &mut Criterion, storage: &str) {
+    let mut group = c.benchmark_group(format!("mix_{storage}"));
+    group.sample_size(10);
+    for case in &CASES {
+        let t = timeline(case);
+        let input = mix_input::<S>(case, &t.sound_events);
+        group.throughput(Throughput::Elements(input.total_len as u64));
+        for chunk_frames in [4096, 16384, default_chunk_frames(SAMPLE_RATE)] {
+            let id = format!("{}/{chunk_frames}", case.name);
+            let (chunk_count, buckets) =
+                bucketize_events(&input.events, input.total_len, chunk_frames, CHANNELS);
+            group.bench_function(BenchmarkId::new("precompute_overlaps", &id), |b| {
+                b.iter(|| {
+                    precompute_overlaps(
+                        black_box(&input.events),
+                        &input.decoded,
+                        &buckets,
+                        input.total_len,
+                        chunk_frames,
+                        CHANNELS,
+                    )
+                })
+            });
+            let overlaps = precompute_overlaps(
+                &input.events,
+                &input.decoded,
+                &buckets,
+                input.total_len,
+                chunk_frames,
+                CHANNELS,
+            );
+            group.bench_function(BenchmarkId::new("mix_chunk", &id), |b| {
+                b.iter(|| {
+                    for ci in 0..chunk_count {
+                        black_box(mix_chunk(
+                            ci,
+                            &input.events,
+                            &input.decoded,
+                            &overlaps,
+                            input.total_len,
+                            chunk_frames,
+                            CHANNELS,
+                        ));
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn mix(c: &mut Criterion) {
+    mix_stages::<f32>(c, "f32");
+    mix_stages::<i16>(c, "i16");
+}
+
+criterion_group!(benches, decode, timeline_stages, mix);
+criterion_main!(benches);
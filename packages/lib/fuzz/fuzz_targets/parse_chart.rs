@@ -0,0 +1,40 @@
+//! Whole charts through the batch and streaming parsers and the timeline.
+
+#![no_main]
+
+use bmxtract::bms::{Bms, ParseOptions, extended_measure};
+use bmxtract::dtx::parse_dtx;
+use bmxtract::encoding::decode_text;
+use bmxtract::stream::StreamingParser;
+use bmxtract::timeline::{
+    TempoMapOptions, build_tempo_map_with_options, extract_sound_events, index_audio_files,
+};
+use libfuzzer_sys::fuzz_target;
+
+fn build_timeline(bms: &Bms) {
+    if let Ok(tempo_map) = build_tempo_map_with_options(bms, &TempoMapOptions::default()) {
+        let (_, filename_to_id) = index_audio_files(bms);
+        extract_sound_events(bms, &tempo_map, &filename_to_id, 8000, 2);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (text, _) = decode_text(data, None);
+    for strict in [false, true] {
+        let options = ParseOptions {
+            strict,
+            measure_parser: strict.then_some(extended_measure as _),
+            ..ParseOptions::default()
+        };
+        if let Ok((bms, _)) = Bms::parse_with_options(&text, &options) {
+            build_timeline(&bms);
+        }
+        // Odd chunk sizes split lines and multi-byte characters.
+        let mut parser = StreamingParser::new(options, None);
+        for chunk in data.chunks(7) {
+            parser.push_bytes(chunk);
+        }
+        let _ = parser.finish();
+    }
+    build_timeline(&parse_dtx(&text).0);
+});
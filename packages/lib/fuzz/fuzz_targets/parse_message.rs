@@ -0,0 +1,13 @@
+//! Single data and header lines.
+
+#![no_main]
+
+use bmxtract::bms::{Message, MsStop, extended_measure};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|line: &str| {
+    let _ = Message::parse(line);
+    let _ = Message::parse_with_base(line, 62);
+    let _ = Message::parse_with_measure_parser(line, 36, extended_measure);
+    let _ = MsStop::parse(line);
+});
@@ -0,0 +1,11 @@
+//! Container sniffing run on every keysound before it is decoded.
+
+#![no_main]
+
+use bmxtract::audio::{estimate_source, parse_wave};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_wave(data);
+    let _ = estimate_source(data, data.len() as u64);
+});
@@ -0,0 +1,215 @@
+use crate::bms::{Bms, Channel};
+use crate::mixer::{mix_range, prepare_events};
+use crate::timeline::{
+    InvalidBpm, SoundEventOptions, TempoMap, TempoMapOptions, build_tempo_map_with_options,
+    extract_sound_events_with_drops, index_audio_files,
+};
+use serde::Serialize;
+
+/// Length of the synthetic click that replaces every keysound, in seconds.
+const CLICK_SEC: f64 = 0.001;
+
+/// Level above which a rendered sample counts as the start of a click.
+const ONSET_THRESHOLD: f32 = 0.5;
+
+/// Default allowed distance between an expected and a detected onset, in milliseconds.
+pub const DEFAULT_ALIGNMENT_TOLERANCE_MS: f64 = 1.0;
+
+/// Settings of an alignment check.
+#[derive(Debug, Clone, Copy)]
+pub struct AlignmentOptions {
+    /// Sample rate the chart is rendered at.
+    pub sample_rate: u32,
+    /// Allowed distance between an expected and a detected onset, in milliseconds.
+    pub tolerance_ms: f64,
+    /// Tempo map settings, as used for the real render.
+    pub tempo_map: TempoMapOptions,
+}
+
+impl Default for AlignmentOptions {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            tolerance_ms: DEFAULT_ALIGNMENT_TOLERANCE_MS,
+            tempo_map: TempoMapOptions::default(),
+        }
+    }
+}
+
+/// Outcome of rendering a chart with clicks and comparing onsets to the tempo map.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlignmentReport {
+    /// Onset times predicted by the tempo map, in seconds.
+    pub expected_sec: Vec<f64>,
+    /// Onset times found in the rendered audio, in seconds.
+    pub detected_sec: Vec<f64>,
+    /// Largest distance between a matched pair of onsets, in milliseconds.
+    pub max_error_ms: f64,
+    /// Expected onsets with no detected onset within the tolerance.
+    pub missing_sec: Vec<f64>,
+    /// Detected onsets with no expected onset within the tolerance.
+    pub unexpected_sec: Vec<f64>,
+    /// Whether every onset was matched within the tolerance.
+    pub passed: bool,
+}
+
+/// Predict note onsets directly from the tempo map.
+///
+/// Long-note channels and `#LNOBJ` tails are left out, since release objects
+/// are silent.
+/// Onsets that start while an earlier click still sounds merge into a single
+/// onset, as they do in the rendered audio.
+fn expected_onsets(
+    bms: &Bms,
+    tempo_map: &TempoMap,
+    sample_rate: u32,
+    click_frames: usize,
+) -> Vec<f64> {
+    let mut times = Vec::new();
+    for message in &bms.messages {
+        if !matches!(message.channel, Channel::Bgm | Channel::Note { .. }) {
+            continue;
+        }
+        let len = message.objects.len() as f64;
+        for (i, object) in message.objects.iter().enumerate() {
+            if Some(*object) != bms.header.ln_obj && bms.header.audio_files.contains_key(object) {
+                times.push(tempo_map.get_timestamp(message.measure, i as f64 / len));
+            }
+        }
+    }
+    times.sort_by(f64::total_cmp);
+    let mut merged: Vec<f64> = Vec::with_capacity(times.len());
+    let mut sounding_until = 0usize;
+    for time in times {
+        let frame = (time * sample_rate as f64).round() as usize;
+        if merged.is_empty() || frame > sounding_until {
+            merged.push(time);
+        }
+        sounding_until = sounding_until.max(frame + click_frames);
+    }
+    merged
+}
+
+/// Find the rising edges of the clicks in a mono render.
+fn detect_onsets(samples: &[f32], sample_rate: u32) -> Vec<f64> {
+    let mut onsets = Vec::new();
+    let mut above = false;
+    for (i, s) in samples.iter().enumerate() {
+        let loud = s.abs() >= ONSET_THRESHOLD;
+        if loud && !above {
+            onsets.push(i as f64 / sample_rate as f64);
+        }
+        above = loud;
+    }
+    onsets
+}
+
+/// Render a chart with a synthetic click per note and check the clicks land on the tempo map.
+///
+/// Every keysound is replaced by the same short click and rendered through
+/// the regular event extraction and mixing steps, so the check covers the
+/// whole timing path without needing the chart's audio files.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `options` - Render rate, tolerance and tempo map settings.
+///
+/// # Returns
+///
+/// * `Result<AlignmentReport, InvalidBpm>` - Onset comparison, or the tempo error that stopped it.
+pub fn verify_alignment(
+    bms: &Bms,
+    options: &AlignmentOptions,
+) -> Result<AlignmentReport, InvalidBpm> {
+    let sample_rate = options.sample_rate;
+    let tempo_map = build_tempo_map_with_options(bms, &options.tempo_map)?;
+    let (filenames, filename_to_id) = index_audio_files(bms);
+    // Only note onsets are checked, so `#LNOBJ` releases stay silent.
+    let event_options = SoundEventOptions {
+        silent_ln_tails: true,
+        ..SoundEventOptions::default()
+    };
+    let (mut sound_events, _) = extract_sound_events_with_drops(
+        bms,
+        &tempo_map,
+        &filename_to_id,
+        sample_rate,
+        1,
+        &event_options,
+    );
+    sound_events.retain(|ev| !matches!(ev.channel, Channel::LongNote { .. }));
+    for ev in &mut sound_events {
+        // Timing is what is being checked, so muted keysounds still click.
+        ev.gain = 1.0;
+    }
+
+    let click_frames = ((CLICK_SEC * sample_rate as f64).round() as usize).max(1);
+    let click = (vec![1.0f32; click_frames], click_frames);
+    let decoded = vec![click; filenames.len()];
+    let prepared = prepare_events(&sound_events, &decoded, 1);
+
+    let mut detected_sec = Vec::new();
+    let chunk = sample_rate as usize;
+    let mut start = 0;
+    let mut tail = 0.0f32;
+    while start < prepared.total_len {
+        let len = chunk.min(prepared.total_len - start);
+        let mut samples = mix_range(&prepared.events, &decoded, start, len);
+        // Keep the previous chunk's last sample so an edge is not counted twice.
+        samples.insert(0, tail);
+        tail = samples[len];
+        detected_sec.extend(
+            detect_onsets(&samples, sample_rate)
+                .into_iter()
+                .map(|t| t - 1.0 / sample_rate as f64 + start as f64 / sample_rate as f64),
+        );
+        start += len;
+    }
+
+    let expected_sec = expected_onsets(bms, &tempo_map, sample_rate, click_frames);
+    let tolerance_sec = options.tolerance_ms / 1000.0;
+    let mut max_error_ms = 0.0f64;
+    let mut missing_sec = Vec::new();
+    let mut unexpected_sec = Vec::new();
+    let (mut e, mut d) = (0, 0);
+    while e < expected_sec.len() && d < detected_sec.len() {
+        let error = detected_sec[d] - expected_sec[e];
+        if error.abs() <= tolerance_sec {
+            max_error_ms = max_error_ms.max(error.abs() * 1000.0);
+            e += 1;
+            d += 1;
+        } else if error < 0.0 {
+            unexpected_sec.push(detected_sec[d]);
+            d += 1;
+        } else {
+            missing_sec.push(expected_sec[e]);
+            e += 1;
+        }
+    }
+    missing_sec.extend_from_slice(&expected_sec[e..]);
+    unexpected_sec.extend_from_slice(&detected_sec[d..]);
+
+    Ok(AlignmentReport {
+        passed: missing_sec.is_empty() && unexpected_sec.is_empty(),
+        expected_sec,
+        detected_sec,
+        max_error_ms,
+        missing_sec,
+        unexpected_sec,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lnobj_tails_are_not_onsets() {
+        let bms =
+            Bms::parse("#BPM 120\n#WAV01 a.wav\n#WAV02 b.wav\n#LNOBJ 02\n#00111:0102\n").unwrap();
+        let report = verify_alignment(&bms, &AlignmentOptions::default()).unwrap();
+        assert_eq!(report.expected_sec, [0.0]);
+        assert!(report.passed, "{report:?}");
+    }
+}
@@ -0,0 +1,261 @@
+use crate::bms::{Bms, MeasureIndex};
+use crate::mixer::{EventRef, Sample, mix_range};
+use crate::timeline::TempoMap;
+use serde::Serialize;
+
+/// Note divisions tested by `detect_snap`, from coarsest to finest.
+pub const SNAP_DIVISIONS: [u32; 10] = [4, 8, 12, 16, 24, 32, 48, 64, 96, 192];
+
+/// Tolerance when checking whether a position lands on a grid line.
+const SNAP_EPSILON: f64 = 1e-6;
+
+/// Quantization of a single note position.
+#[derive(Debug, Clone)]
+pub struct NoteSnap {
+    /// Measure index of the note.
+    pub measure: MeasureIndex,
+    /// Channel identifier of the note.
+    pub channel: u16,
+    /// Position within the measure (0.0..1.0).
+    pub position: f64,
+    /// Coarsest note division the note lies on (4 = quarter, 12 = triplet eighth, ...),
+    /// or `None` if it is finer than 1/192.
+    pub snap: Option<u32>,
+}
+
+/// Detect the coarsest note division a position lies on.
+///
+/// # Arguments
+///
+/// * `index` - Object index within the message.
+/// * `num_objects` - Number of objects in the message.
+/// * `measure_multiplier` - Length multiplier of the measure (1.0 = 4/4).
+///
+/// # Returns
+///
+/// * `Option<u32>` - Note division (e.g. 16 for 1/16), or `None` if off-grid.
+pub fn detect_snap(index: usize, num_objects: usize, measure_multiplier: f64) -> Option<u32> {
+    if num_objects == 0 {
+        return None;
+    }
+    let fraction = index as f64 / num_objects as f64;
+    SNAP_DIVISIONS.iter().copied().find(|&div| {
+        // Number of 1/div notes between the start of the measure and this note.
+        let steps = fraction * measure_multiplier * div as f64;
+        (steps - steps.round()).abs() < SNAP_EPSILON
+    })
+}
+
+/// Compute the snap of every keysounded note in a chart.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+///
+/// # Returns
+///
+/// * `Vec<NoteSnap>` - One entry per non-zero object on sound channels, in message order.
+pub fn note_snaps(bms: &Bms) -> Vec<NoteSnap> {
+    let mut snaps = Vec::new();
+    for message in &bms.messages {
+        if !message.channel.is_sound() {
+            continue;
+        }
+        let num_objects = message.objects.len();
+        let mult = bms
+            .measure_multipliers
+            .get(&message.measure)
+            .copied()
+            .unwrap_or(1.0);
+        for (i, object) in message.objects.iter().enumerate() {
+            if *object == 0 {
+                continue;
+            }
+            snaps.push(NoteSnap {
+                measure: message.measure,
+                channel: message.channel.code(),
+                position: i as f64 / num_objects as f64,
+                snap: detect_snap(i, num_objects, mult),
+            });
+        }
+    }
+    snaps
+}
+
+/// Frames per analysis window when comparing loop boundaries.
+const LOOP_WINDOW_FRAMES: usize = 2048;
+/// Number of DFT bins in a boundary spectrum.
+const LOOP_SPECTRUM_BINS: usize = 48;
+
+/// A measure-aligned loop region of a render.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LoopRegion {
+    /// Measure where the loop starts.
+    pub start_measure: MeasureIndex,
+    /// Measure where the loop ends (exclusive, the loop jumps back at its start).
+    pub end_measure: MeasureIndex,
+    /// Loop start in frames.
+    pub start_frame: usize,
+    /// Loop end in frames (exclusive).
+    pub end_frame: usize,
+    /// Cosine similarity of the spectra at both boundaries (1.0 = identical).
+    pub similarity: f32,
+}
+
+/// Magnitude spectrum of a mono downmix of the window starting at `frame`.
+fn boundary_spectrum<S: Sample>(
+    events: &[EventRef],
+    decoded: &[(Vec<S>, usize)],
+    frame: usize,
+    channels: usize,
+) -> Vec<f32> {
+    let window = mix_range(
+        events,
+        decoded,
+        frame * channels,
+        LOOP_WINDOW_FRAMES * channels,
+    );
+    let mono: Vec<f32> = window
+        .chunks(channels)
+        .enumerate()
+        .map(|(i, frame)| {
+            // Hann window to limit spectral leakage.
+            let w = 0.5
+                - 0.5 * (std::f32::consts::TAU * i as f32 / (LOOP_WINDOW_FRAMES - 1) as f32).cos();
+            frame.iter().sum::<f32>() / channels as f32 * w
+        })
+        .collect();
+    (1..=LOOP_SPECTRUM_BINS)
+        .map(|k| {
+            // Bins are spread quadratically to favor low and mid frequencies.
+            let bin = (k * k) as f32 * (LOOP_WINDOW_FRAMES / 2) as f32
+                / (LOOP_SPECTRUM_BINS * LOOP_SPECTRUM_BINS) as f32;
+            let omega = std::f32::consts::TAU * bin / LOOP_WINDOW_FRAMES as f32;
+            let (mut re, mut im) = (0.0f32, 0.0f32);
+            for (n, &x) in mono.iter().enumerate() {
+                let (sin, cos) = (omega * n as f32).sin_cos();
+                re += x * cos;
+                im -= x * sin;
+            }
+            (re * re + im * im).sqrt()
+        })
+        .collect()
+}
+
+/// Find the measure-aligned region whose start and end sound most alike.
+///
+/// # Arguments
+///
+/// * `events` - Prepared events.
+/// * `decoded` - Decoded audio sources.
+/// * `tempo_map` - Tempo map of the chart.
+/// * `total_len` - Render length in interleaved samples.
+/// * `sample_rate` - Output sample rate.
+/// * `channels` - Number of output channels.
+/// * `min_measures` - Minimum loop length in measures.
+///
+/// # Returns
+///
+/// * `Option<LoopRegion>` - Best loop region, or `None` if the chart is too short.
+pub fn find_loop_region<S: Sample>(
+    events: &[EventRef],
+    decoded: &[(Vec<S>, usize)],
+    tempo_map: &TempoMap,
+    total_len: usize,
+    sample_rate: u32,
+    channels: usize,
+    min_measures: MeasureIndex,
+) -> Option<LoopRegion> {
+    let total_frames = total_len / channels;
+    let boundaries: Vec<(MeasureIndex, usize)> = (tempo_map.base_measure
+        ..=tempo_map.last_measure())
+        .map(|m| (m, tempo_map.get_timestamp_samples(m, 0.0, sample_rate)))
+        .filter(|&(_, frame)| frame + LOOP_WINDOW_FRAMES <= total_frames)
+        .collect();
+    let spectra: Vec<Vec<f32>> = boundaries
+        .iter()
+        .map(|&(_, frame)| boundary_spectrum(events, decoded, frame, channels))
+        .collect();
+    let norms: Vec<f32> = spectra
+        .iter()
+        .map(|s| s.iter().map(|v| v * v).sum::<f32>().sqrt())
+        .collect();
+
+    let mut best: Option<LoopRegion> = None;
+    for a in 0..boundaries.len() {
+        if norms[a] == 0.0 {
+            continue;
+        }
+        for b in a + 1..boundaries.len() {
+            if boundaries[b].0 - boundaries[a].0 < min_measures || norms[b] == 0.0 {
+                continue;
+            }
+            let dot: f32 = spectra[a].iter().zip(&spectra[b]).map(|(x, y)| x * y).sum();
+            let similarity = dot / (norms[a] * norms[b]);
+            // Later pairs are longer loops, so ties keep the longer region.
+            if best.is_none_or(|r| similarity >= r.similarity) {
+                best = Some(LoopRegion {
+                    start_measure: boundaries[a].0,
+                    end_measure: boundaries[b].0,
+                    start_frame: boundaries[a].1,
+                    end_frame: boundaries[b].1,
+                    similarity,
+                });
+            }
+        }
+    }
+    best
+}
+
+/// A stretch of the timeline where no event is scheduled.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SilenceGap {
+    /// Gap start in seconds.
+    pub start_sec: f64,
+    /// Gap end in seconds.
+    pub end_sec: f64,
+}
+
+/// Find long stretches without any scheduled audio.
+///
+/// Such gaps usually point at broken `#RANDOM` handling or missing `#WAV`
+/// definitions rather than intentional rests. Silence before the first event
+/// counts as a gap, so a chart whose intro failed to load is reported too.
+///
+/// # Arguments
+///
+/// * `events` - Prepared events, sorted by start.
+/// * `total_len` - Render length in interleaved samples.
+/// * `sample_rate` - Output sample rate.
+/// * `channels` - Number of output channels.
+/// * `min_gap_sec` - Shortest gap to report, in seconds.
+///
+/// # Returns
+///
+/// * `Vec<SilenceGap>` - Gaps in timeline order.
+pub fn find_silence_gaps(
+    events: &[EventRef],
+    total_len: usize,
+    sample_rate: u32,
+    channels: usize,
+    min_gap_sec: f64,
+) -> Vec<SilenceGap> {
+    let samples_per_sec = sample_rate as f64 * channels as f64;
+    let min_gap = (min_gap_sec * samples_per_sec) as usize;
+    let mut gaps = Vec::new();
+    let mut covered_until = 0usize;
+    let mut push_gap = |start: usize, end: usize| {
+        if end > start && end - start >= min_gap {
+            gaps.push(SilenceGap {
+                start_sec: start as f64 / samples_per_sec,
+                end_sec: end as f64 / samples_per_sec,
+            });
+        }
+    };
+    for ev in events {
+        push_gap(covered_until, ev.start);
+        covered_until = covered_until.max(ev.end);
+    }
+    push_gap(covered_until, total_len);
+    gaps
+}
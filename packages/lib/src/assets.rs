@@ -0,0 +1,400 @@
+use crate::bms::{
+    Bms, ChartMetadata, ChartMode, Header, ObjectId, ParseOptions, format_object_id_with_base,
+};
+use crate::control::random_variants;
+use crate::pitch::PitchEstimate;
+use crate::timeline::{build_tempo_map, extract_sound_events, index_audio_files};
+use serde::Serialize;
+
+/// Audio extensions tried when the file named in the chart does not exist.
+pub const AUDIO_EXTENSIONS: [&str; 7] = ["wav", "ogg", "mp3", "flac", "aiff", "aif", "aifc"];
+
+/// Image extensions tried for stage files, banners and backgrounds.
+pub const IMAGE_EXTENSIONS: [&str; 4] = ["bmp", "png", "jpg", "jpeg"];
+
+/// Image and video extensions tried for `#BMPxx` slots, which may hold BGA videos.
+pub const BGA_EXTENSIONS: [&str; 9] = [
+    "bmp", "png", "jpg", "jpeg", "mpg", "mpeg", "mp4", "avi", "wmv",
+];
+
+/// Build the list of filenames to try for a referenced asset.
+///
+/// The original name comes first, followed by the same stem with each of the
+/// given extensions (charts frequently reference `.wav` but ship `.ogg`).
+///
+/// # Arguments
+///
+/// * `name` - Filename as written in the chart.
+/// * `extensions` - Fallback extensions without the leading dot.
+///
+/// # Returns
+///
+/// * `Vec<String>` - Candidate filenames in lookup order, without duplicates.
+pub fn filename_candidates(name: &str, extensions: &[&str]) -> Vec<String> {
+    let stem = match name.rfind('.') {
+        Some(dot) if !name[dot..].contains(['/', '\\']) => &name[..dot],
+        _ => name,
+    };
+    let mut candidates = vec![name.to_string()];
+    for ext in extensions {
+        let candidate = format!("{}.{}", stem, ext);
+        if !candidates
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(&candidate))
+        {
+            candidates.push(candidate);
+        }
+    }
+    candidates
+}
+
+/// A keysound file referenced by a chart, with how often it is triggered.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeysoundUsage {
+    /// Filename as written in the chart.
+    pub filename: String,
+    /// `#WAVxx` slots (as two-character tokens) pointing at this file.
+    pub object_ids: Vec<String>,
+    /// Filenames to try when loading, in order.
+    pub candidates: Vec<String>,
+    /// Number of sound events that trigger this file.
+    pub event_count: usize,
+    /// Estimated pitch, filled in once the file has been decoded and analyzed.
+    pub pitch: Option<PitchEstimate>,
+}
+
+/// List the keysound files of a chart ordered by importance (most-triggered first).
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+///
+/// # Returns
+///
+/// * `Vec<KeysoundUsage>` - One entry per distinct filename in the `#WAV` table.
+pub fn keysound_usage(bms: &Bms) -> Vec<KeysoundUsage> {
+    let (filenames, filename_to_id) = index_audio_files(bms);
+    let tempo_map = build_tempo_map(bms);
+    let events = extract_sound_events(bms, &tempo_map, &filename_to_id, 44100, 1);
+
+    let mut counts = vec![0usize; filenames.len()];
+    for ev in &events {
+        counts[ev.key_id] += 1;
+    }
+
+    let mut slots: Vec<(&ObjectId, &String)> = bms.header.audio_files.iter().collect();
+    slots.sort();
+
+    let mut usage: Vec<KeysoundUsage> = filenames
+        .iter()
+        .zip(counts)
+        .map(|(filename, event_count)| KeysoundUsage {
+            filename: filename.clone(),
+            object_ids: slots
+                .iter()
+                .filter(|(_, f)| *f == filename)
+                .map(|(id, _)| format_object_id_with_base(**id, bms.header.object_base()))
+                .collect(),
+            candidates: filename_candidates(filename, &AUDIO_EXTENSIONS),
+            event_count,
+            pitch: None,
+        })
+        .collect();
+    usage.sort_by(|a, b| {
+        b.event_count
+            .cmp(&a.event_count)
+            .then_with(|| a.filename.cmp(&b.filename))
+    });
+    usage
+}
+
+/// Compute the union of audio files actually triggered by several charts.
+///
+/// Intended for song folders with multiple difficulties, so the host can
+/// prefetch every needed keysound in a single request.
+///
+/// # Arguments
+///
+/// * `charts` - Parsed charts sharing the same folder.
+///
+/// # Returns
+///
+/// * `Vec<String>` - Sorted, deduplicated filenames with at least one event.
+pub fn required_audio_union(charts: &[Bms]) -> Vec<String> {
+    let mut paths: Vec<String> = charts
+        .iter()
+        .flat_map(|bms| {
+            keysound_usage(bms)
+                .into_iter()
+                .filter(|usage| usage.event_count > 0)
+                .map(|usage| usage.filename)
+        })
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Most `#RANDOM` variants of one chart whose keysounds `discover_song` collects.
+pub const MAX_SONG_VARIANTS: usize = 64;
+
+/// Chart extensions recognized in a song folder.
+pub const CHART_EXTENSIONS: [&str; 5] = ["bms", "bme", "bml", "pms", "dtx"];
+
+/// Button layout implied by a chart filename, or `None` if it is not a chart.
+///
+/// # Arguments
+///
+/// * `path` - Filename or path within the song folder.
+///
+/// # Returns
+///
+/// * `Option<ChartMode>` - `Pms` for `.pms`, `Dtx` for `.dtx`, `Bms` for the other chart extensions.
+pub fn chart_mode_for_path(path: &str) -> Option<ChartMode> {
+    let (_, ext) = path.rsplit_once('.')?;
+    let ext = ext.to_ascii_lowercase();
+    if !CHART_EXTENSIONS.contains(&ext.as_str()) {
+        return None;
+    }
+    Some(match ext.as_str() {
+        "pms" => ChartMode::Pms,
+        "dtx" => ChartMode::Dtx,
+        _ => ChartMode::Bms,
+    })
+}
+
+/// One difficulty of a song folder.
+#[derive(Debug, Clone, Serialize)]
+pub struct SongChart {
+    /// Path of the chart within the folder.
+    pub path: String,
+    /// Button layout the chart was parsed for.
+    pub mode: ChartMode,
+    /// Display metadata of the chart.
+    pub metadata: ChartMetadata,
+    /// Audio files triggered by this chart in any of its `#RANDOM` and
+    /// `#SWITCH` branches, sorted.
+    pub required_files: Vec<String>,
+}
+
+/// Charts of a song folder grouped as one song.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SongFolder {
+    /// Song title, without difficulty tags such as `[ANOTHER]`.
+    pub title: Option<String>,
+    /// Song artist.
+    pub artist: Option<String>,
+    /// Charts ordered by difficulty, then level, then path.
+    pub charts: Vec<SongChart>,
+    /// Union of the audio files triggered by any chart, sorted.
+    pub required_files: Vec<String>,
+    /// Chart files that could not be parsed.
+    pub unreadable: Vec<String>,
+}
+
+/// Strip a trailing difficulty tag (`[...]`, `(...)` or `-...-`) from a title.
+fn song_title(title: &str) -> &str {
+    let title = title.trim_end();
+    for (open, close) in [('[', ']'), ('(', ')'), ('-', '-')] {
+        if let Some(body) = title.strip_suffix(close)
+            && let Some(start) = body.rfind(open)
+            && !body[..start].trim_end().is_empty()
+        {
+            return body[..start].trim_end();
+        }
+    }
+    title
+}
+
+/// Most frequent value, ties going to the first seen.
+fn most_common<'a>(values: impl Iterator<Item = &'a str>) -> Option<String> {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for value in values {
+        match counts.iter_mut().find(|(v, _)| *v == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1)),
+        }
+    }
+    let best = counts.iter().map(|(_, count)| *count).max()?;
+    counts
+        .into_iter()
+        .find(|(_, count)| *count == best)
+        .map(|(value, _)| value.to_string())
+}
+
+/// Identify the charts of a song folder and group them as one song.
+///
+/// Files without a chart extension are ignored, so a full folder or zip
+/// listing can be passed as is. The union of required keysounds lets hosts
+/// fetch and decode audio once for every difficulty. Keysounds of every
+/// control-flow branch are included, up to `MAX_SONG_VARIANTS` variants per
+/// chart, since any of them may be drawn.
+///
+/// # Arguments
+///
+/// * `files` - Path and text content of each file in the folder.
+///
+/// # Returns
+///
+/// * `SongFolder` - The grouped charts and their shared keysounds.
+pub fn discover_song<'a>(files: impl IntoIterator<Item = (&'a str, &'a str)>) -> SongFolder {
+    let mut song = SongFolder::default();
+    for (path, text) in files {
+        let Some(mode) = chart_mode_for_path(path) else {
+            continue;
+        };
+        let options = ParseOptions {
+            mode,
+            ..ParseOptions::default()
+        };
+        match Bms::parse_with_options(text, &options) {
+            Ok((bms, _)) => {
+                let metadata = bms.header.metadata();
+                let mut variants = vec![bms];
+                for forced_random in random_variants(text, MAX_SONG_VARIANTS).variants {
+                    // A chart without control flow has one variant with no choices.
+                    if forced_random.is_empty() {
+                        continue;
+                    }
+                    let options = ParseOptions {
+                        forced_random,
+                        ..options.clone()
+                    };
+                    if let Ok((variant, _)) = Bms::parse_with_options(text, &options) {
+                        variants.push(variant);
+                    }
+                }
+                song.charts.push(SongChart {
+                    path: path.to_string(),
+                    mode,
+                    metadata,
+                    required_files: required_audio_union(&variants),
+                });
+            }
+            Err(_) => song.unreadable.push(path.to_string()),
+        }
+    }
+
+    song.charts.sort_by(|a, b| {
+        a.metadata
+            .difficulty
+            .cmp(&b.metadata.difficulty)
+            .then(a.metadata.play_level.cmp(&b.metadata.play_level))
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    song.title = most_common(
+        song.charts
+            .iter()
+            .filter_map(|chart| chart.metadata.title.as_deref())
+            .map(song_title),
+    );
+    song.artist = most_common(
+        song.charts
+            .iter()
+            .filter_map(|chart| chart.metadata.artist.as_deref()),
+    );
+    song.required_files = song
+        .charts
+        .iter()
+        .flat_map(|chart| chart.required_files.iter().cloned())
+        .collect();
+    song.required_files.sort();
+    song.required_files.dedup();
+    song
+}
+
+/// Role of a non-audio asset referenced by a chart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AssetKind {
+    /// `#STAGEFILE` loading image.
+    StageFile,
+    /// `#BANNER` image.
+    Banner,
+    /// `#BACKBMP` background image.
+    BackBmp,
+    /// `#BMPxx` BGA image or video.
+    Bmp,
+    /// `#PREVIEW` song select audio.
+    Preview,
+}
+
+/// A non-audio asset referenced by a chart.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChartAsset {
+    /// Role of the asset.
+    pub kind: AssetKind,
+    /// Filename as written in the chart.
+    pub filename: String,
+    /// `#BMPxx` slot as a two-character token, for BGA assets.
+    pub object_id: Option<String>,
+    /// Filenames to try when loading, in order.
+    pub candidates: Vec<String>,
+}
+
+/// List every non-audio asset referenced by a chart header.
+///
+/// Charts without any `#BMPxx` are displayed with the stage file as their
+/// background, so the stage file is listed first and is always worth
+/// prefetching even when no BGA is defined.
+///
+/// # Arguments
+///
+/// * `header` - Parsed header (from `Bms::parse_header` or a full parse).
+///
+/// # Returns
+///
+/// * `Vec<ChartAsset>` - Stage file, banner, background, preview, then BGA slots by id.
+pub fn chart_assets(header: &Header) -> Vec<ChartAsset> {
+    let single = [
+        (
+            AssetKind::StageFile,
+            &header.stage_file,
+            &IMAGE_EXTENSIONS[..],
+        ),
+        (AssetKind::Banner, &header.banner, &IMAGE_EXTENSIONS[..]),
+        (AssetKind::BackBmp, &header.back_bmp, &IMAGE_EXTENSIONS[..]),
+        (AssetKind::Preview, &header.preview, &AUDIO_EXTENSIONS[..]),
+    ];
+    let mut assets: Vec<ChartAsset> = single
+        .into_iter()
+        .filter_map(|(kind, filename, extensions)| {
+            let filename = filename.as_ref().filter(|f| !f.is_empty())?;
+            Some(ChartAsset {
+                kind,
+                filename: filename.clone(),
+                object_id: None,
+                candidates: filename_candidates(filename, extensions),
+            })
+        })
+        .collect();
+
+    let mut slots: Vec<(&ObjectId, &String)> = header.bmp_files.iter().collect();
+    slots.sort();
+    assets.extend(slots.into_iter().map(|(id, filename)| ChartAsset {
+        kind: AssetKind::Bmp,
+        filename: filename.clone(),
+        object_id: Some(format_object_id_with_base(*id, header.object_base())),
+        candidates: filename_candidates(filename, &BGA_EXTENSIONS),
+    }));
+    assets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_files_cover_every_random_branch() {
+        let chart = "#TITLE Song [HYPER]\n#WAV01 common.wav\n#WAV02 one.wav\n\
+                     #WAV03 two.wav\n#WAV04 unused.wav\n#00101:01\n\
+                     #RANDOM 2\n#IF 1\n#00111:02\n#ENDIF\n#IF 2\n#00111:03\n#ENDIF\n#ENDRANDOM\n";
+        let song = discover_song([("song.bms", chart), ("notes.txt", "")]);
+        assert_eq!(song.title.as_deref(), Some("Song"));
+        assert_eq!(song.charts.len(), 1);
+        assert_eq!(
+            song.charts[0].required_files,
+            ["common.wav", "one.wav", "two.wav"]
+        );
+        assert_eq!(song.required_files, song.charts[0].required_files);
+    }
+}
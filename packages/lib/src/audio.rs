@@ -1,17 +1,194 @@
-use crate::wasm::ResampleMethod;
+use num_enum::TryFromPrimitive;
 use rubato::{FastFixedIn, Resampler};
+use serde::{Deserialize, Serialize};
 use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::sync::Arc;
-use symphonia::core::audio::{AudioBufferRef, Signal};
-use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Channels, Signal};
+
+use symphonia::core::codecs::{Decoder, DecoderOptions};
 use symphonia::core::errors::Error as SymphoniaError;
-use symphonia::core::formats::FormatOptions;
+use symphonia::core::formats::{FormatOptions, FormatReader};
 use symphonia::core::io::{MediaSource, MediaSourceStream, MediaSourceStreamOptions};
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
+use symphonia::core::sample::Sample;
+use wide::{f32x8, i32x8};
+
+/// Input frames per block fed to the sinc resampler.
+const SINC_CHUNK_SIZE: usize = 1024;
+
+/// Resampling algorithm used when a source rate differs from the target rate.
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, TryFromPrimitive, Serialize)]
+pub enum ResampleMethod {
+    Linear,
+    Sinc,
+}
+
+impl<'de> Deserialize<'de> for ResampleMethod {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct ResampleQualityVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ResampleQualityVisitor {
+            type Value = ResampleMethod;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an i64")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                ResampleMethod::try_from(value as u8)
+                    .map_err(|_| E::custom("Invalid ResampleQuality"))
+            }
+        }
+
+        deserializer.deserialize_any(ResampleQualityVisitor)
+    }
+}
+
+/// Sample rates and problems observed while decoding a file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeInfo {
+    /// Rate declared by the container or codec headers.
+    pub container_rate: Option<u32>,
+    /// Rate reported by the decoded packets, which is the one used for resampling.
+    pub stream_rate: Option<u32>,
+    /// Decoding stopped at an error partway through the file; the audio
+    /// decoded before it was kept.
+    pub truncated: bool,
+}
+
+impl DecodeInfo {
+    /// Describe a disagreement between declared and decoded sample rates.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Warning message, or `None` if the rates agree.
+    pub fn rate_mismatch(&self) -> Option<String> {
+        match (self.container_rate, self.stream_rate) {
+            (Some(container), Some(stream)) if container != stream => Some(format!(
+                "header declares {} Hz but the stream decodes at {} Hz",
+                container, stream
+            )),
+            _ => None,
+        }
+    }
+
+    /// Describe an early end of decoding.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Warning message, or `None` if the whole file was decoded.
+    pub fn truncation(&self) -> Option<String> {
+        self.truncated.then(|| {
+            "file is truncated or corrupt; only the audio before the error is used".to_string()
+        })
+    }
+}
+
+/// Per-file guards applied by `decode_audio_with_limits`. `None` means unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    /// Largest encoded file accepted, in bytes.
+    pub max_file_bytes: Option<usize>,
+    /// Longest wall-clock time spent decoding one file, in milliseconds.
+    pub max_decode_ms: Option<f64>,
+    /// Current time in milliseconds, used to enforce `max_decode_ms`.
+    ///
+    /// The default reads the system clock, which does not exist on
+    /// `wasm32-unknown-unknown`; hosts there must supply their own.
+    pub clock: fn() -> f64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_file_bytes: None,
+            max_decode_ms: None,
+            clock: system_clock_ms,
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch, or `0` where no system clock is available.
+fn system_clock_ms() -> f64 {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0.0, |elapsed| elapsed.as_secs_f64() * 1000.0)
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        0.0
+    }
+}
+
+/// Reason a file could not be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// The encoded file is larger than `DecodeLimits::max_file_bytes`.
+    TooLarge { size: usize, limit: usize },
+    /// Decoding ran longer than `DecodeLimits::max_decode_ms`.
+    TimedOut { limit_ms: f64 },
+    /// The data is not audio that can be decoded.
+    Invalid(String),
+}
+
+impl DecodeError {
+    /// Whether the file was rejected by a `DecodeLimits` guard rather than
+    /// failing to decode.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` for `TooLarge` and `TimedOut`.
+    pub fn is_limit(&self) -> bool {
+        matches!(
+            self,
+            DecodeError::TooLarge { .. } | DecodeError::TimedOut { .. }
+        )
+    }
+}
+
+impl From<String> for DecodeError {
+    fn from(message: String) -> Self {
+        DecodeError::Invalid(message)
+    }
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DecodeError::TooLarge { size, limit } => write!(
+                f,
+                "file is {} bytes, over the per-file limit of {} bytes",
+                size, limit
+            ),
+            DecodeError::TimedOut { limit_ms } => {
+                write!(f, "decoding took longer than {} ms", limit_ms)
+            }
+            DecodeError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
 
 /// Decode audio from a buffer of bytes
 ///
+/// Sources with more than two channels are downmixed to stereo with ITU-R
+/// BS.775 coefficients, scaled down so full-scale input on every channel
+/// cannot clip. This lowers the whole source: for 5.1, front left and right
+/// end up at a gain of about 0.414 (-7.7 dB) and the centre and surrounds at
+/// about 0.293 (-10.7 dB).
+///
 /// # Arguments
 ///
 /// * `data` - Input audio data as Arc<[u8]>
@@ -28,352 +205,1294 @@ pub fn decode_audio(
     target_ch: usize,
     quality: ResampleMethod,
 ) -> Result<(Vec<f32>, usize), String> {
+    decode_audio_with_info(data, target_sr, target_ch, quality).map(|(decoded, _)| decoded)
+}
+
+/// Decode audio like `decode_audio`, also reporting the sample rates that were seen.
+///
+/// Some files declare a rate in their headers that differs from what the
+/// decoder actually produces; the decoded packet rate wins so the keysound is
+/// not detuned, and the disagreement is available through `DecodeInfo`.
+///
+/// A file that fails partway through (such as a truncated MP3) keeps the
+/// audio decoded before the failure and is flagged in `DecodeInfo`; only a
+/// file that yields no audio at all is an error.
+///
+/// # Arguments
+///
+/// * `data` - Input audio data as Arc<[u8]>
+/// * `target_sr` - Target sample rate to resample to
+/// * `target_ch` - Target number of channels
+/// * `quality` - Resampling quality
+///
+/// # Returns
+///
+/// * `Result<((Vec<f32>, usize), DecodeInfo), String>` - Decoded samples and frame count with rate info, or error message
+pub fn decode_audio_with_info(
+    data: Arc<[u8]>,
+    target_sr: u32,
+    target_ch: usize,
+    quality: ResampleMethod,
+) -> Result<((Vec<f32>, usize), DecodeInfo), String> {
+    decode_audio_with_limits(
+        data,
+        target_sr,
+        target_ch,
+        quality,
+        None,
+        &DecodeLimits::default(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Decode audio like `decode_audio_with_info`, giving up on files that exceed `limits`.
+///
+/// The size is checked before probing. The time limit is checked between
+/// packets, so a single packet always finishes, but a file that would take
+/// minutes to decode (such as a video renamed to `.wav`) is abandoned once
+/// the limit passes.
+///
+/// # Arguments
+///
+/// * `data` - Input audio data as Arc<[u8]>
+/// * `target_sr` - Target sample rate to resample to
+/// * `target_ch` - Target number of channels
+/// * `quality` - Resampling quality
+/// * `source_rate` - Rate to assume for the source instead of the one it
+///   decodes at, for files whose headers are wrong
+/// * `limits` - Per-file size and time limits
+///
+/// # Returns
+///
+/// * `Result<((Vec<f32>, usize), DecodeInfo), DecodeError>` - Decoded samples and frame count with rate info, or why the file was rejected
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(bytes = data.len(), target_sr = target_sr)
+    )
+)]
+pub fn decode_audio_with_limits(
+    data: Arc<[u8]>,
+    target_sr: u32,
+    target_ch: usize,
+    quality: ResampleMethod,
+    source_rate: Option<u32>,
+    limits: &DecodeLimits,
+) -> Result<((Vec<f32>, usize), DecodeInfo), DecodeError> {
+    if let Some(limit) = limits.max_file_bytes
+        && data.len() > limit
+    {
+        return Err(DecodeError::TooLarge {
+            size: data.len(),
+            limit,
+        });
+    }
+    let deadline = limits
+        .max_decode_ms
+        .map(|limit_ms| ((limits.clock)() + limit_ms, limit_ms));
+
     let probed = probe_with_fallback(data.clone()).map_err(|e| format!("probe error: {}", e))?;
 
     let mut format = probed.format;
-    let track = format
-        .default_track()
-        .ok_or_else(|| "no default track".to_string())?;
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &DecoderOptions::default())
-        .map_err(|e| format!("decoder create error: {}", e))?;
-
-    let mut src_rate: Option<u32> = track.codec_params.sample_rate;
-    let mut channels: usize = track.codec_params.channels.map(|c| c.count()).unwrap_or(1);
+    let (mut track_id, mut decoder) = open_decodable_track(format.as_ref())?;
+    // Read from the file itself: the decoder's own parameters would always agree with its output.
+    let header = &data[..data.len().min(SOURCE_HEADER_BYTES)];
+    let container_rate =
+        read_source_headers(header, data.len() as u64).map(|source| source.sample_rate);
+    let mut stream_rate: Option<u32> = None;
 
-    let mut source_samples: Vec<f32> = Vec::new();
+    // Each packet is converted as soon as it is decoded, so only one packet
+    // and a resampler chunk are held besides the output.
+    let mut packet_samples: Vec<f32> = Vec::new();
+    let mut converter: Option<RateConverter> = None;
+    let mut out: Vec<f32> = Vec::new();
+    let mut truncated = false;
 
     loop {
+        // Checked before every packet, so a run of undecodable packets is cut off too.
+        if let Some((deadline, limit_ms)) = deadline
+            && (limits.clock)() > deadline
+        {
+            return Err(DecodeError::TimedOut { limit_ms });
+        }
         match format.next_packet() {
+            // Packets of multiplexed streams, such as an Ogg skeleton, are skipped.
+            Ok(packet) if packet.track_id() != track_id => continue,
             Ok(packet) => match decoder.decode(&packet) {
                 Ok(audio_buf) => {
-                    if src_rate.is_none() {
-                        src_rate = Some(audio_buf.spec().rate);
-                    }
-                    if channels == 0 {
-                        channels = audio_buf.spec().channels.count();
+                    let packet_rate = audio_buf.spec().rate;
+                    if stream_rate.is_none() {
+                        stream_rate = Some(packet_rate);
                     }
 
+                    // Sources with more than two channels are downmixed by `append_frames`.
+                    let source_ch = audio_buf.spec().channels.count().clamp(1, 2);
+                    packet_samples.clear();
+                    let packet = &mut packet_samples;
                     match audio_buf {
                         AudioBufferRef::U8(buf) => {
-                            let chans = buf.spec().channels.count();
-                            if chans == 1 {
-                                for &v in buf.chan(0) {
-                                    source_samples.push((v as f32 / 255.0) * 2.0 - 1.0);
-                                }
-                            } else {
-                                let c0 = buf.chan(0);
-                                let c1 = buf.chan(1);
-                                for (l, r) in c0.iter().zip(c1.iter()) {
-                                    source_samples.push((*l as f32 / 255.0) * 2.0 - 1.0);
-                                    source_samples.push((*r as f32 / 255.0) * 2.0 - 1.0);
-                                }
-                            }
+                            append_frames(&buf, 2.0 / u8::MAX as f32, -1.0, packet)
                         }
                         AudioBufferRef::U16(buf) => {
-                            let scale = 2.0 / u16::MAX as f32;
-                            let chans = buf.spec().channels.count();
-                            if chans == 1 {
-                                for &v in buf.chan(0) {
-                                    source_samples.push(v as f32 * scale - 1.0);
-                                }
-                            } else {
-                                let c0 = buf.chan(0);
-                                let c1 = buf.chan(1);
-                                for (l, r) in c0.iter().zip(c1.iter()) {
-                                    source_samples.push(*l as f32 * scale - 1.0);
-                                    source_samples.push(*r as f32 * scale - 1.0);
-                                }
-                            }
+                            append_frames(&buf, 2.0 / u16::MAX as f32, -1.0, packet)
                         }
                         AudioBufferRef::S16(buf) => {
-                            let scale = 1.0 / i16::MAX as f32;
-                            let chans = buf.spec().channels.count();
-                            if chans == 1 {
-                                for &v in buf.chan(0) {
-                                    source_samples.push(v as f32 * scale);
-                                }
-                            } else {
-                                let c0 = buf.chan(0);
-                                let c1 = buf.chan(1);
-                                for (l, r) in c0.iter().zip(c1.iter()) {
-                                    source_samples.push(*l as f32 * scale);
-                                    source_samples.push(*r as f32 * scale);
-                                }
-                            }
+                            append_frames(&buf, 1.0 / i16::MAX as f32, 0.0, packet)
                         }
                         AudioBufferRef::S32(buf) => {
-                            let scale = 1.0 / i32::MAX as f32;
-                            let chans = buf.spec().channels.count();
-                            if chans == 1 {
-                                for &v in buf.chan(0) {
-                                    source_samples.push(v as f32 * scale);
-                                }
-                            } else {
-                                let c0 = buf.chan(0);
-                                let c1 = buf.chan(1);
-                                for (l, r) in c0.iter().zip(c1.iter()) {
-                                    source_samples.push(*l as f32 * scale);
-                                    source_samples.push(*r as f32 * scale);
-                                }
-                            }
-                        }
-                        AudioBufferRef::F32(buf) => {
-                            let chans = buf.spec().channels.count();
-                            if chans == 1 {
-                                source_samples.extend_from_slice(buf.chan(0));
-                            } else {
-                                let c0 = buf.chan(0);
-                                let c1 = buf.chan(1);
-                                for (l, r) in c0.iter().zip(c1.iter()) {
-                                    source_samples.push(*l);
-                                    source_samples.push(*r);
-                                }
-                            }
+                            append_frames(&buf, 1.0 / i32::MAX as f32, 0.0, packet)
                         }
-                        AudioBufferRef::F64(buf) => {
-                            let chans = buf.spec().channels.count();
-                            if chans == 1 {
-                                for &v in buf.chan(0) {
-                                    source_samples.push(v as f32);
-                                }
-                            } else {
-                                let c0 = buf.chan(0);
-                                let c1 = buf.chan(1);
-                                for (l, r) in c0.iter().zip(c1.iter()) {
-                                    source_samples.push(*l as f32);
-                                    source_samples.push(*r as f32);
-                                }
-                            }
-                        }
-                        _ => return Err("unsupported sample format".to_string()),
+                        AudioBufferRef::F32(buf) => append_frames(&buf, 1.0, 0.0, packet),
+                        AudioBufferRef::F64(buf) => append_frames(&buf, 1.0, 0.0, packet),
+                        _ => return Err("unsupported sample format".to_string().into()),
                     }
+                    let converter = match &mut converter {
+                        Some(converter) => converter,
+                        None => converter.insert(RateConverter::new(
+                            quality,
+                            source_rate.unwrap_or(packet_rate),
+                            source_ch,
+                            target_sr,
+                            target_ch,
+                        )?),
+                    };
+                    converter.push(&packet_samples, &mut out)?;
                 }
                 Err(SymphoniaError::DecodeError(_)) => continue,
-                Err(e) => return Err(format!("decode error: {}", e)),
+                Err(_) if converter.is_some() || !out.is_empty() => {
+                    truncated = true;
+                    break;
+                }
+                Err(e) => return Err(format!("decode error: {}", e).into()),
             },
             Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
                 break;
             }
-            Err(e) => return Err(format!("packet error: {}", e)),
+            // The next link of a chained Ogg file starts with new tracks.
+            Err(SymphoniaError::ResetRequired) => {
+                let previous_rate = decoder.codec_params().sample_rate;
+                (track_id, decoder) = match open_decodable_track(format.as_ref()) {
+                    Ok(opened) => opened,
+                    Err(_) => break,
+                };
+                // Links may differ in rate; the converter of the last one is
+                // flushed so the next packet starts a new one.
+                if decoder.codec_params().sample_rate != previous_rate
+                    && let Some(previous) = converter.take()
+                {
+                    previous.finish(&mut out)?;
+                }
+            }
+            Err(_) if converter.is_some() || !out.is_empty() => {
+                truncated = true;
+                break;
+            }
+            Err(e) => return Err(format!("packet error: {}", e).into()),
         }
     }
 
-    let src_sr = src_rate.unwrap_or(target_sr);
+    if let Some(converter) = converter {
+        converter.finish(&mut out)?;
+    }
 
-    // Perform resampling
-    let out_resampled = if src_sr == target_sr {
-        // No resampling needed, just channel conversion
-        convert_channels(&source_samples, channels, target_ch)
-    } else {
-        match quality {
-            ResampleMethod::Linear => {
-                resample_linear(&source_samples, src_sr, channels, target_sr, target_ch)
-            }
-            ResampleMethod::Sinc => {
-                resample_sinc(&source_samples, src_sr, channels, target_sr, target_ch)?
-            }
-        }
+    let out_frames = out.len() / target_ch;
+    let info = DecodeInfo {
+        container_rate,
+        stream_rate,
+        truncated,
     };
+    Ok(((out, out_frames), info))
+}
 
-    let out_frames = out_resampled.len() / target_ch;
-    Ok((out_resampled, out_frames))
+/// Convert interleaved samples to another rate in one pass.
+///
+/// Used where audio decoded at its own rate is joined before resampling, so
+/// the resampler runs across the joins instead of restarting at each one.
+///
+/// # Arguments
+///
+/// * `samples` - Interleaved samples.
+/// * `channels` - Number of channels.
+/// * `src_sr` - Rate of `samples`.
+/// * `target_sr` - Rate to convert to.
+/// * `quality` - Resampling quality.
+///
+/// # Returns
+///
+/// * `Result<Vec<f32>, String>` - Converted samples, or error message
+pub fn resample(
+    samples: &[f32],
+    channels: usize,
+    src_sr: u32,
+    target_sr: u32,
+    quality: ResampleMethod,
+) -> Result<Vec<f32>, String> {
+    let mut converter = RateConverter::new(quality, src_sr, channels, target_sr, channels)?;
+    let mut out = Vec::new();
+    converter.push(samples, &mut out)?;
+    converter.finish(&mut out)?;
+    Ok(out)
 }
 
-fn convert_channels(input: &[f32], src_ch: usize, target_ch: usize) -> Vec<f32> {
-    if src_ch == target_ch {
-        return input.to_vec();
-    }
+/// Open a decoder for the first track a codec is registered for.
+///
+/// Ogg files may multiplex a skeleton or an unsupported stream (such as Opus)
+/// next to the audio, so the default track is not always decodable.
+///
+/// # Arguments
+///
+/// * `format` - Demuxer positioned at the start of a stream.
+///
+/// # Returns
+///
+/// * `Result<(u32, Box<dyn Decoder>), String>` - Track id and its decoder, or error message
+fn open_decodable_track(format: &dyn FormatReader) -> Result<(u32, Box<dyn Decoder>), String> {
+    let codecs = symphonia::default::get_codecs();
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| codecs.get_codec(track.codec_params.codec).is_some())
+        .or_else(|| format.default_track())
+        .ok_or_else(|| "no default track".to_string())?;
+    let decoder = codecs
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| format!("decoder create error: {}", e))?;
+    Ok((track.id, decoder))
+}
 
-    let frames = input.len() / src_ch;
-    let mut out = Vec::with_capacity(frames * target_ch);
+/// Synthesize a short beep used as a stand-in for missing keysounds.
+///
+/// # Arguments
+///
+/// * `sample_rate` - Target sample rate.
+/// * `channels` - Target number of channels.
+///
+/// # Returns
+///
+/// * `(Vec<f32>, usize)` - Interleaved samples and number of frames.
+pub fn synth_beep(sample_rate: u32, channels: usize) -> (Vec<f32>, usize) {
+    const BEEP_SECONDS: f32 = 0.1;
+    const BEEP_HZ: f32 = 1000.0;
+    const BEEP_GAIN: f32 = 0.3;
 
-    if src_ch == 1 && target_ch == 2 {
-        for &s in input {
-            out.push(s);
-            out.push(s);
-        }
-    } else if src_ch == 2 && target_ch == 1 {
-        for chunk in input.chunks(2) {
-            out.push((chunk[0] + chunk[1]) * 0.5);
-        }
-    } else {
-        for chunk in input.chunks(src_ch) {
-            for (i, item) in chunk.iter().enumerate().take(target_ch) {
-                if i < src_ch {
-                    out.push(*item);
-                } else {
-                    out.push(0.0);
-                }
-            }
+    let frames = (BEEP_SECONDS * sample_rate as f32) as usize;
+    let mut out = Vec::with_capacity(frames * channels);
+    for i in 0..frames {
+        let t = i as f32 / sample_rate as f32;
+        // Short linear fades avoid clicks at both ends.
+        let fade = (t / 0.005).min((BEEP_SECONDS - t) / 0.005).min(1.0);
+        let v = (std::f32::consts::TAU * BEEP_HZ * t).sin() * BEEP_GAIN * fade;
+        for _ in 0..channels {
+            out.push(v);
         }
     }
-    out
+    (out, frames)
 }
 
-fn resample_linear(
-    input: &[f32],
-    src_sr: u32,
-    src_ch: usize,
-    target_sr: u32,
-    target_ch: usize,
-) -> Vec<f32> {
-    let mut out = Vec::new();
-    let step = src_sr as f32 / target_sr as f32;
-    let mut pos = 0.0;
+/// Number of leading bytes `estimate_source` needs to read the headers of
+/// every supported format (larger ID3 tags or WAV metadata may need more).
+pub const SOURCE_HEADER_BYTES: usize = 4096;
 
-    let frames = input.len() / src_ch;
-    let out_frames = (frames as f32 / step).ceil() as usize;
-    out.reserve(out_frames * target_ch);
+/// Length, rate and layout of an encoded file, read from its headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceEstimate {
+    /// Sample rate of the stream.
+    pub sample_rate: u32,
+    /// Number of channels in the stream.
+    pub channels: usize,
+    /// Number of frames the stream decodes to.
+    pub frames: u64,
+    /// Whether `frames` was read from the headers rather than derived from a bitrate.
+    pub exact: bool,
+}
 
-    let last_frame = (frames - 1) as f32;
+impl SourceEstimate {
+    /// Size of the stream once decoded, resampled and remixed for the render.
+    ///
+    /// # Arguments
+    ///
+    /// * `target_sr` - Target sample rate.
+    /// * `target_ch` - Target number of channels.
+    /// * `bytes_per_sample` - Size of one stored sample (4 for f32, 2 for i16).
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - Decoded size in bytes.
+    pub fn decoded_bytes(&self, target_sr: u32, target_ch: usize, bytes_per_sample: usize) -> u64 {
+        let frames = (self.frames as u128 * target_sr as u128).div_ceil(self.sample_rate as u128);
+        frames as u64 * (target_ch * bytes_per_sample) as u64
+    }
+}
 
-    while pos <= last_frame {
-        let i0 = pos.floor() as usize;
-        let i1 = (i0 + 1).min(frames - 1);
-        let frac = pos - i0 as f32;
+/// Rates that mistagged files are commonly recorded at.
+const COMMON_SAMPLE_RATES: [u32; 7] = [8000, 11025, 16000, 22050, 32000, 44100, 48000];
 
-        let base0 = i0 * src_ch;
-        let base1 = i1 * src_ch;
+/// Shortest expected length, in seconds, for which a file is checked for a wrong rate.
+const MIN_DRIFT_CHECK_SEC: f64 = 10.0;
 
-        if target_ch == 2 {
-            let l0 = input[base0];
-            let r0 = if src_ch > 1 { input[base0 + 1] } else { l0 };
+/// Guess the true rate of a file whose header makes it play too fast.
+///
+/// A backing track triggered once is expected to last until about the end of
+/// the chart. When the declared rate makes it end well before that, and a
+/// lower common rate makes it fit, the header is assumed to be wrong (such as
+/// 22050 Hz audio tagged 44100 Hz). Only exact header lengths are trusted.
+///
+/// # Arguments
+///
+/// * `source` - Header estimate of the file.
+/// * `expected_sec` - Time from the file's trigger to the end of the chart, in seconds.
+///
+/// # Returns
+///
+/// * `Option<u32>` - Corrected source rate, or `None` if the declared rate is plausible.
+pub fn corrected_source_rate(source: &SourceEstimate, expected_sec: f64) -> Option<u32> {
+    if !source.exact || source.frames == 0 || expected_sec < MIN_DRIFT_CHECK_SEC {
+        return None;
+    }
+    let duration = |rate: u32| source.frames as f64 / rate as f64;
+    if duration(source.sample_rate) >= expected_sec * 0.8 {
+        return None;
+    }
+    // Tracks usually ring out a little past the last note.
+    COMMON_SAMPLE_RATES
+        .into_iter()
+        .filter(|&rate| rate < source.sample_rate)
+        .filter(|&rate| (0.9..1.5).contains(&(duration(rate) / expected_sec)))
+        .min_by(|&a, &b| {
+            (duration(a) - expected_sec)
+                .abs()
+                .total_cmp(&(duration(b) - expected_sec).abs())
+        })
+}
 
-            let l1 = input[base1];
-            let r1 = if src_ch > 1 { input[base1 + 1] } else { l1 };
+/// Estimate the decoded length of an encoded file without decoding it.
+///
+/// WAV, AIFF and FLAC headers give the exact frame count. Ogg Vorbis and MP3 are
+/// estimated from the nominal bitrate and the encoded size. Unrecognized data
+/// is assumed to be 16-bit stereo PCM at 44.1 kHz, which over-estimates most
+/// compressed files.
+///
+/// # Arguments
+///
+/// * `header` - First bytes of the file (`SOURCE_HEADER_BYTES` is enough for most files).
+/// * `encoded_len` - Total size of the file in bytes.
+///
+/// # Returns
+///
+/// * `SourceEstimate` - Estimated stream length and layout.
+pub fn estimate_source(header: &[u8], encoded_len: u64) -> SourceEstimate {
+    read_source_headers(header, encoded_len).unwrap_or(SourceEstimate {
+        sample_rate: 44100,
+        channels: 2,
+        frames: encoded_len / 4,
+        exact: false,
+    })
+}
 
-            out.push(l0 + (l1 - l0) * frac);
-            out.push(r0 + (r1 - r0) * frac);
-        } else {
-            // Target Mono
-            let l0 = input[base0];
-            let val0 = if src_ch > 1 {
-                (l0 + input[base0 + 1]) * 0.5
-            } else {
-                l0
-            };
+/// Read the stream layout from the headers of a recognized format.
+///
+/// # Arguments
+///
+/// * `header` - First bytes of the file.
+/// * `encoded_len` - Total size of the file in bytes.
+///
+/// # Returns
+///
+/// * `Option<SourceEstimate>` - Estimated stream, or `None` if no header was recognized.
+fn read_source_headers(header: &[u8], encoded_len: u64) -> Option<SourceEstimate> {
+    wav_estimate(header, encoded_len)
+        .or_else(|| aiff_estimate(header))
+        .or_else(|| flac_estimate(header))
+        .or_else(|| vorbis_estimate(header, encoded_len))
+        .or_else(|| mp3_estimate(header, encoded_len))
+}
 
-            let l1 = input[base1];
-            let val1 = if src_ch > 1 {
-                (l1 + input[base1 + 1]) * 0.5
-            } else {
-                l1
-            };
+fn read_u16_le(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32_le(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// Offset of the chunk after one whose body starts at `body`.
+///
+/// # Arguments
+///
+/// * `body` - Offset of the chunk body.
+/// * `size` - Body size read from the chunk header.
+///
+/// # Returns
+///
+/// * `Option<usize>` - Offset of the next chunk header, or `None` if the size
+///   overflows, which ends the walk.
+fn next_chunk(body: usize, size: usize) -> Option<usize> {
+    body.checked_add(size)?.checked_add(size & 1)
+}
 
-            out.push(val0 + (val1 - val0) * frac);
+/// Walk the RIFF chunks up to `data`; its declared size gives the frame count.
+fn wav_estimate(header: &[u8], encoded_len: u64) -> Option<SourceEstimate> {
+    if header.get(0..4)? != b"RIFF" || header.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut format: Option<(usize, u32, u64)> = None;
+    let mut at = 12;
+    while let Some(id) = header.get(at..at + 4) {
+        let size = read_u32_le(header, at + 4)? as usize;
+        let body = at + 8;
+        if id == b"fmt " {
+            let channels = read_u16_le(header, body + 2)? as usize;
+            let sample_rate = read_u32_le(header, body + 4)?;
+            let block_align = read_u16_le(header, body + 12)? as u64;
+            format = Some((channels, sample_rate, block_align));
+        } else if id == b"data" {
+            let (channels, sample_rate, block_align) = format?;
+            if channels == 0 || sample_rate == 0 || block_align == 0 {
+                return None;
+            }
+            // Streamed files may leave the size unset; fall back to the file size.
+            let data_len = match size as u64 {
+                0 | 0xFFFF_FFFF => encoded_len.saturating_sub(body as u64),
+                len => len.min(encoded_len.saturating_sub(body as u64)),
+            };
+            return Some(SourceEstimate {
+                sample_rate,
+                channels,
+                frames: data_len / block_align,
+                exact: true,
+            });
         }
+        match next_chunk(body, size) {
+            Some(next) if next > at => at = next,
+            _ => break,
+        }
+    }
+    // The data chunk lies past the header bytes; assume it fills the rest of the file.
+    let (channels, sample_rate, block_align) = format?;
+    if channels == 0 || sample_rate == 0 || block_align == 0 {
+        return None;
+    }
+    Some(SourceEstimate {
+        sample_rate,
+        channels,
+        frames: encoded_len.saturating_sub(at as u64) / block_align,
+        exact: false,
+    })
+}
 
-        pos += step;
+/// Walk the AIFF/AIFF-C chunks up to `COMM`, which holds the frame count.
+fn aiff_estimate(header: &[u8]) -> Option<SourceEstimate> {
+    if header.get(0..4)? != b"FORM" || !matches!(header.get(8..12)?, b"AIFF" | b"AIFC") {
+        return None;
     }
+    let mut at = 12;
+    while let Some(id) = header.get(at..at + 4) {
+        let size = u32::from_be_bytes(header.get(at + 4..at + 8)?.try_into().ok()?) as usize;
+        let body = at + 8;
+        if id == b"COMM" {
+            let comm = header.get(body..body + 18)?;
+            let channels = u16::from_be_bytes([comm[0], comm[1]]) as usize;
+            let frames = u32::from_be_bytes(comm[2..6].try_into().ok()?) as u64;
+            let sample_rate = read_extended(comm[8..18].try_into().ok()?) as u32;
+            if channels == 0 || sample_rate == 0 {
+                return None;
+            }
+            return Some(SourceEstimate {
+                sample_rate,
+                channels,
+                frames,
+                exact: true,
+            });
+        }
+        match next_chunk(body, size) {
+            Some(next) if next > at => at = next,
+            _ => break,
+        }
+    }
+    None
+}
 
-    out
+/// Read the big-endian 80-bit extended float AIFF uses for the sample rate.
+fn read_extended(bytes: [u8; 10]) -> f64 {
+    let exponent = (u16::from_be_bytes([bytes[0], bytes[1]]) & 0x7FFF) as i32;
+    let mantissa = u64::from_be_bytes(bytes[2..10].try_into().unwrap_or_default());
+    if exponent == 0 || mantissa == 0 {
+        return 0.0;
+    }
+    mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
 }
 
-fn resample_sinc(
-    input: &[f32],
-    src_sr: u32,
-    src_ch: usize,
-    target_sr: u32,
-    target_ch: usize,
-) -> Result<Vec<f32>, String> {
-    let ratio = target_sr as f64 / src_sr as f64;
-    let frames = input.len() / src_ch;
+/// Read the STREAMINFO block that opens every FLAC stream.
+fn flac_estimate(header: &[u8]) -> Option<SourceEstimate> {
+    if header.get(0..4)? != b"fLaC" {
+        return None;
+    }
+    let info = header.get(8..26)?;
+    let sample_rate =
+        ((info[10] as u32) << 12) | ((info[11] as u32) << 4) | ((info[12] as u32) >> 4);
+    let channels = ((info[12] >> 1) & 0x07) as usize + 1;
+    let frames = (((info[13] & 0x0F) as u64) << 32)
+        | u32::from_be_bytes(info[14..18].try_into().ok()?) as u64;
+    if sample_rate == 0 || frames == 0 {
+        return None;
+    }
+    Some(SourceEstimate {
+        sample_rate,
+        channels,
+        frames,
+        exact: true,
+    })
+}
 
-    // De-interleave to planar
-    let mut planar_in = vec![Vec::with_capacity(frames); src_ch];
-    if src_ch == 1 {
-        planar_in[0].extend_from_slice(input);
+/// Read the Vorbis identification header from the first Ogg page.
+fn vorbis_estimate(header: &[u8], encoded_len: u64) -> Option<SourceEstimate> {
+    if header.get(0..4)? != b"OggS" {
+        return None;
+    }
+    let packet = 27 + *header.get(26)? as usize;
+    if header.get(packet..packet + 7)? != b"\x01vorbis" {
+        return None;
+    }
+    let channels = *header.get(packet + 11)? as usize;
+    let sample_rate = read_u32_le(header, packet + 12)?;
+    let maximum = read_u32_le(header, packet + 16)? as i32;
+    let nominal = read_u32_le(header, packet + 20)? as i32;
+    let minimum = read_u32_le(header, packet + 24)? as i32;
+    let bitrate = if nominal > 0 {
+        nominal
+    } else if maximum > 0 && minimum > 0 {
+        (maximum + minimum) / 2
     } else {
-        for chunk in input.chunks(src_ch) {
-            planar_in[0].push(chunk[0]);
-            planar_in[1].push(chunk[1]);
-        }
+        maximum.max(minimum)
+    };
+    if channels == 0 || sample_rate == 0 || bitrate <= 0 {
+        return None;
     }
+    Some(SourceEstimate {
+        sample_rate,
+        channels,
+        frames: u64::try_from(encoded_len as u128 * 8 * sample_rate as u128 / bitrate as u128)
+            .unwrap_or(u64::MAX),
+        exact: false,
+    })
+}
 
-    let chunk_size = 1024;
-    let mut resampler = FastFixedIn::<f32>::new(
-        ratio,
-        1.0,
-        rubato::PolynomialDegree::Septic,
-        chunk_size,
-        src_ch,
-    )
-    .map_err(|e| format!("Failed to create resampler: {}", e))?;
+/// Layer III bitrates in kbps for MPEG-1 and for MPEG-2/2.5, by bitrate index.
+const MP3_BITRATES: [[u32; 15]; 2] = [
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
 
-    let mut planar_out = vec![Vec::new(); src_ch];
-    let num_chunks = frames / chunk_size;
+/// Read the first MPEG Layer III frame header after any ID3v2 tag.
+fn mp3_estimate(header: &[u8], encoded_len: u64) -> Option<SourceEstimate> {
+    let mut at = 0;
+    if header.get(0..3)? == b"ID3" {
+        let size = header
+            .get(6..10)?
+            .iter()
+            .fold(0usize, |acc, &b| (acc << 7) | (b & 0x7F) as usize);
+        at = 10 + size;
+    }
+    let frame = header.get(at..at + 4)?;
+    if frame[0] != 0xFF || frame[1] & 0xE0 != 0xE0 || (frame[1] >> 1) & 0x03 != 0x01 {
+        return None;
+    }
+    let version = (frame[1] >> 3) & 0x03;
+    let bitrate_index = (frame[2] >> 4) as usize;
+    let rate_index = ((frame[2] >> 2) & 0x03) as usize;
+    if version == 0x01 || bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+        return None;
+    }
+    let base_rate = [44100, 48000, 32000][rate_index];
+    let (sample_rate, table) = match version {
+        0x03 => (base_rate, 0),
+        0x02 => (base_rate / 2, 1),
+        _ => (base_rate / 4, 1),
+    };
+    let bitrate = MP3_BITRATES[table][bitrate_index] as u64 * 1000;
+    let channels = if frame[3] >> 6 == 0x03 { 1 } else { 2 };
+    Some(SourceEstimate {
+        sample_rate,
+        channels,
+        frames: encoded_len.saturating_sub(at as u64) * 8 * sample_rate as u64 / bitrate,
+        exact: false,
+    })
+}
 
-    // Process full chunks
-    for i in 0..num_chunks {
-        let start = i * chunk_size;
-        let end = start + chunk_size;
-        let mut chunk_in = vec![Vec::with_capacity(chunk_size); src_ch];
-        for c in 0..src_ch {
-            chunk_in[c].extend_from_slice(&planar_in[c][start..end]);
-        }
+/// Analysis window for sustain detection, in seconds.
+const SUSTAIN_WINDOW_SECONDS: f32 = 0.02;
+/// Maximum level change between adjacent windows of a stable region (about 2 dB).
+const SUSTAIN_MAX_STEP: f32 = 1.25;
+/// Windows quieter than this fraction of the peak are never considered stable.
+const SUSTAIN_MIN_LEVEL: f32 = 0.05;
 
-        let chunk_out = resampler
-            .process(&chunk_in, None)
-            .map_err(|e| format!("Resampling error: {}", e))?;
-        for c in 0..src_ch {
-            planar_out[c].extend_from_slice(&chunk_out[c]);
-        }
+/// Find the longest region after the attack whose level stays steady.
+///
+/// # Arguments
+///
+/// * `samples` - Interleaved samples.
+/// * `channels` - Number of channels.
+/// * `sample_rate` - Sample rate.
+///
+/// # Returns
+///
+/// * `Option<(usize, usize)>` - Region as `(start, end)` frames, or `None` if the sound never settles.
+fn find_stable_region(
+    samples: &[f32],
+    channels: usize,
+    sample_rate: u32,
+) -> Option<(usize, usize)> {
+    let window = ((SUSTAIN_WINDOW_SECONDS * sample_rate as f32) as usize).max(1);
+    let levels: Vec<f32> = samples
+        .chunks_exact(window * channels)
+        .map(|w| (w.iter().map(|v| v * v).sum::<f32>() / w.len() as f32).sqrt())
+        .collect();
+    let (peak_idx, &peak) = levels
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+    if peak == 0.0 {
+        return None;
     }
 
-    // Handle remainder
-    let remainder = frames % chunk_size;
-    if remainder > 0 {
-        let start = num_chunks * chunk_size;
-        let mut chunk_in = vec![vec![0.0; chunk_size]; src_ch];
-        for c in 0..src_ch {
-            let slice = &planar_in[c][start..];
-            chunk_in[c][..slice.len()].copy_from_slice(slice);
+    let mut best: Option<(usize, usize)> = None;
+    let mut run_start = peak_idx + 1;
+    for i in peak_idx + 1..=levels.len() {
+        let stable = i < levels.len()
+            && levels[i] >= peak * SUSTAIN_MIN_LEVEL
+            && (i == run_start || {
+                let ratio = levels[i] / levels[i - 1];
+                (1.0 / SUSTAIN_MAX_STEP..=SUSTAIN_MAX_STEP).contains(&ratio)
+            });
+        if !stable {
+            if i - run_start >= 2 && best.is_none_or(|(s, e)| i - run_start > e - s) {
+                best = Some((run_start, i));
+            }
+            run_start = i + 1;
         }
+    }
+    best.map(|(s, e)| (s * window, e * window))
+}
 
-        let chunk_out = resampler
-            .process(&chunk_in, None)
-            .map_err(|e| format!("Resampling error: {}", e))?;
+/// Extend a one-shot keysound to a held length by looping its stable region.
+///
+/// The sound plays up to the end of its stable region, which then repeats
+/// (crossfaded into the following audio so the seam is continuous) until the
+/// hold time is reached, and the remainder of the sound plays as the release.
+/// The release starts at the loop boundary closest to `hold_frames`.
+///
+/// # Arguments
+///
+/// * `samples` - Interleaved samples of the keysound.
+/// * `channels` - Number of channels.
+/// * `sample_rate` - Sample rate.
+/// * `hold_frames` - Frames from the note start to its release.
+///
+/// # Returns
+///
+/// * `Option<(Vec<f32>, usize)>` - Sustained samples and frame count, or `None`
+///   if the sound is already long enough or has no stable region.
+pub fn sustain_keysound(
+    samples: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    hold_frames: usize,
+) -> Option<(Vec<f32>, usize)> {
+    let frames = samples.len() / channels;
+    if frames >= hold_frames {
+        return None;
+    }
+    let (loop_start, loop_end) = find_stable_region(samples, channels, sample_rate)?;
+    let loop_len = loop_end - loop_start;
+    let fade = (loop_len / 4).min(frames - loop_end);
 
-        for c in 0..src_ch {
-            planar_out[c].extend_from_slice(&chunk_out[c]);
+    // Loop body whose head fades in over the audio that follows the loop end.
+    let mut body = samples[loop_start * channels..loop_end * channels].to_vec();
+    for k in 0..fade {
+        let w = k as f32 / fade as f32;
+        for c in 0..channels {
+            let tail = samples[(loop_end + k) * channels + c];
+            let v = &mut body[k * channels + c];
+            *v = *v * w + tail * (1.0 - w);
         }
     }
 
-    // Interleave and convert channels
-    let out_frames = planar_out[0].len();
-    let mut out = Vec::with_capacity(out_frames * target_ch);
+    let repeats = ((hold_frames - loop_end) as f64 / loop_len as f64).round() as usize;
+    let mut out = Vec::with_capacity((frames + repeats * loop_len) * channels);
+    out.extend_from_slice(&samples[..loop_end * channels]);
+    for _ in 0..repeats {
+        out.extend_from_slice(&body);
+    }
+    out.extend_from_slice(&samples[loop_end * channels..]);
+    let out_frames = out.len() / channels;
+    Some((out, out_frames))
+}
 
-    if src_ch == 1 {
-        for &l in planar_out[0].iter().take(out_frames) {
-            if target_ch == 2 {
-                out.push(l);
-                out.push(l);
+/// Stereo gains of each channel of a layout, in the layout's channel order.
+///
+/// Follows the usual ITU-R BS.775 downmix: front left and right go to their
+/// side at full level, centre channels to both sides at -3 dB, surround,
+/// wide and height channels to their side at -3 dB, and LFE is dropped. The
+/// gains are then scaled so full-scale input on every channel cannot clip.
+///
+/// # Arguments
+///
+/// * `layout` - Channel layout of the source.
+///
+/// # Returns
+///
+/// * `Vec<(f32, f32)>` - Left and right gain of each channel.
+fn downmix_gains(layout: Channels) -> Vec<(f32, f32)> {
+    const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+    let left = Channels::REAR_LEFT
+        | Channels::SIDE_LEFT
+        | Channels::FRONT_LEFT_CENTRE
+        | Channels::TOP_FRONT_LEFT
+        | Channels::TOP_REAR_LEFT
+        | Channels::REAR_LEFT_CENTRE
+        | Channels::FRONT_LEFT_WIDE
+        | Channels::FRONT_LEFT_HIGH;
+    let right = Channels::REAR_RIGHT
+        | Channels::SIDE_RIGHT
+        | Channels::FRONT_RIGHT_CENTRE
+        | Channels::TOP_FRONT_RIGHT
+        | Channels::TOP_REAR_RIGHT
+        | Channels::REAR_RIGHT_CENTRE
+        | Channels::FRONT_RIGHT_WIDE
+        | Channels::FRONT_RIGHT_HIGH;
+    let mut gains: Vec<(f32, f32)> = layout
+        .iter()
+        .map(|channel| {
+            if channel == Channels::FRONT_LEFT {
+                (1.0, 0.0)
+            } else if channel == Channels::FRONT_RIGHT {
+                (0.0, 1.0)
+            } else if channel.intersects(Channels::LFE1 | Channels::LFE2) {
+                (0.0, 0.0)
+            } else if left.contains(channel) {
+                (MINUS_3DB, 0.0)
+            } else if right.contains(channel) {
+                (0.0, MINUS_3DB)
             } else {
-                out.push(l);
+                (MINUS_3DB, MINUS_3DB)
+            }
+        })
+        .collect();
+    let (sum_left, sum_right) = gains
+        .iter()
+        .fold((0.0, 0.0), |(l, r), &(gl, gr)| (l + gl, r + gr));
+    let scale = 1.0 / sum_left.max(sum_right).max(1.0);
+    for (gl, gr) in &mut gains {
+        *gl *= scale;
+        *gr *= scale;
+    }
+    gains
+}
+
+/// Decoded sample types that widen to `f32` eight lanes at a time.
+trait WidenSample: Sample {
+    /// Widen eight samples.
+    fn widen8(chunk: &[Self]) -> f32x8;
+
+    /// Widen one sample, for the tail of a plane.
+    fn widen(self) -> f32;
+}
+
+impl WidenSample for u8 {
+    fn widen8(chunk: &[u8]) -> f32x8 {
+        f32x8::from_i32x8(i32x8::from(std::array::from_fn::<i32, 8, _>(|k| {
+            chunk[k] as i32
+        })))
+    }
+
+    fn widen(self) -> f32 {
+        self as f32
+    }
+}
+
+impl WidenSample for u16 {
+    fn widen8(chunk: &[u16]) -> f32x8 {
+        f32x8::from_i32x8(i32x8::from(std::array::from_fn::<i32, 8, _>(|k| {
+            chunk[k] as i32
+        })))
+    }
+
+    fn widen(self) -> f32 {
+        self as f32
+    }
+}
+
+impl WidenSample for i16 {
+    fn widen8(chunk: &[i16]) -> f32x8 {
+        f32x8::from_i32x8(i32x8::from(std::array::from_fn::<i32, 8, _>(|k| {
+            chunk[k] as i32
+        })))
+    }
+
+    fn widen(self) -> f32 {
+        self as f32
+    }
+}
+
+impl WidenSample for i32 {
+    fn widen8(chunk: &[i32]) -> f32x8 {
+        f32x8::from_i32x8(i32x8::from(&chunk[..8]))
+    }
+
+    fn widen(self) -> f32 {
+        self as f32
+    }
+}
+
+impl WidenSample for f32 {
+    fn widen8(chunk: &[f32]) -> f32x8 {
+        f32x8::from(&chunk[..8])
+    }
+
+    fn widen(self) -> f32 {
+        self
+    }
+}
+
+impl WidenSample for f64 {
+    fn widen8(chunk: &[f64]) -> f32x8 {
+        f32x8::from(std::array::from_fn::<f32, 8, _>(|k| chunk[k] as f32))
+    }
+
+    fn widen(self) -> f32 {
+        self as f32
+    }
+}
+
+/// Append the frames of a decoded buffer as interleaved samples.
+///
+/// Samples are mapped to the `[-1.0, 1.0]` range as `sample * scale + offset`,
+/// eight frames at a time. Mono and stereo buffers keep their channels;
+/// buffers with more channels are downmixed to stereo with `downmix_gains`.
+///
+/// # Arguments
+///
+/// * `buf` - Decoded planar buffer.
+/// * `scale` - Factor applied to each widened sample.
+/// * `offset` - Value added after scaling (centers unsigned formats).
+/// * `out` - Interleaved output.
+fn append_frames<S: WidenSample>(
+    buf: &AudioBuffer<S>,
+    scale: f32,
+    offset: f32,
+    out: &mut Vec<f32>,
+) {
+    let layout = buf.spec().channels;
+    let frames = buf.frames();
+    let frames8 = frames & !7;
+    let (scale8, offset8) = (f32x8::splat(scale), f32x8::splat(offset));
+    let convert8 = |plane: &[S], i: usize| S::widen8(&plane[i..i + 8]).mul_add(scale8, offset8);
+    let convert = |v: S| v.widen() * scale + offset;
+    match layout.count() {
+        0 => {}
+        1 => {
+            let plane = buf.chan(0);
+            for i in (0..frames8).step_by(8) {
+                out.extend_from_slice(&<[f32; 8]>::from(convert8(plane, i)));
             }
+            out.extend(plane[frames8..].iter().map(|&v| convert(v)));
         }
-    } else {
-        for (&l, &r) in planar_out[0]
-            .iter()
-            .zip(planar_out[1].iter())
-            .take(out_frames)
-        {
-            if target_ch == 2 {
+        2 => {
+            let (left, right) = (buf.chan(0), buf.chan(1));
+            for i in (0..frames8).step_by(8) {
+                push_stereo(out, convert8(left, i), convert8(right, i));
+            }
+            for (&l, &r) in left[frames8..].iter().zip(&right[frames8..]) {
+                out.push(convert(l));
+                out.push(convert(r));
+            }
+        }
+        count => {
+            let gains = downmix_gains(layout);
+            let planes: Vec<&[S]> = (0..count).map(|ch| buf.chan(ch)).collect();
+            out.reserve(frames * 2);
+            for i in (0..frames8).step_by(8) {
+                let (mut l, mut r) = (f32x8::ZERO, f32x8::ZERO);
+                for (plane, &(gl, gr)) in planes.iter().zip(&gains) {
+                    let v = convert8(plane, i);
+                    l = v.mul_add(f32x8::splat(gl), l);
+                    r = v.mul_add(f32x8::splat(gr), r);
+                }
+                push_stereo(out, l, r);
+            }
+            for frame in frames8..frames {
+                let (mut l, mut r) = (0.0, 0.0);
+                for (plane, &(gl, gr)) in planes.iter().zip(&gains) {
+                    let v = convert(plane[frame]);
+                    l += v * gl;
+                    r += v * gr;
+                }
                 out.push(l);
                 out.push(r);
-            } else {
-                out.push((l + r) * 0.5);
             }
         }
     }
+}
 
-    Ok(out)
+/// Interleave eight left and right samples onto `out`.
+fn push_stereo(out: &mut Vec<f32>, left: f32x8, right: f32x8) {
+    let (left, right): ([f32; 8], [f32; 8]) = (left.into(), right.into());
+    out.extend(left.into_iter().zip(right).flat_map(|(l, r)| [l, r]));
+}
+
+/// Sample for output channel `channel` from one source frame.
+///
+/// Stereo is averaged for mono output, mono fills every output channel, and
+/// output channels beyond the source's stay silent.
+fn target_sample(frame: &[f32], channel: usize, target_ch: usize) -> f32 {
+    if target_ch == 1 && frame.len() > 1 {
+        (frame[0] + frame[1]) * 0.5
+    } else if frame.len() == 1 {
+        frame[0]
+    } else {
+        frame.get(channel).copied().unwrap_or(0.0)
+    }
+}
+
+/// Rate conversion state carried between packets.
+enum Conversion {
+    /// Same rate: only the channel count changes.
+    Passthrough,
+    /// Linear interpolation.
+    Linear {
+        /// Source frames per output frame.
+        step: f32,
+        /// Position of the next output frame, in source frames.
+        pos: f32,
+        /// Index of the first frame in `pending`.
+        offset: usize,
+        /// Interleaved source frames from `offset` on.
+        pending: Vec<f32>,
+    },
+    /// Sinc resampler fed one fixed-size chunk at a time.
+    Sinc {
+        resampler: Box<FastFixedIn<f32>>,
+        ratio: f64,
+        /// Planar source frames of the next, incomplete chunk.
+        pending: Vec<Vec<f32>>,
+        /// Output frames still to drop for the resampler's group delay.
+        skip: usize,
+        /// Output frames produced so far, including the dropped ones.
+        produced: usize,
+    },
+}
+
+/// Converts decoded frames to the target rate and channel count as they arrive.
+///
+/// Only a resampler chunk (or, for linear interpolation, the frames around
+/// the current position) is held between packets, so decoding a long file
+/// never keeps a second full-length copy of it.
+pub(crate) struct RateConverter {
+    src_ch: usize,
+    target_ch: usize,
+    /// Source frames pushed so far.
+    frames_in: usize,
+    conversion: Conversion,
+}
+
+impl RateConverter {
+    /// Set up the conversion for one source.
+    ///
+    /// # Arguments
+    ///
+    /// * `quality` - Resampling quality.
+    /// * `src_sr` - Source sample rate.
+    /// * `src_ch` - Source channels (1 or 2).
+    /// * `target_sr` - Target sample rate.
+    /// * `target_ch` - Target number of channels.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<RateConverter, String>` - Converter, or an error if the resampler cannot be created.
+    pub(crate) fn new(
+        quality: ResampleMethod,
+        src_sr: u32,
+        src_ch: usize,
+        target_sr: u32,
+        target_ch: usize,
+    ) -> Result<Self, String> {
+        let conversion = if src_sr == target_sr {
+            Conversion::Passthrough
+        } else {
+            match quality {
+                ResampleMethod::Linear => Conversion::Linear {
+                    step: src_sr as f32 / target_sr as f32,
+                    pos: 0.0,
+                    offset: 0,
+                    pending: Vec::new(),
+                },
+                ResampleMethod::Sinc => {
+                    let ratio = target_sr as f64 / src_sr as f64;
+                    let resampler = FastFixedIn::<f32>::new(
+                        ratio,
+                        1.0,
+                        rubato::PolynomialDegree::Septic,
+                        SINC_CHUNK_SIZE,
+                        src_ch,
+                    )
+                    .map_err(|e| format!("Failed to create resampler: {}", e))?;
+                    Conversion::Sinc {
+                        resampler: Box::new(resampler),
+                        ratio,
+                        pending: vec![Vec::with_capacity(SINC_CHUNK_SIZE); src_ch],
+                        // Dropping the group delay lines the output up with the
+                        // linear path (first output frame == first input frame).
+                        skip: sinc_delay(ratio),
+                        produced: 0,
+                    }
+                }
+            }
+        };
+        Ok(Self {
+            src_ch,
+            target_ch,
+            frames_in: 0,
+            conversion,
+        })
+    }
+
+    /// Convert the next interleaved source frames.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - Interleaved source frames.
+    /// * `out` - Interleaved output to append to.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Error from the resampler, if any.
+    pub(crate) fn push(&mut self, input: &[f32], out: &mut Vec<f32>) -> Result<(), String> {
+        let (src_ch, target_ch) = (self.src_ch, self.target_ch);
+        self.frames_in += input.len() / src_ch;
+        match &mut self.conversion {
+            Conversion::Passthrough => {
+                out.reserve(input.len() / src_ch * target_ch);
+                for frame in input.chunks_exact(src_ch) {
+                    out.extend((0..target_ch).map(|c| target_sample(frame, c, target_ch)));
+                }
+            }
+            Conversion::Linear {
+                step,
+                pos,
+                offset,
+                pending,
+            } => {
+                pending.extend_from_slice(input);
+                let available = *offset + pending.len() / src_ch;
+                // Interpolate while the frame after `pos` has arrived.
+                while (*pos as usize) + 1 < available {
+                    interpolate(
+                        pending,
+                        *offset,
+                        *pos,
+                        *pos as usize + 1,
+                        src_ch,
+                        target_ch,
+                        out,
+                    );
+                    *pos += *step;
+                }
+                let keep_from = (*pos as usize).min(available);
+                pending.drain(..(keep_from - *offset) * src_ch);
+                *offset = keep_from;
+            }
+            Conversion::Sinc {
+                resampler,
+                pending,
+                skip,
+                produced,
+                ..
+            } => {
+                for frame in input.chunks_exact(src_ch) {
+                    for (plane, &v) in pending.iter_mut().zip(frame) {
+                        plane.push(v);
+                    }
+                    if pending[0].len() == SINC_CHUNK_SIZE {
+                        let chunk_out = resampler
+                            .process(pending, None)
+                            .map_err(|e| format!("Resampling error: {}", e))?;
+                        emit_planar(&chunk_out, skip, produced, usize::MAX, target_ch, out);
+                        for plane in pending.iter_mut() {
+                            plane.clear();
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert the frames still held back at the end of the source.
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - Interleaved output to append to.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Error from the resampler, if any.
+    pub(crate) fn finish(self, out: &mut Vec<f32>) -> Result<(), String> {
+        let (src_ch, target_ch, frames) = (self.src_ch, self.target_ch, self.frames_in);
+        match self.conversion {
+            Conversion::Passthrough => {}
+            Conversion::Linear {
+                step,
+                mut pos,
+                offset,
+                pending,
+            } => {
+                if frames == 0 {
+                    return Ok(());
+                }
+                let last_frame = (frames - 1) as f32;
+                while pos <= last_frame {
+                    let next = (pos as usize + 1).min(frames - 1);
+                    interpolate(&pending, offset, pos, next, src_ch, target_ch, out);
+                    pos += step;
+                }
+            }
+            Conversion::Sinc {
+                mut resampler,
+                ratio,
+                mut pending,
+                mut skip,
+                mut produced,
+            } => {
+                let expected_frames = (frames as f64 * ratio).ceil() as usize;
+                let limit = sinc_delay(ratio) + expected_frames;
+                // The last partial chunk is padded with silence.
+                if !pending[0].is_empty() {
+                    for plane in pending.iter_mut() {
+                        plane.resize(SINC_CHUNK_SIZE, 0.0);
+                    }
+                    let chunk_out = resampler
+                        .process(&pending, None)
+                        .map_err(|e| format!("Resampling error: {}", e))?;
+                    emit_planar(&chunk_out, &mut skip, &mut produced, limit, target_ch, out);
+                }
+                while produced < limit {
+                    let chunk_out = resampler
+                        .process_partial::<Vec<f32>>(None, None)
+                        .map_err(|e| format!("Resampling error: {}", e))?;
+                    if chunk_out[0].is_empty() {
+                        break;
+                    }
+                    emit_planar(&chunk_out, &mut skip, &mut produced, limit, target_ch, out);
+                }
+                out.truncate(expected_frames * target_ch);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Append the output frame at `pos` between source frames `floor(pos)` and `next`.
+fn interpolate(
+    pending: &[f32],
+    offset: usize,
+    pos: f32,
+    next: usize,
+    src_ch: usize,
+    target_ch: usize,
+    out: &mut Vec<f32>,
+) {
+    let i0 = pos as usize;
+    let frac = pos - i0 as f32;
+    let base0 = (i0 - offset) * src_ch;
+    let base1 = (next - offset) * src_ch;
+    let frame0 = &pending[base0..base0 + src_ch];
+    let frame1 = &pending[base1..base1 + src_ch];
+    for c in 0..target_ch {
+        let v0 = target_sample(frame0, c, target_ch);
+        let v1 = target_sample(frame1, c, target_ch);
+        out.push(v0 + (v1 - v0) * frac);
+    }
+}
+
+/// Interleave resampled planar frames into `out`, dropping the first `skip`
+/// frames overall and stopping once `limit` frames were produced.
+fn emit_planar(
+    planar: &[Vec<f32>],
+    skip: &mut usize,
+    produced: &mut usize,
+    limit: usize,
+    target_ch: usize,
+    out: &mut Vec<f32>,
+) {
+    let len = planar[0].len().min(limit.saturating_sub(*produced));
+    let dropped = (*skip).min(len);
+    *skip -= dropped;
+    *produced += len;
+    let mut frame = [0.0f32; 2];
+    for i in dropped..len {
+        for (c, plane) in planar.iter().enumerate() {
+            frame[c] = plane[i];
+        }
+        let frame = &frame[..planar.len()];
+        out.extend((0..target_ch).map(|c| target_sample(frame, c, target_ch)));
+    }
+}
+
+/// Group delay introduced by a resampling method, in output frames.
+///
+/// `decode_audio` already compensates for it; this is exposed for hosts that
+/// align externally resampled material against renders.
+///
+/// # Arguments
+///
+/// * `method` - Resampling method.
+/// * `src_sr` - Source sample rate.
+/// * `target_sr` - Target sample rate.
+///
+/// # Returns
+///
+/// * `usize` - Delay in frames at the target rate.
+pub fn resampler_delay(method: ResampleMethod, src_sr: u32, target_sr: u32) -> usize {
+    if src_sr == target_sr {
+        return 0;
+    }
+    match method {
+        ResampleMethod::Linear => 0,
+        ResampleMethod::Sinc => sinc_delay(target_sr as f64 / src_sr as f64),
+    }
+}
+
+/// Input frames the septic (degree 7) interpolator of the sinc path reads per output frame.
+const SINC_INTERPOLATOR_TAPS: usize = 8;
+
+/// Group delay of the septic `FastFixedIn` resampler, in output frames.
+///
+/// The interpolator is centered half its length (`SINC_INTERPOLATOR_TAPS / 2`
+/// input frames) late, minus one output frame; `Resampler::output_delay`
+/// omits that last frame, which would make compensated keysounds land one
+/// frame early.
+fn sinc_delay(ratio: f64) -> usize {
+    let half = (SINC_INTERPOLATOR_TAPS / 2) as f64;
+    (half * ratio - 1.0).round().max(0.0) as usize
 }
 
 fn probe_with_fallback(
     data: Arc<[u8]>,
 ) -> Result<symphonia::core::probe::ProbeResult, symphonia::core::errors::Error> {
+    // Symphonia looks for the end of a seekable Ogg stream when it opens it,
+    // which skips the first audio page of a chained file; reading the chain
+    // front to back keeps every link intact.
+    if is_chained_ogg(&data) {
+        let src = ArcSliceSource::new(data.clone(), 0, data.len() as u64).sequential();
+        if let Ok(p) = try_probe_source(Box::new(src), Some("ogg")) {
+            return Ok(p);
+        }
+    }
+
     // Probe automatically
     let first_err = match try_probe_arc(data.clone(), None) {
         Ok(p) => return Ok(p),
@@ -404,6 +1523,18 @@ fn probe_with_fallback(
     Err(first_err)
 }
 
+/// Demuxer options shared by every probe.
+///
+/// Gapless mode trims the encoder delay and padding recorded in LAME/Xing
+/// (and Lavf/Lavc) headers, including the 529-sample decoder delay, so MP3
+/// keysounds start on their first real sample and line up with WAV slices.
+fn format_options() -> FormatOptions {
+    FormatOptions {
+        enable_gapless: true,
+        ..FormatOptions::default()
+    }
+}
+
 fn try_probe_arc(
     data: Arc<[u8]>,
     ext: Option<&str>,
@@ -422,7 +1553,7 @@ fn try_probe_arc(
     symphonia::default::get_probe().format(
         &hint,
         mss,
-        &FormatOptions::default(),
+        &format_options(),
         &MetadataOptions::default(),
     )
 }
@@ -444,34 +1575,44 @@ fn try_probe_source(
     symphonia::default::get_probe().format(
         &hint,
         mss,
-        &FormatOptions::default(),
+        &format_options(),
         &MetadataOptions::default(),
     )
 }
 
-fn parse_wave(data: &[u8]) -> Option<(usize, usize, bool, u16)> {
-    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+/// Locate the audio data of a RIFF/WAVE file.
+///
+/// Only the chunk headers are read, so truncated files and chunk sizes
+/// pointing past the end are handled without reading out of bounds.
+///
+/// # Arguments
+///
+/// * `data` - Encoded file.
+///
+/// # Returns
+///
+/// * `Option<(usize, usize, bool, u16)>` - Offset and length of the `data` chunk, whether
+///   the format tag is a compressed codec, and the tag itself, or `None` if the file is
+///   not a WAV file or has no complete `data` chunk.
+pub fn parse_wave(data: &[u8]) -> Option<(usize, usize, bool, u16)> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WAVE" {
         return None;
     }
     let mut off = 12usize;
     let mut fmt_tag: Option<u16> = None;
     let mut data_off = 0usize;
     let mut data_len = 0usize;
-    while off + 8 <= data.len() {
-        let id = &data[off..off + 4];
-        let sz = u32::from_le_bytes([data[off + 4], data[off + 5], data[off + 6], data[off + 7]])
-            as usize;
+    while let Some(header) = data.get(off..off.saturating_add(8)) {
+        let id = &header[..4];
+        let sz = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
         let payload_off = off + 8;
         let payload_end = payload_off.saturating_add(sz);
         if payload_end > data.len() {
             break;
         }
         if id == b"fmt " {
-            if sz >= 2 {
-                fmt_tag = Some(u16::from_le_bytes([
-                    data[payload_off],
-                    data[payload_off + 1],
-                ]));
+            if let Some(tag) = data[payload_off..payload_end].get(..2) {
+                fmt_tag = Some(u16::from_le_bytes([tag[0], tag[1]]));
             }
         } else if id == b"data" {
             data_off = payload_off;
@@ -490,6 +1631,34 @@ fn parse_wave(data: &[u8]) -> Option<(usize, usize, bool, u16)> {
     Some((data_off, data_len, compressed, tag))
 }
 
+/// Whether an Ogg file holds more than one chained link.
+///
+/// A link starts with beginning-of-stream pages, so a beginning-of-stream
+/// page after any other page starts a new link.
+fn is_chained_ogg(data: &[u8]) -> bool {
+    const PAGE_HEADER_LEN: usize = 27;
+    const BOS_FLAG: u8 = 0x02;
+
+    let mut pos = 0;
+    let mut seen_data_page = false;
+    while let Some(header) = data.get(pos..pos + PAGE_HEADER_LEN) {
+        if &header[0..4] != b"OggS" {
+            return false;
+        }
+        let segments = header[26] as usize;
+        let Some(lacing) = data.get(pos + PAGE_HEADER_LEN..pos + PAGE_HEADER_LEN + segments) else {
+            return false;
+        };
+        if header[5] & BOS_FLAG == 0 {
+            seen_data_page = true;
+        } else if seen_data_page {
+            return true;
+        }
+        pos += PAGE_HEADER_LEN + segments + lacing.iter().map(|&l| l as usize).sum::<usize>();
+    }
+    false
+}
+
 fn sniff_format(data: &[u8]) -> Option<&'static str> {
     let n = data.len();
     if n >= 3 && &data[0..3] == b"ID3" {
@@ -510,6 +1679,7 @@ struct ArcSliceSource {
     start: u64,
     len: u64,
     pos: u64,
+    seekable: bool,
 }
 
 impl ArcSliceSource {
@@ -519,8 +1689,15 @@ impl ArcSliceSource {
             start,
             len,
             pos: 0,
+            seekable: true,
         }
     }
+
+    /// Report the source as unseekable so demuxers read it front to back.
+    fn sequential(mut self) -> Self {
+        self.seekable = false;
+        self
+    }
 }
 
 impl Read for ArcSliceSource {
@@ -560,9 +1737,351 @@ impl Seek for ArcSliceSource {
 
 impl MediaSource for ArcSliceSource {
     fn is_seekable(&self) -> bool {
-        true
+        self.seekable
     }
     fn byte_len(&self) -> Option<u64> {
         Some(self.len)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 44.1 kHz as an 80-bit extended float.
+    const RATE_44100: [u8; 10] = [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0];
+
+    /// Stereo test signal; each frame is (left, right).
+    const FRAMES: [(i16, i16); 4] = [(16384, -16384), (8192, -8192), (0, 4096), (-32768, 32767)];
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    /// Build a 16-bit stereo AIFF, or an AIFF-C with the given compression type.
+    fn aiff(compression: Option<&[u8; 4]>) -> Vec<u8> {
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&2u16.to_be_bytes());
+        comm.extend_from_slice(&(FRAMES.len() as u32).to_be_bytes());
+        comm.extend_from_slice(&16u16.to_be_bytes());
+        comm.extend_from_slice(&RATE_44100);
+        let little_endian = compression == Some(b"sowt");
+        if let Some(compression) = compression {
+            // Followed by an empty, padded Pascal string for the compression name.
+            comm.extend_from_slice(compression);
+            comm.extend_from_slice(&[0, 0]);
+        }
+
+        let mut ssnd = vec![0u8; 8];
+        for (left, right) in FRAMES {
+            for sample in [left, right] {
+                ssnd.extend_from_slice(&if little_endian {
+                    sample.to_le_bytes()
+                } else {
+                    sample.to_be_bytes()
+                });
+            }
+        }
+
+        let mut form = if compression.is_some() {
+            let mut form = b"AIFC".to_vec();
+            form.extend(chunk(b"FVER", &0xA280_5140u32.to_be_bytes()));
+            form
+        } else {
+            b"AIFF".to_vec()
+        };
+        form.extend(chunk(b"COMM", &comm));
+        form.extend(chunk(b"SSND", &ssnd));
+        chunk(b"FORM", &form)
+    }
+
+    fn assert_decodes(file: Vec<u8>) {
+        let estimate = estimate_source(&file, file.len() as u64);
+        assert_eq!(
+            (estimate.sample_rate, estimate.channels, estimate.frames),
+            (44100, 2, FRAMES.len() as u64)
+        );
+        assert!(estimate.exact);
+
+        let (samples, frames) =
+            decode_audio(Arc::from(file), 44100, 2, ResampleMethod::Linear).unwrap();
+        assert_eq!(frames, FRAMES.len());
+        let expected: Vec<f32> = FRAMES
+            .iter()
+            .flat_map(|&(left, right)| [left as f32 / 32768.0, right as f32 / 32768.0])
+            .collect();
+        assert_eq!(samples.len(), expected.len());
+        for (actual, expected) in samples.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-4,
+                "expected {expected}, got {actual}"
+            );
+        }
+    }
+
+    /// Frames per FLAC block in the Ogg fixtures.
+    const OGG_BLOCK: usize = 16;
+
+    fn ogg_page(flags: u8, granule: u64, serial: u32, seq: u32, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.push(0);
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&serial.to_le_bytes());
+        page.extend_from_slice(&seq.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        let mut lacing = vec![255u8; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        page.extend_from_slice(packet);
+        set_page_crc(&mut page);
+        page
+    }
+
+    fn set_page_crc(page: &mut [u8]) {
+        use symphonia::core::checksum::Crc32;
+        use symphonia::core::io::Monitor;
+
+        page[22..26].fill(0);
+        let mut crc = Crc32::new(0);
+        crc.process_buf_bytes(page);
+        page[22..26].copy_from_slice(&crc.crc().to_le_bytes());
+    }
+
+    /// One link of a mono 16-bit 44.1 kHz Ogg FLAC stream holding a single
+    /// block of constant samples.
+    fn ogg_flac_link(serial: u32, value: i16) -> Vec<u8> {
+        use symphonia::core::checksum::{Crc8Ccitt, Crc16Ansi};
+        use symphonia::core::io::Monitor;
+
+        let mut header = vec![0x7F];
+        header.extend_from_slice(b"FLAC");
+        header.extend_from_slice(&[1, 0, 0, 0]);
+        header.extend_from_slice(b"fLaC");
+        // Last-block flag and STREAMINFO type, then its 34-byte length.
+        header.extend_from_slice(&[0x80, 0, 0, 34]);
+        header.extend_from_slice(&(OGG_BLOCK as u16).to_be_bytes());
+        header.extend_from_slice(&(OGG_BLOCK as u16).to_be_bytes());
+        header.extend_from_slice(&[0; 6]);
+        // 20-bit rate, 3-bit channels - 1, 5-bit bits - 1, 36-bit frame count.
+        let packed = (44100u64 << 44) | (15 << 36) | OGG_BLOCK as u64;
+        header.extend_from_slice(&packed.to_be_bytes());
+        header.extend_from_slice(&[0; 16]);
+
+        // Fixed-size frame 0 with an 8-bit block size, mono 16-bit samples.
+        let mut frame = vec![0xFF, 0xF8, 0x60, 0x08, 0x00, (OGG_BLOCK - 1) as u8];
+        let mut crc8 = Crc8Ccitt::new(0);
+        crc8.process_buf_bytes(&frame);
+        frame.push(crc8.crc());
+        // Verbatim subframe.
+        frame.push(0x02);
+        for _ in 0..OGG_BLOCK {
+            frame.extend_from_slice(&value.to_be_bytes());
+        }
+        let mut crc16 = Crc16Ansi::new(0);
+        crc16.process_buf_bytes(&frame);
+        frame.extend_from_slice(&crc16.crc().to_be_bytes());
+
+        let mut link = ogg_page(0x02, 0, serial, 0, &header);
+        link.extend(ogg_page(0x04, OGG_BLOCK as u64, serial, 1, &frame));
+        link
+    }
+
+    #[test]
+    fn resampled_impulse_keeps_its_position() {
+        const AT: usize = 300;
+        for (src_sr, target_sr) in [(22050, 44100), (48000, 44100), (44100, 32000)] {
+            let mut impulse = vec![0.0f32; 1000];
+            impulse[AT] = 1.0;
+            let out = resample(&impulse, 1, src_sr, target_sr, ResampleMethod::Sinc).unwrap();
+            let peak = (0..out.len())
+                .max_by(|&a, &b| out[a].total_cmp(&out[b]))
+                .unwrap();
+            // The peak lands within a frame of the impulse's exact position.
+            let expected = AT as f64 * target_sr as f64 / src_sr as f64;
+            assert!(
+                (peak as f64 - expected).abs() < 1.0,
+                "{src_sr} -> {target_sr} Hz: peak at {peak}, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn five_one_downmix_gains() {
+        let layout = Channels::FRONT_LEFT
+            | Channels::FRONT_RIGHT
+            | Channels::FRONT_CENTRE
+            | Channels::LFE1
+            | Channels::SIDE_LEFT
+            | Channels::SIDE_RIGHT;
+        let front = 1.0 / (1.0 + std::f32::consts::SQRT_2);
+        let side = front * std::f32::consts::FRAC_1_SQRT_2;
+        let expected = [
+            (front, 0.0),
+            (0.0, front),
+            (side, side),
+            (0.0, 0.0),
+            (side, 0.0),
+            (0.0, side),
+        ];
+        let gains = downmix_gains(layout);
+        assert_eq!(gains.len(), expected.len());
+        for ((l, r), (el, er)) in gains.into_iter().zip(expected) {
+            assert!((l - el).abs() < 1e-6 && (r - er).abs() < 1e-6);
+        }
+        assert!((20.0 * front.log10() + 7.66).abs() < 0.01);
+    }
+
+    #[test]
+    fn six_channel_wav_is_downmixed() {
+        // WAVE_FORMAT_EXTENSIBLE, 5.1 (mask 0x3F), 16-bit PCM.
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&0xFFFEu16.to_le_bytes());
+        fmt.extend_from_slice(&6u16.to_le_bytes());
+        fmt.extend_from_slice(&44100u32.to_le_bytes());
+        fmt.extend_from_slice(&(44100u32 * 12).to_le_bytes());
+        fmt.extend_from_slice(&12u16.to_le_bytes());
+        fmt.extend_from_slice(&16u16.to_le_bytes());
+        fmt.extend_from_slice(&22u16.to_le_bytes());
+        fmt.extend_from_slice(&16u16.to_le_bytes());
+        fmt.extend_from_slice(&0x3Fu32.to_le_bytes());
+        fmt.extend_from_slice(&[
+            1, 0, 0, 0, 0, 0, 0x10, 0, 0x80, 0, 0, 0xAA, 0, 0x38, 0x9B, 0x71,
+        ]);
+        // Each channel alone at half scale, then all of them at once.
+        let mut data = Vec::new();
+        let mut frames: Vec<[i16; 6]> = (0..6)
+            .map(|ch| {
+                let mut frame = [0i16; 6];
+                frame[ch] = 16384;
+                frame
+            })
+            .collect();
+        frames.push([16384; 6]);
+        for frame in &frames {
+            for sample in frame {
+                data.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&((4 + 8 + fmt.len() + 8 + data.len()) as u32).to_le_bytes());
+        file.extend_from_slice(b"WAVEfmt ");
+        file.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+        file.extend_from_slice(&fmt);
+        file.extend_from_slice(b"data");
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(&data);
+
+        let (samples, frame_count) =
+            decode_audio(Arc::from(file), 44100, 2, ResampleMethod::Linear).unwrap();
+        assert_eq!(frame_count, frames.len());
+        let front = 0.5 / (1.0 + std::f32::consts::SQRT_2);
+        let side = front * std::f32::consts::FRAC_1_SQRT_2;
+        let expected = [
+            (front, 0.0),
+            (0.0, front),
+            (side, side),
+            (0.0, 0.0),
+            (side, 0.0),
+            (0.0, side),
+            (front + 2.0 * side, front + 2.0 * side),
+        ];
+        for (frame, (l, r)) in samples.as_chunks::<2>().0.iter().zip(expected) {
+            assert!(
+                (frame[0] - l).abs() < 1e-3 && (frame[1] - r).abs() < 1e-3,
+                "{frame:?} != ({l}, {r})"
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_aiff() {
+        assert_decodes(aiff(None));
+    }
+
+    #[test]
+    fn header_rate_is_read_from_the_file() {
+        let file = aiff(None);
+        let (_, info) =
+            decode_audio_with_info(Arc::from(file), 44100, 2, ResampleMethod::Linear).unwrap();
+        assert_eq!(
+            (info.container_rate, info.stream_rate),
+            (Some(44100), Some(44100))
+        );
+        assert_eq!(info.rate_mismatch(), None);
+    }
+
+    #[test]
+    fn decodes_big_endian_aiff_c() {
+        assert_decodes(aiff(Some(b"NONE")));
+    }
+
+    #[test]
+    fn decodes_sowt_aiff_c() {
+        assert_decodes(aiff(Some(b"sowt")));
+    }
+
+    #[test]
+    fn undecodable_packets_count_against_the_deadline() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        static NOW: AtomicU32 = AtomicU32::new(0);
+        fn ticking_clock() -> f64 {
+            NOW.fetch_add(1, Ordering::Relaxed) as f64
+        }
+
+        // Break the frame header checksum so the packet fails to decode and is
+        // skipped; the page checksum is redone so the Ogg layer still accepts it.
+        let mut file = ogg_flac_link(1, 8192);
+        let frame_len = 2 * OGG_BLOCK + 10;
+        let audio_page = file.len() - ogg_page(0, 0, 0, 0, &vec![0; frame_len]).len();
+        let header_crc = file.len() - frame_len + 6;
+        file[header_crc] ^= 0xFF;
+        set_page_crc(&mut file[audio_page..]);
+        let limits = DecodeLimits {
+            max_decode_ms: Some(0.5),
+            clock: ticking_clock,
+            ..DecodeLimits::default()
+        };
+        let result = decode_audio_with_limits(
+            Arc::from(file),
+            44100,
+            1,
+            ResampleMethod::Linear,
+            None,
+            &limits,
+        );
+        assert!(
+            matches!(result, Err(DecodeError::TimedOut { .. })),
+            "{:?}",
+            result.map(|r| r.0.1)
+        );
+    }
+
+    #[test]
+    fn decodes_every_link_of_a_chained_ogg() {
+        let single = ogg_flac_link(1, 8192);
+        assert!(!is_chained_ogg(&single));
+
+        let mut chained = single;
+        chained.extend(ogg_flac_link(2, -8192));
+        assert!(is_chained_ogg(&chained));
+
+        let (samples, frames) =
+            decode_audio(Arc::from(chained), 44100, 1, ResampleMethod::Linear).unwrap();
+        assert_eq!(frames, 2 * OGG_BLOCK);
+        let (first, second) = samples.split_at(OGG_BLOCK);
+        assert!(first.iter().all(|&v| (v - 0.25).abs() < 1e-4), "{first:?}");
+        assert!(
+            second.iter().all(|&v| (v + 0.25).abs() < 1e-4),
+            "{second:?}"
+        );
+    }
+}
@@ -0,0 +1,80 @@
+/// Standard base64 alphabet (RFC 4648).
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode complete 3-byte groups, without padding.
+fn encode_groups(data: &[u8], out: &mut String) {
+    debug_assert!(data.len().is_multiple_of(3));
+    out.reserve(data.len() / 3 * 4);
+    for group in data.as_chunks::<3>().0 {
+        let n = (group[0] as u32) << 16 | (group[1] as u32) << 8 | group[2] as u32;
+        for shift in [18, 12, 6, 0] {
+            out.push(ALPHABET[(n >> shift) as usize & 63] as char);
+        }
+    }
+}
+
+/// Incremental base64 encoder for streamed output.
+///
+/// Every chunk it returns encodes a multiple of 3 input bytes, so the chunks
+/// can be concatenated as-is (for example into a data URL) and only the final
+/// chunk carries padding.
+#[derive(Debug, Default)]
+pub struct Base64Chunker {
+    /// Up to 2 bytes carried over to the next chunk.
+    pending: Vec<u8>,
+}
+
+impl Base64Chunker {
+    /// Create an encoder with no pending bytes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encode as much of `data` as fits in whole 3-byte groups.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Next bytes of the stream.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Base64 text without padding (possibly empty).
+    pub fn push(&mut self, data: &[u8]) -> String {
+        let mut out = String::new();
+        let mut data = data;
+        if !self.pending.is_empty() {
+            let take = (3 - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < 3 {
+                return out;
+            }
+            encode_groups(&self.pending, &mut out);
+            self.pending.clear();
+        }
+        let whole = data.len() - data.len() % 3;
+        encode_groups(&data[..whole], &mut out);
+        self.pending.extend_from_slice(&data[whole..]);
+        out
+    }
+
+    /// Encode the remaining bytes with padding.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Final base64 text (empty if nothing was pending).
+    pub fn finish(self) -> String {
+        let mut out = String::new();
+        if self.pending.is_empty() {
+            return out;
+        }
+        let mut group = [0u8; 3];
+        group[..self.pending.len()].copy_from_slice(&self.pending);
+        encode_groups(&group, &mut out);
+        out.truncate(self.pending.len() + 1);
+        while out.len() < 4 {
+            out.push('=');
+        }
+        out
+    }
+}
@@ -1,4 +1,12 @@
+use crate::control::{RandomChoice, resolve_control_flow};
+use crate::dtx::parse_dtx;
+use crate::encoding::{TextEncoding, decode_text};
+use crate::hash::ChartHash;
+use crate::random::{RandomSource, SplitMix64};
 use ahash::AHashMap;
+use num_enum::TryFromPrimitive;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 /// Prefix used by BMS files to mark section headers.
@@ -40,14 +48,19 @@ impl BmsField {
 }
 
 /// Parsed BMS chart containing header and timeline messages.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "chart-serde", derive(Serialize, Deserialize))]
 pub struct Bms {
     /// Parsed header metadata and lookup tables.
     pub header: Header,
     /// Timeline messages (per-measure, per-channel, with objects).
     pub messages: Vec<Message>,
     /// Per-measure length multipliers (e.g., for measure length changes).
-    pub measure_multipliers: AHashMap<u16, f64>,
+    pub measure_multipliers: AHashMap<MeasureIndex, f64>,
+    /// Button layout the chart was parsed for.
+    pub mode: ChartMode,
+    /// Values selected for the `#RANDOM` and `#SWITCH` commands that were reached.
+    pub random_choices: Vec<RandomChoice>,
 }
 
 impl Bms {
@@ -61,53 +74,835 @@ impl Bms {
     ///
     /// * `Result<Bms, ParseError>` - Parsed chart or an error.
     pub fn parse(data: &str) -> Result<Self, ParseError> {
-        let mut bms = Bms::default();
-        let mut current_field = BmsField::Unknown;
+        Self::parse_with_report(data).map(|(bms, _)| bms)
+    }
+
+    /// Parse a BMS file and collect diagnostics about the lines it had to skip.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Full text content of a BMS file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Bms, ParseReport), ParseError>` - Parsed chart and its diagnostics, or an error.
+    pub fn parse_with_report(data: &str) -> Result<(Self, ParseReport), ParseError> {
+        Self::parse_with_options(data, &ParseOptions::default())
+    }
+
+    /// Parse a BMS file with explicit options.
+    ///
+    /// In strict mode, table entry problems and malformed data lines are
+    /// errors, and the parse fails if any error was found. `#RANDOM` and
+    /// `#SWITCH` blocks are resolved with `options.random_seed` (see
+    /// `parse_with_random` for other sources).
+    /// `ChartMode::Dtx` reads the text as a DTXMania chart instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Full text content of a BMS file.
+    /// * `options` - Parsing options.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Bms, ParseReport), ParseError>` - Parsed chart and its diagnostics, or an error.
+    pub fn parse_with_options(
+        data: &str,
+        options: &ParseOptions,
+    ) -> Result<(Self, ParseReport), ParseError> {
+        Self::parse_with_random(data, options, &mut SplitMix64::new(options.random_seed))
+    }
+
+    /// Parse a BMS file, drawing `#RANDOM` and `#SWITCH` values from `rng`.
+    ///
+    /// Same as `parse_with_options`, with `rng` in place of
+    /// `options.random_seed`, so hosts can apply their own randomness policy
+    /// or replay recorded values. `options.forced_random` still takes precedence.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Full text content of a BMS file.
+    /// * `options` - Parsing options.
+    /// * `rng` - Source of the values of `#RANDOM` and `#SWITCH` commands.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Bms, ParseReport), ParseError>` - Parsed chart and its diagnostics, or an error.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(bytes = data.len()))
+    )]
+    pub fn parse_with_random(
+        data: &str,
+        options: &ParseOptions,
+        rng: &mut dyn RandomSource,
+    ) -> Result<(Self, ParseReport), ParseError> {
+        if options.mode == ChartMode::Dtx {
+            let (bms, report) = parse_dtx(data);
+            if options.strict && report.has_errors() {
+                return Err(ParseError::Strict(report));
+            }
+            return Ok((bms, report));
+        }
+        let table_severity = options.table_severity();
+        let mut bms = Bms {
+            mode: options.mode,
+            ..Bms::default()
+        };
+        let mut report = ParseReport::default();
+        let mut data_lines: Vec<(usize, &str)> = Vec::new();
+        // Table definitions with the object id base in effect at their line.
+        let mut table_lines: Vec<(usize, &str, u32)> = Vec::new();
+        let measure_parser = options.measure_parser.unwrap_or(standard_measure);
+
+        // Lines are classified by syntax, so files without section markers parse too.
+        let (lines, random_choices) = resolve_control_flow(data, rng, &options.forced_random);
+        bms.random_choices = random_choices;
+        for (line_no, line) in lines {
+            if line.starts_with(BMS_FIELD_PREFIX) {
+                continue;
+            }
+
+            if DataLine::matches(line, measure_parser) {
+                data_lines.push((line_no, line));
+            } else if TableEntry::matches(line) {
+                table_lines.push((line_no, line, bms.header.object_base()));
+            } else if let Some((category, problem)) =
+                bms.header.parse_line(line, options.duplicate_policy)
+                && options.reports(category)
+            {
+                report.push(line_no, table_severity, category, problem);
+            }
+        }
+
+        // Definitions are parsed in parallel and added in file order, so
+        // duplicate ids resolve as if the lines were read one by one.
+        let entries: Vec<(usize, Result<TableEntry, _>)> = table_lines
+            .par_iter()
+            .with_min_len(TABLE_LINES_PER_TASK)
+            .map(|&(line_no, line, base)| (line_no, TableEntry::parse(line, base)))
+            .collect();
+        for (line_no, entry) in entries {
+            let problem = match entry {
+                Ok(entry) => bms.header.define(entry, options.duplicate_policy),
+                Err(problem) => Some(problem),
+            };
+            if let Some((category, problem)) = problem
+                && options.reports(category)
+            {
+                report.push(line_no, table_severity, category, problem);
+            }
+        }
+
+        let base = bms.header.object_base();
+        let parsed: Vec<(usize, Result<DataLine, String>)> = data_lines
+            .par_iter()
+            .with_min_len(DATA_LINES_PER_TASK)
+            .map(|&(line_no, line)| {
+                (
+                    line_no,
+                    DataLine::parse(line, base, options.strict, measure_parser),
+                )
+            })
+            .collect();
+
+        for (line_no, data_line) in parsed {
+            bms.add_data_line(line_no, data_line, &mut report);
+        }
+        report.diagnostics.sort_by_key(|d| d.line);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            messages = bms.messages.len(),
+            diagnostics = report.diagnostics.len(),
+            "chart parsed"
+        );
+        if options.strict && report.has_errors() {
+            return Err(ParseError::Strict(report));
+        }
+        Ok((bms, report))
+    }
+
+    /// Add a parsed data line, reporting channels the chart's layout does not use.
+    ///
+    /// # Arguments
+    ///
+    /// * `line_no` - 1-based line number for diagnostics.
+    /// * `data_line` - Parsed line, or why it was skipped.
+    /// * `report` - Diagnostics to add to.
+    pub(crate) fn add_data_line(
+        &mut self,
+        line_no: usize,
+        data_line: Result<DataLine, String>,
+        report: &mut ParseReport,
+    ) {
+        match data_line {
+            Ok(DataLine::MeasureLength(measure, mult)) => {
+                self.measure_multipliers.insert(measure, mult);
+            }
+            Ok(DataLine::Message(mut message)) => {
+                // Lanes past 9 only exist in extended layouts, so decode them again
+                // now that the header is known.
+                if let Channel::Unknown(code) = message.channel {
+                    message.channel = Channel::from_code_in(code, self.header.lane_layout());
+                }
+                if !is_known_channel(message.channel) {
+                    report.push(
+                        line_no,
+                        Severity::Warning,
+                        DiagnosticCategory::UnknownChannel,
+                        format!(
+                            "unknown channel {}",
+                            format_object_id(message.channel.code())
+                        ),
+                    );
+                } else if self.mode == ChartMode::Pms
+                    && message.channel.lane().is_some()
+                    && message.channel.pms_button().is_none()
+                {
+                    report.push(
+                        line_no,
+                        Severity::Warning,
+                        DiagnosticCategory::UnknownChannel,
+                        format!(
+                            "channel {} is not part of the PMS layout",
+                            format_object_id(message.channel.code())
+                        ),
+                    );
+                }
+                self.messages.push(message);
+            }
+            Err(problem) => report.push(
+                line_no,
+                Severity::Error,
+                DiagnosticCategory::MalformedLine,
+                problem,
+            ),
+        }
+    }
+
+    /// Parse raw BMS bytes, detecting UTF-8, Shift-JIS or EUC-KR.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Raw file contents.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Bms, ParseError>` - Parsed chart or an error.
+    pub fn parse_bytes(data: &[u8]) -> Result<Self, ParseError> {
+        Self::parse_bytes_with_encoding(data, None)
+    }
+
+    /// Parse raw BMS bytes with an optional encoding override.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Raw file contents.
+    /// * `encoding` - Encoding to use, or `None` to detect it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Bms, ParseError>` - Parsed chart or an error.
+    pub fn parse_bytes_with_encoding(
+        data: &[u8],
+        encoding: Option<TextEncoding>,
+    ) -> Result<Self, ParseError> {
+        let (text, _) = decode_text(data, encoding);
+        Self::parse(&text)
+    }
+
+    /// Parse only the header commands of a BMS file, skipping data lines unparsed.
+    ///
+    /// Meant for song browsers that only need metadata; note data is not
+    /// stored.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Full text content of a BMS file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Header, ParseError>` - Parsed header or an error.
+    pub fn parse_header(data: &str) -> Result<Header, ParseError> {
+        let mut header = Header::default();
 
         for line in data.lines() {
             let line = line.trim();
 
-            if line.starts_with(BMS_FIELD_PREFIX) {
-                current_field = BmsField::parse(line);
+            if line.starts_with(BMS_FIELD_PREFIX) || DataLine::matches(line, standard_measure) {
                 continue;
             }
+            let _ = header.parse_line(line, DuplicatePolicy::default());
+        }
+        Ok(header)
+    }
+}
 
-            match current_field {
-                BmsField::Header => bms.header.parse_line(line),
-                BmsField::Data => {
-                    if line.starts_with('#') && line.len() >= 7 {
-                        let mmm = &line[1..4];
-                        let cc = &line[4..6];
-                        if cc.eq_ignore_ascii_case("02")
-                            && let Some((_hash, rest)) = line.split_once(':')
-                        {
-                            if let Ok(measure) = mmm.parse::<u16>() {
-                                let v = rest.trim();
-                                if let Ok(mult) = v.parse::<f64>()
-                                    && mult.is_finite()
-                                    && mult > 0.0
-                                {
-                                    bms.measure_multipliers.insert(measure, mult);
-                                }
-                            }
-                            continue;
-                        }
-                    }
-                    if let Ok(message) = Message::parse(line) {
-                        bms.messages.push(message);
-                    }
+/// Options controlling how a chart is parsed.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Fail on malformed lines, invalid or duplicate table entries and bad
+    /// object ids instead of skipping them.
+    pub strict: bool,
+    /// Button layout to read note channels with.
+    pub mode: ChartMode,
+    /// Seed for `#RANDOM` and `#SWITCH`; the same seed always selects the same branches.
+    ///
+    /// Defaults to 0, so repeated parses of a chart agree unless a seed is
+    /// given. Pass a fresh seed (or use `forced_random`) to draw other branches.
+    pub random_seed: u64,
+    /// Values to use for `#RANDOM` and `#SWITCH` commands instead of drawing
+    /// them, matched by line number, such as an earlier parse's `random_choices`.
+    pub forced_random: Vec<RandomChoice>,
+    /// Reads the measure number of data lines; `None` accepts the standard
+    /// three digits only (`standard_measure`).
+    pub measure_parser: Option<MeasureParser>,
+    /// Which of several `#WAVxx`, `#BMPxx`, `#BPMxx` or `#STOPxx` definitions of
+    /// the same id is used.
+    pub duplicate_policy: DuplicatePolicy,
+}
+
+impl ParseOptions {
+    /// Severity of rejected and duplicate table entries.
+    pub(crate) fn table_severity(&self) -> Severity {
+        if self.strict {
+            Severity::Error
+        } else {
+            Severity::Warning
+        }
+    }
+
+    /// Whether a table entry problem of `category` is recorded.
+    ///
+    /// Duplicates are only silent under `LastWins` and `FirstWins` outside
+    /// strict mode; strict parses always fail on them.
+    pub(crate) fn reports(&self, category: DiagnosticCategory) -> bool {
+        category != DiagnosticCategory::DuplicateDefinition
+            || self.strict
+            || self.duplicate_policy == DuplicatePolicy::WarnAndPick
+    }
+}
+
+/// How repeated definitions of the same table entry are resolved.
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, TryFromPrimitive, Serialize)]
+pub enum DuplicatePolicy {
+    /// Later definitions replace earlier ones and each conflict is reported.
+    #[default]
+    WarnAndPick,
+    /// Later definitions replace earlier ones without a diagnostic, except
+    /// in strict mode.
+    LastWins,
+    /// Later definitions are ignored without a diagnostic, except in strict
+    /// mode, as many broken charts expect.
+    FirstWins,
+}
+
+impl<'de> Deserialize<'de> for DuplicatePolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct DuplicatePolicyVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for DuplicatePolicyVisitor {
+            type Value = DuplicatePolicy;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an i64")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                DuplicatePolicy::try_from(value as u8)
+                    .map_err(|_| E::custom("Invalid DuplicatePolicy"))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                self.visit_i64(value as i64)
+            }
+        }
+
+        deserializer.deserialize_any(DuplicatePolicyVisitor)
+    }
+}
+
+/// Add a table entry, resolving an existing definition of the same id with `policy`.
+///
+/// # Arguments
+///
+/// * `table` - Table to update.
+/// * `id` - Object id of the entry.
+/// * `value` - Newly defined value.
+/// * `policy` - Which definition to keep.
+/// * `raw_key` - Command as written, for the diagnostic.
+///
+/// # Returns
+///
+/// * `Option<(DiagnosticCategory, String)>` - The conflict, if the id was already defined.
+///   `ParseOptions::reports` decides whether it is recorded.
+fn define<V: std::fmt::Display>(
+    table: &mut HashMap<ObjectId, V>,
+    id: ObjectId,
+    value: V,
+    policy: DuplicatePolicy,
+    raw_key: &str,
+) -> Option<(DiagnosticCategory, String)> {
+    if policy == DuplicatePolicy::FirstWins
+        && let Some(kept) = table.get(&id)
+    {
+        return Some((
+            DiagnosticCategory::DuplicateDefinition,
+            format!("#{} redefined as {} (kept {})", raw_key, value, kept),
+        ));
+    }
+    let previous = table.insert(id, value)?;
+    Some((
+        DiagnosticCategory::DuplicateDefinition,
+        format!("#{} redefined (was {})", raw_key, previous),
+    ))
+}
+
+/// Minimum number of table definitions handed to a single parsing task.
+const TABLE_LINES_PER_TASK: usize = 1024;
+
+/// Value of a parsed table definition.
+pub(crate) enum TableValue {
+    /// `#WAVxx` or `#OGGxx` filename.
+    Audio,
+    /// `#BMPxx` filename.
+    Bmp,
+    /// `#BPMxx` tempo.
+    Bpm(f64),
+    /// `#STOPxx` length in 192nds of a whole note.
+    Stop(f64),
+}
+
+/// A `#WAV`, `#BMP`, `#BPM` or `#STOP` table definition, parsed but not yet
+/// added to its table.
+///
+/// Definitions only depend on their own line and the `#BASE` in effect, so
+/// they can be parsed in any order and added in file order afterwards.
+pub(crate) struct TableEntry<'a> {
+    /// Command as written, for diagnostics.
+    raw_key: &'a str,
+    id: ObjectId,
+    /// Value as written, without surrounding quotes.
+    text: &'a str,
+    value: TableValue,
+}
+
+impl<'a> TableEntry<'a> {
+    /// Whether a header line is a table definition.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - A header line starting with `#`.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` for `#WAVxx`, `#OGGxx`, `#BMPxx`, `#BPMxx` and `#STOPxx` lines.
+    pub(crate) fn matches(line: &str) -> bool {
+        let Some((key, _)) = line.strip_prefix('#').and_then(|l| l.split_once(' ')) else {
+            return false;
+        };
+        let has_prefix = |prefix: &str| {
+            key.get(..prefix.len())
+                .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+        };
+        if key.eq_ignore_ascii_case("WAVCMD") {
+            return false;
+        }
+        has_prefix("WAV")
+            || has_prefix("OGG")
+            || has_prefix("BMP")
+            || (has_prefix("BPM") && key.len() > 3)
+            || has_prefix("STOP")
+    }
+
+    /// Parse a table definition.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - A header line accepted by `TableEntry::matches`.
+    /// * `base` - Object id base in effect at the line.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<TableEntry, (DiagnosticCategory, String)>` - The definition, or why it was rejected.
+    pub(crate) fn parse(line: &'a str, base: u32) -> Result<Self, (DiagnosticCategory, String)> {
+        let invalid = |problem: String| (DiagnosticCategory::InvalidTableEntry, problem);
+        let (raw_key, value) = line
+            .strip_prefix('#')
+            .and_then(|l| l.split_once(' '))
+            .ok_or_else(|| invalid(format!("invalid table definition: {}", line)))?;
+        let text = value.trim().trim_matches('"');
+        let key = raw_key.to_uppercase();
+        let id_at = |start: usize| {
+            raw_key
+                .get(start..)
+                .and_then(|id| parse_object_id_with_base(id.as_bytes(), base))
+                .ok_or_else(|| invalid(format!("invalid object id in #{}", raw_key)))
+        };
+        let (id, value) = if key.starts_with("WAV") || key.starts_with("OGG") {
+            (id_at(3)?, TableValue::Audio)
+        } else if key.starts_with("BMP") {
+            (id_at(3)?, TableValue::Bmp)
+        } else if key.starts_with("BPM") {
+            let id = id_at(3)?;
+            // Non-positive tempos are kept for the tempo map's `BpmPolicy` to handle.
+            match text.parse::<f64>() {
+                Ok(bpm) if bpm.is_finite() => (id, TableValue::Bpm(bpm)),
+                _ => return Err(invalid(format!("invalid BPM in #{}: {}", raw_key, text))),
+            }
+        } else {
+            let id = id_at(4)?;
+            match text.parse::<f64>() {
+                Ok(stop) if stop.is_finite() && stop >= 0.0 => (id, TableValue::Stop(stop)),
+                _ => {
+                    return Err(invalid(format!(
+                        "invalid stop length in #{}: {}",
+                        raw_key, text
+                    )));
+                }
+            }
+        };
+        Ok(TableEntry {
+            raw_key,
+            id,
+            text,
+            value,
+        })
+    }
+}
+
+/// Reads the measure number at the start of a data line.
+///
+/// Receives the line without its leading `#` and returns the measure and the
+/// number of characters it spans, or `None` if the line is not a data line.
+pub type MeasureParser = fn(&str) -> Option<(MeasureIndex, usize)>;
+
+/// Read a standard measure number: exactly three decimal digits (`000`-`999`).
+///
+/// # Arguments
+///
+/// * `key` - Data line without its leading `#`.
+///
+/// # Returns
+///
+/// * `Option<(MeasureIndex, usize)>` - Measure and its length (always 3).
+pub fn standard_measure(key: &str) -> Option<(MeasureIndex, usize)> {
+    let digits = key.get(..3)?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((digits.parse().ok()?, 3))
+}
+
+/// Largest measure accepted by `extended_measure`.
+///
+/// The tempo map holds one entry per measure up to the last, so a measure
+/// number near `u32::MAX` would allocate gigabytes for a one-line chart.
+pub const MAX_EXTENDED_MEASURE: MeasureIndex = 99_999;
+
+/// Read measure numbers past 999 as written by chart converters.
+///
+/// Besides the standard three digits, accepts longer decimal runs
+/// (`#100011:` is measure 1000, channel 11) and a base-36 letter in the
+/// hundreds place (`#A0011:` is also measure 1000). The channel is always the
+/// two characters before the colon. Measures past `MAX_EXTENDED_MEASURE` are
+/// rejected.
+///
+/// # Arguments
+///
+/// * `key` - Data line without its leading `#`.
+///
+/// # Returns
+///
+/// * `Option<(MeasureIndex, usize)>` - Measure and its length in characters.
+pub fn extended_measure(key: &str) -> Option<(MeasureIndex, usize)> {
+    let len = key.find(':')?.checked_sub(2)?;
+    let bytes = &key.as_bytes()[..len];
+    if len < 3 {
+        return None;
+    }
+    if bytes.iter().all(u8::is_ascii_digit) {
+        let measure = key[..len].parse().ok()?;
+        return (measure <= MAX_EXTENDED_MEASURE).then_some((measure, len));
+    }
+    if len == 3 && bytes[0].is_ascii_uppercase() && bytes[1..].iter().all(u8::is_ascii_digit) {
+        let hundreds = (bytes[0] - b'A' + 10) as MeasureIndex;
+        return Some((hundreds * 100 + key[1..3].parse::<MeasureIndex>().ok()?, 3));
+    }
+    None
+}
+
+/// Button layout of a chart.
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, TryFromPrimitive, Serialize)]
+pub enum ChartMode {
+    /// beatmania layout: keys and turntable on channels 11-19 and 21-29.
+    #[default]
+    Bms,
+    /// pop'n music layout: nine buttons on channels 11-15 and 22-25.
+    Pms,
+    /// DTXMania drum layout, read with `dtx::parse_dtx`.
+    Dtx,
+}
+
+impl<'de> Deserialize<'de> for ChartMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct ChartModeVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ChartModeVisitor {
+            type Value = ChartMode;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an i64 or a variant name")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                ChartMode::try_from(value as u8).map_err(|_| E::custom("Invalid ChartMode"))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                self.visit_i64(value as i64)
+            }
+
+            // Accept the variant names written by `Serialize`.
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                match value {
+                    "Bms" => Ok(ChartMode::Bms),
+                    "Pms" => Ok(ChartMode::Pms),
+                    "Dtx" => Ok(ChartMode::Dtx),
+                    _ => Err(E::custom("Invalid ChartMode")),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(ChartModeVisitor)
+    }
+}
+
+/// Minimum number of data lines handed to a single parsing task.
+const DATA_LINES_PER_TASK: usize = 4096;
+
+/// A classified line from the main data section.
+pub(crate) enum DataLine {
+    /// Channel 02 measure length change.
+    MeasureLength(MeasureIndex, f64),
+    /// Any other timeline message.
+    Message(Message),
+}
+
+impl DataLine {
+    /// Whether a line has data syntax (`#mmmcc:...`) rather than a header command.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - A trimmed line of the file.
+    /// * `measure_parser` - Reads the measure number.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` for timeline message lines.
+    pub(crate) fn matches(line: &str, measure_parser: MeasureParser) -> bool {
+        let Some(key) = line.strip_prefix('#') else {
+            return false;
+        };
+        let Some((_, len)) = measure_parser(key) else {
+            return false;
+        };
+        key.as_bytes()
+            .get(len..len + 3)
+            .is_some_and(|rest| rest[..2].iter().all(u8::is_ascii_alphanumeric) && rest[2] == b':')
+    }
+
+    /// Classify and parse a single data line.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - A trimmed line from the data section.
+    /// * `base` - Object id base of the chart.
+    /// * `strict` - Reject object tokens that are not valid ids instead of reading them as `00`.
+    /// * `measure_parser` - Reads the measure number.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DataLine, String>` - Parsed line, or why it was skipped.
+    pub(crate) fn parse(
+        line: &str,
+        base: u32,
+        strict: bool,
+        measure_parser: MeasureParser,
+    ) -> Result<DataLine, String> {
+        let invalid = || ParseError::InvalidFormat.to_string();
+        let (measure, len) = line
+            .strip_prefix('#')
+            .and_then(measure_parser)
+            .ok_or_else(invalid)?;
+        let cc = line.get(1 + len..3 + len).ok_or_else(invalid)?;
+        if cc.eq_ignore_ascii_case("02") {
+            let rest = line.split_once(':').map_or("", |(_, rest)| rest.trim());
+            return match rest.parse::<f64>() {
+                Ok(mult) if mult.is_finite() && mult > 0.0 => {
+                    Ok(DataLine::MeasureLength(measure, mult))
                 }
-                BmsField::Unknown => continue,
+                _ => Err(format!("invalid measure length: {}", rest)),
+            };
+        }
+        let message = Message::parse_with_measure_parser(line, base, measure_parser)
+            .map_err(|e| e.to_string())?;
+        if strict {
+            let base = if message.channel == Channel::Bpm {
+                36
+            } else {
+                base
+            };
+            let objects = line.split_once(':').map_or("", |(_, rest)| rest);
+            if let Some(token) = objects
+                .as_bytes()
+                .chunks(2)
+                .find(|token| parse_object_id_with_base(token, base).is_none())
+            {
+                return Err(format!(
+                    "invalid object id: {}",
+                    String::from_utf8_lossy(token)
+                ));
             }
         }
-        Ok(bms)
+        Ok(DataLine::Message(message))
     }
 }
 
+/// Whether a channel has a known meaning in BMS or its common extensions.
+///
+/// # Arguments
+///
+/// * `channel` - Decoded channel.
+///
+/// # Returns
+///
+/// * `bool` - `false` for channels no player would recognize.
+fn is_known_channel(channel: Channel) -> bool {
+    let Channel::Unknown(code) = channel else {
+        return true;
+    };
+    let (group, lane) = (code / 36, code % 36);
+    match group {
+        // 0B-0E: BGA opacity.
+        0 => (11..=14).contains(&lane),
+        // 99: text.
+        9 => lane == 9,
+        _ => false,
+    }
+}
+
+/// Measure index of a timeline message (the `mmm` of `#mmmcc:`).
+///
+/// Wider than the three digits of standard charts so that long medleys read
+/// with `extended_measure` are not truncated.
+pub type MeasureIndex = u32;
+
+/// Numeric object id decoded from a two-character token (`00` decodes to `0`, the empty object).
 pub type ObjectId = u16;
 
+/// Encode an object id as a two-character base-36 token (e.g. `1` becomes `"01"`).
+///
+/// # Arguments
+///
+/// * `id` - Numeric object id.
+///
+/// # Returns
+///
+/// * `String` - Uppercase token as written in BMS files.
+pub fn format_object_id(id: ObjectId) -> String {
+    format_object_id_with_base(id, 36)
+}
+
+/// Encode an object id as a two-character token in base 36 or 62.
+///
+/// # Arguments
+///
+/// * `id` - Numeric object id.
+/// * `base` - Object id base (`36`, or `62` for `#BASE 62` charts).
+///
+/// # Returns
+///
+/// * `String` - Token as written in BMS files.
+pub fn format_object_id_with_base(id: ObjectId, base: u32) -> String {
+    const DIGITS: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let base = base as ObjectId;
+    let hi = (id / base % base) as usize;
+    let lo = (id % base) as usize;
+    format!("{}{}", DIGITS[hi] as char, DIGITS[lo] as char)
+}
+
+/// Decode a base-36 object token into its numeric id.
+///
+/// # Arguments
+///
+/// * `token` - ASCII digits of the token (usually two characters).
+///
+/// # Returns
+///
+/// * `Option<ObjectId>` - Decoded id, or `None` for invalid digits or overflow.
+pub fn parse_object_id(token: &[u8]) -> Option<ObjectId> {
+    parse_object_id_with_base(token, 36)
+}
+
+/// Decode an object token in base 36 (case-insensitive) or base 62 (case-sensitive).
+///
+/// # Arguments
+///
+/// * `token` - ASCII digits of the token (usually two characters).
+/// * `base` - Object id base (`36`, or `62` for `#BASE 62` charts).
+///
+/// # Returns
+///
+/// * `Option<ObjectId>` - Decoded id, or `None` for invalid digits or overflow.
+pub fn parse_object_id_with_base(token: &[u8], base: u32) -> Option<ObjectId> {
+    if token.is_empty() {
+        return None;
+    }
+    let mut value: ObjectId = 0;
+    for &b in token {
+        let digit = match b {
+            b'0'..=b'9' => b - b'0',
+            b'A'..=b'Z' => b - b'A' + 10,
+            b'a'..=b'z' if base == 62 => b - b'a' + 36,
+            b'a'..=b'z' => b - b'a' + 10,
+            _ => return None,
+        };
+        value = value
+            .checked_mul(base as ObjectId)?
+            .checked_add(digit as ObjectId)?;
+    }
+    Some(value)
+}
+
 /// Header metadata and lookup tables of a BMS chart.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "chart-serde", derive(Serialize, Deserialize))]
 pub struct Header {
     /// Player mode.
     pub player: Option<u8>,
@@ -117,94 +912,505 @@ pub struct Header {
     pub title: Option<String>,
     /// Song artist.
     pub artist: Option<String>,
+    /// Song subtitle.
+    pub subtitle: Option<String>,
+    /// Additional credits, one per `#SUBARTIST` line.
+    pub subartists: Vec<String>,
+    /// Chart comment shown in song select.
+    pub comment: Option<String>,
+    /// Chart author.
+    pub maker: Option<String>,
     /// Base BPM.
     pub bpm: f64,
     /// Displayed difficulty level.
     pub play_level: Option<u8>,
     /// Ranking setting.
     pub rank: Option<u8>,
+    /// Judge window in percent of the normal width, declared with `#DEFEXRANK`.
+    pub def_ex_rank: Option<f64>,
     /// Stage background file path.
     pub stage_file: Option<String>,
     /// Banner image path.
     pub banner: Option<String>,
+    /// Background image shown behind the lanes.
+    pub back_bmp: Option<String>,
+    /// Preview audio path for song select.
+    pub preview: Option<String>,
     /// Difficulty code.
     pub difficulty: Option<u8>,
     /// Gauge total value.
     pub total: Option<f64>,
     /// Long note handling type.
     pub ln_type: Option<u8>,
+    /// Object id base declared with `#BASE` (36 when absent).
+    pub base: Option<u8>,
+    /// Global keysound volume in percent (`#VOLWAV`, 100 when absent).
+    pub vol_wav: Option<f64>,
     /// Long note end object id.
     pub ln_obj: Option<ObjectId>,
+    /// Whether `#OCT/FP` declared the octave and foot-pedal layout.
+    pub oct_fp: bool,
     /// Mapping from object id to audio filename.
     pub audio_files: HashMap<ObjectId, String>,
+    /// Mapping from object id to BGA image or video filename.
+    pub bmp_files: HashMap<ObjectId, String>,
     /// Mapping from BPM id to BPM value.
     pub bpm_table: HashMap<ObjectId, f64>,
     /// Mapping from STOP id to stop duration.
     pub stop_table: HashMap<ObjectId, f64>,
+    /// Millisecond stops declared with `#STP`.
+    pub ms_stops: Vec<MsStop>,
+    /// Pitch offsets in semitones declared with `#WAVCMD 00` (PMS).
+    pub pitch_shifts: HashMap<ObjectId, i8>,
+    /// Per-sound volumes in percent declared with `#VOLUME` (DTX).
+    pub wav_volumes: HashMap<ObjectId, f64>,
+    /// Per-sound pans from -100 (left) to 100 (right) declared with `#PAN` (DTX).
+    pub wav_pans: HashMap<ObjectId, f64>,
+    /// Mapping from `#EXRANKxx` id to judge window in percent.
+    pub ex_rank_table: HashMap<ObjectId, f64>,
+    /// Mapping from `#SCROLLxx` id to scroll speed multiplier.
+    pub scroll_table: HashMap<ObjectId, f64>,
+    /// Mapping from `#SPEEDxx` id to note speed multiplier.
+    pub speed_table: HashMap<ObjectId, f64>,
+}
+
+/// A bmse-style `#STP mmm.ppp duration` stop.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "chart-serde", derive(Serialize, Deserialize))]
+pub struct MsStop {
+    /// Measure index of the stop.
+    pub measure: MeasureIndex,
+    /// Position within the measure (0.0..1.0).
+    pub position: f64,
+    /// Stop length in milliseconds.
+    pub duration_ms: f64,
+}
+
+impl MsStop {
+    /// Parse the value of a `#STP` command (e.g. `001.500 1000`).
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Text after `#STP`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<MsStop>` - Parsed stop, or `None` if the value is malformed.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split_whitespace();
+        let (measure, fraction) = parts.next()?.split_once('.')?;
+        let duration_ms: f64 = parts.next()?.parse().ok()?;
+        if !fraction.bytes().all(|b| b.is_ascii_digit())
+            || !duration_ms.is_finite()
+            || duration_ms < 0.0
+        {
+            return None;
+        }
+        let position: f64 = format!("0.{}", fraction).parse().ok()?;
+        Some(MsStop {
+            measure: measure.parse().ok()?,
+            position,
+            duration_ms,
+        })
+    }
 }
 
 impl Header {
+    /// Judge window of the chart in percent of the normal width.
+    ///
+    /// `#DEFEXRANK` takes precedence; `#RANK` 0-3 maps to 25, 50, 75 and
+    /// 100 percent as in beatoraja.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<f64>` - Judge window, or `None` if neither command is present.
+    pub fn judge_rank(&self) -> Option<f64> {
+        self.def_ex_rank.or(match self.rank {
+            Some(rank @ 0..=3) => Some(25.0 * (rank + 1) as f64),
+            _ => None,
+        })
+    }
+
+    /// Lane layout of the chart's note channels.
+    ///
+    /// # Returns
+    ///
+    /// * `LaneLayout` - `Extended` for `#OCT/FP` charts, otherwise `Standard`.
+    pub fn lane_layout(&self) -> LaneLayout {
+        if self.oct_fp {
+            LaneLayout::Extended
+        } else {
+            LaneLayout::Standard
+        }
+    }
+
+    /// Object id base of the chart.
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - `62` for `#BASE 62` charts, otherwise `36`.
+    pub fn object_base(&self) -> u32 {
+        match self.base {
+            Some(62) => 62,
+            _ => 36,
+        }
+    }
+
     /// Parse a single header line and update fields as needed.
     ///
     /// # Arguments
     ///
     /// * `line` - A header line starting with `#`.
-    fn parse_line(&mut self, line: &str) {
-        if !line.starts_with('#') {
-            return;
+    /// * `duplicates` - How a redefined table entry is resolved.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(DiagnosticCategory, String)>` - Kind and description of a rejected or duplicate table entry, if any.
+    pub(crate) fn parse_line(
+        &mut self,
+        line: &str,
+        duplicates: DuplicatePolicy,
+    ) -> Option<(DiagnosticCategory, String)> {
+        if TableEntry::matches(line) {
+            return match TableEntry::parse(line, self.object_base()) {
+                Ok(entry) => self.define(entry, duplicates),
+                Err(problem) => Some(problem),
+            };
         }
-
-        let parts: Vec<&str> = line[1..].splitn(2, ' ').collect();
+        let parts: Vec<&str> = line.strip_prefix('#')?.splitn(2, ' ').collect();
         if parts.len() < 2 {
-            return;
+            // Layout flags take no value.
+            if parts[0].eq_ignore_ascii_case("OCT/FP") {
+                self.oct_fp = true;
+            }
+            return None;
         }
 
-        let key = parts[0].to_uppercase();
+        // Object ids keep their original case, which matters for `#BASE 62`.
+        let raw_key = parts[0];
+        let key = raw_key.to_uppercase();
         let value = parts[1].trim().trim_matches('"');
+        let base = self.object_base();
+        let id_at = |start: usize| {
+            raw_key
+                .get(start..)
+                .and_then(|id| parse_object_id_with_base(id.as_bytes(), base))
+                .ok_or_else(|| format!("invalid object id in #{}", raw_key))
+        };
 
         match key.as_str() {
             "PLAYER" => self.player = value.parse().ok(),
             "GENRE" => self.genre = Some(value.to_string()),
             "TITLE" => self.title = Some(value.to_string()),
             "ARTIST" => self.artist = Some(value.to_string()),
+            "SUBTITLE" => self.subtitle = Some(value.to_string()),
+            "SUBARTIST" => self.subartists.push(value.to_string()),
+            "COMMENT" => self.comment = Some(value.to_string()),
+            "MAKER" => self.maker = Some(value.to_string()),
             "BPM" => self.bpm = value.parse().unwrap_or(120.0),
             "PLAYLEVEL" => self.play_level = value.parse().ok(),
             "RANK" => self.rank = value.parse().ok(),
+            "DEFEXRANK" => {
+                self.def_ex_rank = value
+                    .parse()
+                    .ok()
+                    .filter(|v: &f64| v.is_finite() && *v > 0.0)
+            }
             "STAGEFILE" => self.stage_file = Some(value.to_string()),
             "BANNER" => self.banner = Some(value.to_string()),
+            "BACKBMP" => self.back_bmp = Some(value.to_string()),
+            "PREVIEW" => self.preview = Some(value.to_string()),
             "DIFFICULTY" => self.difficulty = value.parse().ok(),
             "TOTAL" => self.total = value.parse().ok(),
             "LNTYPE" => self.ln_type = value.parse().ok(),
-            "LNOBJ" => self.ln_obj = Some(u16::from_str_radix(value, 36).unwrap_or(0)),
-            _ if key.starts_with("WAV") || key.starts_with("OGG") => {
-                let audio_id = key[3..].to_string();
-                self.audio_files.insert(
-                    u16::from_str_radix(&audio_id, 36).unwrap_or(0),
-                    value.to_string(),
-                );
+            "VOLWAV" => {
+                self.vol_wav = value
+                    .parse()
+                    .ok()
+                    .filter(|v: &f64| v.is_finite() && *v >= 0.0)
             }
-            _ if key.starts_with("BPM") && key.len() > 3 => {
-                let bpm_id = key[3..].to_string();
-                if let Ok(bpm_value) = value.parse::<f64>()
-                    && bpm_value.is_finite()
-                    && bpm_value > 0.0
-                {
-                    self.bpm_table
-                        .insert(u16::from_str_radix(&bpm_id, 36).unwrap_or(0), bpm_value);
+            "WAVCMD" => {
+                let mut parts = value.split_whitespace();
+                let command = parts.next();
+                let id = parts
+                    .next()
+                    .and_then(|id| parse_object_id_with_base(id.as_bytes(), base));
+                let amount = parts.next().and_then(|v| v.parse::<u8>().ok());
+                match (command, id, amount) {
+                    // Command 00 sets the pitch, with 60 as the original.
+                    (Some("00"), Some(id), Some(note @ 0..=127)) => {
+                        self.pitch_shifts.insert(id, note as i8 - 60);
+                    }
+                    // Volume (01) and length (02) commands are accepted but not applied.
+                    (Some("01" | "02"), Some(_), Some(_)) => {}
+                    _ => {
+                        return Some((
+                            DiagnosticCategory::InvalidTableEntry,
+                            format!("invalid #WAVCMD: {}", value),
+                        ));
+                    }
                 }
             }
-            _ if key.starts_with("STOP") => {
-                let stop_id = key[4..].to_string();
-                if let Ok(stop_value) = value.parse::<f64>()
-                    && stop_value.is_finite()
-                    && stop_value >= 0.0
-                {
-                    self.stop_table
-                        .insert(u16::from_str_radix(&stop_id, 36).unwrap_or(0), stop_value);
+            "STP" => match MsStop::parse(value) {
+                Some(stop) => self.ms_stops.push(stop),
+                None => {
+                    return Some((
+                        DiagnosticCategory::InvalidTableEntry,
+                        format!("invalid #STP: {}", value),
+                    ));
+                }
+            },
+            "BASE" => self.base = value.parse().ok().filter(|b| *b == 36 || *b == 62),
+            "LNOBJ" => match parse_object_id_with_base(value.as_bytes(), base) {
+                Some(id) => self.ln_obj = Some(id),
+                None => {
+                    self.ln_obj = Some(0);
+                    return Some((
+                        DiagnosticCategory::InvalidTableEntry,
+                        format!("invalid #LNOBJ object id: {}", value),
+                    ));
+                }
+            },
+            _ if key.starts_with("EXRANK") => {
+                let id = match id_at(6) {
+                    Ok(id) => id,
+                    Err(problem) => return Some((DiagnosticCategory::InvalidTableEntry, problem)),
+                };
+                match value.parse::<f64>() {
+                    Ok(percent) if percent.is_finite() && percent > 0.0 => {
+                        self.ex_rank_table.insert(id, percent);
+                    }
+                    _ => {
+                        return Some((
+                            DiagnosticCategory::InvalidTableEntry,
+                            format!("invalid judge rank in #{}: {}", raw_key, value),
+                        ));
+                    }
+                }
+            }
+            _ if key.starts_with("SCROLL") || key.starts_with("SPEED") => {
+                let (table, id) = if key.starts_with("SCROLL") {
+                    (&mut self.scroll_table, id_at(6))
+                } else {
+                    (&mut self.speed_table, id_at(5))
+                };
+                let id = match id {
+                    Ok(id) => id,
+                    Err(problem) => return Some((DiagnosticCategory::InvalidTableEntry, problem)),
+                };
+                // Zero and negative scroll speeds are valid (frozen and reversed notes).
+                match value.parse::<f64>() {
+                    Ok(speed) if speed.is_finite() => {
+                        table.insert(id, speed);
+                    }
+                    _ => {
+                        return Some((
+                            DiagnosticCategory::InvalidTableEntry,
+                            format!("invalid speed in #{}: {}", raw_key, value),
+                        ));
+                    }
                 }
             }
             _ => (),
         }
+        None
+    }
+
+    /// Add a parsed table definition to its table.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - Parsed definition.
+    /// * `duplicates` - How a redefined table entry is resolved.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(DiagnosticCategory, String)>` - Kind and description of a duplicate or non-positive tempo, if any.
+    pub(crate) fn define(
+        &mut self,
+        entry: TableEntry,
+        duplicates: DuplicatePolicy,
+    ) -> Option<(DiagnosticCategory, String)> {
+        let TableEntry {
+            raw_key,
+            id,
+            text,
+            value,
+        } = entry;
+        match value {
+            TableValue::Audio => define(
+                &mut self.audio_files,
+                id,
+                text.to_string(),
+                duplicates,
+                raw_key,
+            ),
+            TableValue::Bmp => define(
+                &mut self.bmp_files,
+                id,
+                text.to_string(),
+                duplicates,
+                raw_key,
+            ),
+            TableValue::Bpm(bpm) => {
+                let conflict = define(&mut self.bpm_table, id, bpm, duplicates, raw_key);
+                // An ignored definition is reported as the duplicate it is.
+                let ignored = duplicates == DuplicatePolicy::FirstWins && conflict.is_some();
+                if bpm <= 0.0 && !ignored {
+                    return Some((
+                        DiagnosticCategory::InvalidTableEntry,
+                        format!("non-positive BPM in #{}: {}", raw_key, text),
+                    ));
+                }
+                conflict
+            }
+            TableValue::Stop(stop) => define(&mut self.stop_table, id, stop, duplicates, raw_key),
+        }
+    }
+}
+
+/// Display metadata of a chart, without lookup tables.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChartMetadata {
+    /// Player mode.
+    pub player: Option<u8>,
+    /// Music genre.
+    pub genre: Option<String>,
+    /// Song title.
+    pub title: Option<String>,
+    /// Song artist.
+    pub artist: Option<String>,
+    /// Song subtitle.
+    pub subtitle: Option<String>,
+    /// Additional credits, one per `#SUBARTIST` line.
+    pub subartists: Vec<String>,
+    /// Chart comment shown in song select.
+    pub comment: Option<String>,
+    /// Chart author.
+    pub maker: Option<String>,
+    /// Base BPM.
+    pub bpm: f64,
+    /// Displayed difficulty level.
+    pub play_level: Option<u8>,
+    /// Ranking setting.
+    pub rank: Option<u8>,
+    /// Judge window in percent of the normal width, from `#DEFEXRANK` or `#RANK`.
+    pub judge_rank: Option<f64>,
+    /// Difficulty code.
+    pub difficulty: Option<u8>,
+    /// Gauge total value.
+    pub total: Option<f64>,
+    /// Stage background file path.
+    pub stage_file: Option<String>,
+    /// Banner image path.
+    pub banner: Option<String>,
+    /// Preview audio path for song select.
+    pub preview: Option<String>,
+    /// Score-tracker hashes of the file, when computed from its raw bytes.
+    pub hash: Option<ChartHash>,
+    /// Lane layout of the note channels.
+    pub lane_layout: LaneLayout,
+}
+
+impl Header {
+    /// Collect the display metadata of this header.
+    ///
+    /// # Returns
+    ///
+    /// * `ChartMetadata` - Metadata without lookup tables.
+    pub fn metadata(&self) -> ChartMetadata {
+        ChartMetadata {
+            player: self.player,
+            genre: self.genre.clone(),
+            title: self.title.clone(),
+            artist: self.artist.clone(),
+            subtitle: self.subtitle.clone(),
+            subartists: self.subartists.clone(),
+            comment: self.comment.clone(),
+            maker: self.maker.clone(),
+            bpm: self.bpm,
+            play_level: self.play_level,
+            rank: self.rank,
+            judge_rank: self.judge_rank(),
+            difficulty: self.difficulty,
+            total: self.total,
+            stage_file: self.stage_file.clone(),
+            banner: self.banner.clone(),
+            preview: self.preview.clone(),
+            hash: None,
+            lane_layout: self.lane_layout(),
+        }
+    }
+}
+
+/// How serious a parse diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Severity {
+    /// The line was understood but looks wrong.
+    Warning,
+    /// The line could not be parsed and was dropped.
+    Error,
+}
+
+/// Kind of problem reported by a parse diagnostic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DiagnosticCategory {
+    /// A data line that does not follow the `#mmmcc:objects` syntax.
+    MalformedLine,
+    /// A message on a channel with no known meaning.
+    UnknownChannel,
+    /// A `#WAV`, `#BMP`, `#BPM`, `#STOP`, `#STP`, `#LNOBJ` or `#WAVCMD` entry that was rejected.
+    InvalidTableEntry,
+    /// A `#WAV`, `#BMP`, `#BPM` or `#STOP` slot defined more than once;
+    /// `ParseOptions::duplicate_policy` decides which definition is kept.
+    DuplicateDefinition,
+}
+
+/// A problem found on a single line of a chart.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    /// 1-based line number in the source text.
+    pub line: usize,
+    /// How serious the problem is.
+    pub severity: Severity,
+    /// Human-readable description.
+    pub message: String,
+    /// Kind of problem.
+    pub category: DiagnosticCategory,
+}
+
+/// Diagnostics collected while parsing a chart.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ParseReport {
+    /// Diagnostics ordered by line number.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ParseReport {
+    pub(crate) fn push(
+        &mut self,
+        line: usize,
+        severity: Severity,
+        category: DiagnosticCategory,
+        message: String,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            line,
+            severity,
+            message,
+            category,
+        });
+    }
+
+    /// Whether any line had to be dropped.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if at least one diagnostic is an error.
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
     }
 }
 
@@ -219,6 +1425,8 @@ pub enum ParseError {
     InvalidChannel(std::num::ParseIntError),
     /// Object data was malformed.
     InvalidObjectData,
+    /// Strict parsing found problems; the report lists every one with its line.
+    Strict(ParseReport),
 }
 
 impl core::fmt::Display for ParseError {
@@ -230,19 +1438,309 @@ impl core::fmt::Display for ParseError {
             ParseError::InvalidObjectData => {
                 write!(f, "invalid object data (must be pairs of two chars)")
             }
+            ParseError::Strict(report) => {
+                let mut errors = report
+                    .diagnostics
+                    .iter()
+                    .filter(|d| d.severity == Severity::Error);
+                match errors.next() {
+                    Some(first) => {
+                        write!(f, "line {}: {}", first.line, first.message)?;
+                        let more = errors.count();
+                        if more > 0 {
+                            write!(f, " (and {} more)", more)?;
+                        }
+                        Ok(())
+                    }
+                    None => write!(f, "strict parse failed"),
+                }
+            }
         }
     }
 }
 
 impl std::error::Error for ParseError {}
 
+/// BGA layer targeted by a BGA channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[cfg_attr(feature = "chart-serde", derive(Deserialize))]
+pub enum BgaLayer {
+    /// Channel 04: base image.
+    Base,
+    /// Channel 06: image shown on a miss.
+    Poor,
+    /// Channel 07: first overlay layer.
+    Layer,
+    /// Channel 0A: second overlay layer.
+    Layer2,
+}
+
+/// Lane layout of a BMS chart, which decides the channels of its note lanes.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+pub enum LaneLayout {
+    /// Lanes 1-9 of each player, as in 5, 7, 10 and 14-key and pop'n charts.
+    #[default]
+    Standard,
+    /// Octave and foot-pedal layout declared with `#OCT/FP`, whose extra keys
+    /// continue on lanes A-Z (channels 1A-1Z and 2A-2Z and their invisible,
+    /// long-note and mine counterparts).
+    Extended,
+}
+
+impl LaneLayout {
+    /// Highest lane digit of the layout.
+    ///
+    /// # Returns
+    ///
+    /// * `u8` - `9` for `Standard`, `35` (Z) for `Extended`.
+    pub fn max_lane(self) -> u8 {
+        match self {
+            LaneLayout::Standard => 9,
+            LaneLayout::Extended => 35,
+        }
+    }
+}
+
+/// Meaning of a message channel, decoded from its two base-36 digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "chart-serde", derive(Serialize, Deserialize))]
+pub enum Channel {
+    /// Channel 01: background keysound.
+    Bgm,
+    /// Channel 02: measure length change.
+    MeasureLength,
+    /// Channel 03: BPM change written as a hexadecimal value.
+    Bpm,
+    /// Channels 04, 06, 07 and 0A: BGA image changes.
+    Bga(BgaLayer),
+    /// Channel 08: BPM change referencing the `#BPMxx` table.
+    ExBpm,
+    /// Channel 09: stop referencing the `#STOPxx` table.
+    Stop,
+    /// Channels 1x (player 1) and 2x (player 2): visible notes. Lanes are 1-9,
+    /// or up to 35 in `LaneLayout::Extended`.
+    Note { player: u8, lane: u8 },
+    /// Channels 3x and 4x: invisible notes.
+    Invisible { player: u8, lane: u8 },
+    /// Channels 5x and 6x: long notes.
+    LongNote { player: u8, lane: u8 },
+    /// Channels Dx and Ex: mines. Objects hold the damage in base 36 rather
+    /// than a keysound id.
+    Mine { player: u8, lane: u8 },
+    /// Channel 97: BGM volume.
+    BgmVolume,
+    /// Channel 98: key volume.
+    KeyVolume,
+    /// Channel SC: scroll speed change referencing the `#SCROLLxx` table.
+    Scroll,
+    /// Channel SP: note speed change referencing the `#SPEEDxx` table.
+    Speed,
+    /// Channel A0: judge rank change referencing the `#EXRANKxx` table.
+    ExRank,
+    /// Any other channel, as its base-36 decoded value.
+    Unknown(u16),
+}
+
+impl Channel {
+    /// Decode a base-36 channel value in the standard lane layout.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - Channel digits decoded as a base-36 number.
+    ///
+    /// # Returns
+    ///
+    /// * `Channel` - Decoded channel, `Unknown` if it has no dedicated variant.
+    pub fn from_code(code: u16) -> Self {
+        Self::from_code_in(code, LaneLayout::Standard)
+    }
+
+    /// Decode a base-36 channel value in a lane layout.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - Channel digits decoded as a base-36 number.
+    /// * `layout` - Lane layout of the chart.
+    ///
+    /// # Returns
+    ///
+    /// * `Channel` - Decoded channel, `Unknown` if it has no dedicated variant
+    ///   or its lane is outside the layout.
+    pub fn from_code_in(code: u16, layout: LaneLayout) -> Self {
+        let (group, lane) = ((code / 36) as u8, (code % 36) as u8);
+        if group == 0 {
+            return match lane {
+                1 => Channel::Bgm,
+                2 => Channel::MeasureLength,
+                3 => Channel::Bpm,
+                4 => Channel::Bga(BgaLayer::Base),
+                6 => Channel::Bga(BgaLayer::Poor),
+                7 => Channel::Bga(BgaLayer::Layer),
+                8 => Channel::ExBpm,
+                9 => Channel::Stop,
+                10 => Channel::Bga(BgaLayer::Layer2),
+                _ => Channel::Unknown(code),
+            };
+        }
+        if group == 9 && lane == 7 {
+            return Channel::BgmVolume;
+        }
+        if group == 9 && lane == 8 {
+            return Channel::KeyVolume;
+        }
+        if group == 10 && lane == 0 {
+            return Channel::ExRank;
+        }
+        if group == 28 && lane == 12 {
+            return Channel::Scroll;
+        }
+        if group == 28 && lane == 25 {
+            return Channel::Speed;
+        }
+        if !(1..=layout.max_lane()).contains(&lane) {
+            return Channel::Unknown(code);
+        }
+        match group {
+            1 | 2 => Channel::Note {
+                player: group,
+                lane,
+            },
+            3 | 4 => Channel::Invisible {
+                player: group - 2,
+                lane,
+            },
+            5 | 6 => Channel::LongNote {
+                player: group - 4,
+                lane,
+            },
+            13 | 14 => Channel::Mine {
+                player: group - 12,
+                lane,
+            },
+            _ => Channel::Unknown(code),
+        }
+    }
+
+    /// Encode the channel back to its base-36 value.
+    ///
+    /// # Returns
+    ///
+    /// * `u16` - Channel digits as a base-36 number (e.g. `37` for `11`).
+    pub fn code(self) -> u16 {
+        let lane_code = |first_group: u8, player: u8, lane: u8| {
+            (first_group + player - 1) as u16 * 36 + lane as u16
+        };
+        match self {
+            Channel::Bgm => 1,
+            Channel::MeasureLength => 2,
+            Channel::Bpm => 3,
+            Channel::Bga(BgaLayer::Base) => 4,
+            Channel::Bga(BgaLayer::Poor) => 6,
+            Channel::Bga(BgaLayer::Layer) => 7,
+            Channel::ExBpm => 8,
+            Channel::Stop => 9,
+            Channel::Bga(BgaLayer::Layer2) => 10,
+            Channel::Note { player, lane } => lane_code(1, player, lane),
+            Channel::Invisible { player, lane } => lane_code(3, player, lane),
+            Channel::LongNote { player, lane } => lane_code(5, player, lane),
+            Channel::Mine { player, lane } => lane_code(13, player, lane),
+            Channel::BgmVolume => 9 * 36 + 7,
+            Channel::KeyVolume => 9 * 36 + 8,
+            Channel::ExRank => 10 * 36,
+            Channel::Scroll => 28 * 36 + 12,
+            Channel::Speed => 28 * 36 + 25,
+            Channel::Unknown(code) => code,
+        }
+    }
+
+    /// Whether objects on this channel trigger keysounds in the render.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` for BGM, visible note and long-note channels.
+    pub fn is_sound(self) -> bool {
+        matches!(
+            self,
+            Channel::Bgm | Channel::Note { .. } | Channel::LongNote { .. }
+        )
+    }
+
+    /// Kind of playable event produced by objects on this channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<EventKind>` - Event kind, `None` for control and BGA channels.
+    pub fn event_kind(self) -> Option<EventKind> {
+        match self {
+            Channel::Bgm => Some(EventKind::Bgm),
+            Channel::Note { .. } => Some(EventKind::Note),
+            Channel::Invisible { .. } => Some(EventKind::Invisible),
+            Channel::LongNote { .. } => Some(EventKind::LongNote),
+            Channel::Mine { .. } => Some(EventKind::Mine),
+            _ => None,
+        }
+    }
+
+    /// Button number of a note channel in the PMS layout.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u8>` - Button (1-9) for lanes 11-15 and 22-25 and their
+    ///   invisible, long-note and mine counterparts.
+    pub fn pms_button(self) -> Option<u8> {
+        match self {
+            Channel::Note { player, lane }
+            | Channel::Invisible { player, lane }
+            | Channel::LongNote { player, lane }
+            | Channel::Mine { player, lane } => match (player, lane) {
+                (1, 1..=5) => Some(lane),
+                (2, 2..=5) => Some(lane + 4),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Lane digit of a note channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u8>` - Lane (1-9, or up to 35 in `LaneLayout::Extended`) for visible,
+    ///   invisible, long-note and mine channels.
+    pub fn lane(self) -> Option<u8> {
+        match self {
+            Channel::Note { lane, .. }
+            | Channel::Invisible { lane, .. }
+            | Channel::LongNote { lane, .. }
+            | Channel::Mine { lane, .. } => Some(lane),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of a playable event, independent of player and lane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum EventKind {
+    /// Background keysound.
+    Bgm,
+    /// Visible note.
+    Note,
+    /// Invisible note.
+    Invisible,
+    /// Long-note start or end.
+    LongNote,
+    /// Mine; hitting it plays the `#WAV00` sound.
+    Mine,
+}
+
 /// A per-measure, per-channel message with a list of 2-char object tokens.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "chart-serde", derive(Serialize, Deserialize))]
 pub struct Message {
     /// Measure index of this message.
-    pub measure: u16,
-    /// Channel identifier.
-    pub channel: u8,
+    pub measure: MeasureIndex,
+    /// Channel of this message.
+    pub channel: Channel,
     /// Objects appearing in this message line.
     pub objects: Vec<ObjectId>,
 }
@@ -258,28 +1756,68 @@ impl Message {
     ///
     /// * `Result<Message, ParseError>` - Parsed message or an error.
     pub fn parse(data: &str) -> Result<Self, ParseError> {
+        Self::parse_with_base(data, 36)
+    }
+
+    /// Parse a message line whose object ids use the given base.
+    ///
+    /// Channel 03 holds hexadecimal BPM values rather than object ids, so it
+    /// is always read case-insensitively.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - A full message line.
+    /// * `base` - Object id base (`36`, or `62` for `#BASE 62` charts).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Message, ParseError>` - Parsed message or an error.
+    pub fn parse_with_base(data: &str, base: u32) -> Result<Self, ParseError> {
         if !data.starts_with('#') || data.len() < 7 || !data.contains(':') {
             return Err(ParseError::InvalidFormat);
         }
+        // Checked slicing: the measure may end inside a multi-byte character.
+        let measure = data.get(1..4).ok_or(ParseError::InvalidFormat)?;
+        if let Err(e) = measure.parse::<MeasureIndex>() {
+            return Err(ParseError::InvalidMeasure(e));
+        }
+        Self::parse_with_measure_parser(data, base, standard_measure)
+    }
 
-        let measure_str = &data[1..4];
-        let channel_str = &data[4..6];
-        let objects_str = match data.split_once(':') {
+    /// Parse a message line whose measure number is read by `measure_parser`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - A full message line.
+    /// * `base` - Object id base (`36`, or `62` for `#BASE 62` charts).
+    /// * `measure_parser` - Reads the measure number, e.g. `extended_measure`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Message, ParseError>` - Parsed message or an error.
+    pub fn parse_with_measure_parser(
+        data: &str,
+        base: u32,
+        measure_parser: MeasureParser,
+    ) -> Result<Self, ParseError> {
+        let key = data.strip_prefix('#').ok_or(ParseError::InvalidFormat)?;
+        let (measure, len) = measure_parser(key).ok_or(ParseError::InvalidFormat)?;
+        let channel_str = key.get(len..len + 2).ok_or(ParseError::InvalidFormat)?;
+        let objects_str = match key[len..].split_once(':') {
             Some((_, objects)) => objects,
             None => return Err(ParseError::InvalidFormat),
         };
 
-        let measure: u16 = measure_str.parse().map_err(ParseError::InvalidMeasure)?;
-        let channel: u8 = u8::from_str_radix(channel_str, 36).unwrap_or(0);
+        let channel = Channel::from_code(u16::from_str_radix(channel_str, 36).unwrap_or(0));
 
         if objects_str.len() % 2 != 0 {
             return Err(ParseError::InvalidObjectData);
         }
 
+        let base = if channel == Channel::Bpm { 36 } else { base };
         let mut objects: Vec<ObjectId> = Vec::with_capacity(objects_str.len() / 2);
         for chunk in objects_str.as_bytes().chunks(2) {
-            let s = std::str::from_utf8(chunk).map_err(|_| ParseError::InvalidObjectData)?;
-            objects.push(u16::from_str_radix(s, 36).unwrap_or(0));
+            objects.push(parse_object_id_with_base(chunk, base).unwrap_or(0));
         }
 
         Ok(Message {
@@ -289,3 +1827,301 @@ impl Message {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `#WAV01` and `#BPM01` each defined twice, the second `#BPM01` as zero.
+    const DUPLICATES: &str = "#WAV01 first.wav\n#WAV01 second.wav\n#BPM01 150\n#BPM01 0\n";
+
+    fn parse(
+        text: &str,
+        strict: bool,
+        policy: DuplicatePolicy,
+    ) -> Result<(Bms, ParseReport), ParseError> {
+        let options = ParseOptions {
+            strict,
+            duplicate_policy: policy,
+            ..ParseOptions::default()
+        };
+        Bms::parse_with_options(text, &options)
+    }
+
+    fn categories(report: &ParseReport) -> Vec<(usize, DiagnosticCategory)> {
+        report
+            .diagnostics
+            .iter()
+            .map(|d| (d.line, d.category))
+            .collect()
+    }
+
+    #[test]
+    fn warn_and_pick_keeps_the_last_definition() {
+        let (bms, report) = parse(DUPLICATES, false, DuplicatePolicy::WarnAndPick).unwrap();
+        assert_eq!(bms.header.audio_files[&1], "second.wav");
+        assert_eq!(bms.header.bpm_table[&1], 0.0);
+        assert_eq!(
+            categories(&report),
+            [
+                (2, DiagnosticCategory::DuplicateDefinition),
+                (4, DiagnosticCategory::InvalidTableEntry),
+            ]
+        );
+    }
+
+    #[test]
+    fn last_wins_keeps_the_last_definition_quietly() {
+        let (bms, report) = parse(DUPLICATES, false, DuplicatePolicy::LastWins).unwrap();
+        assert_eq!(bms.header.audio_files[&1], "second.wav");
+        assert_eq!(bms.header.bpm_table[&1], 0.0);
+        assert_eq!(
+            categories(&report),
+            [(4, DiagnosticCategory::InvalidTableEntry)]
+        );
+    }
+
+    #[test]
+    fn first_wins_keeps_the_first_definition_quietly() {
+        let (bms, report) = parse(DUPLICATES, false, DuplicatePolicy::FirstWins).unwrap();
+        assert_eq!(bms.header.audio_files[&1], "first.wav");
+        assert_eq!(bms.header.bpm_table[&1], 150.0);
+        // The ignored zero tempo is not reported as an invalid value.
+        assert!(report.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn strict_reports_duplicates_under_every_policy() {
+        for policy in [
+            DuplicatePolicy::WarnAndPick,
+            DuplicatePolicy::LastWins,
+            DuplicatePolicy::FirstWins,
+        ] {
+            let text = "#WAV01 first.wav\n#WAV01 second.wav\n";
+            let Err(ParseError::Strict(report)) = parse(text, true, policy) else {
+                panic!("{policy:?} accepted a duplicate slot");
+            };
+            assert_eq!(
+                categories(&report),
+                [(2, DiagnosticCategory::DuplicateDefinition)]
+            );
+        }
+
+        // Under `FirstWins` a duplicate with a bad value is still a duplicate.
+        let Err(ParseError::Strict(report)) = parse(DUPLICATES, true, DuplicatePolicy::FirstWins)
+        else {
+            panic!("duplicates accepted");
+        };
+        assert_eq!(
+            categories(&report),
+            [
+                (2, DiagnosticCategory::DuplicateDefinition),
+                (4, DiagnosticCategory::DuplicateDefinition),
+            ]
+        );
+    }
+
+    #[test]
+    fn strict_rejects_what_lenient_skips() {
+        // Input, line and category of the strict error, and the messages a
+        // lenient parse keeps.
+        for (text, line, category, lenient_messages) in [
+            (
+                "#WAV01 a.wav\n#00111:01!!\n",
+                2,
+                DiagnosticCategory::MalformedLine,
+                1,
+            ),
+            (
+                "#WAV01 a.wav\n#00111:010\n#00112:01\n",
+                2,
+                DiagnosticCategory::MalformedLine,
+                1,
+            ),
+            (
+                "#WAV01 a.wav\n#WAV01 b.wav\n#00111:01\n",
+                2,
+                DiagnosticCategory::DuplicateDefinition,
+                1,
+            ),
+        ] {
+            let Err(ParseError::Strict(report)) = parse(text, true, DuplicatePolicy::default())
+            else {
+                panic!("strict parse accepted {text:?}");
+            };
+            assert_eq!(categories(&report), [(line, category)], "{text:?}");
+
+            let (bms, _) = parse(text, false, DuplicatePolicy::default()).unwrap();
+            assert_eq!(bms.messages.len(), lenient_messages, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn oct_fp_charts_use_lanes_past_nine() {
+        let lines = "#WAV01 a.wav\n#0011A:01\n#0012Z:01\n#0015B:01\n#0014C:01\n";
+        let channels = |text: &str| {
+            let (bms, report) = Bms::parse_with_report(text).unwrap();
+            let channels: Vec<Channel> = bms.messages.iter().map(|m| m.channel).collect();
+            (bms.header.lane_layout(), channels, categories(&report))
+        };
+
+        let (layout, extended, report) = channels(&format!("#OCT/FP\n{lines}"));
+        assert_eq!(layout, LaneLayout::Extended);
+        assert_eq!(
+            extended,
+            [
+                Channel::Note {
+                    player: 1,
+                    lane: 10
+                },
+                Channel::Note {
+                    player: 2,
+                    lane: 35
+                },
+                Channel::LongNote {
+                    player: 1,
+                    lane: 11
+                },
+                Channel::Invisible {
+                    player: 2,
+                    lane: 12
+                },
+            ]
+        );
+        assert!(report.is_empty());
+
+        // Without the flag the same channels are unknown.
+        let (layout, standard, report) = channels(lines);
+        assert_eq!(layout, LaneLayout::Standard);
+        assert!(standard.iter().all(|ch| matches!(ch, Channel::Unknown(_))));
+        assert_eq!(
+            report,
+            (2..=5)
+                .map(|line| (line, DiagnosticCategory::UnknownChannel))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn parallel_table_parse_matches_line_by_line() {
+        // Enough definitions for several parsing tasks, with redefinitions,
+        // rejected entries and a base change partway through.
+        let mut text = String::from("#BPM 130\n");
+        for i in 0..3 * TABLE_LINES_PER_TASK {
+            let id = format_object_id(1 + (i % 1200) as ObjectId);
+            text.push_str(&match i % 4 {
+                0 => format!("#WAV{id} sound{i}.wav\n"),
+                1 => format!("#BMP{id} image{i}.png\n"),
+                2 => format!("#BPM{id} {}\n", i as f64 / 7.0 - 10.0),
+                _ => format!("#STOP{id} {}\n", i % 97),
+            });
+            if i == 2 * TABLE_LINES_PER_TASK {
+                text.push_str("#BASE 62\n#WAVzz lower.wav\n");
+            }
+        }
+        text.push_str("#WAV!! bad.wav\n#00111:01\n");
+
+        let (bms, report) = Bms::parse_with_report(&text).unwrap();
+        let mut header = Header::default();
+        let mut expected = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if DataLine::matches(line, standard_measure) {
+                continue;
+            }
+            if let Some((category, message)) = header.parse_line(line, DuplicatePolicy::default()) {
+                expected.push((i + 1, category, message));
+            }
+        }
+        assert_eq!(bms.header.audio_files, header.audio_files);
+        assert_eq!(bms.header.bmp_files, header.bmp_files);
+        assert_eq!(bms.header.bpm_table, header.bpm_table);
+        assert_eq!(bms.header.stop_table, header.stop_table);
+        let found: Vec<_> = report
+            .diagnostics
+            .into_iter()
+            .map(|d| (d.line, d.category, d.message))
+            .collect();
+        assert!(expected.len() > TABLE_LINES_PER_TASK);
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn report_lists_each_problem_with_its_line() {
+        let text = "#BPM 120\n\
+                    #WAV01 a.wav\n\
+                    #00111:010\n\
+                    #0018Z:01\n\
+                    #WAV01 b.wav\n\
+                    #BPM02 abc\n\
+                    #00111:01\n\
+                    #00102:-1\n";
+        let (bms, report) = Bms::parse_with_report(text).unwrap();
+        let found: Vec<_> = report
+            .diagnostics
+            .iter()
+            .map(|d| (d.line, d.severity, d.category))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (3, Severity::Error, DiagnosticCategory::MalformedLine),
+                (4, Severity::Warning, DiagnosticCategory::UnknownChannel),
+                (
+                    5,
+                    Severity::Warning,
+                    DiagnosticCategory::DuplicateDefinition
+                ),
+                (6, Severity::Warning, DiagnosticCategory::InvalidTableEntry),
+                (8, Severity::Error, DiagnosticCategory::MalformedLine),
+            ]
+        );
+        assert!(report.has_errors());
+        // The unknown channel is kept, the malformed lines are dropped.
+        assert_eq!(bms.messages.len(), 2);
+
+        // Strict mode turns the table problems into errors and lists every one.
+        let Err(ParseError::Strict(strict)) = parse(text, true, DuplicatePolicy::default()) else {
+            panic!("strict parse accepted the chart");
+        };
+        let errors = strict
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .count();
+        assert_eq!((strict.diagnostics.len(), errors), (5, 4));
+
+        let (_, clean) = Bms::parse_with_report("#BPM 120\n#WAV01 a.wav\n#00111:01\n").unwrap();
+        assert!(clean.diagnostics.is_empty() && !clean.has_errors());
+    }
+
+    /// Random source replaying fixed values.
+    struct Replay(std::vec::IntoIter<u64>);
+
+    impl RandomSource for Replay {
+        fn next_u64(&mut self) -> u64 {
+            self.0.next().unwrap_or(0)
+        }
+    }
+
+    #[test]
+    fn host_random_source_picks_the_branches() {
+        let text = "#RANDOM 2\n#IF 1\n#TITLE one\n#ENDIF\n#IF 2\n#TITLE two\n#ENDIF\n#ENDRANDOM\n\
+                    #SWITCH 3\n#CASE 1\n#ARTIST first\n#SKIP\n#CASE 3\n#ARTIST third\n#SKIP\n#ENDSW\n";
+        let parse = |values: Vec<u64>| {
+            let mut rng = Replay(values.into_iter());
+            Bms::parse_with_random(text, &ParseOptions::default(), &mut rng)
+                .unwrap()
+                .0
+        };
+        // `below(n)` scales the drawn bits, so 0 picks the first value and
+        // `u64::MAX` the last.
+        let bms = parse(vec![u64::MAX, u64::MAX]);
+        assert_eq!(bms.header.title.as_deref(), Some("two"));
+        assert_eq!(bms.header.artist.as_deref(), Some("third"));
+        let values: Vec<u64> = bms.random_choices.iter().map(|c| c.value).collect();
+        assert_eq!(values, [2, 3]);
+        let bms = parse(vec![0, 0]);
+        assert_eq!(bms.header.title.as_deref(), Some("one"));
+        assert_eq!(bms.header.artist.as_deref(), Some("first"));
+    }
+}
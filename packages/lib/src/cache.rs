@@ -0,0 +1,232 @@
+use crate::audio::ResampleMethod;
+use crate::hash::{sha256, to_hex};
+use ahash::AHashMap;
+use std::cell::RefCell;
+use std::future::Future;
+
+/// Identity of a decoded keysound: the encoded content and how it was converted.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// SHA-256 of the encoded file, in lowercase hexadecimal.
+    pub hash: String,
+    /// Output sample rate.
+    pub sample_rate: u32,
+    /// Output channel count.
+    pub channels: usize,
+    /// Resampling algorithm.
+    pub quality: ResampleMethod,
+    /// Source rate assumed instead of the declared one, if overridden.
+    pub source_rate: Option<u32>,
+}
+
+impl CacheKey {
+    /// Key for decoding `data` with the given conversion.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Encoded file.
+    /// * `sample_rate` - Output sample rate.
+    /// * `channels` - Output channel count.
+    /// * `quality` - Resampling algorithm.
+    /// * `source_rate` - Source rate override, if any.
+    ///
+    /// # Returns
+    ///
+    /// * `CacheKey` - Key identifying the decoded PCM.
+    pub fn new(
+        data: &[u8],
+        sample_rate: u32,
+        channels: usize,
+        quality: ResampleMethod,
+        source_rate: Option<u32>,
+    ) -> Self {
+        Self {
+            hash: to_hex(&sha256(data)),
+            sample_rate,
+            channels,
+            quality,
+            source_rate,
+        }
+    }
+}
+
+impl core::fmt::Display for CacheKey {
+    /// Flat form usable as a file name or storage key.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}-{}-{}-{}",
+            self.hash, self.sample_rate, self.channels, self.quality as u8
+        )?;
+        if let Some(rate) = self.source_rate {
+            write!(f, "-{}", rate)?;
+        }
+        Ok(())
+    }
+}
+
+/// Store of decoded keysounds that outlives a render.
+///
+/// Re-rendering the same song after changing mix settings then skips
+/// decoding and resampling. Entries hold interleaved samples at the
+/// conversion described by their key.
+pub trait DecodeCache {
+    /// Look up decoded samples.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Key of the decoded file.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<f32>>` - Interleaved samples, or `None` on a miss.
+    fn get(&self, key: &CacheKey) -> impl Future<Output = Option<Vec<f32>>>;
+
+    /// Store decoded samples. Failures are ignored; the cache is only an optimization.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Key of the decoded file.
+    /// * `samples` - Interleaved samples.
+    fn put(&self, key: &CacheKey, samples: &[f32]) -> impl Future<Output = ()>;
+}
+
+/// Serialize samples as little-endian `f32`.
+///
+/// # Arguments
+///
+/// * `samples` - Samples to store.
+///
+/// # Returns
+///
+/// * `Vec<u8>` - Stored form.
+pub fn samples_to_le_bytes(samples: &[f32]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+/// Read samples stored by `samples_to_le_bytes`.
+///
+/// # Arguments
+///
+/// * `bytes` - Stored form.
+///
+/// # Returns
+///
+/// * `Option<Vec<f32>>` - Samples, or `None` if the length is not a whole number of samples.
+pub fn samples_from_le_bytes(bytes: &[u8]) -> Option<Vec<f32>> {
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    Some(
+        bytes
+            .as_chunks::<4>()
+            .0
+            .iter()
+            .map(|b| f32::from_le_bytes(*b))
+            .collect(),
+    )
+}
+
+/// Cache keeping decoded keysounds in memory, for several renders of one song.
+///
+/// Entries are never evicted, so the cache holds every keysound it was
+/// given until it is dropped.
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    entries: RefCell<AHashMap<CacheKey, Vec<f32>>>,
+}
+
+impl MemoryCache {
+    /// Create an empty cache.
+    ///
+    /// # Returns
+    ///
+    /// * `MemoryCache` - Cache without entries.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DecodeCache for MemoryCache {
+    async fn get(&self, key: &CacheKey) -> Option<Vec<f32>> {
+        self.entries.borrow().get(key).cloned()
+    }
+
+    async fn put(&self, key: &CacheKey, samples: &[f32]) {
+        self.entries
+            .borrow_mut()
+            .insert(key.clone(), samples.to_vec());
+    }
+}
+
+/// Cache keeping one file per decoded keysound in a folder.
+///
+/// Pass it to `render::render` so keysounds are decoded once across runs of
+/// a native host.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FsCache {
+    dir: std::path::PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FsCache {
+    /// Create a cache in a folder, which is created on the first store.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Folder holding the entries.
+    ///
+    /// # Returns
+    ///
+    /// * `FsCache` - Cache backed by `dir`.
+    pub fn new(dir: impl Into<std::path::PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, key: &CacheKey) -> std::path::PathBuf {
+        self.dir.join(format!("{}.pcm", key))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl DecodeCache for FsCache {
+    async fn get(&self, key: &CacheKey) -> Option<Vec<f32>> {
+        samples_from_le_bytes(&std::fs::read(self.path(key)).ok()?)
+    }
+
+    async fn put(&self, key: &CacheKey, samples: &[f32]) {
+        // Written under a temporary name first so a concurrent reader never
+        // sees a partial entry.
+        let path = self.path(key);
+        let partial = path.with_extension("part");
+        let _ = std::fs::create_dir_all(&self.dir)
+            .and_then(|_| std::fs::write(&partial, samples_to_le_bytes(samples)))
+            .and_then(|_| std::fs::rename(&partial, &path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_round_trip_through_le_bytes() {
+        let samples = [
+            0.0,
+            -0.0,
+            1.0,
+            -1.0,
+            0.123_456_79,
+            f32::MIN_POSITIVE,
+            f32::MAX,
+        ];
+        let bytes = samples_to_le_bytes(&samples);
+        assert_eq!(bytes.len(), samples.len() * 4);
+        assert_eq!(&bytes[8..12], &1.0f32.to_le_bytes());
+        let restored = samples_from_le_bytes(&bytes).unwrap();
+        let bits = |s: &[f32]| s.iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&restored), bits(&samples));
+        assert_eq!(samples_from_le_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(samples_from_le_bytes(&[]), Some(Vec::new()));
+    }
+}
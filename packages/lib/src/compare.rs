@@ -0,0 +1,224 @@
+use crate::bms::MeasureIndex;
+use crate::timeline::TempoMap;
+use serde::Serialize;
+
+/// Default largest offset searched between the two renders, in milliseconds.
+pub const DEFAULT_MAX_OFFSET_MS: f64 = 500.0;
+
+/// Length of the envelope blocks used for the coarse offset search, in seconds.
+const ENVELOPE_BLOCK_SEC: f64 = 0.001;
+
+/// Length of the excerpt used to refine the offset to a single sample, in seconds.
+const REFINE_WINDOW_SEC: f64 = 10.0;
+
+/// Level reported for digital silence, in dBFS.
+const SILENCE_DB: f32 = -120.0;
+
+/// Level comparison of one measure.
+#[derive(Debug, Clone, Serialize)]
+pub struct SectionDifference {
+    /// Measure index.
+    pub measure: MeasureIndex,
+    /// Start of the measure in the render, in seconds.
+    pub start_sec: f64,
+    /// End of the measure in the render, in seconds.
+    pub end_sec: f64,
+    /// RMS level of the render, in dBFS.
+    pub rendered_rms_db: f32,
+    /// RMS level of the aligned reference, in dBFS.
+    pub reference_rms_db: f32,
+    /// RMS level of the difference between the two, in dBFS.
+    pub difference_rms_db: f32,
+}
+
+/// Outcome of comparing a render with a reference rendering of the same chart.
+#[derive(Debug, Clone, Serialize)]
+pub struct ComparisonReport {
+    /// How far the reference lags behind the render, in milliseconds (negative if it leads).
+    pub offset_ms: f64,
+    /// Normalized correlation of the level envelopes at that offset (1.0 = identical shape).
+    pub correlation: f32,
+    /// RMS level of the difference over the whole render, in dBFS.
+    pub difference_rms_db: f32,
+    /// Per-measure comparison.
+    pub sections: Vec<SectionDifference>,
+}
+
+/// Start time of every measure covered by a tempo map.
+///
+/// # Arguments
+///
+/// * `tempo_map` - Tempo map of the rendered chart.
+///
+/// # Returns
+///
+/// * `Vec<(MeasureIndex, f64)>` - Measure index and start time in seconds.
+pub fn measure_starts(tempo_map: &TempoMap) -> Vec<(MeasureIndex, f64)> {
+    (tempo_map.base_measure..=tempo_map.last_measure())
+        .map(|measure| (measure, tempo_map.get_timestamp(measure, 0.0)))
+        .collect()
+}
+
+fn to_db(rms: f64) -> f32 {
+    if rms > 0.0 {
+        (20.0 * rms.log10()).max(SILENCE_DB as f64) as f32
+    } else {
+        SILENCE_DB
+    }
+}
+
+/// Mean absolute level of consecutive blocks, with the mean level removed.
+fn envelope(mono: &[f32], block: usize) -> Vec<f64> {
+    let mut env: Vec<f64> = mono
+        .chunks(block)
+        .map(|b| b.iter().map(|s| s.abs() as f64).sum::<f64>() / b.len() as f64)
+        .collect();
+    let mean = env.iter().sum::<f64>() / env.len().max(1) as f64;
+    for v in &mut env {
+        *v -= mean;
+    }
+    env
+}
+
+/// Normalized correlation of `a[i]` with `b[i + lag]` over their overlap.
+fn correlation_at(a: &[f64], b: &[f64], lag: isize) -> f64 {
+    let (mut sum, mut energy_a, mut energy_b) = (0.0, 0.0, 0.0);
+    for (i, &x) in a.iter().enumerate() {
+        let j = i as isize + lag;
+        if j < 0 || j as usize >= b.len() {
+            continue;
+        }
+        let y = b[j as usize];
+        sum += x * y;
+        energy_a += x * x;
+        energy_b += y * y;
+    }
+    if energy_a > 0.0 && energy_b > 0.0 {
+        sum / (energy_a * energy_b).sqrt()
+    } else {
+        0.0
+    }
+}
+
+/// Compare a render with a reference rendering of the same chart.
+///
+/// The offset is found by correlating 1 ms level envelopes, then refined to
+/// a single sample on an excerpt starting at the first sound of the render.
+/// Levels are then compared measure by measure with the reference shifted
+/// by that offset, so a matching render leaves only a quiet difference.
+///
+/// # Arguments
+///
+/// * `rendered` - Interleaved samples rendered by this crate.
+/// * `reference` - Interleaved reference samples at the same rate and channel count.
+/// * `channels` - Number of interleaved channels.
+/// * `sample_rate` - Sample rate of both buffers.
+/// * `sections` - Measure index and start time of each section, as from `measure_starts`.
+/// * `max_offset_ms` - Largest offset searched in either direction.
+///
+/// # Returns
+///
+/// * `ComparisonReport` - Offset, correlation and per-measure level differences.
+pub fn compare_renders(
+    rendered: &[f32],
+    reference: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    sections: &[(MeasureIndex, f64)],
+    max_offset_ms: f64,
+) -> ComparisonReport {
+    let channels = channels.max(1);
+    let mono = |samples: &[f32]| -> Vec<f32> {
+        samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect()
+    };
+    let (a, b) = (mono(rendered), mono(reference));
+
+    let block = ((ENVELOPE_BLOCK_SEC * sample_rate as f64) as usize).max(1);
+    let (env_a, env_b) = (envelope(&a, block), envelope(&b, block));
+    let max_blocks = (max_offset_ms / 1000.0 * sample_rate as f64 / block as f64).ceil() as isize;
+    let (coarse, correlation) = (-max_blocks..=max_blocks)
+        .map(|lag| (lag, correlation_at(&env_a, &env_b, lag)))
+        .fold((0, f64::MIN), |best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        });
+
+    let start = a.iter().position(|s| s.abs() > 0.0).unwrap_or(0);
+    let end = (start + (REFINE_WINDOW_SEC * sample_rate as f64) as usize).min(a.len());
+    let excerpt: Vec<f64> = a[start..end].iter().map(|&s| s as f64).collect();
+    let tail: Vec<f64> = b.iter().map(|&s| s as f64).collect();
+    let block = block as isize;
+    let offset = (coarse * block - block..=coarse * block + block)
+        .map(|lag| {
+            (
+                lag,
+                correlation_at(&excerpt, &tail[start.min(tail.len())..], lag),
+            )
+        })
+        .fold((coarse * block, f64::MIN), |best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        })
+        .0;
+
+    let reference_at = |frame: usize| -> f32 {
+        let j = frame as isize + offset;
+        if j < 0 {
+            0.0
+        } else {
+            b.get(j as usize).copied().unwrap_or(0.0)
+        }
+    };
+    let levels = |from: usize, to: usize| -> (f32, f32, f32) {
+        let (mut sum_a, mut sum_b, mut sum_diff) = (0.0f64, 0.0f64, 0.0f64);
+        for (frame, &x) in a.iter().enumerate().take(to).skip(from) {
+            let y = reference_at(frame);
+            sum_a += (x * x) as f64;
+            sum_b += (y * y) as f64;
+            sum_diff += ((x - y) * (x - y)) as f64;
+        }
+        let n = (to.saturating_sub(from)).max(1) as f64;
+        (
+            to_db((sum_a / n).sqrt()),
+            to_db((sum_b / n).sqrt()),
+            to_db((sum_diff / n).sqrt()),
+        )
+    };
+
+    let frame_of = |sec: f64| ((sec * sample_rate as f64).round() as usize).min(a.len());
+    let sections = sections
+        .iter()
+        .enumerate()
+        .map(|(i, &(measure, start_sec))| {
+            let end_sec = sections
+                .get(i + 1)
+                .map_or(a.len() as f64 / sample_rate as f64, |next| next.1);
+            let (rendered_rms_db, reference_rms_db, difference_rms_db) =
+                levels(frame_of(start_sec), frame_of(end_sec));
+            SectionDifference {
+                measure,
+                start_sec,
+                end_sec,
+                rendered_rms_db,
+                reference_rms_db,
+                difference_rms_db,
+            }
+        })
+        .collect();
+
+    ComparisonReport {
+        offset_ms: offset as f64 * 1000.0 / sample_rate as f64,
+        correlation: correlation.max(0.0) as f32,
+        difference_rms_db: levels(0, a.len()).2,
+        sections,
+    }
+}
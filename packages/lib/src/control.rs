@@ -0,0 +1,568 @@
+use crate::random::RandomSource;
+use ahash::{AHashMap, AHashSet};
+use serde::{Deserialize, Serialize};
+
+/// Most charts resolved by `random_variants` before it gives up.
+const MAX_VARIANT_RESOLUTIONS: usize = 4096;
+
+/// Upper-cased keyword and numeric argument of a header command.
+type Command = (String, Option<u64>);
+
+/// The value selected for a `#RANDOM` or `#SWITCH` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RandomChoice {
+    /// 1-based line number of the command.
+    pub line: usize,
+    /// Number of values the command chooses from.
+    #[serde(default)]
+    pub max: u64,
+    /// Selected value, normally in `1..=max`.
+    pub value: u64,
+}
+
+/// Distinct variants of a chart's `#RANDOM` and `#SWITCH` blocks.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RandomVariants {
+    /// Values selected by each variant, usable as `ParseOptions::forced_random`.
+    pub variants: Vec<Vec<RandomChoice>>,
+    /// Whether enumeration stopped before every combination was tried.
+    pub truncated: bool,
+}
+
+/// Random source that always draws the lowest value.
+struct Lowest;
+
+impl RandomSource for Lowest {
+    fn next_u64(&mut self) -> u64 {
+        0
+    }
+}
+
+/// An open control-flow block.
+enum Block {
+    /// `#RANDOM` or `#SETRANDOM` value that `#IF` compares against.
+    Random(u64),
+    /// `#IF` chain; `outer` is whether the enclosing code runs.
+    If {
+        outer: bool,
+        taken: bool,
+        active: bool,
+    },
+    /// `#SWITCH` block; a matched `#CASE` runs until `#SKIP`, falling through
+    /// later cases like a C switch.
+    Switch {
+        outer: bool,
+        value: u64,
+        has_case: bool,
+        matched: bool,
+        active: bool,
+    },
+}
+
+/// Evaluates `#RANDOM`/`#IF` and `#SWITCH`/`#CASE` blocks line by line.
+struct Evaluator {
+    stack: Vec<Block>,
+    /// `#CASE` labels of each `#SWITCH` block, in the order the blocks open,
+    /// or `None` when the lines are not known in advance.
+    case_labels: Option<std::vec::IntoIter<Vec<u64>>>,
+    /// Values to use instead of drawing, by line number.
+    forced: AHashMap<usize, u64>,
+    /// Values selected so far, in line order.
+    choices: Vec<RandomChoice>,
+}
+
+impl Evaluator {
+    /// Start with no open blocks.
+    fn new(case_labels: Option<Vec<Vec<u64>>>, forced: &[RandomChoice]) -> Self {
+        Self {
+            stack: Vec::new(),
+            case_labels: case_labels.map(Vec::into_iter),
+            forced: forced.iter().map(|c| (c.line, c.value)).collect(),
+            choices: Vec::new(),
+        }
+    }
+
+    /// Whether lines at the current position are part of the chart.
+    fn active(&self) -> bool {
+        self.stack
+            .iter()
+            .rev()
+            .find_map(|block| match block {
+                Block::Random(_) => None,
+                Block::If { active, .. } | Block::Switch { active, .. } => Some(*active),
+            })
+            .unwrap_or(true)
+    }
+
+    /// Draw a value in `1..=max` and record it, or `0` for an empty range or skipped code.
+    ///
+    /// A forced value is used as given, without consuming a random draw.
+    fn draw(&mut self, line: usize, max: u64, rng: &mut dyn RandomSource) -> u64 {
+        if !self.active() {
+            return 0;
+        }
+        let value = match self.forced.get(&line) {
+            Some(&value) => value,
+            None if max == 0 => return 0,
+            None => 1 + rng.below(max),
+        };
+        self.choices.push(RandomChoice { line, max, value });
+        value
+    }
+
+    /// Value of the innermost `#RANDOM`.
+    fn random_value(&self) -> u64 {
+        self.stack
+            .iter()
+            .rev()
+            .find_map(|block| match block {
+                Block::Random(value) => Some(*value),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    /// Close blocks up to and including the innermost one matching `is_target`.
+    fn close(&mut self, is_target: impl Fn(&Block) -> bool) {
+        if let Some(idx) = self.stack.iter().rposition(is_target) {
+            self.stack.truncate(idx);
+        }
+    }
+
+    /// Close unterminated blocks nested in the innermost one matching `is_target`.
+    fn innermost(&mut self, is_target: impl Fn(&Block) -> bool) -> Option<&mut Block> {
+        let idx = self.stack.iter().rposition(is_target)?;
+        self.stack.truncate(idx + 1);
+        self.stack.last_mut()
+    }
+
+    /// Apply a control-flow command.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `false` if the line is not a control-flow command.
+    fn apply(
+        &mut self,
+        line: usize,
+        command: &str,
+        arg: Option<u64>,
+        rng: &mut dyn RandomSource,
+    ) -> bool {
+        match command {
+            "RANDOM" | "SETRANDOM" => {
+                let value = if command == "RANDOM" {
+                    self.draw(line, arg.unwrap_or(0), rng)
+                } else {
+                    arg.unwrap_or(0)
+                };
+                // Charts often start a new #RANDOM without closing the previous one.
+                if let Some(Block::Random(current)) = self.stack.last_mut() {
+                    *current = value;
+                } else {
+                    self.stack.push(Block::Random(value));
+                }
+            }
+            "ENDRANDOM" => self.close(|block| matches!(block, Block::Random(_))),
+            "IF" => {
+                let outer = self.active();
+                let taken = arg.is_some_and(|v| v == self.random_value());
+                self.stack.push(Block::If {
+                    outer,
+                    taken,
+                    active: outer && taken,
+                });
+            }
+            "ELSEIF" | "ELSE" => {
+                let value = self.random_value();
+                if let Some(Block::If {
+                    outer,
+                    taken,
+                    active,
+                }) = self.innermost(|block| matches!(block, Block::If { .. }))
+                {
+                    let matches = command == "ELSE" || arg.is_some_and(|v| v == value);
+                    *active = *outer && !*taken && matches;
+                    *taken |= matches;
+                }
+            }
+            "ENDIF" | "END" => self.close(|block| matches!(block, Block::If { .. })),
+            "SWITCH" | "SETSWITCH" => {
+                let outer = self.active();
+                let value = if command == "SWITCH" {
+                    self.draw(line, arg.unwrap_or(0), rng)
+                } else {
+                    arg.unwrap_or(0)
+                };
+                let has_case = self
+                    .case_labels
+                    .as_mut()
+                    .and_then(Iterator::next)
+                    .is_some_and(|labels| labels.contains(&value));
+                self.stack.push(Block::Switch {
+                    outer,
+                    value,
+                    has_case,
+                    matched: false,
+                    active: false,
+                });
+            }
+            "CASE" | "DEF" => {
+                if let Some(Block::Switch {
+                    outer,
+                    value,
+                    has_case,
+                    matched,
+                    active,
+                }) = self.innermost(|block| matches!(block, Block::Switch { .. }))
+                {
+                    // #DEF runs only when no #CASE of the block matches, wherever it is placed
+                    // (or, without the labels in advance, when none matched before it).
+                    let hit = if command == "DEF" {
+                        !*has_case && !*matched
+                    } else {
+                        arg == Some(*value)
+                    };
+                    *matched |= hit && command == "CASE";
+                    if hit {
+                        *active = *outer;
+                    }
+                }
+            }
+            "SKIP" => {
+                if let Some(Block::Switch { active, .. }) =
+                    self.innermost(|block| matches!(block, Block::Switch { .. }))
+                {
+                    *active = false;
+                }
+            }
+            "ENDSW" => self.close(|block| matches!(block, Block::Switch { .. })),
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Read the keyword and numeric argument of a header command.
+///
+/// # Arguments
+///
+/// * `line` - A trimmed line.
+///
+/// # Returns
+///
+/// * `Option<Command>` - Upper-cased keyword and argument, or `None` for data and other lines.
+fn command_of(line: &str) -> Option<Command> {
+    // Data lines start with a digit and never hold control flow.
+    line.strip_prefix('#')
+        .filter(|command| command.starts_with(|c: char| c.is_ascii_alphabetic()))
+        .map(|command| {
+            let mut parts = command.split_whitespace();
+            let keyword = parts.next().unwrap_or("").to_uppercase();
+            (keyword, parts.next().and_then(|v| v.parse::<u64>().ok()))
+        })
+}
+
+/// Control-flow resolution for lines that arrive one at a time.
+///
+/// The labels of a `#SWITCH` block are not known before its lines arrive, so
+/// `#DEF` runs when no earlier `#CASE` of the block matched; charts that place
+/// `#DEF` before the matching `#CASE` resolve differently than with
+/// `resolve_control_flow`.
+pub(crate) struct IncrementalControlFlow<R> {
+    evaluator: Evaluator,
+    rng: R,
+}
+
+impl<R: RandomSource> IncrementalControlFlow<R> {
+    /// Start with no open blocks.
+    ///
+    /// # Arguments
+    ///
+    /// * `rng` - Source of the `#RANDOM` and `#SWITCH` values.
+    /// * `forced` - Values to use instead of drawing, matched by line number.
+    pub(crate) fn new(rng: R, forced: &[RandomChoice]) -> Self {
+        Self {
+            evaluator: Evaluator::new(None, forced),
+            rng,
+        }
+    }
+
+    /// Apply the next line.
+    ///
+    /// # Arguments
+    ///
+    /// * `line_no` - 1-based line number.
+    /// * `line` - A trimmed line.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the line is part of the chart, `false` for skipped
+    ///   lines and control-flow commands.
+    pub(crate) fn keep(&mut self, line_no: usize, line: &str) -> bool {
+        if let Some((keyword, arg)) = command_of(line)
+            && self.evaluator.apply(line_no, &keyword, arg, &mut self.rng)
+        {
+            return false;
+        }
+        self.evaluator.active()
+    }
+
+    /// Values selected for the `#RANDOM` and `#SWITCH` commands seen so far.
+    pub(crate) fn choices(&self) -> &[RandomChoice] {
+        &self.evaluator.choices
+    }
+}
+
+/// Resolve `#RANDOM` and `#SWITCH` control flow and keep the lines that are part of the chart.
+///
+/// `#RANDOM`, `#SETRANDOM`, `#IF`, `#ELSEIF`, `#ELSE`, `#ENDIF` and
+/// `#ENDRANDOM` are supported, as are the `#SWITCH`, `#SETSWITCH`, `#CASE`,
+/// `#SKIP`, `#DEF` and `#ENDSW` extensions. Blocks nest freely; values are only
+/// drawn for blocks that are reached, so the same source always selects the
+/// same branches.
+///
+/// Commands listed in `forced` take the given value instead of a random one,
+/// so passing back the returned choices reproduces a parse, and changing one
+/// of them selects another variant.
+///
+/// # Arguments
+///
+/// * `data` - Full text content of a chart.
+/// * `rng` - Source of the `#RANDOM` and `#SWITCH` values.
+/// * `forced` - Values to use instead of drawing, matched by line number.
+///
+/// # Returns
+///
+/// * `(Vec<(usize, &str)>, Vec<RandomChoice>)` - 1-based line numbers and trimmed text of
+///   the selected lines, without the control-flow commands, and the value selected for
+///   each `#RANDOM` and `#SWITCH` command that was reached.
+pub fn resolve_control_flow<'a>(
+    data: &'a str,
+    rng: &mut dyn RandomSource,
+    forced: &[RandomChoice],
+) -> (Vec<(usize, &'a str)>, Vec<RandomChoice>) {
+    let commands: Vec<(usize, &str, Option<Command>)> = data
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            let line = line.trim();
+            (idx + 1, line, command_of(line))
+        })
+        .collect();
+
+    let mut case_labels: Vec<Vec<u64>> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    for (keyword, arg) in commands
+        .iter()
+        .filter_map(|(_, _, command)| command.as_ref())
+    {
+        match keyword.as_str() {
+            "SWITCH" | "SETSWITCH" => {
+                open.push(case_labels.len());
+                case_labels.push(Vec::new());
+            }
+            "CASE" => {
+                if let (Some(&block), Some(label)) = (open.last(), arg) {
+                    case_labels[block].push(*label);
+                }
+            }
+            "ENDSW" => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+
+    let mut evaluator = Evaluator::new(Some(case_labels), forced);
+    let mut lines = Vec::new();
+    for (line_no, line, command) in commands {
+        if let Some((keyword, arg)) = command
+            && evaluator.apply(line_no, &keyword, arg, rng)
+        {
+            continue;
+        }
+        if evaluator.active() {
+            lines.push((line_no, line));
+        }
+    }
+    (lines, evaluator.choices)
+}
+
+/// Enumerate the distinct variants of a chart's `#RANDOM` and `#SWITCH` blocks.
+///
+/// Every value of every block that is reached is tried, nested blocks
+/// included, in ascending order. Combinations that select the same lines as
+/// an earlier one (such as values no `#IF` tests) are skipped, so each
+/// variant is a different chart. A chart without control flow has a single
+/// variant with no choices.
+///
+/// # Arguments
+///
+/// * `data` - Full text content of a chart.
+/// * `max_variants` - Largest number of variants returned.
+///
+/// # Returns
+///
+/// * `RandomVariants` - Choices of each variant, and whether the enumeration was cut short
+///   by `max_variants` or by the number of combinations.
+pub fn random_variants(data: &str, max_variants: usize) -> RandomVariants {
+    let mut result = RandomVariants::default();
+    let mut seen: AHashSet<Vec<usize>> = AHashSet::new();
+    // Each entry fixes the choices made before some block and a new value for it.
+    let mut pending: Vec<Vec<RandomChoice>> = vec![Vec::new()];
+    let mut resolutions = 0;
+    while let Some(forced) = pending.pop() {
+        if result.variants.len() >= max_variants || resolutions >= MAX_VARIANT_RESOLUTIONS {
+            result.truncated = true;
+            break;
+        }
+        resolutions += 1;
+        let (lines, choices) = resolve_control_flow(data, &mut Lowest, &forced);
+        // Blocks after the forced ones took their lowest value; queue the others so
+        // the stack yields later blocks first and values in ascending order.
+        for (i, choice) in choices.iter().enumerate().skip(forced.len()) {
+            let last = choice.max.min(MAX_VARIANT_RESOLUTIONS as u64);
+            result.truncated |= last < choice.max;
+            for value in (2..=last).rev() {
+                let mut alternative = choices[..i].to_vec();
+                alternative.push(RandomChoice { value, ..*choice });
+                pending.push(alternative);
+            }
+        }
+        if pending.len() > MAX_VARIANT_RESOLUTIONS {
+            // The bottom of the stack would not be reached within the limit.
+            pending.drain(..pending.len() - MAX_VARIANT_RESOLUTIONS);
+            result.truncated = true;
+        }
+        if seen.insert(lines.iter().map(|&(line_no, _)| line_no).collect()) {
+            result.variants.push(choices);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::SplitMix64;
+
+    /// Lines kept with the given `(line, value)` choices forced.
+    fn selected(text: &str, forced: &[(usize, u64)]) -> Vec<String> {
+        let forced: Vec<RandomChoice> = forced
+            .iter()
+            .map(|&(line, value)| RandomChoice {
+                line,
+                max: 0,
+                value,
+            })
+            .collect();
+        let (lines, _) = resolve_control_flow(text, &mut SplitMix64::new(0), &forced);
+        lines
+            .into_iter()
+            .map(|(_, line)| line.to_string())
+            .collect()
+    }
+
+    const NESTED: &str = "#RANDOM 2\n#IF 1\n#WAV01 a\n#RANDOM 3\n#IF 3\n#WAV02 b\n#ENDIF\n\
+                          #ENDRANDOM\n#ELSE\n#WAV03 c\n#ENDIF\n#ENDRANDOM\n#WAV04 d\n";
+
+    #[test]
+    fn nested_random_blocks_select_inner_branches() {
+        assert_eq!(
+            selected(NESTED, &[(1, 1), (4, 3)]),
+            ["#WAV01 a", "#WAV02 b", "#WAV04 d"]
+        );
+        assert_eq!(
+            selected(NESTED, &[(1, 1), (4, 2)]),
+            ["#WAV01 a", "#WAV04 d"]
+        );
+        assert_eq!(selected(NESTED, &[(1, 2)]), ["#WAV03 c", "#WAV04 d"]);
+    }
+
+    #[test]
+    fn unreached_blocks_draw_nothing() {
+        let forced = [RandomChoice {
+            line: 1,
+            max: 2,
+            value: 2,
+        }];
+        let (_, choices) = resolve_control_flow(NESTED, &mut SplitMix64::new(0), &forced);
+        assert_eq!(choices, forced);
+    }
+
+    #[test]
+    fn switch_cases_fall_through_until_skip() {
+        let text = "#SWITCH 4\n#CASE 1\n#WAV01 a\n#CASE 2\n#WAV02 b\n#SKIP\n\
+                    #CASE 3\n#WAV03 c\n#DEF\n#WAV04 d\n#ENDSW\n";
+        assert_eq!(selected(text, &[(1, 1)]), ["#WAV01 a", "#WAV02 b"]);
+        assert_eq!(selected(text, &[(1, 2)]), ["#WAV02 b"]);
+        // Case 3 falls through into #DEF, which also runs on its own.
+        assert_eq!(selected(text, &[(1, 3)]), ["#WAV03 c", "#WAV04 d"]);
+        assert_eq!(selected(text, &[(1, 4)]), ["#WAV04 d"]);
+    }
+
+    #[test]
+    fn def_runs_only_without_a_matching_case() {
+        let text = "#SWITCH 2\n#DEF\n#WAV04 d\n#SKIP\n#CASE 2\n#WAV02 b\n#SKIP\n#ENDSW\n";
+        assert_eq!(selected(text, &[(1, 2)]), ["#WAV02 b"]);
+        assert_eq!(selected(text, &[(1, 1)]), ["#WAV04 d"]);
+
+        // Line by line, the later #CASE is not known yet when #DEF is reached.
+        let forced = [RandomChoice {
+            line: 1,
+            max: 2,
+            value: 2,
+        }];
+        let mut incremental = IncrementalControlFlow::new(SplitMix64::new(0), &forced);
+        let kept: Vec<&str> = text
+            .lines()
+            .enumerate()
+            .filter(|&(idx, line)| incremental.keep(idx + 1, line))
+            .map(|(_, line)| line)
+            .collect();
+        assert_eq!(kept, ["#WAV04 d", "#WAV02 b"]);
+    }
+
+    /// `(line, value)` of each choice of each variant.
+    fn variants(text: &str, max_variants: usize) -> (Vec<Vec<(usize, u64)>>, bool) {
+        let result = random_variants(text, max_variants);
+        let variants = result
+            .variants
+            .iter()
+            .map(|choices| choices.iter().map(|c| (c.line, c.value)).collect())
+            .collect();
+        (variants, result.truncated)
+    }
+
+    #[test]
+    fn two_branch_random_has_two_variants() {
+        let text = "#RANDOM 2\n#IF 1\n#WAV01 a\n#ENDIF\n#IF 2\n#WAV02 b\n#ENDIF\n#ENDRANDOM\n";
+        assert_eq!(
+            variants(text, 16),
+            (vec![vec![(1, 1)], vec![(1, 2)]], false)
+        );
+        assert_eq!(variants(text, 1), (vec![vec![(1, 1)]], true));
+        assert_eq!(variants("#WAV01 a\n", 16), (vec![vec![]], false));
+    }
+
+    #[test]
+    fn nested_random_variants_follow_the_reached_blocks() {
+        // The inner #RANDOM is only reached from the first outer branch, and
+        // its values 1 and 2 select the same lines.
+        assert_eq!(
+            variants(NESTED, 16),
+            (
+                vec![vec![(1, 1), (4, 1)], vec![(1, 1), (4, 3)], vec![(1, 2)]],
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn the_same_seed_selects_the_same_branches() {
+        let text = "#RANDOM 1000\n#IF 1\n#WAV01 a\n#ENDIF\n#SWITCH 1000\n#ENDSW\n";
+        let choices = |seed| resolve_control_flow(text, &mut SplitMix64::new(seed), &[]).1;
+        assert_eq!(choices(7), choices(7));
+        assert_ne!(choices(7), choices(8));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::bms::{Bms, MeasureIndex, ObjectId};
+use crate::timeline::{TempoMap, build_tempo_map};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// A single keysounded object of a chart.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChartNote {
+    /// Measure index.
+    pub measure: MeasureIndex,
+    /// Channel identifier.
+    pub channel: u16,
+    /// Position within the measure (0.0..1.0).
+    pub position: f64,
+    /// Object id of the keysound.
+    pub object: ObjectId,
+    /// Absolute time in seconds, computed with the tempo map of its own chart.
+    pub time_sec: f64,
+}
+
+/// Exact identity of a note: position is kept as a reduced fraction so that
+/// `01` in a 2-object message matches `02` in a 4-object message.
+type NoteKey = (MeasureIndex, u16, usize, usize, ObjectId);
+
+/// Change to the notes of a chart.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NoteChange {
+    /// Note only present in the new chart.
+    Added { note: ChartNote },
+    /// Note only present in the old chart.
+    Removed { note: ChartNote },
+    /// Same keysound on the same channel at a different position.
+    Moved { from: ChartNote, to: ChartNote },
+}
+
+/// Change to an entry of a header table (`#WAVxx`, `#BPMxx`).
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TableChange<T> {
+    /// Entry only defined in the new chart.
+    Added { id: ObjectId, value: T },
+    /// Entry only defined in the old chart.
+    Removed { id: ObjectId, value: T },
+    /// Entry defined in both charts with different values.
+    Changed { id: ObjectId, old: T, new: T },
+}
+
+/// Structural differences between two versions of a chart.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChartDiff {
+    /// Old and new base BPM, if it changed.
+    pub base_bpm: Option<(f64, f64)>,
+    /// Note changes, ordered by time.
+    pub notes: Vec<NoteChange>,
+    /// `#BPMxx` table changes, ordered by id.
+    pub bpm_table: Vec<TableChange<f64>>,
+    /// `#WAVxx` table changes, ordered by id.
+    pub wav_table: Vec<TableChange<String>>,
+}
+
+impl ChartDiff {
+    /// Whether the two charts are structurally identical.
+    pub fn is_empty(&self) -> bool {
+        self.base_bpm.is_none()
+            && self.notes.is_empty()
+            && self.bpm_table.is_empty()
+            && self.wav_table.is_empty()
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Collect the notes of a chart keyed by exact position, keeping duplicates.
+fn collect_notes(bms: &Bms, tempo_map: &TempoMap) -> BTreeMap<NoteKey, Vec<ChartNote>> {
+    let mut notes: BTreeMap<NoteKey, Vec<ChartNote>> = BTreeMap::new();
+    for message in &bms.messages {
+        if !message.channel.is_sound() {
+            continue;
+        }
+        let len = message.objects.len();
+        for (i, &object) in message.objects.iter().enumerate() {
+            if object == 0 {
+                continue;
+            }
+            let g = gcd(i, len);
+            let position = i as f64 / len as f64;
+            notes
+                .entry((
+                    message.measure,
+                    message.channel.code(),
+                    i / g,
+                    len / g,
+                    object,
+                ))
+                .or_default()
+                .push(ChartNote {
+                    measure: message.measure,
+                    channel: message.channel.code(),
+                    position,
+                    object,
+                    time_sec: tempo_map.get_timestamp(message.measure, position),
+                });
+        }
+    }
+    notes
+}
+
+/// Diff two header tables, ordered by id.
+fn diff_table<T: Clone + PartialEq>(
+    old: &HashMap<ObjectId, T>,
+    new: &HashMap<ObjectId, T>,
+) -> Vec<TableChange<T>> {
+    let ids: BTreeSet<ObjectId> = old.keys().chain(new.keys()).copied().collect();
+    ids.into_iter()
+        .filter_map(|id| match (old.get(&id), new.get(&id)) {
+            (Some(o), Some(n)) if o != n => Some(TableChange::Changed {
+                id,
+                old: o.clone(),
+                new: n.clone(),
+            }),
+            (Some(o), None) => Some(TableChange::Removed {
+                id,
+                value: o.clone(),
+            }),
+            (None, Some(n)) => Some(TableChange::Added {
+                id,
+                value: n.clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Compute the structural differences between two versions of a chart.
+///
+/// Notes present at the same position in both charts are unchanged. Of the
+/// rest, a removed and an added note using the same keysound on the same
+/// channel are paired in time order and reported as a move.
+///
+/// # Arguments
+///
+/// * `old` - Previous version of the chart.
+/// * `new` - Updated version of the chart.
+///
+/// # Returns
+///
+/// * `ChartDiff` - Note and header table changes.
+pub fn diff_charts(old: &Bms, new: &Bms) -> ChartDiff {
+    let mut old_notes = collect_notes(old, &build_tempo_map(old));
+    let mut new_notes = collect_notes(new, &build_tempo_map(new));
+
+    // Drop notes present in both charts.
+    for (key, olds) in old_notes.iter_mut() {
+        if let Some(news) = new_notes.get_mut(key) {
+            let common = olds.len().min(news.len());
+            olds.drain(..common);
+            news.drain(..common);
+        }
+    }
+    let mut removed: Vec<ChartNote> = old_notes.into_values().flatten().collect();
+    let mut added: Vec<ChartNote> = new_notes.into_values().flatten().collect();
+    removed.sort_by(|a, b| a.time_sec.total_cmp(&b.time_sec));
+    added.sort_by(|a, b| a.time_sec.total_cmp(&b.time_sec));
+
+    let mut notes: Vec<NoteChange> = Vec::new();
+    let mut unmatched_added: Vec<Option<ChartNote>> = added.into_iter().map(Some).collect();
+    for from in removed {
+        let partner = unmatched_added.iter_mut().find(|slot| {
+            slot.as_ref()
+                .is_some_and(|to| to.channel == from.channel && to.object == from.object)
+        });
+        match partner.and_then(Option::take) {
+            Some(to) => notes.push(NoteChange::Moved { from, to }),
+            None => notes.push(NoteChange::Removed { note: from }),
+        }
+    }
+    notes.extend(
+        unmatched_added
+            .into_iter()
+            .flatten()
+            .map(|note| NoteChange::Added { note }),
+    );
+    notes.sort_by(|a, b| change_time(a).total_cmp(&change_time(b)));
+
+    ChartDiff {
+        base_bpm: (old.header.bpm != new.header.bpm).then_some((old.header.bpm, new.header.bpm)),
+        notes,
+        bpm_table: diff_table(&old.header.bpm_table, &new.header.bpm_table),
+        wav_table: diff_table(&old.header.audio_files, &new.header.audio_files),
+    }
+}
+
+/// Time used to order a note change (the original position for moves).
+fn change_time(change: &NoteChange) -> f64 {
+    match change {
+        NoteChange::Added { note } | NoteChange::Removed { note } => note.time_sec,
+        NoteChange::Moved { from, .. } => from.time_sec,
+    }
+}
@@ -0,0 +1,264 @@
+use crate::bms::{
+    Bms, Channel, ChartMode, DiagnosticCategory, MeasureIndex, Message, ObjectId, ParseReport,
+    Severity, parse_object_id,
+};
+
+/// Map a DTX channel to the channel its chips are rendered from.
+///
+/// The twelve drum lanes (11-1C) become player 1 lanes 1-9 and player 2
+/// lanes 1-3. Guitar (20-27), bass (A0-A7) and sound effect (61-92) chips
+/// play as background keysounds.
+///
+/// # Arguments
+///
+/// * `code` - Channel digits read as a hexadecimal number.
+///
+/// # Returns
+///
+/// * `Option<Channel>` - Internal channel, or `None` for channels without audio or timing.
+pub fn dtx_channel(code: u8) -> Option<Channel> {
+    match code {
+        0x01
+        | 0x20..=0x27
+        | 0x61..=0x69
+        | 0x70..=0x79
+        | 0x80..=0x89
+        | 0x90..=0x92
+        | 0xA0..=0xA7 => Some(Channel::Bgm),
+        0x03 => Some(Channel::Bpm),
+        0x08 => Some(Channel::ExBpm),
+        0x11..=0x19 => Some(Channel::Note {
+            player: 1,
+            lane: code - 0x10,
+        }),
+        0x1A..=0x1C => Some(Channel::Note {
+            player: 2,
+            lane: code - 0x19,
+        }),
+        _ => None,
+    }
+}
+
+/// Split a DTX command into its key and value; the value may follow a colon.
+fn split_command(line: &str) -> (&str, &str) {
+    let body = line.strip_prefix('#').unwrap_or(line);
+    let end = body
+        .find(|c: char| c == ':' || c.is_whitespace())
+        .unwrap_or(body.len());
+    let value = body[end..].trim_start();
+    let value = value.strip_prefix(':').unwrap_or(value).trim();
+    (&body[..end], value)
+}
+
+/// Parse a DTXMania chart into the internal chart representation.
+///
+/// `#WAV`, `#BPM` and `#BPMxx` fill the same tables as their BMS
+/// counterparts, `#VOLUME`/`#WAVVOL` and `#PAN`/`#WAVPAN` set the per-sound
+/// volume and pan, and chips are mapped with `dtx_channel`. Commands and
+/// channels without audio (images, videos, lines) are skipped silently.
+///
+/// # Arguments
+///
+/// * `data` - Full text content of a DTX file.
+///
+/// # Returns
+///
+/// * `(Bms, ParseReport)` - Parsed chart and diagnostics about the lines that were skipped.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(bytes = data.len()))
+)]
+pub fn parse_dtx(data: &str) -> (Bms, ParseReport) {
+    let mut bms = Bms {
+        mode: ChartMode::Dtx,
+        ..Bms::default()
+    };
+    let mut report = ParseReport::default();
+
+    for (idx, line) in data.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = line.split(';').next().unwrap_or("").trim();
+        if !line.starts_with('#') {
+            continue;
+        }
+        let (raw_key, value) = split_command(line);
+        let key = raw_key.to_uppercase();
+        let id_at = |start: usize| {
+            raw_key
+                .get(start..)
+                .and_then(|id| parse_object_id(id.as_bytes()))
+        };
+
+        let is_data = key.len() == 5
+            && key.is_ascii()
+            && key[..3].bytes().all(|b| b.is_ascii_digit())
+            && key[3..].bytes().all(|b| b.is_ascii_hexdigit());
+        if is_data {
+            let measure: MeasureIndex = key[..3].parse().unwrap_or(0);
+            let code = u8::from_str_radix(&key[3..], 16).unwrap_or(0);
+            if code == 0x02 {
+                match value.parse::<f64>() {
+                    Ok(mult) if mult.is_finite() && mult > 0.0 => {
+                        bms.measure_multipliers.insert(measure, mult);
+                    }
+                    _ => report.push(
+                        line_no,
+                        Severity::Error,
+                        DiagnosticCategory::MalformedLine,
+                        format!("invalid measure length: {}", value),
+                    ),
+                }
+                continue;
+            }
+            let Some(channel) = dtx_channel(code) else {
+                continue;
+            };
+            let chips: Vec<u8> = value
+                .bytes()
+                .filter(|b| !b.is_ascii_whitespace() && *b != b'_')
+                .collect();
+            if !chips.len().is_multiple_of(2) {
+                report.push(
+                    line_no,
+                    Severity::Error,
+                    DiagnosticCategory::MalformedLine,
+                    "invalid object data (must be pairs of two chars)".to_string(),
+                );
+                continue;
+            }
+            let objects: Vec<ObjectId> = chips
+                .chunks(2)
+                .map(|chip| parse_object_id(chip).unwrap_or(0))
+                .collect();
+            bms.messages.push(Message {
+                measure,
+                channel,
+                objects,
+            });
+            continue;
+        }
+
+        let header = &mut bms.header;
+        let table_entry = match key.as_str() {
+            "TITLE" => {
+                header.title = Some(value.to_string());
+                None
+            }
+            "ARTIST" => {
+                header.artist = Some(value.to_string());
+                None
+            }
+            "GENRE" => {
+                header.genre = Some(value.to_string());
+                None
+            }
+            "COMMENT" => {
+                header.comment = Some(value.to_string());
+                None
+            }
+            "PREVIEW" => {
+                header.preview = Some(value.to_string());
+                None
+            }
+            "DLEVEL" => {
+                header.play_level = value.parse().ok();
+                None
+            }
+            "BPM" => {
+                header.bpm = value.parse().unwrap_or(120.0);
+                None
+            }
+            _ if key.starts_with("BPM") => {
+                Some(id_at(3).zip(value.parse().ok()).map(|(id, bpm)| {
+                    header.bpm_table.insert(id, bpm);
+                }))
+            }
+            _ if key.starts_with("WAVVOL") => {
+                Some(id_at(6).zip(value.parse().ok()).map(|(id, volume)| {
+                    header.wav_volumes.insert(id, volume);
+                }))
+            }
+            _ if key.starts_with("WAVPAN") => {
+                Some(id_at(6).zip(value.parse().ok()).map(|(id, pan)| {
+                    header.wav_pans.insert(id, pan);
+                }))
+            }
+            _ if key.starts_with("VOLUME") => {
+                Some(id_at(6).zip(value.parse().ok()).map(|(id, volume)| {
+                    header.wav_volumes.insert(id, volume);
+                }))
+            }
+            // `#PANEL` is the scrolling panel text, whose suffix also reads as an id.
+            "PANEL" => None,
+            _ if key.starts_with("PAN") && key.len() == 5 => {
+                Some(id_at(3).zip(value.parse().ok()).map(|(id, pan)| {
+                    header.wav_pans.insert(id, pan);
+                }))
+            }
+            _ if key.starts_with("WAV") => Some(id_at(3).map(|id| {
+                header.audio_files.insert(id, value.to_string());
+            })),
+            _ => None,
+        };
+        if table_entry == Some(None) {
+            report.push(
+                line_no,
+                Severity::Warning,
+                DiagnosticCategory::InvalidTableEntry,
+                format!("invalid #{}: {}", raw_key, value),
+            );
+        }
+    }
+
+    (bms, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panel_is_not_a_pan() {
+        let (bms, report) = parse_dtx("#PANEL Some song\n#PAN01 -50\n#WAV01: kick.wav\n");
+        assert!(report.diagnostics.is_empty());
+        assert_eq!(bms.header.wav_pans.len(), 1);
+        assert_eq!(bms.header.wav_pans[&1], -50.0);
+        assert_eq!(bms.header.audio_files[&1], "kick.wav");
+    }
+
+    #[test]
+    fn volume_and_pan_commands_fill_the_tables() {
+        let text = "#VOLUME02 80\n#WAVVOL03 60\n#WAVPAN02 25\n#PAN03 abc\n";
+        let (bms, report) = parse_dtx(text);
+        assert_eq!(bms.header.wav_volumes[&2], 80.0);
+        assert_eq!(bms.header.wav_volumes[&3], 60.0);
+        assert_eq!(bms.header.wav_pans[&2], 25.0);
+        // A malformed pan is reported and skipped.
+        assert_eq!(report.diagnostics.len(), 1);
+        assert_eq!(report.diagnostics[0].line, 4);
+        assert!(!bms.header.wav_pans.contains_key(&3));
+    }
+
+    #[test]
+    fn chips_map_to_internal_channels() {
+        let text = "#BPM 150\n#00011: 01_02\n#0001A: 0300\n#00061: 04\n#00002: 0.5\n#00050: 01\n";
+        let (bms, report) = parse_dtx(text);
+        assert!(report.diagnostics.is_empty());
+        assert_eq!(bms.mode, ChartMode::Dtx);
+        assert_eq!(bms.header.bpm, 150.0);
+        assert_eq!(bms.measure_multipliers[&0], 0.5);
+        let channels: Vec<(Channel, Vec<ObjectId>)> = bms
+            .messages
+            .iter()
+            .map(|m| (m.channel, m.objects.clone()))
+            .collect();
+        assert_eq!(
+            channels,
+            [
+                (Channel::Note { player: 1, lane: 1 }, vec![1, 2]),
+                (Channel::Note { player: 2, lane: 1 }, vec![3, 0]),
+                (Channel::Bgm, vec![4]),
+            ]
+        );
+    }
+}
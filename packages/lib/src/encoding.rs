@@ -0,0 +1,117 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{EUC_KR, Encoding, SHIFT_JIS, UTF_8};
+use num_enum::TryFromPrimitive;
+use serde::{Deserialize, Serialize};
+
+/// Text encodings commonly used by BMS files.
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive, Serialize)]
+pub enum TextEncoding {
+    Utf8,
+    ShiftJis,
+    EucKr,
+}
+
+impl<'de> Deserialize<'de> for TextEncoding {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct TextEncodingVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for TextEncodingVisitor {
+            type Value = TextEncoding;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an i64")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                TextEncoding::try_from(value as u8).map_err(|_| E::custom("Invalid TextEncoding"))
+            }
+        }
+
+        deserializer.deserialize_any(TextEncodingVisitor)
+    }
+}
+
+impl TextEncoding {
+    pub(crate) fn encoding(self) -> &'static Encoding {
+        match self {
+            TextEncoding::Utf8 => UTF_8,
+            TextEncoding::ShiftJis => SHIFT_JIS,
+            TextEncoding::EucKr => EUC_KR,
+        }
+    }
+}
+
+/// Count characters typical of a script, used to break ties between legacy encodings.
+fn count_chars(text: &str, range: std::ops::RangeInclusive<char>) -> usize {
+    text.chars().filter(|c| range.contains(c)).count()
+}
+
+/// Guess the encoding of raw BMS bytes.
+///
+/// Valid UTF-8 (with or without BOM) wins. Otherwise a statistical detector
+/// picks between Shift-JIS and EUC-KR. When it suggests something else (short
+/// files give it little to work with), an encoding that decodes without errors
+/// is preferred, and if both do, the one producing more kana or hangul wins,
+/// with Shift-JIS as the default since most charts are Japanese.
+///
+/// # Arguments
+///
+/// * `bytes` - Raw file contents.
+///
+/// # Returns
+///
+/// * `TextEncoding` - Detected encoding.
+pub fn detect_encoding(bytes: &[u8]) -> TextEncoding {
+    if std::str::from_utf8(bytes).is_ok() {
+        return TextEncoding::Utf8;
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    let guess = detector.guess(None, false);
+    if guess == SHIFT_JIS {
+        return TextEncoding::ShiftJis;
+    }
+    if guess == EUC_KR {
+        return TextEncoding::EucKr;
+    }
+
+    let (sjis, _, sjis_errors) = SHIFT_JIS.decode(bytes);
+    let (euc, _, euc_errors) = EUC_KR.decode(bytes);
+    match (sjis_errors, euc_errors) {
+        (false, true) => TextEncoding::ShiftJis,
+        (true, false) => TextEncoding::EucKr,
+        _ => {
+            let kana = count_chars(&sjis, '\u{3040}'..='\u{30FF}');
+            let hangul = count_chars(&euc, '\u{AC00}'..='\u{D7A3}');
+            if hangul > kana {
+                TextEncoding::EucKr
+            } else {
+                TextEncoding::ShiftJis
+            }
+        }
+    }
+}
+
+/// Decode raw BMS bytes to text.
+///
+/// # Arguments
+///
+/// * `bytes` - Raw file contents.
+/// * `encoding` - Encoding to use, or `None` to detect it.
+///
+/// # Returns
+///
+/// * `(String, TextEncoding)` - Decoded text (invalid sequences replaced) and the encoding used.
+pub fn decode_text(bytes: &[u8], encoding: Option<TextEncoding>) -> (String, TextEncoding) {
+    let encoding = encoding.unwrap_or_else(|| detect_encoding(bytes));
+    let (text, _) = encoding.encoding().decode_with_bom_removal(bytes);
+    (text.into_owned(), encoding)
+}
@@ -0,0 +1,88 @@
+use crate::bms::MeasureIndex;
+use crate::timeline::TempoMap;
+
+/// Length of a single click in seconds.
+const CLICK_SECONDS: f64 = 0.02;
+/// Click pitch on the first beat of a measure.
+const DOWNBEAT_HZ: f64 = 1760.0;
+/// Click pitch on the remaining beats.
+const BEAT_HZ: f64 = 880.0;
+/// Peak amplitude of a click.
+const CLICK_GAIN: f32 = 0.5;
+
+/// A metronome beat on the tempo map.
+#[derive(Debug, Clone, Copy)]
+pub struct Beat {
+    /// Measure the beat belongs to.
+    pub measure: MeasureIndex,
+    /// Absolute time in seconds.
+    pub time_sec: f64,
+    /// Whether this is the first beat of its measure.
+    pub downbeat: bool,
+}
+
+/// List every quarter-note beat of the chart, honoring measure length changes.
+///
+/// # Arguments
+///
+/// * `tempo_map` - Precomputed tempo map.
+///
+/// # Returns
+///
+/// * `Vec<Beat>` - Beats in chronological order.
+pub fn beat_times(tempo_map: &TempoMap) -> Vec<Beat> {
+    let mut beats = Vec::new();
+    for measure in tempo_map.base_measure..=tempo_map.last_measure() {
+        let beats_in_measure = 4.0 * tempo_map.measure_multiplier(measure);
+        let mut beat = 0.0f64;
+        while beat < beats_in_measure - 1e-9 {
+            beats.push(Beat {
+                measure,
+                time_sec: tempo_map.get_timestamp(measure, beat / beats_in_measure),
+                downbeat: beat == 0.0,
+            });
+            beat += 1.0;
+        }
+    }
+    beats
+}
+
+/// Render a click track (silence plus a short decaying tone per beat).
+///
+/// # Arguments
+///
+/// * `beats` - Beats to click on.
+/// * `total_len` - Output length in interleaved samples.
+/// * `sample_rate` - Target sample rate.
+/// * `channels` - Number of output channels.
+///
+/// # Returns
+///
+/// * `Vec<f32>` - Interleaved click track of exactly `total_len` samples.
+pub fn render_click_track(
+    beats: &[Beat],
+    total_len: usize,
+    sample_rate: u32,
+    channels: usize,
+) -> Vec<f32> {
+    let mut out = vec![0.0f32; total_len];
+    let total_frames = total_len / channels;
+    let click_frames = (CLICK_SECONDS * sample_rate as f64) as usize;
+
+    for beat in beats {
+        let start = (beat.time_sec * sample_rate as f64).round() as usize;
+        if start >= total_frames {
+            break;
+        }
+        let freq = if beat.downbeat { DOWNBEAT_HZ } else { BEAT_HZ };
+        let end = (start + click_frames).min(total_frames);
+        for frame in start..end {
+            let t = (frame - start) as f64 / sample_rate as f64;
+            let envelope = 1.0 - t / CLICK_SECONDS;
+            let v = ((std::f64::consts::TAU * freq * t).sin() * envelope) as f32 * CLICK_GAIN;
+            let base = frame * channels;
+            out[base..base + channels].fill(v);
+        }
+    }
+    out
+}
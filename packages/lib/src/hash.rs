@@ -0,0 +1,117 @@
+use md5::{Digest, Md5};
+use serde::Serialize;
+use sha2::Sha256;
+
+/// MD5 digest of `data`.
+///
+/// # Arguments
+///
+/// * `data` - Bytes to hash.
+///
+/// # Returns
+///
+/// * `[u8; 16]` - Digest.
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    Md5::digest(data).into()
+}
+
+/// SHA-256 digest of `data`.
+///
+/// # Arguments
+///
+/// * `data` - Bytes to hash.
+///
+/// # Returns
+///
+/// * `[u8; 32]` - Digest.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Lowercase hexadecimal form of a digest.
+pub(crate) fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Identifiers of a chart file as used by score trackers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChartHash {
+    /// MD5 of the file, the key used by LR2 and LR2IR.
+    pub md5: String,
+    /// SHA-256 of the file, the key used by beatoraja.
+    pub sha256: String,
+}
+
+/// Compute the hashes that identify a chart in LR2IR and beatoraja.
+///
+/// Both ecosystems hash the file exactly as stored, before any decoding or
+/// `#RANDOM` resolution, so the raw bytes must be passed rather than the
+/// decoded text; re-encoding a Shift-JIS chart as UTF-8 changes its hash.
+///
+/// # Arguments
+///
+/// * `bytes` - Raw contents of the chart file.
+///
+/// # Returns
+///
+/// * `ChartHash` - Lowercase hexadecimal MD5 and SHA-256.
+pub fn chart_hash(bytes: &[u8]) -> ChartHash {
+    ChartHash {
+        md5: to_hex(&md5(bytes)),
+        sha256: to_hex(&sha256(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One million repetitions of `a`, the long vector of RFC 1321 and FIPS 180-2.
+    fn million_a() -> Vec<u8> {
+        vec![b'a'; 1_000_000]
+    }
+
+    #[test]
+    fn md5_known_answers() {
+        let cases: [(&[u8], &str); 3] = [
+            (b"", "d41d8cd98f00b204e9800998ecf8427e"),
+            (b"abc", "900150983cd24fb0d6963f7d28e17f72"),
+            (
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "57edf4a22be3c955ac49da2e2107b67a",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(to_hex(&md5(input)), expected);
+        }
+        assert_eq!(
+            to_hex(&md5(&million_a())),
+            "7707d6ae4e027c70eea2a935c2296f21"
+        );
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        let cases: [(&[u8], &str); 3] = [
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(to_hex(&sha256(input)), expected);
+        }
+        assert_eq!(
+            to_hex(&sha256(&million_a())),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+}
@@ -1,7 +1,33 @@
+pub mod alignment;
+pub mod analysis;
+pub mod assets;
 pub mod audio;
+pub mod base64;
 pub mod bms;
+pub mod cache;
+pub mod compare;
+pub mod control;
+pub mod diff;
+pub mod dtx;
+pub mod encoding;
+pub mod guide;
+pub mod hash;
+pub mod limits;
+pub mod loader;
 pub mod mixer;
+pub mod pcm;
+pub mod pitch;
+pub mod preview;
+pub mod random;
+pub mod render;
+pub mod sfz;
+pub mod stems;
+pub mod stream;
+pub mod stretch;
 pub mod timeline;
+pub mod transform;
+#[cfg(feature = "wasm")]
 pub mod wasm;
 
+#[cfg(feature = "wasm")]
 pub use wasm_bindgen_rayon::init_thread_pool;
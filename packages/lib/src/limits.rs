@@ -0,0 +1,90 @@
+use serde::Serialize;
+
+/// Kind of resource guarded by `ResourceLimits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LimitKind {
+    /// Number of parsed timeline messages.
+    Messages,
+    /// Number of scheduled sound events.
+    Events,
+    /// Total output length in interleaved samples.
+    TotalLength,
+    /// Total size of decoded PCM in bytes.
+    DecodedBytes,
+}
+
+impl core::fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LimitKind::Messages => write!(f, "messages"),
+            LimitKind::Events => write!(f, "events"),
+            LimitKind::TotalLength => write!(f, "total length"),
+            LimitKind::DecodedBytes => write!(f, "decoded bytes"),
+        }
+    }
+}
+
+/// Error returned when a chart exceeds one of the configured resource limits.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceLimitExceeded {
+    /// Which limit was exceeded.
+    pub kind: LimitKind,
+    /// Configured maximum.
+    pub limit: usize,
+    /// Actual amount required by the chart.
+    pub actual: usize,
+}
+
+impl core::fmt::Display for ResourceLimitExceeded {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "resource limit exceeded: {} ({} > {})",
+            self.kind, self.actual, self.limit
+        )
+    }
+}
+
+impl std::error::Error for ResourceLimitExceeded {}
+
+/// Upper bounds applied during conversion. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceLimits {
+    /// Maximum number of parsed timeline messages.
+    pub max_messages: Option<usize>,
+    /// Maximum number of scheduled sound events.
+    pub max_events: Option<usize>,
+    /// Maximum output length in interleaved samples.
+    pub max_total_len: Option<usize>,
+    /// Maximum total size of decoded PCM in bytes.
+    pub max_decoded_bytes: Option<usize>,
+}
+
+impl ResourceLimits {
+    /// Check an amount against the corresponding limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - Resource being checked.
+    /// * `actual` - Amount required by the chart.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), ResourceLimitExceeded>` - Ok if within bounds.
+    pub fn check(&self, kind: LimitKind, actual: usize) -> Result<(), ResourceLimitExceeded> {
+        let limit = match kind {
+            LimitKind::Messages => self.max_messages,
+            LimitKind::Events => self.max_events,
+            LimitKind::TotalLength => self.max_total_len,
+            LimitKind::DecodedBytes => self.max_decoded_bytes,
+        };
+        match limit {
+            Some(limit) if actual > limit => Err(ResourceLimitExceeded {
+                kind,
+                limit,
+                actual,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,514 @@
+use crate::assets::{AUDIO_EXTENSIONS, filename_candidates};
+use ahash::AHashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+/// Files returned by an `AudioLoader`, in the order they were requested.
+///
+/// Contents are read one file at a time, so a loader may keep them outside
+/// the decoder's memory (such as in JavaScript buffers) until they are needed.
+pub trait LoadedFiles {
+    /// Encoded size of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Position of the file in the request.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - Size in bytes, or `None` if the file is missing.
+    fn size(&self, index: usize) -> Option<u64>;
+
+    /// First bytes of a file, for estimates that only need the header.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Position of the file in the request.
+    /// * `len` - Largest number of bytes to read.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<u8>>` - Up to `len` bytes, or `None` if the file is missing.
+    fn header(&self, index: usize, len: usize) -> Option<Vec<u8>> {
+        self.bytes(index)
+            .map(|bytes| bytes[..len.min(bytes.len())].to_vec())
+    }
+
+    /// Full contents of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Position of the file in the request.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Arc<[u8]>>` - Encoded bytes, or `None` if the file is missing.
+    fn bytes(&self, index: usize) -> Option<Arc<[u8]>>;
+}
+
+impl LoadedFiles for Vec<Option<Arc<[u8]>>> {
+    fn size(&self, index: usize) -> Option<u64> {
+        self.get(index)?.as_ref().map(|bytes| bytes.len() as u64)
+    }
+
+    fn bytes(&self, index: usize) -> Option<Arc<[u8]>> {
+        self.get(index)?.clone()
+    }
+}
+
+/// Source of keysound (and chart) file contents.
+///
+/// The conversion only asks for filenames relative to the chart; where the
+/// bytes come from (a JavaScript callback, the filesystem, a zip archive or a
+/// ranged HTTP fetch done by the host) is up to the loader, and loaders can be
+/// wrapped to add fallbacks such as `CaseInsensitive` or `Fallback`.
+pub trait AudioLoader {
+    /// Files returned by `load`.
+    type Files: LoadedFiles;
+
+    /// Fetch a batch of files.
+    ///
+    /// # Arguments
+    ///
+    /// * `paths` - Filenames relative to the chart, as written in it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self::Files, String>` - Files in request order (missing ones
+    ///   included as `None`), or an error if the loader itself failed.
+    fn load(&self, paths: &[String]) -> impl Future<Output = Result<Self::Files, String>>;
+}
+
+/// Normalize a chart-relative path for lookups: forward slashes, no leading `./`.
+fn normalize_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    path.trim_start_matches("./").to_string()
+}
+
+/// Loader reading files below a folder on disk.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FsLoader {
+    root: std::path::PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FsLoader {
+    /// Create a loader for a song folder.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Folder the chart's filenames are relative to.
+    ///
+    /// # Returns
+    ///
+    /// * `FsLoader` - Loader reading from `root`.
+    pub fn new(root: impl Into<std::path::PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// List the files below the folder, for `CaseInsensitive`.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - Paths relative to the folder, with forward slashes.
+    pub fn file_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut pending = vec![self.root.clone()];
+        while let Some(dir) = pending.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else if let Ok(relative) = path.strip_prefix(&self.root) {
+                    names.push(normalize_path(&relative.to_string_lossy()));
+                }
+            }
+        }
+        names.sort();
+        names
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl AudioLoader for FsLoader {
+    type Files = Vec<Option<Arc<[u8]>>>;
+
+    async fn load(&self, paths: &[String]) -> Result<Self::Files, String> {
+        Ok(paths
+            .iter()
+            .map(|path| {
+                std::fs::read(self.root.join(normalize_path(path)))
+                    .ok()
+                    .map(Arc::from)
+            })
+            .collect())
+    }
+}
+
+/// Location of a file inside a zip archive.
+#[derive(Debug, Clone, Copy)]
+struct ZipEntry {
+    /// Offset of the local file header.
+    header_offset: usize,
+    /// 0 for stored, 8 for deflated.
+    method: u16,
+    compressed_size: usize,
+    size: usize,
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// Loader reading the entries of a zip archive held in memory.
+///
+/// Stored and deflated entries are supported; entries are inflated only
+/// when they are loaded.
+pub struct ZipLoader {
+    archive: Arc<[u8]>,
+    entries: AHashMap<String, ZipEntry>,
+}
+
+impl ZipLoader {
+    /// Index the central directory of an archive.
+    ///
+    /// # Arguments
+    ///
+    /// * `archive` - Contents of the zip file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ZipLoader, String>` - Loader over the archive's files, or an
+    ///   error if the central directory cannot be read.
+    pub fn new(archive: Arc<[u8]>) -> Result<Self, String> {
+        let data = &archive[..];
+        // The end-of-central-directory record is followed by a comment of up to 64 KiB.
+        let eocd = (0..=data.len().saturating_sub(22))
+            .rev()
+            .take(22 + u16::MAX as usize)
+            .find(|&at| data[at..].starts_with(b"PK\x05\x06"))
+            .ok_or("zip: end of central directory not found")?;
+        let count = read_u16(data, eocd + 10).ok_or("zip: truncated directory record")?;
+        let mut at = read_u32(data, eocd + 16).ok_or("zip: truncated directory record")? as usize;
+
+        let mut entries = AHashMap::new();
+        for _ in 0..count {
+            let record = data
+                .get(at..at + 46)
+                .ok_or("zip: truncated central directory")?;
+            if !record.starts_with(b"PK\x01\x02") {
+                return Err("zip: invalid central directory entry".to_string());
+            }
+            let field = |offset: usize| read_u16(record, offset).unwrap_or(0) as usize;
+            let (name_len, extra_len, comment_len) = (field(28), field(30), field(32));
+            let name = data
+                .get(at + 46..at + 46 + name_len)
+                .ok_or("zip: truncated entry name")?;
+            // Bit 11 marks UTF-8 names; older archives use the system code page.
+            let name = if field(8) & 0x800 != 0 {
+                String::from_utf8_lossy(name).into_owned()
+            } else {
+                crate::encoding::decode_text(name, None).0
+            };
+            if !name.ends_with('/') {
+                entries.insert(
+                    normalize_path(&name),
+                    ZipEntry {
+                        header_offset: read_u32(record, 42).unwrap_or(0) as usize,
+                        method: field(10) as u16,
+                        compressed_size: read_u32(record, 20).unwrap_or(0) as usize,
+                        size: read_u32(record, 24).unwrap_or(0) as usize,
+                    },
+                );
+            }
+            at += 46 + name_len + extra_len + comment_len;
+        }
+        Ok(Self { archive, entries })
+    }
+
+    /// Paths of the files in the archive, for `CaseInsensitive`.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - Sorted paths with forward slashes.
+    pub fn file_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.entries.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Extract one entry.
+    fn extract(&self, entry: ZipEntry) -> Option<Arc<[u8]>> {
+        let data = &self.archive[..];
+        let header = entry.header_offset;
+        if data.get(header..header + 4)? != b"PK\x03\x04" {
+            return None;
+        }
+        let start = header
+            + 30
+            + read_u16(data, header + 26)? as usize
+            + read_u16(data, header + 28)? as usize;
+        let compressed = data.get(start..start + entry.compressed_size)?;
+        match entry.method {
+            0 => Some(Arc::from(compressed)),
+            8 => miniz_oxide::inflate::decompress_to_vec_with_limit(compressed, entry.size)
+                .ok()
+                .map(Arc::from),
+            _ => None,
+        }
+    }
+}
+
+impl AudioLoader for ZipLoader {
+    type Files = Vec<Option<Arc<[u8]>>>;
+
+    async fn load(&self, paths: &[String]) -> Result<Self::Files, String> {
+        Ok(paths
+            .iter()
+            .map(|path| {
+                let entry = *self.entries.get(&normalize_path(path))?;
+                self.extract(entry)
+            })
+            .collect())
+    }
+}
+
+/// Loader resolving filenames case-insensitively, with extension fallbacks.
+///
+/// Charts authored on Windows often differ in case from the files they name,
+/// or reference `.wav` where `.ogg` is shipped. Each requested path is matched
+/// against a listing of the available files (trying `AUDIO_EXTENSIONS` in
+/// order) and the inner loader is asked for the listed name.
+pub struct CaseInsensitive<L> {
+    inner: L,
+    /// Lowercased path to the path as listed.
+    names: AHashMap<String, String>,
+}
+
+impl<L: AudioLoader> CaseInsensitive<L> {
+    /// Wrap a loader.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - Loader serving the listed names.
+    /// * `names` - Paths available from `inner`.
+    ///
+    /// # Returns
+    ///
+    /// * `CaseInsensitive<L>` - Loader resolving requests against `names`.
+    pub fn new(inner: L, names: impl IntoIterator<Item = String>) -> Self {
+        let names = names
+            .into_iter()
+            .map(|name| (normalize_path(&name).to_lowercase(), name))
+            .collect();
+        Self { inner, names }
+    }
+
+    /// Listed name for a requested path, or the path itself if nothing matches.
+    fn resolve(&self, path: &str) -> String {
+        filename_candidates(&normalize_path(path), &AUDIO_EXTENSIONS)
+            .iter()
+            .find_map(|candidate| self.names.get(&candidate.to_lowercase()))
+            .cloned()
+            .unwrap_or_else(|| path.to_string())
+    }
+}
+
+impl<L: AudioLoader> AudioLoader for CaseInsensitive<L> {
+    type Files = L::Files;
+
+    async fn load(&self, paths: &[String]) -> Result<Self::Files, String> {
+        let resolved: Vec<String> = paths.iter().map(|path| self.resolve(path)).collect();
+        self.inner.load(&resolved).await
+    }
+}
+
+/// Loader trying a second loader for the files the first one is missing.
+pub struct Fallback<A, B> {
+    primary: A,
+    secondary: B,
+}
+
+impl<A: AudioLoader, B: AudioLoader> Fallback<A, B> {
+    /// Combine two loaders.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary` - Loader asked first.
+    /// * `secondary` - Loader asked for the files `primary` does not have.
+    ///
+    /// # Returns
+    ///
+    /// * `Fallback<A, B>` - Combined loader.
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+impl<A: AudioLoader, B: AudioLoader> AudioLoader for Fallback<A, B> {
+    type Files = Vec<Option<Arc<[u8]>>>;
+
+    async fn load(&self, paths: &[String]) -> Result<Self::Files, String> {
+        let primary = self.primary.load(paths).await?;
+        let mut files: Vec<Option<Arc<[u8]>>> =
+            (0..paths.len()).map(|i| primary.bytes(i)).collect();
+        let missing: Vec<usize> = (0..paths.len()).filter(|&i| files[i].is_none()).collect();
+        if missing.is_empty() {
+            return Ok(files);
+        }
+        let missing_paths: Vec<String> = missing.iter().map(|&i| paths[i].clone()).collect();
+        let secondary = self.secondary.load(&missing_paths).await?;
+        for (j, &i) in missing.iter().enumerate() {
+            files[i] = secondary.bytes(j);
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    /// Run a loader future that never waits, as the in-memory loaders do.
+    fn load(loader: &impl AudioLoader, paths: &[&str]) -> Vec<Option<Vec<u8>>> {
+        let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+        let future = pin!(loader.load(&paths));
+        let Poll::Ready(files) = future.poll(&mut Context::from_waker(Waker::noop())) else {
+            panic!("loader future is pending");
+        };
+        let files = files.unwrap();
+        (0..paths.len())
+            .map(|i| files.bytes(i).map(|bytes| bytes.to_vec()))
+            .collect()
+    }
+
+    /// Build an archive of `(name, contents, deflate)` entries.
+    fn zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, contents, deflate) in entries {
+            let (method, stored) = if deflate {
+                (8u16, miniz_oxide::deflate::compress_to_vec(contents, 6))
+            } else {
+                (0u16, contents.to_vec())
+            };
+            let offset = out.len() as u32;
+            let sizes = [stored.len() as u32, contents.len() as u32];
+            out.extend_from_slice(b"PK\x03\x04");
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            out.extend_from_slice(&method.to_le_bytes());
+            out.extend_from_slice(&[0; 8]); // time, date, crc
+            sizes
+                .iter()
+                .for_each(|size| out.extend_from_slice(&size.to_le_bytes()));
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&stored);
+
+            directory.extend_from_slice(b"PK\x01\x02");
+            directory.extend_from_slice(&[20, 0, 20, 0]);
+            directory.extend_from_slice(&0x800u16.to_le_bytes()); // UTF-8 name
+            directory.extend_from_slice(&method.to_le_bytes());
+            directory.extend_from_slice(&[0; 8]);
+            sizes
+                .iter()
+                .for_each(|size| directory.extend_from_slice(&size.to_le_bytes()));
+            directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]); // extra, comment, disk, attributes
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_offset = out.len() as u32;
+        out.extend_from_slice(&directory);
+        out.extend_from_slice(b"PK\x05\x06");
+        out.extend_from_slice(&[0; 4]);
+        let count = (entries.len() as u16).to_le_bytes();
+        out.extend_from_slice(&count);
+        out.extend_from_slice(&count);
+        out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        out.extend_from_slice(&directory_offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out
+    }
+
+    const KICK: &[u8] = b"kick kick kick kick kick kick kick kick";
+
+    fn song() -> ZipLoader {
+        let archive = zip(&[
+            ("Song/Kick.WAV", KICK, true),
+            ("Song/snare.ogg", b"snare", false),
+            ("Song/", b"", false),
+        ]);
+        ZipLoader::new(Arc::from(archive)).unwrap()
+    }
+
+    #[test]
+    fn zip_reads_stored_and_deflated_entries() {
+        let loader = song();
+        assert_eq!(loader.file_names(), ["Song/Kick.WAV", "Song/snare.ogg"]);
+        assert_eq!(
+            load(
+                &loader,
+                &["Song/Kick.WAV", "./Song\\snare.ogg", "Song/kick.wav"]
+            ),
+            [Some(KICK.to_vec()), Some(b"snare".to_vec()), None]
+        );
+    }
+
+    #[test]
+    fn case_insensitive_resolves_case_and_extension() {
+        let zip = song();
+        let names = zip.file_names();
+        let loader = CaseInsensitive::new(zip, names);
+        assert_eq!(
+            load(
+                &loader,
+                &["song/kick.wav", "SONG/SNARE.wav", "song/hat.wav"]
+            ),
+            [Some(KICK.to_vec()), Some(b"snare".to_vec()), None]
+        );
+    }
+
+    #[test]
+    fn fallback_fills_in_missing_files() {
+        let extra = zip(&[
+            ("hat.wav", b"hat", false),
+            ("Song/Kick.WAV", b"other", false),
+        ]);
+        let loader = Fallback::new(song(), ZipLoader::new(Arc::from(extra)).unwrap());
+        assert_eq!(
+            load(&loader, &["hat.wav", "Song/Kick.WAV", "crash.wav"]),
+            [Some(b"hat".to_vec()), Some(KICK.to_vec()), None]
+        );
+    }
+
+    #[test]
+    fn truncated_and_corrupt_archives_are_rejected() {
+        let archive = zip(&[("Song/Kick.WAV", KICK, true)]);
+        assert!(ZipLoader::new(Arc::from(&archive[..archive.len() - 10])).is_err());
+        assert!(ZipLoader::new(Arc::from(&b"not a zip"[..])).is_err());
+
+        // A directory that points past the entries fails to index.
+        let mut moved = archive.clone();
+        let at = moved.len() - 6;
+        moved[at..at + 4].copy_from_slice(&1u32.to_le_bytes());
+        assert!(ZipLoader::new(Arc::from(moved)).is_err());
+
+        // Damaged compressed data leaves the entry missing instead of failing the batch.
+        let mut damaged = archive.clone();
+        let data_start = 30 + "Song/Kick.WAV".len();
+        damaged[data_start..data_start + 4].fill(0xFF);
+        let loader = ZipLoader::new(Arc::from(damaged)).unwrap();
+        assert_eq!(load(&loader, &["Song/Kick.WAV"]), [None]);
+    }
+}
@@ -1,4 +1,6 @@
-use crate::timeline::SoundEvent;
+use crate::audio::sustain_keysound;
+use crate::random::RandomSource;
+use crate::timeline::{LongNoteSpan, SoundEvent};
 use ahash::AHashMap;
 use rayon::prelude::*;
 use wide::f32x8;
@@ -6,6 +8,67 @@ use wide::f32x8;
 /// Chunk duration in seconds for parallel processing.
 const CHUNK_SIZE_SECONDS: usize = 1;
 
+/// Default chunk length in frames for a sample rate.
+///
+/// # Arguments
+///
+/// * `sample_rate` - Target sample rate.
+///
+/// # Returns
+///
+/// * `usize` - Frames per chunk (one second of audio).
+pub fn default_chunk_frames(sample_rate: u32) -> usize {
+    sample_rate as usize * CHUNK_SIZE_SECONDS
+}
+
+/// Storage type of decoded keysound samples.
+///
+/// `f32` keeps full precision, while `i16` halves the memory held by decoded
+/// keysounds at 16-bit resolution.
+pub trait Sample: Copy + Default + Send + Sync {
+    /// Convert to a float sample in `-1.0..=1.0`.
+    fn to_f32(self) -> f32;
+    /// Convert from a float sample, clipping values outside `-1.0..=1.0`.
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Sample for f32 {
+    #[inline]
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+impl Sample for i16 {
+    #[inline]
+    fn to_f32(self) -> f32 {
+        self as f32 / 32768.0
+    }
+
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        (value.clamp(-1.0, 1.0) * 32767.0).round() as i16
+    }
+}
+
+/// Convert decoded float samples to another storage type.
+///
+/// # Arguments
+///
+/// * `samples` - Decoded samples.
+///
+/// # Returns
+///
+/// * `Vec<S>` - Samples in the requested storage type.
+pub fn to_storage<S: Sample>(samples: Vec<f32>) -> Vec<S> {
+    samples.into_iter().map(S::from_f32).collect()
+}
+
 /// Reference to a scheduled sound event.
 #[derive(Clone)]
 pub struct EventRef {
@@ -15,6 +78,30 @@ pub struct EventRef {
     pub start: usize,
     /// Exclusive end position in the output buffer.
     pub end: usize,
+    /// Output positions (between `start` and `end`) where the source restarts from
+    /// its beginning, produced by `coalesce_retriggers`.
+    pub restarts: Vec<usize>,
+    /// Linear gain applied while mixing.
+    pub gain: f32,
+}
+
+impl EventRef {
+    /// Output ranges during which the source plays from offset 0.
+    ///
+    /// Each restart begins a new segment, so an uncoalesced event has exactly one.
+    ///
+    /// # Returns
+    ///
+    /// * `impl Iterator<Item = (usize, usize)>` - `(start, end)` pairs in output samples.
+    pub fn segments(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let seg_starts = std::iter::once(self.start).chain(self.restarts.iter().copied());
+        let seg_ends = self
+            .restarts
+            .iter()
+            .copied()
+            .chain(std::iter::once(self.end));
+        seg_starts.zip(seg_ends)
+    }
 }
 
 /// Result of pre-processing events for mixing.
@@ -25,6 +112,110 @@ pub struct Prepared {
     pub total_len: usize,
 }
 
+/// An event segment sounding inside an inspected time window.
+#[derive(Debug, Clone)]
+pub struct ActiveEvent {
+    /// Index of the audio source in decoded buffer.
+    pub key_id: usize,
+    /// Segment start in seconds.
+    pub start_sec: f64,
+    /// Segment end in seconds (exclusive).
+    pub end_sec: f64,
+    /// Frame of the source playing at the start of the window (or of the
+    /// segment, if it starts inside the window).
+    pub source_frame: usize,
+}
+
+impl Prepared {
+    /// List the event segments sounding between two times, without rendering.
+    ///
+    /// # Arguments
+    ///
+    /// * `start_sec` - Window start in seconds.
+    /// * `end_sec` - Window end in seconds (exclusive).
+    /// * `sample_rate` - Output sample rate.
+    /// * `channels` - Number of output channels.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<ActiveEvent>` - Overlapping segments ordered by start.
+    pub fn events_between(
+        &self,
+        start_sec: f64,
+        end_sec: f64,
+        sample_rate: u32,
+        channels: usize,
+    ) -> Vec<ActiveEvent> {
+        let samples_per_sec = sample_rate as f64 * channels as f64;
+        let to_sample =
+            |sec: f64| ((sec.max(0.0) * sample_rate as f64).round() as usize) * channels;
+        let (window_start, window_end) = (to_sample(start_sec), to_sample(end_sec));
+        let mut active = Vec::new();
+        for ev in &self.events {
+            if ev.start >= window_end {
+                break;
+            }
+            if ev.end <= window_start {
+                continue;
+            }
+            for (seg_start, seg_end) in ev.segments() {
+                if seg_start >= window_end || seg_end <= window_start {
+                    continue;
+                }
+                active.push(ActiveEvent {
+                    key_id: ev.key_id,
+                    start_sec: seg_start as f64 / samples_per_sec,
+                    end_sec: seg_end as f64 / samples_per_sec,
+                    source_frame: window_start.saturating_sub(seg_start) / channels,
+                });
+            }
+        }
+        active
+    }
+
+    /// Largest number of events sounding at the same time.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - Peak voice count (0 for an empty plan).
+    pub fn peak_voice_count(&self) -> usize {
+        // Zero-length events never sound; counting their ends would release other voices.
+        let sounding = || self.events.iter().filter(|ev| ev.end > ev.start);
+        let mut ends: Vec<usize> = sounding().map(|ev| ev.end).collect();
+        ends.sort_unstable();
+        let (mut active, mut peak, mut ended) = (0usize, 0usize, 0usize);
+        for ev in sounding() {
+            while ended < ends.len() && ends[ended] <= ev.start {
+                ended += 1;
+                active -= 1;
+            }
+            active += 1;
+            peak = peak.max(active);
+        }
+        peak
+    }
+
+    /// Scale every event so that the densest part of the plan stays near full scale.
+    ///
+    /// Uncorrelated sources add up in power rather than amplitude, so the gain
+    /// is `1 / sqrt(peak voices)`. Plans with a single voice are left untouched.
+    ///
+    /// # Returns
+    ///
+    /// * `f32` - Master gain that was applied.
+    pub fn apply_auto_gain(&mut self) -> f32 {
+        let voices = self.peak_voice_count();
+        if voices <= 1 {
+            return 1.0;
+        }
+        let gain = 1.0 / (voices as f32).sqrt();
+        for ev in &mut self.events {
+            ev.gain *= gain;
+        }
+        gain
+    }
+}
+
 /// Validate and arrange timeline events for mixing.
 ///
 /// # Arguments
@@ -36,9 +227,13 @@ pub struct Prepared {
 /// # Returns
 ///
 /// * `Prepared` - Result containing validated, sorted, non‑overlapping `EventRef`s for mixing and total output length.
-pub fn prepare_events(
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(events = sound_events.len()))
+)]
+pub fn prepare_events<S: Sample>(
     sound_events: &[SoundEvent],
-    decoded: &[(Vec<f32>, usize)],
+    decoded: &[(Vec<S>, usize)],
     channels: usize,
 ) -> Prepared {
     let mut pre_events: Vec<EventRef> = Vec::with_capacity(sound_events.len());
@@ -47,20 +242,23 @@ pub fn prepare_events(
         let kid = ev.key_id;
         let (_buf, frames) = &decoded[kid];
         let start_sample = ev.start;
+        // An explicit end only shortens the sound; past its natural end there is nothing to play.
         let natural_end = start_sample + (*frames) * channels;
-        let end_sample = ev.end.unwrap_or(natural_end);
+        let end_sample = ev.end.map_or(natural_end, |end| end.min(natural_end));
         if end_sample > start_sample {
             pre_events.push(EventRef {
                 key_id: kid,
                 start: start_sample,
                 end: end_sample,
+                restarts: Vec::new(),
+                gain: ev.gain,
             });
             if end_sample > total_len {
                 total_len = end_sample;
             }
         }
     }
-    pre_events.sort_by(|a, b| a.start.cmp(&b.start));
+    pre_events.sort_by_key(|a| a.start);
     let mut final_events: Vec<EventRef> = Vec::with_capacity(pre_events.len());
     let mut next_start_for_key: AHashMap<usize, usize> = AHashMap::new();
     next_start_for_key.reserve(pre_events.len());
@@ -77,6 +275,8 @@ pub fn prepare_events(
                 key_id: ev.key_id,
                 start: ev.start,
                 end: truncated_end,
+                restarts: Vec::new(),
+                gain: ev.gain,
             });
         }
     }
@@ -87,26 +287,255 @@ pub fn prepare_events(
     }
 }
 
+/// Replace the keysounds of long notes with sustained versions held until release.
+///
+/// Each distinct (keysound, hold length) pair is synthesized once and appended
+/// to `decoded`; the matching events are pointed at the new buffer.
+///
+/// # Arguments
+///
+/// * `sound_events` - Timeline events to update.
+/// * `decoded` - Decoded audio sources, extended with sustained buffers.
+/// * `spans` - Long notes of the chart.
+/// * `sample_rate` - Output sample rate.
+/// * `channels` - Number of output channels.
+///
+/// # Returns
+///
+/// * `usize` - Number of events that now use a sustained buffer.
+pub fn apply_long_note_sustain<S: Sample>(
+    sound_events: &mut [SoundEvent],
+    decoded: &mut Vec<(Vec<S>, usize)>,
+    spans: &[LongNoteSpan],
+    sample_rate: u32,
+    channels: usize,
+) -> usize {
+    let holds: AHashMap<(usize, usize), usize> = spans
+        .iter()
+        .map(|span| {
+            (
+                (span.key_id, span.start),
+                (span.end - span.start) / channels,
+            )
+        })
+        .collect();
+    let mut sustained: AHashMap<(usize, usize), Option<usize>> = AHashMap::new();
+    let mut count = 0;
+    for ev in sound_events.iter_mut() {
+        let Some(&hold_frames) = holds.get(&(ev.key_id, ev.start)) else {
+            continue;
+        };
+        let new_id = *sustained
+            .entry((ev.key_id, hold_frames))
+            .or_insert_with(|| {
+                let buf: Vec<f32> = decoded[ev.key_id].0.iter().map(|s| s.to_f32()).collect();
+                let (out, frames) = sustain_keysound(&buf, channels, sample_rate, hold_frames)?;
+                decoded.push((to_storage(out), frames));
+                Some(decoded.len() - 1)
+            });
+        if let Some(new_id) = new_id {
+            ev.key_id = new_id;
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Replace the keysounds of pitched events with resampled copies.
+///
+/// Each distinct (keysound, semitones) pair is resampled once, like a sampler
+/// playing the note faster or slower, and appended to `decoded`; the matching
+/// events are pointed at the new buffer and their offset is cleared.
+///
+/// # Arguments
+///
+/// * `sound_events` - Timeline events to update.
+/// * `decoded` - Decoded audio sources, extended with pitched buffers.
+/// * `channels` - Number of output channels.
+///
+/// # Returns
+///
+/// * `usize` - Number of events that now use a pitched buffer.
+pub fn apply_pitch_shifts<S: Sample>(
+    sound_events: &mut [SoundEvent],
+    decoded: &mut Vec<(Vec<S>, usize)>,
+    channels: usize,
+) -> usize {
+    let mut pitched: AHashMap<(usize, i8), usize> = AHashMap::new();
+    let mut count = 0;
+    for ev in sound_events.iter_mut() {
+        if ev.semitones == 0 {
+            continue;
+        }
+        let new_id = *pitched.entry((ev.key_id, ev.semitones)).or_insert_with(|| {
+            let (src, frames) = &decoded[ev.key_id];
+            let step = 2f64.powf(ev.semitones as f64 / 12.0);
+            let out_frames = if *frames == 0 {
+                0
+            } else {
+                ((*frames - 1) as f64 / step) as usize + 1
+            };
+            let mut out = Vec::with_capacity(out_frames * channels);
+            for i in 0..out_frames {
+                let pos = i as f64 * step;
+                let i0 = pos as usize;
+                let i1 = (i0 + 1).min(*frames - 1);
+                let t = (pos - i0 as f64) as f32;
+                for c in 0..channels {
+                    let a = src[i0 * channels + c].to_f32();
+                    let b = src[i1 * channels + c].to_f32();
+                    out.push(S::from_f32(a + (b - a) * t));
+                }
+            }
+            decoded.push((out, out_frames));
+            decoded.len() - 1
+        });
+        ev.key_id = new_id;
+        ev.semitones = 0;
+        count += 1;
+    }
+    count
+}
+
+/// Replace the keysounds of panned events with copies balanced between left and right.
+///
+/// Each distinct (keysound, pan) pair is rendered once and appended to
+/// `decoded`; the matching events are pointed at the new buffer and their pan
+/// is cleared. The side opposite the pan is attenuated linearly, so centered
+/// sounds keep their level. Non-stereo output is left untouched.
+///
+/// # Arguments
+///
+/// * `sound_events` - Timeline events to update.
+/// * `decoded` - Decoded audio sources, extended with panned buffers.
+/// * `channels` - Number of output channels.
+///
+/// # Returns
+///
+/// * `usize` - Number of events that now use a panned buffer.
+pub fn apply_pans<S: Sample>(
+    sound_events: &mut [SoundEvent],
+    decoded: &mut Vec<(Vec<S>, usize)>,
+    channels: usize,
+) -> usize {
+    if channels != 2 {
+        return 0;
+    }
+    let mut panned: AHashMap<(usize, i8), usize> = AHashMap::new();
+    let mut count = 0;
+    for ev in sound_events.iter_mut() {
+        if ev.pan == 0 {
+            continue;
+        }
+        let new_id = *panned.entry((ev.key_id, ev.pan)).or_insert_with(|| {
+            let pan = ev.pan as f32 / 100.0;
+            let gains = [(1.0 - pan).min(1.0), (1.0 + pan).min(1.0)];
+            let (src, frames) = &decoded[ev.key_id];
+            let out: Vec<S> = src
+                .as_chunks::<2>()
+                .0
+                .iter()
+                .flat_map(|frame| {
+                    [
+                        S::from_f32(frame[0].to_f32() * gains[0]),
+                        S::from_f32(frame[1].to_f32() * gains[1]),
+                    ]
+                })
+                .collect();
+            let frames = *frames;
+            decoded.push((out, frames));
+            decoded.len() - 1
+        });
+        ev.key_id = new_id;
+        ev.pan = 0;
+        count += 1;
+    }
+    count
+}
+
+/// Offset each event start by a random amount to make renders less mechanical.
+///
+/// Offsets are uniform in `-max_frames..=max_frames` and drawn from `rng` in
+/// event order, so a seeded source always gives the same render.
+///
+/// # Arguments
+///
+/// * `sound_events` - Timeline events to shift.
+/// * `max_frames` - Largest offset in frames.
+/// * `channels` - Number of output channels.
+/// * `rng` - Source of the offsets.
+pub fn apply_timing_jitter(
+    sound_events: &mut [SoundEvent],
+    max_frames: usize,
+    channels: usize,
+    rng: &mut dyn RandomSource,
+) {
+    if max_frames == 0 {
+        return;
+    }
+    let span = 2 * max_frames as u64 + 1;
+    for ev in sound_events.iter_mut() {
+        let offset = (rng.below(span) as isize - max_frames as isize) * channels as isize;
+        ev.start = ev.start.saturating_add_signed(offset);
+        ev.end = ev.end.map(|end| end.saturating_add_signed(offset));
+    }
+}
+
+/// Merge rapid retriggers of the same source into single events with internal restarts.
+///
+/// Rolls of a short sample otherwise produce one truncated event per note; merging
+/// them keeps the output identical while cutting bucket and overlap-slice counts.
+///
+/// # Arguments
+///
+/// * `events` - Prepared events, sorted by start.
+/// * `max_gap` - Maximum distance (in interleaved samples) between two triggers
+///   of the same source for them to be merged.
+///
+/// # Returns
+///
+/// * `Vec<EventRef>` - Coalesced events, still sorted by start.
+pub fn coalesce_retriggers(events: Vec<EventRef>, max_gap: usize) -> Vec<EventRef> {
+    let mut out: Vec<EventRef> = Vec::with_capacity(events.len());
+    // Index in `out` of the latest event per key, and the position of its last trigger.
+    let mut last_for_key: AHashMap<usize, (usize, usize)> = AHashMap::new();
+    for ev in events {
+        if let Some(&(idx, last_trigger)) = last_for_key.get(&ev.key_id)
+            && ev.start - last_trigger <= max_gap
+            && out[idx].gain == ev.gain
+        {
+            let merged = &mut out[idx];
+            merged.restarts.push(ev.start);
+            merged.end = merged.end.max(ev.end);
+            last_for_key.insert(ev.key_id, (idx, ev.start));
+            continue;
+        }
+        last_for_key.insert(ev.key_id, (out.len(), ev.start));
+        out.push(ev);
+    }
+    out
+}
+
 /// Group event indices into fixed-size time buckets ("chunks").
 ///
 /// # Arguments
 ///
 /// * `events` - Events to group.
 /// * `total_len` - Total output length.
-/// * `sample_rate` - Target sample rate.
+/// * `chunk_frames` - Chunk length in frames (see `default_chunk_frames`).
 /// * `channels` - Number of output channels.
 ///
 /// # Returns
 ///
 /// * `(chunk_count, buckets)` where `buckets[c]` contains indices of events
-///   that intersect chunk `c`. Chunk size is 1 second of samples.
+///   that intersect chunk `c`.
 pub fn bucketize_events(
     events: &[EventRef],
     total_len: usize,
-    sample_rate: u32,
+    chunk_frames: usize,
     channels: usize,
 ) -> (usize, Vec<Vec<usize>>) {
-    let chunk_samples = sample_rate as usize * channels * CHUNK_SIZE_SECONDS;
+    let chunk_samples = chunk_frames * channels;
     let chunk_count = total_len.div_ceil(chunk_samples);
     let mut buckets: Vec<Vec<usize>> = vec![Vec::new(); chunk_count];
     for (idx, ev) in events.iter().enumerate() {
@@ -144,21 +573,25 @@ pub struct OverlapSlice {
 /// * `decoded` - Decoded audio sources.
 /// * `bucketed` - Events grouped into chunks.
 /// * `total_len` - Total output length.
-/// * `sample_rate` - Target sample rate.
+/// * `chunk_frames` - Chunk length in frames, as passed to `bucketize_events`.
 /// * `channels` - Number of output channels.
 ///
 /// # Returns
 ///
 /// * `Vec<Vec<OverlapSlice>>` - Overlap slices for each chunk.
-pub fn precompute_overlaps(
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(chunks = bucketed.len()))
+)]
+pub fn precompute_overlaps<S: Sample>(
     events: &[EventRef],
-    decoded: &[(Vec<f32>, usize)],
+    decoded: &[(Vec<S>, usize)],
     bucketed: &[Vec<usize>],
     total_len: usize,
-    sample_rate: u32,
+    chunk_frames: usize,
     channels: usize,
 ) -> Vec<Vec<OverlapSlice>> {
-    let chunk_samples = sample_rate as usize * channels * CHUNK_SIZE_SECONDS;
+    let chunk_samples = chunk_frames * channels;
     let chunk_count = bucketed.len();
 
     let src_lens: Vec<usize> = decoded.iter().map(|(v, _)| v.len()).collect();
@@ -172,21 +605,26 @@ pub fn precompute_overlaps(
                 let ev = &events[ev_idx];
                 let src_len = src_lens[ev.key_id];
 
-                let overlap_start = std::cmp::max(start, ev.start);
-                let sample_end = ev.start + src_len;
-                let overlap_end = std::cmp::min(std::cmp::min(end, ev.end), sample_end);
-                if overlap_start >= overlap_end {
-                    continue;
+                for (seg_start, seg_end) in ev.segments() {
+                    if seg_end <= start || seg_start >= end {
+                        continue;
+                    }
+                    let overlap_start = std::cmp::max(start, seg_start);
+                    let sample_end = seg_start + src_len;
+                    let overlap_end = std::cmp::min(std::cmp::min(end, seg_end), sample_end);
+                    if overlap_start >= overlap_end {
+                        continue;
+                    }
+                    let src_off = overlap_start - seg_start;
+                    let dst_off = overlap_start - start;
+                    let overlap_len = overlap_end - overlap_start;
+                    slices.push(OverlapSlice {
+                        ev_idx,
+                        src_off,
+                        dst_off,
+                        len: overlap_len,
+                    });
                 }
-                let src_off = overlap_start - ev.start;
-                let dst_off = overlap_start - start;
-                let overlap_len = overlap_end - overlap_start;
-                slices.push(OverlapSlice {
-                    ev_idx,
-                    src_off,
-                    dst_off,
-                    len: overlap_len,
-                });
             }
             slices
         })
@@ -202,22 +640,26 @@ pub fn precompute_overlaps(
 /// * `decoded` - Decoded audio sources.
 /// * `precomputed` - Overlap slices for each chunk.
 /// * `total_len` - Total output length.
-/// * `sample_rate` - Target sample rate.
+/// * `chunk_frames` - Chunk length in frames, as passed to `bucketize_events`.
 /// * `channels` - Number of output channels.
 ///
 /// # Returns
 ///
 /// * `Vec<f32>` - Mixed chunk.
-pub fn mix_chunk(
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "trace", skip_all, fields(chunk = ci))
+)]
+pub fn mix_chunk<S: Sample>(
     ci: usize,
     events: &[EventRef],
-    decoded: &[(Vec<f32>, usize)],
+    decoded: &[(Vec<S>, usize)],
     precomputed: &[Vec<OverlapSlice>],
     total_len: usize,
-    sample_rate: u32,
+    chunk_frames: usize,
     channels: usize,
 ) -> Vec<f32> {
-    let chunk_samples = sample_rate as usize * channels * CHUNK_SIZE_SECONDS;
+    let chunk_samples = chunk_frames * channels;
     let start = ci * chunk_samples;
     let end = std::cmp::min(start + chunk_samples, total_len);
     let mut buf = vec![0.0f32; end - start];
@@ -229,11 +671,13 @@ pub fn mix_chunk(
 
         let n = sl.len;
         let n8 = n & !7;
+        let gain8 = f32x8::splat(ev.gain);
 
         for i in (0..n8).step_by(8) {
             let d = f32x8::from(&dst_slice[i..i + 8]);
-            let s = f32x8::from(&src_slice[i..i + 8]);
-            let r = d + s;
+            let src8 = &src_slice[i..i + 8];
+            let s = f32x8::from(std::array::from_fn::<f32, 8, _>(|k| src8[k].to_f32()));
+            let r = d + s * gain8;
 
             let result: [f32; 8] = r.into();
             dst_slice[i..i + 8].copy_from_slice(&result);
@@ -241,8 +685,370 @@ pub fn mix_chunk(
 
         // Scalar path: process remaining samples
         for i in n8..n {
-            dst_slice[i] += src_slice[i];
+            dst_slice[i] += src_slice[i].to_f32() * ev.gain;
         }
     }
     buf
 }
+
+/// Signal levels of a mixed chunk.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkLevels {
+    /// Largest absolute sample value.
+    pub peak: f32,
+    /// Root mean square over all interleaved samples.
+    pub rms: f32,
+}
+
+/// Measure peak and RMS levels of a mixed chunk.
+///
+/// # Arguments
+///
+/// * `samples` - Interleaved samples of the chunk.
+///
+/// # Returns
+///
+/// * `ChunkLevels` - Peak and RMS levels (both 0.0 for an empty chunk).
+pub fn measure_levels(samples: &[f32]) -> ChunkLevels {
+    if samples.is_empty() {
+        return ChunkLevels {
+            peak: 0.0,
+            rms: 0.0,
+        };
+    }
+    let n = samples.len();
+    let n8 = n & !7;
+    let mut peak8 = f32x8::splat(0.0);
+    let mut sq8 = f32x8::splat(0.0);
+    for i in (0..n8).step_by(8) {
+        let v = f32x8::from(&samples[i..i + 8]);
+        peak8 = peak8.max(v.abs());
+        sq8 += v * v;
+    }
+    let peaks: [f32; 8] = peak8.into();
+    let mut peak = peaks.iter().fold(0.0f32, |a, &b| a.max(b));
+    let mut sum_sq = sq8.reduce_add() as f64;
+    for &v in &samples[n8..] {
+        peak = peak.max(v.abs());
+        sum_sq += (v * v) as f64;
+    }
+    ChunkLevels {
+        peak,
+        rms: (sum_sq / n as f64).sqrt() as f32,
+    }
+}
+
+/// Mix an arbitrary output range directly from the event list.
+///
+/// Slower than the chunked path for full renders, but needs no precomputation,
+/// which suits analysis passes and on-demand previews of short windows.
+///
+/// # Arguments
+///
+/// * `events` - Prepared events.
+/// * `decoded` - Decoded audio sources.
+/// * `start` - First output sample of the range.
+/// * `len` - Number of interleaved samples to mix.
+///
+/// # Returns
+///
+/// * `Vec<f32>` - Mixed samples of the range.
+pub fn mix_range<S: Sample>(
+    events: &[EventRef],
+    decoded: &[(Vec<S>, usize)],
+    start: usize,
+    len: usize,
+) -> Vec<f32> {
+    let end = start + len;
+    let mut buf = vec![0.0f32; len];
+    for ev in events {
+        if ev.end <= start || ev.start >= end {
+            continue;
+        }
+        let src = &decoded[ev.key_id].0;
+        for (seg_start, seg_end) in ev.segments() {
+            let overlap_start = start.max(seg_start);
+            let overlap_end = end.min(seg_end).min(seg_start + src.len());
+            if overlap_start >= overlap_end {
+                continue;
+            }
+            let src_slice = &src[overlap_start - seg_start..overlap_end - seg_start];
+            let dst_slice = &mut buf[overlap_start - start..overlap_end - start];
+            for (d, s) in dst_slice.iter_mut().zip(src_slice) {
+                *d += s.to_f32() * ev.gain;
+            }
+        }
+    }
+    buf
+}
+
+/// Choose the keysounds to leave out of the decoded table so the rest fits a memory budget.
+///
+/// Keysounds are kept resident in order of use, most-used first and smaller
+/// files first among equals; any that no longer fit are spilled and must be
+/// decoded again whenever they are played.
+///
+/// # Arguments
+///
+/// * `candidates` - `(key id, decoded bytes, event count)` of each keysound that may be spilled.
+/// * `budget` - Bytes available to the candidates.
+///
+/// # Returns
+///
+/// * `Vec<usize>` - Key ids to spill, sorted.
+pub fn select_spilled(candidates: &[(usize, u64, usize)], budget: u64) -> Vec<usize> {
+    let mut ranked = candidates.to_vec();
+    ranked.sort_unstable_by_key(|&(id, bytes, uses)| (std::cmp::Reverse(uses), bytes, id));
+    let mut used = 0u64;
+    let mut spilled: Vec<usize> = ranked
+        .into_iter()
+        .filter_map(|(id, bytes, _)| {
+            if used + bytes <= budget {
+                used += bytes;
+                None
+            } else {
+                Some(id)
+            }
+        })
+        .collect();
+    spilled.sort_unstable();
+    spilled
+}
+
+/// Find slices of one stem that play back to back, such as a song cut into BGM pieces.
+///
+/// An event continues a run when it starts within `tolerance` frames of the
+/// run start plus the length of its slices so far, plays unmodified (no cut,
+/// pitch or pan) at the same gain, and no other event of the stem starts in
+/// between. Measuring from the run start keeps every slice within
+/// `tolerance` of where the joined audio plays it, however long the run.
+///
+/// # Arguments
+///
+/// * `sound_events` - Timeline events.
+/// * `lengths` - Decoded length of each keysound, in frames.
+/// * `channels` - Number of output channels.
+/// * `tolerance` - Largest gap or overlap between two slices, in frames.
+/// * `stem_of` - Stem of an event, or `None` for events that never join a run.
+///
+/// # Returns
+///
+/// * `Vec<Vec<usize>>` - Indices into `sound_events` of each run of two or more slices, in order.
+pub fn find_gapless_runs<K: Copy + Eq + std::hash::Hash>(
+    sound_events: &[SoundEvent],
+    lengths: &[usize],
+    channels: usize,
+    tolerance: usize,
+    stem_of: impl Fn(&SoundEvent) -> Option<K>,
+) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..sound_events.len()).collect();
+    order.sort_by_key(|&i| sound_events[i].start);
+
+    let mut runs: Vec<Vec<usize>> = Vec::new();
+    // Open run of each stem and where its joined audio ends.
+    let mut open: AHashMap<K, (Vec<usize>, usize)> = AHashMap::new();
+    let mut close = |run: &mut Vec<usize>| {
+        if run.len() >= 2 {
+            runs.push(std::mem::take(run));
+        }
+        run.clear();
+    };
+    for i in order {
+        let ev = &sound_events[i];
+        let Some(stem) = stem_of(ev) else {
+            continue;
+        };
+        let (run, joined_end) = open.entry(stem).or_default();
+        let length = lengths.get(ev.key_id).copied().unwrap_or(0);
+        if ev.end.is_some() || ev.semitones != 0 || ev.pan != 0 || length == 0 {
+            // Other sounds of the stem break the run.
+            close(run);
+            continue;
+        }
+        let continues = run.first().is_some_and(|&first| {
+            sound_events[first].gain == ev.gain
+                && ev.start.abs_diff(*joined_end) <= tolerance * channels
+        });
+        if !continues {
+            close(run);
+            *joined_end = ev.start;
+        }
+        run.push(i);
+        *joined_end += length * channels;
+    }
+    for (run, _) in open.values_mut() {
+        close(run);
+    }
+    runs.sort_by_key(|run| sound_events[run[0]].start);
+    runs
+}
+
+/// Replace runs of back-to-back slices with one event playing their joined audio.
+///
+/// Each joined buffer is appended to `decoded`; the first event of its run
+/// is pointed at it and the other events of the run are removed.
+///
+/// # Arguments
+///
+/// * `sound_events` - Timeline events to update.
+/// * `decoded` - Decoded audio sources, extended with joined buffers.
+/// * `joined` - Indices into `sound_events` of each run, with its joined samples.
+/// * `channels` - Number of output channels.
+///
+/// # Returns
+///
+/// * `usize` - Number of events removed.
+pub fn join_gapless_runs<S: Sample>(
+    sound_events: &mut Vec<SoundEvent>,
+    decoded: &mut Vec<(Vec<S>, usize)>,
+    joined: Vec<(Vec<usize>, Vec<S>)>,
+    channels: usize,
+) -> usize {
+    let mut removed: Vec<bool> = vec![false; sound_events.len()];
+    for (run, samples) in joined {
+        let frames = samples.len() / channels;
+        decoded.push((samples, frames));
+        sound_events[run[0]].key_id = decoded.len() - 1;
+        for &i in &run[1..] {
+            removed[i] = true;
+        }
+    }
+    let before = sound_events.len();
+    let mut flags = removed.into_iter();
+    sound_events.retain(|_| !flags.next().unwrap_or(false));
+    before - sound_events.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bms::Channel;
+
+    /// An unmodified BGM event.
+    fn event(key_id: usize, start: usize) -> SoundEvent {
+        SoundEvent {
+            key_id,
+            start,
+            end: None,
+            gain: 1.0,
+            channel: Channel::Bgm,
+            semitones: 0,
+            pan: 0,
+            bgm_lane: 0,
+        }
+    }
+
+    fn runs(events: &[SoundEvent], lengths: &[usize], tolerance: usize) -> Vec<Vec<usize>> {
+        find_gapless_runs(events, lengths, 1, tolerance, |ev| Some(ev.bgm_lane))
+    }
+
+    fn event_ref(start: usize, end: usize) -> EventRef {
+        EventRef {
+            key_id: 0,
+            start,
+            end,
+            restarts: Vec::new(),
+            gain: 1.0,
+        }
+    }
+
+    #[test]
+    fn zero_length_events_are_not_voices() {
+        let prepared = Prepared {
+            events: vec![
+                event_ref(0, 0),
+                event_ref(0, 10),
+                event_ref(5, 5),
+                event_ref(5, 8),
+            ],
+            total_len: 10,
+        };
+        assert_eq!(prepared.peak_voice_count(), 2);
+        let empty = Prepared {
+            events: vec![event_ref(3, 3)],
+            total_len: 3,
+        };
+        assert_eq!(empty.peak_voice_count(), 0);
+    }
+
+    #[test]
+    fn window_includes_its_start_and_excludes_its_end() {
+        let mut ringing = event_ref(20, 60);
+        ringing.restarts = vec![40];
+        let prepared = Prepared {
+            events: vec![
+                event_ref(0, 10),
+                event_ref(10, 20),
+                ringing,
+                event_ref(30, 31),
+            ],
+            total_len: 60,
+        };
+        // 10 samples per second: the window covers samples 10..30.
+        let active = prepared.events_between(1.0, 3.0, 10, 1);
+        let found: Vec<(f64, f64, usize)> = active
+            .iter()
+            .map(|ev| (ev.start_sec, ev.end_sec, ev.source_frame))
+            .collect();
+        assert_eq!(found, [(1.0, 2.0, 0), (2.0, 4.0, 0)]);
+        let later = prepared.events_between(3.5, 4.5, 10, 1);
+        let found: Vec<(f64, f64, usize)> = later
+            .iter()
+            .map(|ev| (ev.start_sec, ev.end_sec, ev.source_frame))
+            .collect();
+        assert_eq!(found, [(2.0, 4.0, 15), (4.0, 6.0, 0)]);
+    }
+
+    #[test]
+    fn event_ends_only_shorten_sounds() {
+        let decoded: Vec<(Vec<f32>, usize)> = vec![(vec![0.5; 100], 100)];
+        let mut events = vec![event(0, 0), event(0, 200), event(0, 400)];
+        events[0].end = Some(50);
+        events[1].end = Some(1000);
+        let prepared = prepare_events(&events, &decoded, 1);
+        let spans: Vec<(usize, usize)> = prepared
+            .events
+            .iter()
+            .map(|ev| (ev.start, ev.end))
+            .collect();
+        assert_eq!(spans, [(0, 50), (200, 300), (400, 500)]);
+        assert_eq!(prepared.total_len, 500);
+    }
+
+    #[test]
+    fn back_to_back_slices_form_a_run() {
+        let events = [event(0, 0), event(1, 100), event(2, 202), event(0, 500)];
+        assert_eq!(runs(&events, &[100, 100, 100], 2), [vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn modified_events_and_other_stems_break_runs() {
+        let mut events = vec![event(0, 0), event(1, 100), event(0, 200), event(1, 300)];
+        events[2].pan = 50;
+        assert_eq!(runs(&events, &[100, 100], 0), [vec![0, 1]]);
+        // A slice on another BGM lane neither joins nor breaks the run.
+        events[2].pan = 0;
+        events[2].bgm_lane = 1;
+        events[3].start = 200;
+        assert_eq!(runs(&events, &[100, 100], 0), [vec![0, 1, 3]]);
+    }
+
+    #[test]
+    fn slices_are_checked_against_the_run_start() {
+        // Each slice starts 2 frames after the previous one ends, which is
+        // within tolerance step by step but drifts from the joined audio.
+        let events: Vec<SoundEvent> = (0..5).map(|i| event(0, i * 102)).collect();
+        assert_eq!(runs(&events, &[100], 4), [vec![0, 1, 2], vec![3, 4]]);
+    }
+
+    #[test]
+    fn joined_runs_replace_their_slices() {
+        let mut events = vec![event(0, 0), event(1, 2), event(0, 4), event(1, 10)];
+        let mut decoded: Vec<(Vec<f32>, usize)> = vec![(vec![0.5; 2], 2), (vec![0.25; 2], 2)];
+        let joined = vec![(vec![0, 1, 2], vec![0.5, 0.5, 0.25, 0.25, 0.5, 0.5])];
+        assert_eq!(join_gapless_runs(&mut events, &mut decoded, joined, 1), 2);
+        let left: Vec<(usize, usize)> = events.iter().map(|ev| (ev.key_id, ev.start)).collect();
+        assert_eq!(left, [(2, 0), (1, 10)]);
+        assert_eq!(decoded[2].1, 6);
+    }
+}
@@ -0,0 +1,139 @@
+use wide::f32x8;
+
+/// Sample encoding of PCM data written to a WAV `data` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcmFormat {
+    /// 8-bit unsigned integer (offset binary, silence = 128).
+    U8,
+    /// 16-bit signed integer.
+    I16,
+    /// 24-bit signed integer, packed in 3 bytes.
+    I24,
+    /// 32-bit signed integer.
+    I32,
+    /// 32-bit IEEE float.
+    F32,
+}
+
+impl PcmFormat {
+    /// Select the format for a bit depth and integer/float choice.
+    ///
+    /// # Arguments
+    ///
+    /// * `bits_per_sample` - Bits per sample (8, 16, 24 or 32).
+    /// * `float` - Whether floating point samples are requested.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<PcmFormat>` - Matching format, or `None` for unsupported combinations.
+    pub fn from_bits(bits_per_sample: u16, float: bool) -> Option<Self> {
+        match (bits_per_sample, float) {
+            (8, false) => Some(PcmFormat::U8),
+            (16, false) => Some(PcmFormat::I16),
+            (24, false) => Some(PcmFormat::I24),
+            (32, false) => Some(PcmFormat::I32),
+            (32, true) => Some(PcmFormat::F32),
+            _ => None,
+        }
+    }
+
+    /// Bits per sample written to the `fmt ` chunk.
+    pub fn bits_per_sample(self) -> u16 {
+        match self {
+            PcmFormat::U8 => 8,
+            PcmFormat::I16 => 16,
+            PcmFormat::I24 => 24,
+            PcmFormat::I32 | PcmFormat::F32 => 32,
+        }
+    }
+
+    /// Size of one encoded sample in bytes.
+    pub fn bytes_per_sample(self) -> usize {
+        self.bits_per_sample() as usize / 8
+    }
+
+    /// WAV format tag (1 = integer PCM, 3 = IEEE float).
+    pub fn format_tag(self) -> u16 {
+        match self {
+            PcmFormat::F32 => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// Scale, round and clamp 8 samples to the integer range `[min, max]`.
+#[inline]
+fn quantize8(samples: &[f32], scale: f32, min: f32, max: f32) -> [f32; 8] {
+    let v = f32x8::from(samples);
+    let q = (v * f32x8::splat(scale)).round();
+    q.max(f32x8::splat(min)).min(f32x8::splat(max)).into()
+}
+
+/// Scalar counterpart of `quantize8` for the tail of a buffer.
+#[inline]
+fn quantize1(sample: f32, scale: f32, min: f32, max: f32) -> f32 {
+    (sample * scale).round().clamp(min, max)
+}
+
+/// Quantize samples to integers and append each one with `push`.
+#[inline]
+fn encode_int(
+    samples: &[f32],
+    scale: f32,
+    min: f32,
+    max: f32,
+    out: &mut Vec<u8>,
+    push: impl Fn(&mut Vec<u8>, f32),
+) {
+    let n8 = samples.len() & !7;
+    for block in samples[..n8].as_chunks::<8>().0 {
+        for q in quantize8(block, scale, min, max) {
+            push(out, q);
+        }
+    }
+    for &s in &samples[n8..] {
+        push(out, quantize1(s, scale, min, max));
+    }
+}
+
+/// Encode interleaved float samples into little-endian PCM bytes.
+///
+/// # Arguments
+///
+/// * `samples` - Interleaved samples in `-1.0..=1.0`; values outside are clipped.
+/// * `format` - Target sample encoding.
+/// * `out` - Output buffer, cleared before writing.
+pub fn encode_samples(samples: &[f32], format: PcmFormat, out: &mut Vec<u8>) {
+    out.clear();
+    out.reserve(samples.len() * format.bytes_per_sample());
+    match format {
+        PcmFormat::U8 => encode_int(samples, 127.0, -128.0, 127.0, out, |out, q| {
+            out.push((q as i16 + 128) as u8)
+        }),
+        PcmFormat::I16 => encode_int(
+            samples,
+            i16::MAX as f32,
+            i16::MIN as f32,
+            i16::MAX as f32,
+            out,
+            |out, q| out.extend_from_slice(&(q as i16).to_le_bytes()),
+        ),
+        PcmFormat::I24 => {
+            const MAX: f32 = 8_388_607.0;
+            encode_int(samples, MAX, -MAX - 1.0, MAX, out, |out, q| {
+                out.extend_from_slice(&(q as i32).to_le_bytes()[..3])
+            })
+        }
+        // i32::MAX is not representable in f32, so the upper bound is the
+        // largest float below 2^31.
+        PcmFormat::I32 => encode_int(
+            samples,
+            2_147_483_648.0,
+            i32::MIN as f32,
+            2_147_483_520.0,
+            out,
+            |out, q| out.extend_from_slice(&(q as i32).to_le_bytes()),
+        ),
+        PcmFormat::F32 => out.extend_from_slice(bytemuck::cast_slice(samples)),
+    }
+}
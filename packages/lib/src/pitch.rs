@@ -0,0 +1,176 @@
+use serde::Serialize;
+
+/// Lowest fundamental frequency searched for, in Hz.
+const MIN_FREQUENCY: f32 = 50.0;
+
+/// Highest fundamental frequency searched for, in Hz.
+const MAX_FREQUENCY: f32 = 2000.0;
+
+/// Cumulative mean normalized difference below which a period is accepted.
+const YIN_THRESHOLD: f32 = 0.15;
+
+/// Time skipped at the start of a keysound so the attack transient is not analyzed.
+const ATTACK_SEC: f32 = 0.02;
+
+/// RMS level under which the analysis window is treated as silence.
+const SILENCE_RMS: f32 = 1e-4;
+
+/// Estimated fundamental pitch of a keysound.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PitchEstimate {
+    /// Fundamental frequency in Hz.
+    pub frequency_hz: f32,
+    /// Nearest MIDI note number (69 = A4).
+    pub midi_note: u8,
+    /// Offset from the nearest note in cents (-50.0..50.0).
+    pub cents: f32,
+    /// How periodic the sound is (0.0..1.0); drums and noise score low.
+    pub confidence: f32,
+}
+
+/// Convert a frequency to the nearest MIDI note and its offset in cents.
+///
+/// # Arguments
+///
+/// * `frequency_hz` - Frequency to convert.
+///
+/// # Returns
+///
+/// * `(u8, f32)` - MIDI note (clamped to 0-127) and offset in cents.
+pub fn frequency_to_midi(frequency_hz: f32) -> (u8, f32) {
+    let midi = 69.0 + 12.0 * (frequency_hz / 440.0).log2();
+    let note = midi.round();
+    (note.clamp(0.0, 127.0) as u8, (midi - note) * 100.0)
+}
+
+/// Estimate the pitch of a keysound with the YIN algorithm.
+///
+/// A single window just after the attack is analyzed, which is where most
+/// keysounds are at their most stable.
+///
+/// # Arguments
+///
+/// * `samples` - Interleaved audio samples.
+/// * `channels` - Number of interleaved channels.
+/// * `sample_rate` - Sample rate of `samples`.
+///
+/// # Returns
+///
+/// * `Option<PitchEstimate>` - Estimated pitch, or `None` for silent, too short or unpitched sounds.
+pub fn detect_pitch(samples: &[f32], channels: usize, sample_rate: u32) -> Option<PitchEstimate> {
+    if channels == 0 || sample_rate == 0 {
+        return None;
+    }
+    let sr = sample_rate as f32;
+    let min_tau = ((sr / MAX_FREQUENCY) as usize).max(2);
+    let max_tau = (sr / MIN_FREQUENCY) as usize;
+    let window = max_tau * 2;
+
+    let mono: Vec<f32> = samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    let needed = window + max_tau;
+    let start = if mono.len() >= (sr * ATTACK_SEC) as usize + needed {
+        (sr * ATTACK_SEC) as usize
+    } else if mono.len() >= needed {
+        0
+    } else {
+        return None;
+    };
+    let x = &mono[start..start + needed];
+
+    let rms = (x[..window].iter().map(|s| s * s).sum::<f32>() / window as f32).sqrt();
+    if rms < SILENCE_RMS {
+        return None;
+    }
+
+    // Cumulative mean normalized difference function.
+    let mut cmnd = vec![1.0f32; max_tau + 1];
+    let mut running_sum = 0.0f32;
+    for tau in 1..=max_tau {
+        let diff: f32 = x[..window]
+            .iter()
+            .zip(&x[tau..tau + window])
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum();
+        running_sum += diff;
+        cmnd[tau] = if running_sum > 0.0 {
+            diff * tau as f32 / running_sum
+        } else {
+            1.0
+        };
+    }
+
+    let mut tau = (min_tau..=max_tau).find(|&t| cmnd[t] < YIN_THRESHOLD)?;
+    while tau < max_tau && cmnd[tau + 1] < cmnd[tau] {
+        tau += 1;
+    }
+
+    // Parabolic interpolation around the minimum for sub-sample accuracy.
+    let refined = if tau > 1 && tau < max_tau {
+        let (a, b, c) = (cmnd[tau - 1], cmnd[tau], cmnd[tau + 1]);
+        let denom = a - 2.0 * b + c;
+        if denom.abs() > f32::EPSILON {
+            tau as f32 + 0.5 * (a - c) / denom
+        } else {
+            tau as f32
+        }
+    } else {
+        tau as f32
+    };
+
+    let frequency_hz = sr / refined;
+    let (midi_note, cents) = frequency_to_midi(frequency_hz);
+    Some(PitchEstimate {
+        frequency_hz,
+        midi_note,
+        cents,
+        confidence: (1.0 - cmnd[tau]).clamp(0.0, 1.0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::{RandomSource, SplitMix64};
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    /// A fifth of a second of a stereo sine.
+    fn sine(frequency_hz: f32) -> Vec<f32> {
+        (0..SAMPLE_RATE as usize / 5)
+            .map(|i| (std::f32::consts::TAU * frequency_hz * i as f32 / SAMPLE_RATE as f32).sin())
+            .flat_map(|v| [v * 0.5, v * 0.5])
+            .collect()
+    }
+
+    #[test]
+    fn sines_are_found_at_their_frequency() {
+        for (frequency_hz, midi_note) in [(220.0, 57), (440.0, 69), (1000.0, 83)] {
+            let estimate = detect_pitch(&sine(frequency_hz), 2, SAMPLE_RATE).unwrap();
+            assert!(
+                (estimate.frequency_hz - frequency_hz).abs() < frequency_hz * 0.005,
+                "{frequency_hz} Hz detected as {}",
+                estimate.frequency_hz
+            );
+            assert_eq!(estimate.midi_note, midi_note);
+            assert!(estimate.confidence > 0.9);
+        }
+    }
+
+    #[test]
+    fn silence_and_noise_have_no_pitch() {
+        let silence = vec![0.0f32; SAMPLE_RATE as usize / 5];
+        assert!(detect_pitch(&silence, 1, SAMPLE_RATE).is_none());
+
+        let mut rng = SplitMix64::new(7);
+        let noise: Vec<f32> = (0..SAMPLE_RATE as usize / 5)
+            .map(|_| (rng.next_u64() >> 40) as f32 / (1u64 << 23) as f32 - 1.0)
+            .collect();
+        assert!(detect_pitch(&noise, 1, SAMPLE_RATE).is_none());
+
+        // Too short to hold two periods of the lowest frequency.
+        assert!(detect_pitch(&sine(440.0)[..200], 2, SAMPLE_RATE).is_none());
+    }
+}
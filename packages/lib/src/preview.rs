@@ -0,0 +1,327 @@
+use crate::audio::{ResampleMethod, decode_audio};
+use crate::bms::{Bms, MeasureIndex};
+use crate::mixer::{EventRef, mix_range, prepare_events};
+use crate::timeline::{SoundEvent, TempoMap, build_tempo_map, extract_sound_events};
+use ahash::AHashMap;
+use std::sync::Arc;
+
+/// On-demand renderer for auditioning parts of a chart.
+///
+/// Decoded keysounds are cached by filename and survive `set_chart`, so an
+/// editor can re-render a measure after every edit without decoding again.
+pub struct MeasureRenderer {
+    sample_rate: u32,
+    channels: usize,
+    /// Decoded audio per cached file; `(Vec::new(), 0)` until it is inserted.
+    decoded: Vec<(Vec<f32>, usize)>,
+    /// Mapping from filename to its index in `decoded`.
+    filename_to_id: AHashMap<String, usize>,
+    bms: Bms,
+    tempo_map: TempoMap,
+    /// Scheduled events, including those of keysounds not decoded yet.
+    sound_events: Vec<SoundEvent>,
+    events: Vec<EventRef>,
+}
+
+impl MeasureRenderer {
+    /// Create a renderer for a chart with an empty keysound cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `bms` - Parsed BMS data.
+    /// * `sample_rate` - Output sample rate.
+    /// * `channels` - Number of output channels.
+    ///
+    /// # Returns
+    ///
+    /// * `MeasureRenderer` - Renderer producing silence until keysounds are inserted.
+    pub fn new(bms: Bms, sample_rate: u32, channels: usize) -> Self {
+        let mut renderer = Self {
+            sample_rate,
+            channels,
+            decoded: Vec::new(),
+            filename_to_id: AHashMap::new(),
+            tempo_map: build_tempo_map(&bms),
+            bms,
+            sound_events: Vec::new(),
+            events: Vec::new(),
+        };
+        renderer.rebuild();
+        renderer
+    }
+
+    /// Replace the chart being rendered, keeping already decoded keysounds.
+    ///
+    /// # Arguments
+    ///
+    /// * `bms` - Edited BMS data.
+    pub fn set_chart(&mut self, bms: Bms) {
+        self.tempo_map = build_tempo_map(&bms);
+        self.bms = bms;
+        self.rebuild();
+    }
+
+    /// Current chart.
+    ///
+    /// # Returns
+    ///
+    /// * `&Bms` - Chart being rendered.
+    pub fn chart(&self) -> &Bms {
+        &self.bms
+    }
+
+    /// Register new filenames and reschedule the events of the current chart.
+    fn rebuild(&mut self) {
+        for filename in self.bms.header.audio_files.values() {
+            if !self.filename_to_id.contains_key(filename) {
+                self.filename_to_id
+                    .insert(filename.clone(), self.decoded.len());
+                self.decoded.push((Vec::new(), 0));
+            }
+        }
+        self.sound_events = extract_sound_events(
+            &self.bms,
+            &self.tempo_map,
+            &self.filename_to_id,
+            self.sample_rate,
+            self.channels,
+        );
+        self.sound_events.sort_by_key(|ev| ev.start);
+        self.events = prepare_events(&self.sound_events, &self.decoded, self.channels).events;
+    }
+
+    /// Filenames referenced by the chart that have no decoded audio yet.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - Sorted filenames still missing from the cache.
+    pub fn missing_files(&self) -> Vec<String> {
+        let mut missing: Vec<String> = self
+            .bms
+            .header
+            .audio_files
+            .values()
+            .filter(|filename| self.decoded[self.filename_to_id[*filename]].1 == 0)
+            .cloned()
+            .collect();
+        missing.sort();
+        missing.dedup();
+        missing
+    }
+
+    /// Filenames without decoded audio that can sound within a span of the chart.
+    ///
+    /// Decoding only these before `render_range` is enough to hear the span in
+    /// full. The length of a keysound is unknown until it is decoded, so one
+    /// triggered before `start` is assumed to ring for `max_tail_sec`, or until
+    /// it is retriggered or cut by the end of its long note.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - Start as `(measure, position)`, position in `0.0..=1.0`.
+    /// * `end` - Exclusive end as `(measure, position)`.
+    /// * `max_tail_sec` - Longest time a keysound is assumed to ring, in seconds.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - Sorted filenames still missing from the cache.
+    pub fn missing_files_in_range(
+        &self,
+        start: (MeasureIndex, f64),
+        end: (MeasureIndex, f64),
+        max_tail_sec: f64,
+    ) -> Vec<String> {
+        let start_sample = self.sample_at(start.0, start.1);
+        let end_sample = self.sample_at(end.0, end.1);
+        let tail = (max_tail_sec.max(0.0) * self.sample_rate as f64) as usize * self.channels;
+
+        let mut needed: Vec<bool> = vec![false; self.decoded.len()];
+        let mut next_start: AHashMap<usize, usize> = AHashMap::new();
+        for ev in self.sound_events.iter().rev() {
+            let retrigger = next_start.insert(ev.key_id, ev.start);
+            if self.decoded[ev.key_id].1 != 0 || ev.start >= end_sample {
+                continue;
+            }
+            let sound_end = ev
+                .end
+                .unwrap_or(ev.start.saturating_add(tail))
+                .min(retrigger.unwrap_or(usize::MAX));
+            if ev.start >= start_sample || sound_end > start_sample {
+                needed[ev.key_id] = true;
+            }
+        }
+
+        let mut missing: Vec<String> = self
+            .filename_to_id
+            .iter()
+            .filter(|(_, id)| needed[**id])
+            .map(|(filename, _)| filename.clone())
+            .collect();
+        missing.sort();
+        missing
+    }
+
+    /// Decode a keysound and store it in the cache.
+    ///
+    /// Events of the current chart are rebuilt so the new audio is heard immediately.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Filename as written in the `#WAV` table.
+    /// * `data` - Encoded audio file contents.
+    /// * `quality` - Resampling quality.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Ok on success, or the decode error.
+    pub fn insert_keysound(
+        &mut self,
+        filename: &str,
+        data: Arc<[u8]>,
+        quality: ResampleMethod,
+    ) -> Result<(), String> {
+        let decoded = decode_audio(data, self.sample_rate, self.channels, quality)?;
+        let id = match self.filename_to_id.get(filename) {
+            Some(&id) => id,
+            None => {
+                self.filename_to_id
+                    .insert(filename.to_string(), self.decoded.len());
+                self.decoded.push((Vec::new(), 0));
+                self.decoded.len() - 1
+            }
+        };
+        self.decoded[id] = decoded;
+        self.rebuild();
+        Ok(())
+    }
+
+    /// Render a span of the chart, including the tails of notes triggered before it.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - Start as `(measure, position)`, position in `0.0..=1.0`.
+    /// * `end` - Exclusive end as `(measure, position)`.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<f32>` - Interleaved samples of the span (empty if `end` is not after `start`).
+    pub fn render_range(&self, start: (MeasureIndex, f64), end: (MeasureIndex, f64)) -> Vec<f32> {
+        let start_sample = self.sample_at(start.0, start.1);
+        let end_sample = self.sample_at(end.0, end.1);
+        if end_sample <= start_sample {
+            return Vec::new();
+        }
+        mix_range(
+            &self.events,
+            &self.decoded,
+            start_sample,
+            end_sample - start_sample,
+        )
+    }
+
+    /// Render a single measure.
+    ///
+    /// # Arguments
+    ///
+    /// * `measure` - Measure index.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<f32>` - Interleaved samples of the measure.
+    pub fn render_measure(&self, measure: MeasureIndex) -> Vec<f32> {
+        self.render_range((measure, 0.0), (measure, 1.0))
+    }
+
+    /// Render a range of beats within a measure.
+    ///
+    /// # Arguments
+    ///
+    /// * `measure` - Measure index.
+    /// * `first_beat` - First beat to render (0-based, a quarter note each).
+    /// * `beat_count` - Number of beats to render.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<f32>` - Interleaved samples of the beats, clipped to the measure.
+    pub fn render_beats(
+        &self,
+        measure: MeasureIndex,
+        first_beat: f64,
+        beat_count: f64,
+    ) -> Vec<f32> {
+        let beats = 4.0 * self.tempo_map.measure_multiplier(measure);
+        let start = (first_beat / beats).clamp(0.0, 1.0);
+        let end = ((first_beat + beat_count) / beats).clamp(0.0, 1.0);
+        self.render_range((measure, start), (measure, end))
+    }
+
+    /// Output sample (interleaved) of a musical position, clamped to the chart.
+    fn sample_at(&self, measure: MeasureIndex, position: f64) -> usize {
+        let last = self.tempo_map.last_measure();
+        let (measure, position) = if measure > last {
+            (last, 1.0)
+        } else {
+            (measure, position)
+        };
+        self.tempo_map
+            .get_timestamp_samples(measure, position, self.sample_rate)
+            * self.channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bms::Channel;
+
+    fn event(key_id: usize, start: usize, end: Option<usize>) -> SoundEvent {
+        SoundEvent {
+            key_id,
+            start,
+            end,
+            gain: 1.0,
+            channel: Channel::Bgm,
+            semitones: 0,
+            pan: 0,
+            bgm_lane: 0,
+        }
+    }
+
+    #[test]
+    fn missing_files_cover_sounds_ringing_into_the_range() {
+        let chart = "#BPM 120\n#WAV01 ring.wav\n#WAV02 retrigger.wav\n#WAV03 long.wav\n\
+                     #WAV04 decoded.wav\n#WAV05 inside.wav\n#WAV06 after.wav\n#00101:01\n#00401:01\n";
+        // Mono at 1 kHz, so samples are milliseconds and a tail of 1 s is 1000 samples.
+        let mut renderer = MeasureRenderer::new(Bms::parse(chart).unwrap(), 1000, 1);
+        let start = renderer.sample_at(2, 0.0);
+        let end = renderer.sample_at(3, 0.0);
+        let id = |filename: &str| renderer.filename_to_id[filename];
+        let mut events = vec![
+            // Rings past the start of the range.
+            event(id("ring.wav"), start - 500, None),
+            // Would ring past the start, but is retriggered by a long note
+            // that ends before it.
+            event(id("retrigger.wav"), start - 900, None),
+            event(id("retrigger.wav"), start - 600, Some(start - 300)),
+            // Would ring past the start, but its long note ends first.
+            event(id("long.wav"), start - 500, Some(start - 100)),
+            event(id("decoded.wav"), start + 100, None),
+            event(id("inside.wav"), end - 1, None),
+            event(id("after.wav"), end, None),
+        ];
+        events.sort_by_key(|ev| ev.start);
+        renderer.sound_events = events;
+        let decoded = id("decoded.wav");
+        renderer.decoded[decoded] = (vec![0.0; 10], 10);
+
+        assert_eq!(
+            renderer.missing_files_in_range((2, 0.0), (3, 0.0), 1.0),
+            ["inside.wav", "ring.wav"]
+        );
+        // Without a tail, only sounds starting within the range are needed.
+        assert_eq!(
+            renderer.missing_files_in_range((2, 0.0), (3, 0.0), 0.0),
+            ["inside.wav"]
+        );
+    }
+}
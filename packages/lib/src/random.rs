@@ -0,0 +1,79 @@
+/// Source of randomness for humanization and chart control flow.
+///
+/// Hosts can implement this to apply their own randomness policy, or to replay
+/// the values drawn by an earlier render.
+pub trait RandomSource {
+    /// Draw the next 64 random bits.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - Uniformly distributed bits.
+    fn next_u64(&mut self) -> u64;
+
+    /// Draw an integer uniformly from `0..n`.
+    ///
+    /// Uses the high bits of `next_u64`, so sources with coarse low bits (such
+    /// as scaled floating-point values) stay unbiased.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Exclusive upper bound; `0` always yields `0`.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - Value in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+/// Seedable SplitMix64 generator, the default random source.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    /// Create a generator whose sequence depends only on `seed`.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - Seed of the sequence.
+    ///
+    /// # Returns
+    ///
+    /// * `SplitMix64` - New generator.
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+}
+
+impl RandomSource for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Convert a value in `0.0..1.0` (as returned by `Math.random`) to random bits.
+///
+/// # Arguments
+///
+/// * `value` - Random fraction; out-of-range and NaN values are clamped.
+///
+/// # Returns
+///
+/// * `u64` - Bits whose high part follows the fraction.
+pub fn fraction_to_bits(value: f64) -> u64 {
+    let value = if value.is_nan() {
+        0.0
+    } else {
+        value.clamp(0.0, 1.0)
+    };
+    // 2^53 steps keep every bit of an f64 fraction.
+    let steps = (value * (1u64 << 53) as f64) as u64;
+    steps.min((1u64 << 53) - 1) << 11
+}
@@ -0,0 +1,1772 @@
+use crate::analysis::{LoopRegion, SilenceGap, find_loop_region, find_silence_gaps};
+use crate::audio::{
+    DecodeError, DecodeInfo, DecodeLimits, ResampleMethod, SOURCE_HEADER_BYTES,
+    corrected_source_rate, estimate_source, synth_beep,
+};
+use crate::bms::{Bms, MeasureIndex};
+use crate::cache::{CacheKey, DecodeCache};
+use crate::control::RandomChoice;
+use crate::guide::{beat_times, render_click_track};
+use crate::limits::{LimitKind, ResourceLimitExceeded, ResourceLimits};
+use crate::loader::{AudioLoader, LoadedFiles};
+use crate::mixer::{
+    ChunkLevels, EventRef, OverlapSlice, Sample, apply_long_note_sustain, apply_pans,
+    apply_pitch_shifts, apply_timing_jitter, bucketize_events, coalesce_retriggers,
+    default_chunk_frames, find_gapless_runs, join_gapless_runs, measure_levels, mix_chunk,
+    mix_range, precompute_overlaps, prepare_events, select_spilled, to_storage,
+};
+use crate::pcm::{PcmFormat, encode_samples};
+use crate::random::{RandomSource, SplitMix64};
+use crate::stems::{STEM_GROUPS, StemGroup, interleave_stems};
+use crate::stretch::{MAX_RATE, MAX_SEMITONES, MIN_RATE, PitchShift, stretched_frames};
+use crate::timeline::{
+    DropReason, DroppedObjects, SoundEvent, SoundEventOptions, TempoMap, TempoMapOptions,
+    build_tempo_map_with_options, extract_sound_events_with_drops, first_use_order,
+    index_audio_files, long_note_spans,
+};
+use ahash::AHashMap;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Shortest loop considered by loop detection, in measures.
+const MIN_LOOP_MEASURES: MeasureIndex = 4;
+
+/// Upper bound of the humanization offset, in milliseconds.
+const MAX_JITTER_MS: f64 = 50.0;
+
+/// Shortest silence reported when `min_silence_gap_sec` is not set, in seconds.
+const DEFAULT_MIN_SILENCE_GAP_SEC: f64 = 10.0;
+
+/// Chunks per second of audio in low-memory mode.
+const LOW_MEMORY_CHUNK_DIVISOR: usize = 4;
+
+/// Mixed chunks held before being written in low-memory mode.
+const LOW_MEMORY_WINDOW_CHUNKS: usize = 8;
+
+/// Mixed chunks held before being written otherwise, enough to keep every worker busy.
+const MIX_WINDOW_CHUNKS: usize = 32;
+
+/// Default minimum time between two progress callbacks within a stage, in milliseconds.
+const DEFAULT_PROGRESS_INTERVAL_MS: f64 = 100.0;
+
+/// Largest gap or overlap between two slices joined by `gapless_slices`, in milliseconds.
+const GAPLESS_TOLERANCE_MS: f64 = 2.0;
+
+type DecodeResult<S> = Result<(usize, (Vec<S>, usize), DecodeInfo), (usize, DecodeError)>;
+
+/// Events, overlap slices and spilled-keysound events of one stem.
+type StemPlan = (Vec<EventRef>, Vec<Vec<OverlapSlice>>, Vec<EventRef>);
+
+/// Settings of a render: output format, limits and processing.
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    /// Output channel count.
+    pub channels: u16,
+    /// Output sample rate.
+    pub sample_rate: u32,
+    /// Output sample format.
+    pub format: PcmFormat,
+    /// Resampling algorithm for keysounds at other rates.
+    pub resample_quality: ResampleMethod,
+    /// Bounds on the chart, the output and the decoded keysounds.
+    pub limits: ResourceLimits,
+    /// Per-file guards applied while decoding keysounds.
+    pub decode_limits: DecodeLimits,
+    /// How the chart's timing is read.
+    pub tempo_map: TempoMapOptions,
+    /// Which notes are scheduled and how long they play.
+    pub sound_events: SoundEventOptions,
+    /// Retriggers of a keysound closer than this are merged into one voice, in milliseconds.
+    pub coalesce_threshold_ms: Option<f64>,
+    /// Render silence instead of failing when nothing can be mixed.
+    pub silent_fallback: bool,
+    /// Play a placeholder in place of keysounds that are missing or fail to decode.
+    pub substitute_missing: bool,
+    /// Look for a loop and write it as a `smpl` chunk.
+    pub detect_loop: bool,
+    /// Shortest silence reported in `ConversionReport::silence_gaps`, in
+    /// seconds; 10 seconds if `None`.
+    pub min_silence_gap_sec: Option<f64>,
+    /// Load and decode keysounds in the order they are first played.
+    pub prioritize_decode: bool,
+    /// Hold long-note keysounds until the note is released.
+    pub sustain_long_notes: bool,
+    /// Largest random offset applied to each note, in milliseconds (capped at 50).
+    pub jitter_ms: Option<f64>,
+    /// Seed of the jitter when no random source is given.
+    pub jitter_seed: u64,
+    /// Scale the mix so its peak stays within full scale.
+    pub auto_gain: bool,
+    /// Write one stereo pair per lane group instead of a single mix.
+    pub multichannel_stems: bool,
+    /// Store keysounds as 16-bit samples and mix in smaller windows.
+    pub low_memory: bool,
+    /// Largest size of the decoded keysounds held at once; the others are
+    /// decoded again while they play.
+    pub memory_budget_bytes: Option<u64>,
+    /// Fail instead of skipping a file rejected by `decode_limits`.
+    pub fail_on_file_limit: bool,
+    /// Decode once-triggered backing tracks at the rate the chart implies
+    /// when their header disagrees.
+    pub correct_rate_drift: bool,
+    /// Join back-to-back slices of one song and resample them together.
+    pub gapless_slices: bool,
+    /// Playback speed, also changing the pitch unless `pitch_semitones` compensates.
+    pub rate: Option<f64>,
+    /// Pitch shift of the whole render in semitones.
+    pub pitch_semitones: Option<f64>,
+    /// Minimum time between two progress updates within a stage, in milliseconds.
+    pub progress_interval_ms: f64,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            channels: 2,
+            sample_rate: 44100,
+            format: PcmFormat::I16,
+            resample_quality: ResampleMethod::Linear,
+            limits: ResourceLimits::default(),
+            decode_limits: DecodeLimits::default(),
+            tempo_map: TempoMapOptions::default(),
+            sound_events: SoundEventOptions::default(),
+            coalesce_threshold_ms: None,
+            silent_fallback: false,
+            substitute_missing: false,
+            detect_loop: false,
+            min_silence_gap_sec: None,
+            prioritize_decode: false,
+            sustain_long_notes: false,
+            jitter_ms: None,
+            jitter_seed: 0,
+            auto_gain: false,
+            multichannel_stems: false,
+            low_memory: false,
+            memory_budget_bytes: None,
+            fail_on_file_limit: false,
+            correct_rate_drift: false,
+            gapless_slices: false,
+            rate: None,
+            pitch_semitones: None,
+            progress_interval_ms: DEFAULT_PROGRESS_INTERVAL_MS,
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Playback rate of the render, or `None` when it plays at normal speed.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<f64>, RenderError>` - Rate other than 1, or an error if out of range.
+    pub fn playback_rate(&self) -> Result<Option<f64>, RenderError> {
+        match self.rate {
+            Some(rate) if !(MIN_RATE..=MAX_RATE).contains(&rate) => Err(format!(
+                "Rate must be between {} and {}: {}",
+                MIN_RATE, MAX_RATE, rate
+            )
+            .into()),
+            Some(rate) if rate != 1.0 => Ok(Some(rate)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Pitch shift of the render in semitones, or `None` when it keeps its pitch.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<f64>, RenderError>` - Non-zero shift, or an error if out of range.
+    pub fn pitch_shift(&self) -> Result<Option<f64>, RenderError> {
+        match self.pitch_semitones {
+            Some(semitones) if !(-MAX_SEMITONES..=MAX_SEMITONES).contains(&semitones) => {
+                Err(format!(
+                    "Pitch shift must be within {} semitones: {}",
+                    MAX_SEMITONES, semitones
+                )
+                .into())
+            }
+            Some(semitones) if semitones != 0.0 => Ok(Some(semitones)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Reason a chart could not be analyzed or rendered.
+#[derive(Debug, Clone)]
+pub enum RenderError {
+    /// The chart or its audio exceeded one of `RenderOptions::limits`.
+    Limit(ResourceLimitExceeded),
+    /// Any other failure, such as invalid options or an output error.
+    Failed(String),
+}
+
+impl From<ResourceLimitExceeded> for RenderError {
+    fn from(err: ResourceLimitExceeded) -> Self {
+        RenderError::Limit(err)
+    }
+}
+
+impl From<String> for RenderError {
+    fn from(message: String) -> Self {
+        RenderError::Failed(message)
+    }
+}
+
+impl From<&str> for RenderError {
+    fn from(message: &str) -> Self {
+        RenderError::Failed(message.to_string())
+    }
+}
+
+impl core::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RenderError::Limit(err) => write!(f, "{}", err),
+            RenderError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// Summary returned once the render has been emitted.
+#[derive(Debug, Default, Serialize)]
+pub struct ConversionReport {
+    /// Non-fatal problems encountered during conversion.
+    pub warnings: Vec<String>,
+    /// Detected loop region, also written as a `smpl` chunk after the audio data.
+    pub loop_region: Option<LoopRegion>,
+    /// Long stretches without scheduled audio, in timeline order.
+    pub silence_gaps: Vec<SilenceGap>,
+    /// Notes that could not be rendered, by reason.
+    pub dropped: DroppedObjects,
+    /// Values selected for the chart's `#RANDOM` and `#SWITCH` commands. Pass
+    /// them as `forced_random` to render the same variant again.
+    pub random_choices: Vec<RandomChoice>,
+}
+
+/// Destination of a WAV file written by a render.
+pub trait ByteSink {
+    /// Write the next bytes of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Bytes following the previous ones.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Error that stops the render.
+    fn write(&mut self, data: &[u8]) -> Result<(), String>;
+
+    /// Called once after the last bytes of the file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - Error that stops the render.
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl ByteSink for Vec<u8> {
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Outputs and callbacks of a render.
+pub struct RenderCallbacks<'a> {
+    /// Receives the WAV file.
+    pub output: &'a mut dyn ByteSink,
+    /// Receives a WAV click track on the chart's beats, rendered after the mix.
+    pub guide: Option<&'a mut dyn ByteSink>,
+    /// Called with the percentage done and the name of the current stage.
+    pub progress: &'a mut dyn FnMut(f64, &str),
+    /// Called with the index and levels of each mixed chunk, in output order.
+    pub meter: Option<&'a mut dyn FnMut(usize, ChunkLevels)>,
+    /// Source of the timing jitter, used instead of `RenderOptions::jitter_seed`.
+    pub random: Option<&'a mut dyn RandomSource>,
+}
+
+/// Estimates from the analysis pass, shown to users before rendering.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalysisSummary {
+    /// Chart length in seconds (keysound tails may extend the render slightly).
+    pub duration_sec: f64,
+    /// Audio files the render will request, sorted.
+    pub required_files: Vec<String>,
+    /// Number of scheduled sound events.
+    pub event_count: usize,
+    /// Approximate size of the WAV output in bytes.
+    pub estimated_output_bytes: f64,
+    /// Approximate mixing memory in bytes, excluding decoded keysounds (unknown until loaded).
+    pub estimated_memory_bytes: f64,
+    /// Non-fatal problems found so far.
+    pub warnings: Vec<String>,
+    /// Values selected for the chart's `#RANDOM` and `#SWITCH` commands.
+    pub random_choices: Vec<RandomChoice>,
+}
+
+/// A parsed and scheduled chart, ready to be rendered with `render`.
+pub struct Analysis {
+    pub(crate) options: RenderOptions,
+    pub(crate) bms: Bms,
+    pub(crate) tempo_map: TempoMap,
+    pub(crate) filenames: Vec<String>,
+    pub(crate) filename_to_id: AHashMap<String, usize>,
+    pub(crate) sound_events: Vec<SoundEvent>,
+    pub(crate) report: ConversionReport,
+    pub(crate) summary: AnalysisSummary,
+    /// Source rates to assume instead of the declared ones, by key id.
+    pub(crate) source_rates: AHashMap<usize, u32>,
+}
+
+impl Analysis {
+    /// Schedule a parsed chart without loading any audio.
+    ///
+    /// In low-memory mode, float output is replaced with 16-bit integers.
+    ///
+    /// # Arguments
+    ///
+    /// * `bms` - Parsed chart.
+    /// * `options` - Render settings.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Analysis, RenderError>` - Scheduled chart, or an error if it
+    ///   exceeds a limit or has nothing to render.
+    pub fn new(bms: Bms, mut options: RenderOptions) -> Result<Self, RenderError> {
+        let mut report = ConversionReport {
+            random_choices: bms.random_choices.clone(),
+            ..ConversionReport::default()
+        };
+        if options.low_memory && options.format == PcmFormat::F32 {
+            options.format = PcmFormat::I16;
+            report
+                .warnings
+                .push("Low-memory mode writes 16-bit integer output instead of float".to_string());
+        }
+        if options.multichannel_stems && options.channels != 2 {
+            return Err("Multichannel stems require stereo output".into());
+        }
+
+        options
+            .limits
+            .check(LimitKind::Messages, bms.messages.len())?;
+        let tempo_map = build_tempo_map_with_options(&bms, &options.tempo_map)
+            .map_err(|e| format!("Tempo error: {}", e))?;
+
+        let (filenames, filename_to_id) = index_audio_files(&bms);
+
+        let channels = options.channels as usize;
+        let sample_rate = options.sample_rate;
+        let (sound_events, dropped) = extract_sound_events_with_drops(
+            &bms,
+            &tempo_map,
+            &filename_to_id,
+            sample_rate,
+            channels,
+            &options.sound_events,
+        );
+        report.dropped = dropped;
+        if sound_events.is_empty() {
+            if !options.silent_fallback {
+                return Err("No sound events found".into());
+            }
+            report
+                .warnings
+                .push("No sound events found, rendering silence".to_string());
+        }
+        options
+            .limits
+            .check(LimitKind::Events, sound_events.len())?;
+
+        let used_ids: HashSet<usize> = sound_events.iter().map(|ev| ev.key_id).collect();
+        let mut required_files: Vec<String> =
+            used_ids.iter().map(|&id| filenames[id].clone()).collect();
+        required_files.sort();
+        let duration_sec = tempo_map.get_timestamp(tempo_map.last_measure(), 1.0);
+        let frames = (duration_sec * sample_rate as f64).ceil();
+        let out_channels = if options.multichannel_stems {
+            STEM_GROUPS.len() * 2
+        } else {
+            channels
+        };
+        let summary = AnalysisSummary {
+            duration_sec,
+            required_files,
+            event_count: sound_events.len(),
+            estimated_output_bytes: WAV_HEADER_SIZE as f64
+                + frames * (out_channels * options.format.bytes_per_sample()) as f64,
+            estimated_memory_bytes: if options.low_memory {
+                let window_frames = default_chunk_frames(sample_rate) / LOW_MEMORY_CHUNK_DIVISOR
+                    * LOW_MEMORY_WINDOW_CHUNKS;
+                (window_frames * out_channels) as f64 * std::mem::size_of::<f32>() as f64
+            } else {
+                let window_frames = (default_chunk_frames(sample_rate) * MIX_WINDOW_CHUNKS) as f64;
+                frames.min(window_frames) * out_channels as f64 * std::mem::size_of::<f32>() as f64
+            },
+            warnings: report.warnings.clone(),
+            random_choices: bms.random_choices.clone(),
+        };
+
+        Ok(Self {
+            options,
+            bms,
+            tempo_map,
+            filenames,
+            filename_to_id,
+            sound_events,
+            report,
+            summary,
+            source_rates: AHashMap::new(),
+        })
+    }
+
+    /// Estimates shown before rendering.
+    pub fn summary(&self) -> &AnalysisSummary {
+        &self.summary
+    }
+
+    /// The parsed chart.
+    pub fn chart(&self) -> &Bms {
+        &self.bms
+    }
+
+    /// Settings the chart was scheduled with, after low-memory adjustments.
+    pub fn options(&self) -> &RenderOptions {
+        &self.options
+    }
+
+    /// Decode a keysound as if it were recorded at `sample_rate`, for files
+    /// whose headers declare the wrong rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Keysound file name, compared case-insensitively.
+    /// * `sample_rate` - Rate to assume.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `false` if no keysound uses `filename`.
+    pub fn override_source_rate(&mut self, filename: &str, sample_rate: u32) -> bool {
+        if sample_rate == 0 {
+            return false;
+        }
+        let mut found = false;
+        for (id, name) in self.filenames.iter().enumerate() {
+            if name.eq_ignore_ascii_case(filename) {
+                self.source_rates.insert(id, sample_rate);
+                found = true;
+            }
+        }
+        found
+    }
+}
+
+/// Render an analyzed chart to a WAV file, fetching keysounds through
+/// `loader` and reusing the decoded ones stored in `cache`.
+///
+/// # Arguments
+///
+/// * `analysis` - Chart scheduled by `Analysis::new`.
+/// * `loader` - Source of the keysound files.
+/// * `cache` - Store of decoded keysounds shared across renders.
+/// * `placeholder` - Encoded sound played by `substitute_missing`; a short beep if `None`.
+/// * `callbacks` - Outputs and progress callbacks.
+///
+/// # Returns
+///
+/// * `Result<ConversionReport, RenderError>` - Report of the written render.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+pub async fn render<'a, L: AudioLoader, C: DecodeCache>(
+    analysis: Analysis,
+    loader: &L,
+    cache: Option<&C>,
+    placeholder: Option<&'a [u8]>,
+    callbacks: RenderCallbacks<'a>,
+) -> Result<ConversionReport, RenderError> {
+    let Analysis {
+        options,
+        bms,
+        tempo_map,
+        filenames,
+        filename_to_id,
+        sound_events,
+        mut report,
+        mut source_rates,
+        ..
+    } = analysis;
+    let RenderCallbacks {
+        output,
+        guide,
+        progress,
+        meter,
+        random,
+    } = callbacks;
+    let channels = options.channels as usize;
+    let sample_rate = options.sample_rate;
+    let resample_quality = options.resample_quality;
+    let decode_limits = options.decode_limits;
+    let mut progress =
+        ProgressReporter::new(progress, options.progress_interval_ms, decode_limits.clock);
+
+    // With decode priority, keysounds needed earliest are fetched and decoded first.
+    let ordered_ids: Vec<usize> = if options.prioritize_decode {
+        first_use_order(&sound_events)
+    } else {
+        let used_ids: HashSet<usize> = sound_events.iter().map(|ev| ev.key_id).collect();
+        let mut ids: Vec<usize> = used_ids.into_iter().collect();
+        ids.sort_unstable();
+        ids
+    };
+    let mut paths: Vec<String> = Vec::with_capacity(ordered_ids.len());
+    for &id in &ordered_ids {
+        paths.push(filenames[id].clone());
+    }
+
+    progress.stage(15.0, "Loading audio files");
+    let files = loader.load(&paths).await?;
+
+    if options.correct_rate_drift {
+        for (id, declared, corrected) in
+            detect_rate_drift(&sound_events, &ordered_ids, &files, sample_rate, channels)
+        {
+            if let std::collections::hash_map::Entry::Vacant(entry) = source_rates.entry(id) {
+                entry.insert(corrected);
+                report.warnings.push(format!(
+                    "{}: header declares {} Hz but the chart implies {} Hz; decoding at {} Hz",
+                    filenames[id], declared, corrected, corrected
+                ));
+            }
+        }
+    }
+
+    let spilled: HashSet<usize> = match options.memory_budget_bytes {
+        Some(budget) => plan_spills(
+            &options,
+            &bms,
+            &tempo_map,
+            &filename_to_id,
+            &sound_events,
+            &ordered_ids,
+            &files,
+            budget,
+        ),
+        None => HashSet::new(),
+    };
+    // Reject oversized charts before decoding, counting only the files whose
+    // headers give their exact length; the check after decoding covers the rest.
+    if options.limits.max_decoded_bytes.is_some() {
+        let sample_bytes = if options.low_memory {
+            size_of::<i16>()
+        } else {
+            size_of::<f32>()
+        };
+        let estimated: u64 = ordered_ids
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| !spilled.contains(id))
+            .filter_map(|(i, _)| {
+                let source =
+                    estimate_source(&files.header(i, SOURCE_HEADER_BYTES)?, files.size(i)?);
+                source
+                    .exact
+                    .then(|| source.decoded_bytes(sample_rate, channels, sample_bytes))
+            })
+            .sum();
+        options.limits.check(
+            LimitKind::DecodedBytes,
+            usize::try_from(estimated).unwrap_or(usize::MAX),
+        )?;
+    }
+    let mut spilled_sources: AHashMap<usize, Arc<[u8]>> = AHashMap::new();
+
+    // Unmodified keysounds may be joined with their neighbours once decoded.
+    let gapless_ids: HashSet<usize> = if options.gapless_slices {
+        sound_events
+            .iter()
+            .filter(|ev| ev.end.is_none() && ev.semitones == 0 && ev.pan == 0)
+            .map(|ev| ev.key_id)
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    let mut missing_ids: HashSet<usize> = HashSet::new();
+    let mut job = RenderJob {
+        options,
+        bms,
+        tempo_map,
+        filenames,
+        filename_to_id,
+        sound_events,
+        report,
+        progress,
+        ordered_ids,
+        source_rates,
+        gapless_sources: AHashMap::new(),
+        output,
+        guide,
+        meter,
+        placeholder,
+        random,
+    };
+
+    if job.options.low_memory {
+        // Files are copied out of the loader and decoded one at a time,
+        // so only a single compressed and float copy is alive next to the
+        // compact keysound bank.
+        job.progress.stage(20.0, "Decoding audio files");
+        let count = job.ordered_ids.len();
+        let mut results: Vec<DecodeResult<i16>> = Vec::with_capacity(count);
+        for (i, &id) in job.ordered_ids.iter().enumerate() {
+            if let Err(e) = check_file_size(&decode_limits, files.size(i)) {
+                results.push(Err((id, e)));
+                continue;
+            }
+            let Some(bytes) = files.bytes(i) else {
+                missing_ids.insert(id);
+                continue;
+            };
+            let spill = spilled.contains(&id);
+            if spill {
+                spilled_sources.insert(id, bytes.clone());
+            }
+            if gapless_ids.contains(&id) {
+                job.gapless_sources.insert(id, bytes.clone());
+            }
+            let source_rate = job.source_rates.get(&id).copied();
+            let key = cache.map(|_| {
+                CacheKey::new(&bytes, sample_rate, channels, resample_quality, source_rate)
+            });
+            let decoded = match (cache, &key) {
+                (Some(cache), Some(key)) => cache.get(key).await,
+                _ => None,
+            };
+            let decoded = match decoded {
+                Some(samples) => Ok((samples, DecodeInfo::default())),
+                None => {
+                    #[cfg(feature = "tracing")]
+                    let _span =
+                        tracing::debug_span!("decode_file", file = %job.filenames[id]).entered();
+                    crate::audio::decode_audio_with_limits(
+                        bytes,
+                        sample_rate,
+                        channels,
+                        resample_quality,
+                        source_rate,
+                        &decode_limits,
+                    )
+                    .map(|((samples, _), info)| (samples, info))
+                }
+            };
+            let result = match decoded {
+                Ok((samples, info)) => {
+                    if let (Some(cache), Some(key)) = (cache, &key) {
+                        cache.put(key, &samples).await;
+                    }
+                    let frames = samples.len() / channels;
+                    let samples = if spill { Vec::new() } else { samples };
+                    Ok((id, (to_storage(samples), frames), info))
+                }
+                Err(e) => Err((id, e)),
+            };
+            results.push(result);
+            job.progress.update(
+                20.0 + (i + 1) as f64 / count as f64 * 30.0,
+                "Decoding audio files",
+            );
+        }
+        return finish_render(job, results, missing_ids, spilled_sources);
+    }
+
+    let mut inputs: Vec<(usize, Arc<[u8]>)> = Vec::with_capacity(job.ordered_ids.len());
+    // Files rejected before decoding and files found in the cache.
+    let mut settled: Vec<DecodeResult<f32>> = Vec::new();
+    let mut keys: AHashMap<usize, CacheKey> = AHashMap::new();
+    for (i, &id) in job.ordered_ids.iter().enumerate() {
+        if let Err(e) = check_file_size(&decode_limits, files.size(i)) {
+            settled.push(Err((id, e)));
+            continue;
+        }
+        // Audio is missing (or not a byte array) so skip it.
+        let Some(bytes) = files.bytes(i) else {
+            missing_ids.insert(id);
+            continue;
+        };
+        if gapless_ids.contains(&id) {
+            job.gapless_sources.insert(id, bytes.clone());
+        }
+        if let Some(cache) = cache {
+            let source_rate = job.source_rates.get(&id).copied();
+            let key = CacheKey::new(&bytes, sample_rate, channels, resample_quality, source_rate);
+            if let Some(samples) = cache.get(&key).await {
+                if spilled.contains(&id) {
+                    spilled_sources.insert(id, bytes);
+                }
+                settled.push(Ok((id, (samples, 0), DecodeInfo::default())));
+                continue;
+            }
+            // Spilled samples are freed inside the decode workers, before they could be stored.
+            if !spilled.contains(&id) {
+                keys.insert(id, key);
+            }
+        }
+        inputs.push((id, bytes));
+    }
+
+    spilled_sources.extend(
+        inputs
+            .iter()
+            .filter(|(id, _)| spilled.contains(id))
+            .map(|(id, bytes)| (*id, bytes.clone())),
+    );
+    job.progress.stage(20.0, "Decoding audio files");
+    let source_rates = &job.source_rates;
+    #[cfg(feature = "tracing")]
+    let filenames = &job.filenames;
+    let decode = |(id, bytes): (usize, Arc<[u8]>)| -> DecodeResult<f32> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("decode_file", file = %filenames[id]).entered();
+        crate::audio::decode_audio_with_limits(
+            bytes,
+            sample_rate,
+            channels,
+            resample_quality,
+            source_rates.get(&id).copied(),
+            &decode_limits,
+        )
+        .map_err(|e| (id, e))
+        .map(|((samples, _), info)| {
+            let frames = samples.len() / channels;
+            // Spilled keysounds are decoded again while they play; only their length is kept.
+            let samples = if spilled.contains(&id) {
+                Vec::new()
+            } else {
+                samples
+            };
+            (id, (samples, frames), info)
+        })
+    };
+    let count = inputs.len();
+    let progress = &mut job.progress;
+    let mut results: Vec<DecodeResult<f32>> = decode_in_parallel(inputs, decode, |done| {
+        progress.update(
+            20.0 + done as f64 / count as f64 * 30.0,
+            "Decoding audio files",
+        );
+    });
+    if let Some(cache) = cache {
+        for (id, (samples, _), _) in results.iter().flatten() {
+            if let Some(key) = keys.get(id) {
+                cache.put(key, samples).await;
+            }
+        }
+    }
+    for (id, (samples, frames), _) in settled.iter_mut().flatten() {
+        *frames = samples.len() / channels;
+        if spilled.contains(id) {
+            *samples = Vec::new();
+        }
+    }
+    results.extend(settled);
+    finish_render(job, results, missing_ids, spilled_sources)
+}
+
+/// Decode slices at their own rate, join them and resample the result once.
+///
+/// Resampling the joined audio avoids the edge effects of resampling each
+/// slice on its own, so slices cut from one song play back as that song.
+///
+/// # Arguments
+///
+/// * `ids` - Key ids of the slices, in playback order.
+/// * `sources` - Encoded bytes by key id.
+/// * `source_rates` - Source rates to assume instead of the declared ones.
+/// * `options` - Output format.
+///
+/// # Returns
+///
+/// * `Option<Vec<f32>>` - Joined samples at the output rate, or `None` if a
+///   slice is unavailable or the slices differ in rate.
+fn join_slices(
+    ids: &[usize],
+    sources: &AHashMap<usize, Arc<[u8]>>,
+    source_rates: &AHashMap<usize, u32>,
+    options: &RenderOptions,
+) -> Option<Vec<f32>> {
+    let channels = options.channels as usize;
+    let quality = options.resample_quality;
+    let mut rate: Option<u32> = None;
+    let mut joined: Vec<f32> = Vec::new();
+    for id in ids {
+        let bytes = sources.get(id)?;
+        let source_rate = source_rates.get(id).copied();
+        let native = source_rate.unwrap_or_else(|| {
+            let header = &bytes[..bytes.len().min(SOURCE_HEADER_BYTES)];
+            estimate_source(header, bytes.len() as u64).sample_rate
+        });
+        if *rate.get_or_insert(native) != native {
+            return None;
+        }
+        let ((samples, _), info) = crate::audio::decode_audio_with_limits(
+            bytes.clone(),
+            native,
+            channels,
+            quality,
+            source_rate,
+            &DecodeLimits::default(),
+        )
+        .ok()?;
+        // The header rate is only a guess; slices must really be decoded unconverted.
+        if source_rate.is_none() && info.stream_rate != Some(native) {
+            return None;
+        }
+        joined.extend(samples);
+    }
+    crate::audio::resample(&joined, channels, rate?, options.sample_rate, quality).ok()
+}
+
+/// Find once-triggered backing tracks whose headers make them end too early.
+///
+/// # Arguments
+///
+/// * `sound_events` - Scheduled events.
+/// * `ordered_ids` - Key ids in the order of the loaded files.
+/// * `files` - Loaded keysound files.
+/// * `sample_rate` - Output sample rate.
+/// * `channels` - Output channel count.
+///
+/// # Returns
+///
+/// * `Vec<(usize, u32, u32)>` - Key id, declared rate and corrected rate of each mistagged file.
+fn detect_rate_drift(
+    sound_events: &[SoundEvent],
+    ordered_ids: &[usize],
+    files: &impl LoadedFiles,
+    sample_rate: u32,
+    channels: usize,
+) -> Vec<(usize, u32, u32)> {
+    let to_sec = |start: usize| (start / channels) as f64 / sample_rate as f64;
+    let chart_end = sound_events.iter().map(|ev| ev.start).max().unwrap_or(0);
+    let mut triggers: AHashMap<usize, (usize, usize)> = AHashMap::new();
+    for ev in sound_events {
+        triggers.entry(ev.key_id).or_insert((0, ev.start)).0 += 1;
+    }
+
+    let mut corrections = Vec::new();
+    for (i, &id) in ordered_ids.iter().enumerate() {
+        let Some(&(1, start)) = triggers.get(&id) else {
+            continue;
+        };
+        let (Some(size), Some(header)) = (files.size(i), files.header(i, SOURCE_HEADER_BYTES))
+        else {
+            continue;
+        };
+        let source = estimate_source(&header, size);
+        if let Some(rate) = corrected_source_rate(&source, to_sec(chart_end) - to_sec(start)) {
+            corrections.push((id, source.sample_rate, rate));
+        }
+    }
+    corrections
+}
+
+/// Reject a file by its size before its bytes are copied out of the loader.
+///
+/// # Arguments
+///
+/// * `limits` - Per-file decode limits.
+/// * `size` - Size of the file in bytes, if known.
+///
+/// # Returns
+///
+/// * `Result<(), DecodeError>` - Ok if the file may be decoded.
+pub(crate) fn check_file_size(limits: &DecodeLimits, size: Option<u64>) -> Result<(), DecodeError> {
+    match (limits.max_file_bytes, size) {
+        (Some(limit), Some(size)) if size > limit as u64 => Err(DecodeError::TooLarge {
+            size: size as usize,
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Pick the keysounds to spill so the decoded table stays within `budget` bytes.
+///
+/// Decoded sizes are estimated from the container headers. Pitched, panned
+/// and sustained keysounds are copied into derived buffers before mixing, so
+/// they always stay resident and are charged against the budget first.
+#[allow(clippy::too_many_arguments)]
+fn plan_spills(
+    options: &RenderOptions,
+    bms: &Bms,
+    tempo_map: &TempoMap,
+    filename_to_id: &AHashMap<String, usize>,
+    sound_events: &[SoundEvent],
+    ordered_ids: &[usize],
+    files: &impl LoadedFiles,
+    budget: u64,
+) -> HashSet<usize> {
+    let sample_rate = options.sample_rate;
+    let channels = options.channels as usize;
+    let sample_bytes = if options.low_memory {
+        size_of::<i16>()
+    } else {
+        size_of::<f32>()
+    };
+
+    let mut uses: AHashMap<usize, usize> = AHashMap::new();
+    let mut pinned: HashSet<usize> = HashSet::new();
+    for ev in sound_events {
+        *uses.entry(ev.key_id).or_default() += 1;
+        if ev.semitones != 0 || ev.pan != 0 {
+            pinned.insert(ev.key_id);
+        }
+    }
+    if options.sustain_long_notes {
+        let spans = long_note_spans(
+            bms,
+            tempo_map,
+            filename_to_id,
+            sample_rate,
+            channels,
+            options.sound_events.ln_pairing,
+        );
+        pinned.extend(spans.iter().map(|span| span.key_id));
+    }
+
+    let mut pinned_bytes = 0u64;
+    let mut candidates: Vec<(usize, u64, usize)> = Vec::new();
+    for (i, &id) in ordered_ids.iter().enumerate() {
+        let (Some(size), Some(header)) = (files.size(i), files.header(i, SOURCE_HEADER_BYTES))
+        else {
+            continue;
+        };
+        let bytes =
+            estimate_source(&header, size).decoded_bytes(sample_rate, channels, sample_bytes);
+        if pinned.contains(&id) {
+            pinned_bytes += bytes;
+        } else {
+            candidates.push((id, bytes, uses.get(&id).copied().unwrap_or(0)));
+        }
+    }
+    select_spilled(&candidates, budget.saturating_sub(pinned_bytes))
+        .into_iter()
+        .collect()
+}
+
+/// Keysounds spilled by the memory budget, decoded again while a mixing window plays them.
+struct StreamedKeysounds<S> {
+    /// Encoded bytes of each spilled keysound.
+    sources: AHashMap<usize, Arc<[u8]>>,
+    /// Source rates to assume instead of the declared ones.
+    source_rates: AHashMap<usize, u32>,
+    /// Guards applied each time a spilled keysound is decoded again.
+    limits: DecodeLimits,
+    /// Output ranges during which each spilled keysound plays.
+    ranges: AHashMap<usize, Vec<(usize, usize)>>,
+    /// Decoded buffers by key id; only the keysounds of the current window are filled.
+    table: Vec<(Vec<S>, usize)>,
+}
+
+impl<S: Sample> StreamedKeysounds<S> {
+    fn new(
+        sources: AHashMap<usize, Arc<[u8]>>,
+        source_rates: AHashMap<usize, u32>,
+        limits: DecodeLimits,
+        key_count: usize,
+    ) -> Self {
+        Self {
+            sources,
+            source_rates,
+            limits,
+            ranges: AHashMap::new(),
+            table: vec![(Vec::new(), 0); key_count],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Keep the events of spilled keysounds, remembering when they play.
+    fn track(&mut self, events: &[EventRef]) -> Vec<EventRef> {
+        let spilled: Vec<EventRef> = events
+            .iter()
+            .filter(|ev| self.sources.contains_key(&ev.key_id))
+            .cloned()
+            .collect();
+        for ev in &spilled {
+            self.ranges
+                .entry(ev.key_id)
+                .or_default()
+                .push((ev.start, ev.end));
+        }
+        spilled
+    }
+
+    /// Decode the keysounds playing in `start..end` and free the others.
+    fn load(
+        &mut self,
+        start: usize,
+        end: usize,
+        sample_rate: u32,
+        channels: usize,
+        quality: ResampleMethod,
+    ) {
+        for (&id, bytes) in &self.sources {
+            let playing = self.ranges.get(&id).is_some_and(|ranges| {
+                ranges
+                    .iter()
+                    .any(|&(ev_start, ev_end)| ev_start < end && ev_end > start)
+            });
+            let slot = &mut self.table[id];
+            if !playing {
+                *slot = (Vec::new(), 0);
+            } else if slot.0.is_empty()
+                && let Ok(((samples, frames), _)) = crate::audio::decode_audio_with_limits(
+                    bytes.clone(),
+                    sample_rate,
+                    channels,
+                    quality,
+                    self.source_rates.get(&id).copied(),
+                    &self.limits,
+                )
+            {
+                *slot = (to_storage(samples), frames);
+            }
+        }
+    }
+
+    /// Add the spilled keysounds of `events` to a chunk starting at output sample `start`.
+    fn mix_into(&self, chunk: &mut [f32], events: &[EventRef], start: usize) {
+        if events.is_empty() {
+            return;
+        }
+        let streamed = mix_range(events, &self.table, start, chunk.len());
+        for (out, sample) in chunk.iter_mut().zip(streamed) {
+            *out += sample;
+        }
+    }
+}
+
+/// Chart, options and callbacks of a render, handed from decoding to mixing.
+struct RenderJob<'a> {
+    options: RenderOptions,
+    bms: Bms,
+    tempo_map: TempoMap,
+    filenames: Vec<String>,
+    filename_to_id: AHashMap<String, usize>,
+    sound_events: Vec<SoundEvent>,
+    report: ConversionReport,
+    progress: ProgressReporter<'a>,
+    ordered_ids: Vec<usize>,
+    source_rates: AHashMap<usize, u32>,
+    /// Encoded bytes of the keysounds that may be joined by `gapless_slices`.
+    gapless_sources: AHashMap<usize, Arc<[u8]>>,
+    output: &'a mut dyn ByteSink,
+    guide: Option<&'a mut dyn ByteSink>,
+    meter: Option<&'a mut dyn FnMut(usize, ChunkLevels)>,
+    placeholder: Option<&'a [u8]>,
+    random: Option<&'a mut dyn RandomSource>,
+}
+
+/// Mix decoded keysounds and emit the WAV, with keysounds stored as `S`.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+fn finish_render<S: Sample>(
+    job: RenderJob<'_>,
+    results: Vec<DecodeResult<S>>,
+    missing_ids: HashSet<usize>,
+    spilled_sources: AHashMap<usize, Arc<[u8]>>,
+) -> Result<ConversionReport, RenderError> {
+    let RenderJob {
+        options,
+        bms,
+        tempo_map,
+        filenames,
+        filename_to_id,
+        mut sound_events,
+        mut report,
+        mut progress,
+        ordered_ids,
+        source_rates,
+        gapless_sources,
+        output,
+        guide,
+        mut meter,
+        placeholder,
+        random,
+    } = job;
+    let format = options.format;
+    let playback_rate = options.playback_rate()?;
+    let pitch_shift = options.pitch_shift()?;
+    let limits = &options.limits;
+    let channels = options.channels as usize;
+    let sample_rate = options.sample_rate;
+    let resample_quality = options.resample_quality;
+    let chunk_frames = if options.low_memory {
+        default_chunk_frames(sample_rate) / LOW_MEMORY_CHUNK_DIVISOR
+    } else {
+        default_chunk_frames(sample_rate)
+    };
+
+    progress.stage(50.0, "Audio decoded");
+    let mut decoded_pairs: Vec<(usize, (Vec<S>, usize))> = Vec::with_capacity(results.len());
+    let mut failed_ids: HashSet<usize> = HashSet::new();
+    for r in results {
+        match r {
+            Ok((id, decoded, info)) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(file = %filenames[id], frames = decoded.1, "keysound decoded");
+                for warning in [info.rate_mismatch(), info.truncation()]
+                    .into_iter()
+                    .flatten()
+                {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(file = %filenames[id], "{}", warning);
+                    report
+                        .warnings
+                        .push(format!("{}: {}", filenames[id], warning));
+                }
+                decoded_pairs.push((id, decoded));
+            }
+            Err((id, e)) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(file = %filenames[id], error = %e, "keysound not decoded");
+                // Ignore decode errors to continue rendering without this audio,
+                // but say why a file that hit a per-file limit was skipped.
+                if e.is_limit() {
+                    let message = format!("{}: {}", filenames[id], e);
+                    if options.fail_on_file_limit {
+                        return Err(message.into());
+                    }
+                    report.warnings.push(format!("{} (skipped)", message));
+                }
+                failed_ids.insert(id);
+            }
+        }
+    }
+    #[cfg(feature = "tracing")]
+    for &id in &missing_ids {
+        tracing::warn!(file = %filenames[id], "keysound missing");
+    }
+    for ev in &sound_events {
+        let reason = if missing_ids.contains(&ev.key_id) {
+            DropReason::MissingFile
+        } else if failed_ids.contains(&ev.key_id) {
+            DropReason::DecodeFailure
+        } else {
+            continue;
+        };
+        let time_sec = (ev.start / channels) as f64 / sample_rate as f64;
+        report.dropped.record(reason, time_sec);
+    }
+
+    let decoded_bytes: usize = decoded_pairs
+        .iter()
+        .map(|(_, (buf, _))| buf.len() * std::mem::size_of::<S>())
+        .sum();
+    limits.check(LimitKind::DecodedBytes, decoded_bytes)?;
+
+    let mut decoded_vec: Vec<(Vec<S>, usize)> = vec![(Vec::new(), 0); filenames.len()];
+    for (id, (buf, frames)) in decoded_pairs.into_iter() {
+        decoded_vec[id] = (buf, frames);
+    }
+
+    if options.substitute_missing {
+        let (substitute, substitute_frames) = match placeholder {
+            Some(bytes) => crate::audio::decode_audio(
+                Arc::from(bytes),
+                sample_rate,
+                channels,
+                resample_quality,
+            )
+            .map_err(|e| format!("Error while decoding placeholder: {}", e))?,
+            None => synth_beep(sample_rate, channels),
+        };
+        let missing: Vec<usize> = ordered_ids
+            .iter()
+            .copied()
+            .filter(|&id| decoded_vec[id].1 == 0)
+            .collect();
+        for &id in &missing {
+            decoded_vec[id] = (to_storage(substitute.clone()), substitute_frames);
+        }
+        if !missing.is_empty() {
+            report.warnings.push(format!(
+                "{} missing keysound(s) replaced with a placeholder",
+                missing.len()
+            ));
+        }
+    }
+
+    if !gapless_sources.is_empty() {
+        let lengths: Vec<usize> = decoded_vec.iter().map(|(_, frames)| *frames).collect();
+        let tolerance = (GAPLESS_TOLERANCE_MS / 1000.0 * sample_rate as f64) as usize;
+        // Parallel BGM columns are separate runs, so a song cut into slices on
+        // one column is not broken up by sounds on another.
+        let runs = find_gapless_runs(&sound_events, &lengths, channels, tolerance, |ev| {
+            StemGroup::of(ev.channel, bms.mode).map(|group| (group, ev.bgm_lane))
+        });
+        let joined: Vec<(Vec<usize>, Vec<S>)> = runs
+            .into_iter()
+            .filter_map(|run| {
+                let ids: Vec<usize> = run.iter().map(|&i| sound_events[i].key_id).collect();
+                join_slices(&ids, &gapless_sources, &source_rates, &options)
+                    .map(|samples| (run, to_storage(samples)))
+            })
+            .collect();
+        let run_count = joined.len();
+        let slice_count =
+            join_gapless_runs(&mut sound_events, &mut decoded_vec, joined, channels) + run_count;
+        if run_count > 0 {
+            report.warnings.push(format!(
+                "{} back-to-back slices joined into {} gapless run(s)",
+                slice_count, run_count
+            ));
+        }
+    }
+
+    if options.sustain_long_notes {
+        let spans = long_note_spans(
+            &bms,
+            &tempo_map,
+            &filename_to_id,
+            sample_rate,
+            channels,
+            options.sound_events.ln_pairing,
+        );
+        apply_long_note_sustain(
+            &mut sound_events,
+            &mut decoded_vec,
+            &spans,
+            sample_rate,
+            channels,
+        );
+    }
+    apply_pitch_shifts(&mut sound_events, &mut decoded_vec, channels);
+    apply_pans(&mut sound_events, &mut decoded_vec, channels);
+
+    if let Some(ms) = options.jitter_ms
+        && ms > 0.0
+    {
+        let max_frames = (ms.min(MAX_JITTER_MS) / 1000.0 * sample_rate as f64) as usize;
+        // A caller-provided source takes precedence over the seed.
+        match random {
+            Some(rng) => apply_timing_jitter(&mut sound_events, max_frames, channels, rng),
+            None => apply_timing_jitter(
+                &mut sound_events,
+                max_frames,
+                channels,
+                &mut SplitMix64::new(options.jitter_seed),
+            ),
+        }
+    }
+
+    progress.stage(55.0, "Preparing events");
+    let mut prepared = prepare_events(&sound_events, &decoded_vec, channels);
+    if prepared.total_len == 0 && options.silent_fallback {
+        let duration = tempo_map.get_timestamp(tempo_map.last_measure(), 1.0);
+        prepared.total_len = (duration * sample_rate as f64).round() as usize * channels;
+        if !sound_events.is_empty() {
+            report
+                .warnings
+                .push("No keysounds could be loaded, rendering silence".to_string());
+        }
+    }
+    if prepared.total_len == 0 {
+        return Err("Nothing to mix".into());
+    }
+    let coalesce_gap = options
+        .coalesce_threshold_ms
+        .filter(|ms| *ms > 0.0)
+        .map(|ms| (ms / 1000.0 * sample_rate as f64) as usize * channels);
+    if let Some(max_gap) = coalesce_gap {
+        prepared.events = coalesce_retriggers(prepared.events, max_gap);
+    }
+    let master_gain = if options.auto_gain {
+        prepared.apply_auto_gain()
+    } else {
+        1.0
+    };
+    limits.check(LimitKind::TotalLength, prepared.total_len)?;
+    let mut streamed: StreamedKeysounds<S> = StreamedKeysounds::new(
+        spilled_sources,
+        source_rates,
+        options.decode_limits,
+        decoded_vec.len(),
+    );
+    let streamed_events = streamed.track(&prepared.events);
+    let (chunk_count, buckets) =
+        bucketize_events(&prepared.events, prepared.total_len, chunk_frames, channels);
+    let pre = precompute_overlaps(
+        &prepared.events,
+        &decoded_vec,
+        &buckets,
+        prepared.total_len,
+        chunk_frames,
+        channels,
+    );
+
+    // Stems are mixed in stereo on the same chunk grid as the full mix, then
+    // interleaved one pair per lane group.
+    let stem_plans: Vec<StemPlan> = if options.multichannel_stems {
+        STEM_GROUPS
+            .iter()
+            .map(|&group| {
+                let events: Vec<SoundEvent> = sound_events
+                    .iter()
+                    .filter(|ev| StemGroup::of(ev.channel, bms.mode) == Some(group))
+                    .cloned()
+                    .collect();
+                let mut stem = prepare_events(&events, &decoded_vec, channels);
+                if let Some(max_gap) = coalesce_gap {
+                    stem.events = coalesce_retriggers(stem.events, max_gap);
+                }
+                for ev in &mut stem.events {
+                    ev.gain *= master_gain;
+                }
+                let (_, buckets) =
+                    bucketize_events(&stem.events, prepared.total_len, chunk_frames, channels);
+                let pre = precompute_overlaps(
+                    &stem.events,
+                    &decoded_vec,
+                    &buckets,
+                    prepared.total_len,
+                    chunk_frames,
+                    channels,
+                );
+                let streamed_stem = streamed.track(&stem.events);
+                (stem.events, pre, streamed_stem)
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let out_channels = if stem_plans.is_empty() {
+        options.channels
+    } else {
+        (stem_plans.len() * 2) as u16
+    };
+    progress.stage(60.0, "Mixing audio");
+
+    report.silence_gaps = find_silence_gaps(
+        &prepared.events,
+        prepared.total_len,
+        sample_rate,
+        channels,
+        options
+            .min_silence_gap_sec
+            .unwrap_or(DEFAULT_MIN_SILENCE_GAP_SEC),
+    );
+    if !report.silence_gaps.is_empty() {
+        report.warnings.push(format!(
+            "{} long silent gap(s) in the timeline",
+            report.silence_gaps.len()
+        ));
+    }
+    if options.detect_loop {
+        report.loop_region = find_loop_region(
+            &prepared.events,
+            &decoded_vec,
+            &tempo_map,
+            prepared.total_len,
+            sample_rate,
+            channels,
+            MIN_LOOP_MEASURES,
+        );
+    }
+    // Reported positions follow the stretched output.
+    if let Some(rate) = playback_rate {
+        for gap in &mut report.silence_gaps {
+            gap.start_sec /= rate;
+            gap.end_sec /= rate;
+        }
+        if let Some(region) = &mut report.loop_region {
+            region.start_frame = stretched_frames(region.start_frame, rate);
+            region.end_frame = stretched_frames(region.end_frame, rate);
+        }
+    }
+    let out_frames = prepared.total_len / channels;
+    let out_frames = playback_rate.map_or(out_frames, |rate| stretched_frames(out_frames, rate));
+    let smpl_chunk = report
+        .loop_region
+        .map(|region| build_smpl_chunk(&region, sample_rate))
+        .unwrap_or_default();
+    let out_len = out_frames * out_channels as usize;
+    let header = build_wav_header(
+        format,
+        sample_rate,
+        out_channels,
+        out_len,
+        smpl_chunk.len() as u32,
+    )?;
+    output.write(&header)?;
+    progress.stage(65.0, "Writing WAV header");
+
+    let chunk_samples = chunk_frames * channels;
+    let mix = |ci: usize, streamed: &StreamedKeysounds<S>| -> Vec<f32> {
+        if stem_plans.is_empty() {
+            let mut mixed = mix_chunk(
+                ci,
+                &prepared.events,
+                &decoded_vec,
+                &pre,
+                prepared.total_len,
+                chunk_frames,
+                channels,
+            );
+            streamed.mix_into(&mut mixed, &streamed_events, ci * chunk_samples);
+            mixed
+        } else {
+            let stems: Vec<Vec<f32>> = stem_plans
+                .iter()
+                .map(|(events, stem_pre, streamed_stem)| {
+                    let mut mixed = mix_chunk(
+                        ci,
+                        events,
+                        &decoded_vec,
+                        stem_pre,
+                        prepared.total_len,
+                        chunk_frames,
+                        channels,
+                    );
+                    streamed.mix_into(&mut mixed, streamed_stem, ci * chunk_samples);
+                    mixed
+                })
+                .collect();
+            interleave_stems(&stems)
+        }
+    };
+
+    // Chunks are mixed in parallel one window at a time and written in order,
+    // so at most `window` mixed chunks are held before being emitted. Spilled
+    // keysounds are only decoded for the windows they play in.
+    let window = if options.low_memory || !streamed.is_empty() {
+        LOW_MEMORY_WINDOW_CHUNKS
+    } else {
+        MIX_WINDOW_CHUNKS
+    };
+    // Speed and pitch changes run on the finished mix as it is written.
+    let mut post_mix = if playback_rate.is_some() || pitch_shift.is_some() {
+        let stage = PitchShift::new(
+            pitch_shift.unwrap_or(0.0),
+            playback_rate.unwrap_or(1.0),
+            sample_rate,
+            out_channels as usize,
+            resample_quality,
+        );
+        Some(stage?)
+    } else {
+        None
+    };
+    let mut buf_bytes: Vec<u8> = Vec::new();
+    for window_start in (0..chunk_count).step_by(window) {
+        let window_end = (window_start + window).min(chunk_count);
+        if !streamed.is_empty() {
+            streamed.load(
+                window_start * chunk_samples,
+                (window_end * chunk_samples).min(prepared.total_len),
+                sample_rate,
+                channels,
+                resample_quality,
+            );
+        }
+        let mixed: Vec<Vec<f32>> = (window_start..window_end)
+            .into_par_iter()
+            .map(|ci| mix(ci, &streamed))
+            .collect();
+        for (ci, samples) in (window_start..).zip(mixed) {
+            match &mut post_mix {
+                Some(stage) => {
+                    let shifted = stage.process(&samples)?;
+                    write_samples(output, &shifted, format, &mut buf_bytes)?
+                }
+                None => write_samples(output, &samples, format, &mut buf_bytes)?,
+            }
+            if let Some(meter) = &mut meter {
+                meter(ci, measure_levels(&samples));
+            }
+            progress.update(
+                65.0 + (ci + 1) as f64 / chunk_count as f64 * 30.0,
+                "Mixing audio",
+            );
+        }
+    }
+
+    if let Some(stage) = post_mix {
+        let shifted = stage.finish()?;
+        write_samples(output, &shifted, format, &mut buf_bytes)?;
+    }
+    let pad = data_padding(out_len, format);
+    if !pad.is_empty() {
+        output.write(pad)?;
+    }
+    if !smpl_chunk.is_empty() {
+        output.write(&smpl_chunk)?;
+    }
+    output.finish()?;
+
+    if let Some(guide) = guide {
+        progress.stage(95.0, "Rendering guide track");
+        let mut beats = beat_times(&tempo_map);
+        // Clicks are placed at the stretched beat times rather than stretched themselves.
+        if let Some(rate) = playback_rate {
+            for beat in &mut beats {
+                beat.time_sec /= rate;
+            }
+        }
+        let clicks = render_click_track(&beats, out_frames * channels, sample_rate, channels);
+        guide.write(&build_wav_header(
+            format,
+            sample_rate,
+            options.channels,
+            clicks.len(),
+            0,
+        )?)?;
+        let chunk_samples = sample_rate as usize * channels;
+        let guide_chunks = clicks.len().div_ceil(chunk_samples);
+        for (i, samples) in clicks.chunks(chunk_samples).enumerate() {
+            write_samples(guide, samples, format, &mut buf_bytes)?;
+            progress.update(
+                95.0 + (i + 1) as f64 / guide_chunks as f64 * 5.0,
+                "Rendering guide track",
+            );
+        }
+        let pad = data_padding(clicks.len(), format);
+        if !pad.is_empty() {
+            guide.write(pad)?;
+        }
+        guide.finish()?;
+    }
+    progress.finish();
+    Ok(report)
+}
+
+/// Progress callback that limits how often updates within a stage are sent.
+struct ProgressReporter<'a> {
+    callback: &'a mut dyn FnMut(f64, &str),
+    interval_ms: f64,
+    clock: fn() -> f64,
+    last_ms: f64,
+}
+
+impl<'a> ProgressReporter<'a> {
+    fn new(callback: &'a mut dyn FnMut(f64, &str), interval_ms: f64, clock: fn() -> f64) -> Self {
+        Self {
+            callback,
+            interval_ms,
+            clock,
+            last_ms: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Report the start of a stage. Always forwarded.
+    fn stage(&mut self, progress: f64, stage: &str) {
+        self.last_ms = (self.clock)();
+        (self.callback)(progress, stage);
+    }
+
+    /// Report progress within a stage, skipped if the last report is too recent.
+    fn update(&mut self, progress: f64, stage: &str) {
+        if (self.clock)() - self.last_ms >= self.interval_ms {
+            self.stage(progress, stage);
+        }
+    }
+
+    /// Report the end of the render. Always forwarded, so callers see 100%
+    /// even when the last update of the final stage was throttled.
+    fn finish(&mut self) {
+        self.stage(100.0, "Done");
+    }
+}
+
+/// Decode files on the thread pool, reporting each completed file.
+///
+/// Files are handed out in order, so keysounds listed first (such as with
+/// `prioritize_decode`) are decoded first. The calling thread decodes too and
+/// is the only one calling `on_done`, since progress callbacks may not be
+/// callable from the pool's threads (as with JavaScript functions).
+///
+/// # Arguments
+///
+/// * `inputs` - Key ids and encoded bytes of the files.
+/// * `decode` - Decoder run for each file.
+/// * `on_done` - Called with the number of completed files after each file
+///   decoded by the calling thread.
+///
+/// # Returns
+///
+/// * `Vec<T>` - Results of `decode`, in completion order.
+fn decode_in_parallel<T: Send>(
+    inputs: Vec<(usize, Arc<[u8]>)>,
+    decode: impl Fn((usize, Arc<[u8]>)) -> T + Sync,
+    mut on_done: impl FnMut(usize),
+) -> Vec<T> {
+    let count = inputs.len();
+    // Inputs are taken out of their slot so each file's bytes are freed once decoded.
+    let queue: Vec<Mutex<Option<_>>> = inputs
+        .into_iter()
+        .map(|input| Mutex::new(Some(input)))
+        .collect();
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(count));
+    // Decode the next file in the queue; `false` once it is empty.
+    let work = || {
+        let Some(input) = queue
+            .get(next.fetch_add(1, Ordering::Relaxed))
+            .and_then(|slot| slot.lock().unwrap().take())
+        else {
+            return false;
+        };
+        let result = decode(input);
+        results.lock().unwrap().push(result);
+        done.fetch_add(1, Ordering::Relaxed);
+        true
+    };
+    rayon::in_place_scope(|scope| {
+        let work = &work;
+        for _ in 1..rayon::current_num_threads().min(count) {
+            scope.spawn(move |_| while work() {});
+        }
+        while work() {
+            on_done(done.load(Ordering::Relaxed));
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// Size of the canonical WAV header written by `build_wav_header`.
+const WAV_HEADER_SIZE: usize = 44;
+
+/// Build a WAV header for `total_len` interleaved samples, followed by
+/// `trailing_len` bytes of chunks written after the audio data.
+///
+/// An odd-sized `data` chunk is followed by a pad byte (see `data_padding`),
+/// which the RIFF size includes.
+///
+/// Outputs with more than two channels use `WAVE_FORMAT_EXTENSIBLE` with no
+/// speaker mask, so players and DAWs treat the channels as discrete tracks.
+fn build_wav_header(
+    format: PcmFormat,
+    out_sample_rate: u32,
+    out_channels: u16,
+    total_len: usize,
+    trailing_len: u32,
+) -> Result<Vec<u8>, RenderError> {
+    let bits_per_sample = format.bits_per_sample();
+    let audio_format = format.format_tag();
+    let block_align: u16 = out_channels * (bits_per_sample / 8);
+    let byte_rate: u32 = out_sample_rate * block_align as u32;
+
+    let bytes_per_sample = format.bytes_per_sample();
+    let total_bytes_64 = (total_len as u64) * (bytes_per_sample as u64);
+    if total_bytes_64 > (u32::MAX as u64) {
+        return Err("Output exceeds WAV 4GB limit".into());
+    }
+    let data_len: u32 = total_bytes_64 as u32;
+    let extensible = out_channels > 2;
+    let fmt_len: u32 = if extensible { 40 } else { 16 };
+    let file_size_minus_8 =
+        20 + fmt_len as u64 + total_bytes_64 + (total_bytes_64 & 1) + trailing_len as u64;
+    if file_size_minus_8 > u32::MAX as u64 {
+        return Err("Output exceeds WAV 4GB limit".into());
+    }
+    let file_size_minus_8 = file_size_minus_8 as u32;
+    let mut header: Vec<u8> = Vec::with_capacity(WAV_HEADER_SIZE);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&file_size_minus_8.to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&fmt_len.to_le_bytes());
+    let format_tag: u16 = if extensible { 0xFFFE } else { audio_format };
+    header.extend_from_slice(&format_tag.to_le_bytes());
+    header.extend_from_slice(&out_channels.to_le_bytes());
+    header.extend_from_slice(&out_sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&bits_per_sample.to_le_bytes());
+    if extensible {
+        header.extend_from_slice(&22u16.to_le_bytes()); // extension size
+        header.extend_from_slice(&bits_per_sample.to_le_bytes()); // valid bits
+        header.extend_from_slice(&0u32.to_le_bytes()); // channel mask
+        // Sub-format GUID: the plain format tag followed by the KSDATAFORMAT suffix.
+        header.extend_from_slice(&(audio_format as u32).to_le_bytes());
+        header.extend_from_slice(&[
+            0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+        ]);
+    }
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    Ok(header)
+}
+
+/// Pad byte written after the audio data when the `data` chunk has an odd size.
+///
+/// RIFF chunks start on even offsets, so chunks written after the audio (such
+/// as `smpl`) would otherwise be misaligned. 24-bit audio with an odd number
+/// of samples is the only format that needs it.
+///
+/// # Arguments
+///
+/// * `total_len` - Number of interleaved samples in the `data` chunk.
+/// * `format` - Output sample format.
+///
+/// # Returns
+///
+/// * `&'static [u8]` - A single zero byte, or nothing for even-sized data.
+fn data_padding(total_len: usize, format: PcmFormat) -> &'static [u8] {
+    if (total_len * format.bytes_per_sample()) % 2 == 1 {
+        &[0]
+    } else {
+        &[]
+    }
+}
+
+/// Build a `smpl` chunk describing a single forward loop.
+///
+/// The chunk is 68 bytes long, so it never needs a pad byte itself.
+fn build_smpl_chunk(loop_region: &LoopRegion, sample_rate: u32) -> Vec<u8> {
+    let mut chunk: Vec<u8> = Vec::with_capacity(68);
+    chunk.extend_from_slice(b"smpl");
+    chunk.extend_from_slice(&60u32.to_le_bytes());
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // manufacturer
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // product
+    chunk.extend_from_slice(&(1_000_000_000 / sample_rate).to_le_bytes()); // sample period (ns)
+    chunk.extend_from_slice(&60u32.to_le_bytes()); // MIDI unity note
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // MIDI pitch fraction
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // SMPTE format
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // SMPTE offset
+    chunk.extend_from_slice(&1u32.to_le_bytes()); // number of loops
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // sampler data
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // cue point id
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // loop type (forward)
+    chunk.extend_from_slice(&(loop_region.start_frame as u32).to_le_bytes());
+    // The end offset is inclusive in `smpl`.
+    chunk.extend_from_slice(&(loop_region.end_frame.saturating_sub(1) as u32).to_le_bytes());
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // fraction
+    chunk.extend_from_slice(&0u32.to_le_bytes()); // play count (infinite)
+    chunk
+}
+
+/// Convert mixed samples to the output sample format and write them to `sink`.
+#[inline]
+fn write_samples(
+    sink: &mut dyn ByteSink,
+    samples: &[f32],
+    format: PcmFormat,
+    buf_bytes: &mut Vec<u8>,
+) -> Result<(), String> {
+    if format == PcmFormat::F32 {
+        sink.write(bytemuck::cast_slice(samples))
+    } else {
+        encode_samples(samples, format, buf_bytes);
+        sink.write(buf_bytes)
+    }
+}
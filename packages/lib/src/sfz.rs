@@ -0,0 +1,147 @@
+use crate::assets::{KeysoundUsage, keysound_usage};
+use crate::bms::Bms;
+use serde::Serialize;
+use std::fmt::Write;
+
+/// Number of MIDI keys available to an SFZ instrument.
+const MIDI_KEYS: usize = 128;
+
+/// Pitch confidence needed before a keysound is placed on its detected key.
+const MIN_PITCH_CONFIDENCE: f32 = 0.8;
+
+/// A keysound assigned to a MIDI key.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyAssignment {
+    /// Filename as written in the chart.
+    pub filename: String,
+    /// MIDI key number (0-127).
+    pub key: u8,
+}
+
+/// SFZ instrument covering the keysounds of a chart.
+#[derive(Debug, Clone, Serialize)]
+pub struct SfzExport {
+    /// Contents of the `.sfz` file.
+    pub sfz: String,
+    /// Key of every mapped keysound, in key order.
+    pub keys: Vec<KeyAssignment>,
+    /// Triggered keysounds that did not fit in the MIDI key range.
+    pub skipped: Vec<String>,
+}
+
+/// Assign consecutive MIDI keys to the triggered keysounds of a chart.
+///
+/// Keysounds are ordered by filename so the mapping is stable across
+/// difficulties sharing a sound set.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `first_key` - Key of the first keysound.
+///
+/// # Returns
+///
+/// * `(Vec<KeyAssignment>, Vec<String>)` - Assigned keys and the keysounds left over.
+pub fn assign_keys(bms: &Bms, first_key: u8) -> (Vec<KeyAssignment>, Vec<String>) {
+    assign_keys_from_usage(keysound_usage(bms), first_key)
+}
+
+/// Assign MIDI keys to keysounds, placing pitched ones on their detected key.
+///
+/// Keysounds with a confident pitch estimate take the key of their note when
+/// it is still free; the rest get consecutive free keys from `first_key`, in
+/// filename order.
+///
+/// # Arguments
+///
+/// * `usage` - Keysound manifest, optionally with pitch estimates.
+/// * `first_key` - Key of the first keysound without a usable pitch.
+///
+/// # Returns
+///
+/// * `(Vec<KeyAssignment>, Vec<String>)` - Assigned keys in key order and the keysounds left over.
+pub fn assign_keys_from_usage(
+    mut usage: Vec<KeysoundUsage>,
+    first_key: u8,
+) -> (Vec<KeyAssignment>, Vec<String>) {
+    usage.retain(|u| u.event_count > 0);
+    usage.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+    let mut taken = [false; MIDI_KEYS];
+    let mut keys = Vec::with_capacity(usage.len());
+    let mut unpitched = Vec::new();
+    for u in usage {
+        match u.pitch {
+            Some(pitch)
+                if pitch.confidence >= MIN_PITCH_CONFIDENCE && !taken[pitch.midi_note as usize] =>
+            {
+                taken[pitch.midi_note as usize] = true;
+                keys.push(KeyAssignment {
+                    filename: u.filename,
+                    key: pitch.midi_note,
+                });
+            }
+            _ => unpitched.push(u.filename),
+        }
+    }
+
+    let mut free = (first_key as usize..MIDI_KEYS).filter(|&k| !taken[k]);
+    let mut skipped = Vec::new();
+    for filename in unpitched {
+        match free.next() {
+            Some(key) => keys.push(KeyAssignment {
+                filename,
+                key: key as u8,
+            }),
+            None => skipped.push(filename),
+        }
+    }
+    keys.sort_by_key(|k| k.key);
+    (keys, skipped)
+}
+
+/// Write an SFZ instrument that plays each keysound of a chart on its own key.
+///
+/// Sample paths are the chart's filenames, so the `.sfz` file is meant to be
+/// saved next to the extracted keysounds.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `first_key` - Key of the first keysound.
+///
+/// # Returns
+///
+/// * `SfzExport` - SFZ text and the key mapping.
+pub fn export_sfz(bms: &Bms, first_key: u8) -> SfzExport {
+    export_sfz_from_usage(bms, keysound_usage(bms), first_key)
+}
+
+/// Write an SFZ instrument from a keysound manifest, using pitch estimates when present.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `usage` - Keysound manifest of the chart, optionally with pitch estimates.
+/// * `first_key` - Key of the first keysound without a usable pitch.
+///
+/// # Returns
+///
+/// * `SfzExport` - SFZ text and the key mapping.
+pub fn export_sfz_from_usage(bms: &Bms, usage: Vec<KeysoundUsage>, first_key: u8) -> SfzExport {
+    let (keys, skipped) = assign_keys_from_usage(usage, first_key);
+    let mut sfz = String::new();
+    if let Some(title) = &bms.header.title {
+        let _ = writeln!(sfz, "// {}", title);
+    }
+    sfz.push_str("<control>\ndefault_path=./\n\n<group>\nloop_mode=one_shot\n\n");
+    for assignment in &keys {
+        let _ = writeln!(
+            sfz,
+            "<region> sample={} key={}",
+            assignment.filename.replace('\\', "/"),
+            assignment.key
+        );
+    }
+    SfzExport { sfz, keys, skipped }
+}
@@ -0,0 +1,79 @@
+use crate::bms::{Channel, ChartMode};
+
+/// Lane group rendered to its own stereo pair in multichannel stem output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StemGroup {
+    /// Background keysounds (channel 01).
+    Bgm,
+    /// Turntable lanes of both players.
+    Scratch,
+    /// Key lanes of player 1, or all buttons or drums of a PMS or DTX chart.
+    Player1Keys,
+    /// Key lanes of player 2.
+    Player2Keys,
+}
+
+/// Stem groups in output order; group `i` occupies channels `2i` and `2i + 1`.
+pub const STEM_GROUPS: [StemGroup; 4] = [
+    StemGroup::Bgm,
+    StemGroup::Scratch,
+    StemGroup::Player1Keys,
+    StemGroup::Player2Keys,
+];
+
+/// Lane digit of the turntable in the BMS channel layout.
+const SCRATCH_LANE: u8 = 6;
+
+impl StemGroup {
+    /// Find the stem group of a keysounded channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - Channel of a sound event.
+    /// * `mode` - Button layout of the chart; PMS and DTX charts have a single player and no turntable.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<StemGroup>` - Group of the channel, or `None` for channels without audio.
+    pub fn of(channel: Channel, mode: ChartMode) -> Option<Self> {
+        match channel {
+            Channel::Bgm => Some(StemGroup::Bgm),
+            _ if mode == ChartMode::Pms => channel.pms_button().map(|_| StemGroup::Player1Keys),
+            // DTX drum lanes all belong to the single drummer.
+            Channel::Note { .. } if mode == ChartMode::Dtx => Some(StemGroup::Player1Keys),
+            Channel::Note { player, lane }
+            | Channel::Invisible { player, lane }
+            | Channel::LongNote { player, lane }
+            | Channel::Mine { player, lane } => Some(if lane == SCRATCH_LANE {
+                StemGroup::Scratch
+            } else if player == 1 {
+                StemGroup::Player1Keys
+            } else {
+                StemGroup::Player2Keys
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Interleave stereo stems into a single multichannel buffer.
+///
+/// # Arguments
+///
+/// * `stems` - Interleaved stereo buffers of equal length, one per output pair.
+///
+/// # Returns
+///
+/// * `Vec<f32>` - Interleaved buffer with `2 * stems.len()` channels.
+pub fn interleave_stems(stems: &[Vec<f32>]) -> Vec<f32> {
+    let out_channels = stems.len() * 2;
+    let frames = stems.first().map_or(0, |stem| stem.len() / 2);
+    let mut out = vec![0.0f32; frames * out_channels];
+    for (pair, stem) in stems.iter().enumerate() {
+        for (frame, samples) in stem.as_chunks::<2>().0.iter().enumerate() {
+            let base = frame * out_channels + pair * 2;
+            out[base..base + 2].copy_from_slice(samples);
+        }
+    }
+    out
+}
@@ -0,0 +1,167 @@
+use crate::bms::{
+    BMS_FIELD_PREFIX, Bms, ChartMode, DataLine, ParseError, ParseOptions, ParseReport,
+    standard_measure,
+};
+use crate::control::IncrementalControlFlow;
+use crate::dtx::parse_dtx;
+use crate::encoding::{TextEncoding, detect_encoding};
+use crate::random::SplitMix64;
+
+/// Incremental chart parser for input that arrives in chunks.
+///
+/// Only the current incomplete line is buffered; every complete line is
+/// decoded and parsed as soon as it arrives, so memory grows with the parsed
+/// chart rather than with the file. Lines are split on `\n` before decoding,
+/// which is safe for UTF-8, Shift-JIS and EUC-KR alike.
+///
+/// The result matches `Bms::parse_with_options` for charts that define
+/// `#BASE` before their data lines and place `#DEF` after the `#CASE` lines
+/// of its `#SWITCH` block. DTX input is still collected and parsed in
+/// `finish`, since it is a different format.
+pub struct StreamingParser {
+    options: ParseOptions,
+    encoding: Option<TextEncoding>,
+    pending: Vec<u8>,
+    line_no: usize,
+    control: IncrementalControlFlow<SplitMix64>,
+    bms: Bms,
+    report: ParseReport,
+    dtx_text: String,
+}
+
+impl StreamingParser {
+    /// Start parsing a chart.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - Parsing options.
+    /// * `encoding` - Encoding of the bytes, or `None` to detect it from the
+    ///   first chunk that contains non-ASCII text.
+    ///
+    /// # Returns
+    ///
+    /// * `StreamingParser` - Parser waiting for the first chunk.
+    pub fn new(options: ParseOptions, encoding: Option<TextEncoding>) -> Self {
+        Self {
+            encoding,
+            pending: Vec::new(),
+            line_no: 0,
+            control: IncrementalControlFlow::new(
+                SplitMix64::new(options.random_seed),
+                &options.forced_random,
+            ),
+            bms: Bms {
+                mode: options.mode,
+                ..Bms::default()
+            },
+            options,
+            report: ParseReport::default(),
+            dtx_text: String::new(),
+        }
+    }
+
+    /// Feed the next chunk of raw bytes.
+    ///
+    /// Chunks may end anywhere, including inside a line or a multi-byte character.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - Next bytes of the file.
+    pub fn push_bytes(&mut self, chunk: &[u8]) {
+        self.pending.extend_from_slice(chunk);
+        let Some(end) = self.pending.iter().rposition(|&b| b == b'\n') else {
+            return;
+        };
+        let complete: Vec<u8> = self.pending.drain(..=end).collect();
+        let complete = &complete[..end];
+        if self.encoding.is_none() && !complete.is_ascii() {
+            self.encoding = Some(detect_encoding(complete));
+        }
+        for line in complete.split(|&b| b == b'\n') {
+            self.feed_line(line);
+        }
+    }
+
+    /// Feed the next chunk of already decoded text.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - Next text of the file.
+    pub fn push_str(&mut self, chunk: &str) {
+        self.encoding.get_or_insert(TextEncoding::Utf8);
+        self.push_bytes(chunk.as_bytes());
+    }
+
+    /// Decode and parse one complete line.
+    fn feed_line(&mut self, bytes: &[u8]) {
+        self.line_no += 1;
+        if bytes.is_empty() {
+            return;
+        }
+        let text = match self.encoding {
+            Some(encoding) => encoding.encoding().decode_with_bom_removal(bytes).0,
+            // Only ASCII lines arrive before the encoding is known.
+            None => String::from_utf8_lossy(bytes),
+        };
+        let line = text.trim();
+
+        if self.options.mode == ChartMode::Dtx {
+            self.dtx_text.push_str(line);
+            self.dtx_text.push('\n');
+            return;
+        }
+        if !self.control.keep(self.line_no, line) || line.starts_with(BMS_FIELD_PREFIX) {
+            return;
+        }
+        let measure_parser = self.options.measure_parser.unwrap_or(standard_measure);
+        if DataLine::matches(line, measure_parser) {
+            let data_line = DataLine::parse(
+                line,
+                self.bms.header.object_base(),
+                self.options.strict,
+                measure_parser,
+            );
+            self.bms
+                .add_data_line(self.line_no, data_line, &mut self.report);
+        } else if let Some((category, problem)) = self
+            .bms
+            .header
+            .parse_line(line, self.options.duplicate_policy)
+            && self.options.reports(category)
+        {
+            self.report.push(
+                self.line_no,
+                self.options.table_severity(),
+                category,
+                problem,
+            );
+        }
+    }
+
+    /// Parse the final unterminated line and return the chart.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Bms, ParseReport), ParseError>` - Parsed chart and diagnostics, or the
+    ///   diagnostics in strict mode if any line was malformed.
+    pub fn finish(mut self) -> Result<(Bms, ParseReport), ParseError> {
+        if !self.pending.is_empty() {
+            let rest = std::mem::take(&mut self.pending);
+            if self.encoding.is_none() && !rest.is_ascii() {
+                self.encoding = Some(detect_encoding(&rest));
+            }
+            self.feed_line(&rest);
+        }
+        let (bms, mut report) = if self.options.mode == ChartMode::Dtx {
+            parse_dtx(&self.dtx_text)
+        } else {
+            self.bms.random_choices = self.control.choices().to_vec();
+            (self.bms, self.report)
+        };
+        report.diagnostics.sort_by_key(|d| d.line);
+        if self.options.strict && report.has_errors() {
+            return Err(ParseError::Strict(report));
+        }
+        Ok((bms, report))
+    }
+}
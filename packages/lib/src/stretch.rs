@@ -0,0 +1,380 @@
+use crate::audio::{RateConverter, ResampleMethod};
+
+/// Length of a synthesis window in seconds.
+const WINDOW_SEC: f64 = 0.04;
+
+/// Largest shift searched around the nominal read position, in seconds.
+const SEEK_SEC: f64 = 0.008;
+
+/// Only every Nth frame of the overlap is compared when searching for the best shift.
+const SIMILARITY_STRIDE: usize = 4;
+
+/// Slowest playback rate accepted by `TimeStretch`.
+pub const MIN_RATE: f64 = 0.25;
+
+/// Fastest playback rate accepted by `TimeStretch`.
+pub const MAX_RATE: f64 = 4.0;
+
+/// Largest shift accepted by `PitchShift`, in semitones either way.
+pub const MAX_SEMITONES: f64 = 12.0;
+
+/// Number of output frames `TimeStretch` produces for an input.
+///
+/// # Arguments
+///
+/// * `frames` - Input length in frames.
+/// * `rate` - Playback rate.
+///
+/// # Returns
+///
+/// * `usize` - Output length in frames.
+pub fn stretched_frames(frames: usize, rate: f64) -> usize {
+    (frames as f64 / rate).round() as usize
+}
+
+/// Streaming pitch-preserving time stretch (WSOLA).
+///
+/// Input is cut into Hann-windowed frames read at `rate` times the output
+/// hop and overlap-added. Each read position is shifted by up to a few
+/// milliseconds to where the audio best continues the previous frame, which
+/// keeps periodic sounds in phase instead of smearing them like plain
+/// overlap-add. Pitch and timbre are unchanged; only the speed differs.
+///
+/// Feed interleaved input with `process` in chunks of any size, then call
+/// `finish`; the concatenated output is exactly
+/// `stretched_frames(input_frames, rate)` frames long.
+pub struct TimeStretch {
+    rate: f64,
+    channels: usize,
+    /// Output hop in frames; windows are twice as long.
+    hop: usize,
+    seek: usize,
+    window: Vec<f32>,
+    /// Buffered input, starting at frame `input_start`.
+    input: Vec<f32>,
+    input_start: usize,
+    input_frames: usize,
+    /// Index of the next synthesis frame.
+    frame: usize,
+    /// Read position of the previous synthesis frame.
+    last_read: i64,
+    /// Windowed second half of the previous frame, awaiting the next one.
+    tail: Vec<f32>,
+    emitted: usize,
+}
+
+impl TimeStretch {
+    /// Create a stretcher.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Playback rate (`0.7` plays at 70% speed), clamped to `MIN_RATE..=MAX_RATE`
+    ///   widened by the octave a `PitchShift` may add.
+    /// * `sample_rate` - Sample rate of the audio.
+    /// * `channels` - Number of interleaved channels.
+    ///
+    /// # Returns
+    ///
+    /// * `TimeStretch` - Stretcher waiting for input.
+    pub fn new(rate: f64, sample_rate: u32, channels: usize) -> Self {
+        let hop = ((WINDOW_SEC * sample_rate as f64 / 2.0) as usize).max(1);
+        let size = hop * 2;
+        // Periodic Hann windows at 50% overlap sum to exactly one.
+        let window = (0..size)
+            .map(|i| {
+                let phase = std::f64::consts::TAU * i as f64 / size as f64;
+                (0.5 - 0.5 * phase.cos()) as f32
+            })
+            .collect();
+        Self {
+            rate: rate.clamp(MIN_RATE / 2.0, MAX_RATE * 2.0),
+            channels,
+            hop,
+            seek: (SEEK_SEC * sample_rate as f64) as usize,
+            window,
+            input: Vec::new(),
+            input_start: 0,
+            input_frames: 0,
+            frame: 0,
+            last_read: 0,
+            tail: vec![0.0; hop * channels],
+            emitted: 0,
+        }
+    }
+
+    /// Feed the next chunk of input.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` - Interleaved input samples.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<f32>` - Interleaved output that became available.
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        self.input.extend_from_slice(samples);
+        self.input_frames += samples.len() / self.channels;
+        let mut out = Vec::new();
+        while self.nominal_read(self.frame) + (self.seek + 2 * self.hop) as i64
+            <= self.input_frames as i64
+        {
+            self.synthesize(&mut out);
+        }
+        out
+    }
+
+    /// Flush the remaining output once all input has been fed.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<f32>` - Interleaved output completing the stretched audio.
+    pub fn finish(mut self) -> Vec<f32> {
+        let total = stretched_frames(self.input_frames, self.rate);
+        let mut out = Vec::new();
+        while self.emitted < total {
+            self.synthesize(&mut out);
+        }
+        let excess = self.emitted - total;
+        out.truncate(out.len() - excess * self.channels);
+        out
+    }
+
+    /// Input frame where synthesis frame `k` would be read without any shift.
+    fn nominal_read(&self, k: usize) -> i64 {
+        // Frames are centered on their position, so the first starts before the audio.
+        (k as f64 * self.hop as f64 * self.rate).round() as i64 - self.hop as i64
+    }
+
+    /// Input sample at an absolute frame position, silent outside the audio.
+    #[inline]
+    fn at(&self, frame: i64, ch: usize) -> f32 {
+        if frame < self.input_start as i64 || frame >= self.input_frames as i64 {
+            return 0.0;
+        }
+        self.input[(frame as usize - self.input_start) * self.channels + ch]
+    }
+
+    /// Sum of all channels at an absolute frame position.
+    #[inline]
+    fn mono(&self, frame: i64) -> f32 {
+        (0..self.channels).map(|ch| self.at(frame, ch)).sum()
+    }
+
+    /// Read position near `nominal` whose audio best continues the previous frame.
+    fn best_read(&self, nominal: i64) -> i64 {
+        if self.frame == 0 {
+            return nominal;
+        }
+        let natural = self.last_read + self.hop as i64;
+        let reference: Vec<f32> = (0..self.hop)
+            .step_by(SIMILARITY_STRIDE)
+            .map(|i| self.mono(natural + i as i64))
+            .collect();
+        let seek = self.seek as i64;
+        let mut best = (nominal, f32::MIN);
+        for shift in -seek..=seek {
+            let start = nominal + shift;
+            let mut dot = 0.0f32;
+            let mut energy = 0.0f32;
+            for (j, r) in reference.iter().enumerate() {
+                let v = self.mono(start + (j * SIMILARITY_STRIDE) as i64);
+                dot += v * r;
+                energy += v * v;
+            }
+            let score = dot / (energy.sqrt() + 1e-9);
+            if score > best.1 {
+                best = (start, score);
+            }
+        }
+        best.0
+    }
+
+    /// Overlap-add the next synthesis frame and append the completed output.
+    fn synthesize(&mut self, out: &mut Vec<f32>) {
+        let read = self.best_read(self.nominal_read(self.frame));
+        let channels = self.channels;
+        let half = self.hop * channels;
+        let mut head = vec![0.0f32; half];
+        let mut tail = vec![0.0f32; half];
+        for i in 0..self.hop * 2 {
+            let w = self.window[i];
+            let (dest, base) = if i < self.hop {
+                (&mut head, i * channels)
+            } else {
+                (&mut tail, (i - self.hop) * channels)
+            };
+            for ch in 0..channels {
+                dest[base + ch] = self.at(read + i as i64, ch) * w;
+            }
+        }
+        // The first frame only covers time before the audio starts.
+        if self.frame > 0 {
+            out.extend(self.tail.iter().zip(&head).map(|(a, b)| a + b));
+            self.emitted += self.hop;
+        }
+        self.tail = tail;
+        self.last_read = read;
+        self.frame += 1;
+
+        let keep_from = (self.nominal_read(self.frame) - self.seek as i64)
+            .min(self.last_read + self.hop as i64)
+            .max(0) as usize;
+        if keep_from > self.input_start {
+            let drop = (keep_from - self.input_start).min(self.input.len() / channels);
+            self.input.drain(..drop * channels);
+            self.input_start += drop;
+        }
+    }
+}
+
+/// Streaming pitch shift of a full mix, optionally combined with a speed change.
+///
+/// The audio is first time-stretched by the pitch ratio, then resampled by
+/// the same ratio, which restores its length while moving every frequency.
+/// The resampling runs at the rate nearest the exact ratio, which is well
+/// within a cent of it.
+///
+/// Like `TimeStretch`, the concatenated output of `process` and `finish` is
+/// exactly `stretched_frames(input_frames, rate)` frames long.
+pub struct PitchShift {
+    stretch: TimeStretch,
+    converter: RateConverter,
+    rate: f64,
+    channels: usize,
+    input_frames: usize,
+    emitted: usize,
+}
+
+impl PitchShift {
+    /// Create a pitch shifter.
+    ///
+    /// # Arguments
+    ///
+    /// * `semitones` - Shift in semitones, clamped to `MAX_SEMITONES` either way.
+    /// * `rate` - Playback rate, as for `TimeStretch`.
+    /// * `sample_rate` - Sample rate of the audio.
+    /// * `channels` - Number of interleaved channels.
+    /// * `quality` - Resampling quality.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<PitchShift, String>` - Shifter waiting for input, or an error if the
+    ///   resampler cannot be created.
+    pub fn new(
+        semitones: f64,
+        rate: f64,
+        sample_rate: u32,
+        channels: usize,
+        quality: ResampleMethod,
+    ) -> Result<Self, String> {
+        let ratio = 2f64.powf(semitones.clamp(-MAX_SEMITONES, MAX_SEMITONES) / 12.0);
+        let rate = rate.clamp(MIN_RATE, MAX_RATE);
+        let source_rate = (sample_rate as f64 * ratio).round() as u32;
+        Ok(Self {
+            stretch: TimeStretch::new(rate / ratio, sample_rate, channels),
+            converter: RateConverter::new(quality, source_rate, channels, sample_rate, channels)?,
+            rate,
+            channels,
+            input_frames: 0,
+            emitted: 0,
+        })
+    }
+
+    /// Feed the next chunk of input.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` - Interleaved input samples.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<f32>, String>` - Interleaved output that became available, or an
+    ///   error from the resampler.
+    pub fn process(&mut self, samples: &[f32]) -> Result<Vec<f32>, String> {
+        self.input_frames += samples.len() / self.channels;
+        let mut out = Vec::new();
+        self.converter
+            .push(&self.stretch.process(samples), &mut out)?;
+        self.emitted += out.len() / self.channels;
+        Ok(out)
+    }
+
+    /// Flush the remaining output once all input has been fed.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<f32>, String>` - Interleaved output completing the shifted audio,
+    ///   or an error from the resampler.
+    pub fn finish(mut self) -> Result<Vec<f32>, String> {
+        let mut out = Vec::new();
+        self.converter.push(&self.stretch.finish(), &mut out)?;
+        self.converter.finish(&mut out)?;
+        // Resampling rounds the length; pad or trim to the promised one.
+        let remaining = stretched_frames(self.input_frames, self.rate).saturating_sub(self.emitted);
+        out.resize(remaining * self.channels, 0.0);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// One second of a stereo sine.
+    fn sine(hz: f64) -> Vec<f32> {
+        (0..SAMPLE_RATE)
+            .flat_map(|i| {
+                let s = (std::f64::consts::TAU * hz * i as f64 / SAMPLE_RATE as f64).sin() as f32;
+                [s, s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stretch_length_follows_the_rate() {
+        let input = sine(440.0);
+        for rate in [0.5, 0.7, 1.0, 1.5, 3.0] {
+            let mut stretch = TimeStretch::new(rate, SAMPLE_RATE, 2);
+            // Uneven chunks exercise the buffering between calls.
+            let mut out: Vec<f32> = input
+                .chunks(1234)
+                .flat_map(|c| stretch.process(c))
+                .collect();
+            out.extend(stretch.finish());
+            let expected = (SAMPLE_RATE as f64 / rate).round() as usize;
+            assert_eq!(out.len(), expected * 2, "rate {rate}");
+        }
+    }
+
+    /// Frequency of a stereo signal's left channel from its rising zero crossings,
+    /// ignoring the first and last tenth.
+    fn dominant_hz(samples: &[f32]) -> f64 {
+        let left: Vec<f32> = samples.iter().step_by(2).copied().collect();
+        let body = &left[left.len() / 10..left.len() * 9 / 10];
+        let crossings = body
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count();
+        crossings as f64 * SAMPLE_RATE as f64 / body.len() as f64
+    }
+
+    #[test]
+    fn pitch_shift_moves_a_sine() {
+        let input = sine(440.0);
+        for (semitones, rate) in [(12.0, 1.0), (-5.0, 1.0), (7.0, 0.75)] {
+            let mut shift =
+                PitchShift::new(semitones, rate, SAMPLE_RATE, 2, ResampleMethod::Sinc).unwrap();
+            let mut out = shift.process(&input).unwrap();
+            out.extend(shift.finish().unwrap());
+            assert_eq!(out.len(), stretched_frames(SAMPLE_RATE as usize, rate) * 2);
+            let expected = 440.0 * 2f64.powf(semitones / 12.0);
+            let hz = dominant_hz(&out);
+            assert!(
+                (hz - expected).abs() < expected * 0.02,
+                "{semitones} semitones: expected {expected} Hz, got {hz} Hz"
+            );
+        }
+    }
+}
@@ -1,6 +1,9 @@
-use crate::bms::Bms;
+use crate::bms::{
+    BgaLayer, Bms, Channel, EventKind, MeasureIndex, ObjectId, format_object_id_with_base,
+};
 use ahash::AHashMap;
-use std::collections::HashSet;
+use num_enum::TryFromPrimitive;
+use serde::{Deserialize, Serialize};
 
 /// A scheduled audio event on the timeline.
 #[derive(Clone)]
@@ -9,15 +12,43 @@ pub struct SoundEvent {
     pub key_id: usize,
     /// Start position in the output buffer.
     pub start: usize,
-    /// Optional exclusive end position in the output buffer.
+    /// Exclusive end position in the output buffer, from a long-note release
+    /// (`cut_long_notes`), a channel volume change (`cut_at_volume_changes`) or
+    /// the end of a range render (`end_measure`). `None` plays the keysound to
+    /// its end; an end past it does not lengthen the sound.
     pub end: Option<usize>,
+    /// Linear gain applied while mixing (`#VOLWAV` times the channel 97/98 volume and the
+    /// per-sound `#VOLUME`).
+    pub gain: f32,
+    /// Channel the event was scheduled from.
+    pub channel: Channel,
+    /// Pitch offset in semitones from `#WAVCMD`, applied by `apply_pitch_shifts`.
+    pub semitones: i8,
+    /// Stereo pan from -100 (left) to 100 (right) from `#PAN`, applied by `apply_pans`.
+    pub pan: i8,
+    /// Index of the channel 01 line the event came from among the BGM lines of
+    /// its measure, in file order, so parallel BGM columns stay apart. Always 0
+    /// for other channels.
+    pub bgm_lane: u16,
+}
+
+impl SoundEvent {
+    /// Kind of chart object that scheduled this event.
+    ///
+    /// # Returns
+    ///
+    /// * `EventKind` - Event kind derived from the channel.
+    pub fn kind(&self) -> EventKind {
+        self.channel.event_kind().unwrap_or(EventKind::Bgm)
+    }
 }
 
 /// A point-in-time tempo marker with its absolute timestamp.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "chart-serde", derive(Serialize, Deserialize))]
 pub struct TempoEvent {
     /// Measure index where this tempo applies.
-    pub measure: u16,
+    pub measure: MeasureIndex,
     /// Position within the measure.
     pub position: f64,
     /// Beats per minute at this point.
@@ -27,13 +58,14 @@ pub struct TempoEvent {
 }
 
 /// A precomputed tempo timeline and helpers to convert musical time to seconds.
+#[cfg_attr(feature = "chart-serde", derive(Serialize, Deserialize))]
 pub struct TempoMap {
     /// The first measure index covered by this map.
-    pub base_measure: u16,
+    pub base_measure: MeasureIndex,
     /// Ordered tempo events along the timeline.
     pub events: Vec<TempoEvent>,
     /// Per-measure multipliers.
-    measure_multipliers: AHashMap<u16, f64>,
+    measure_multipliers: AHashMap<MeasureIndex, f64>,
     /// Multipliers as a dense vector indexed from `base_measure`.
     mult_vec: Vec<f64>,
     /// Cumulative multipliers to speed up span queries between measures.
@@ -51,7 +83,7 @@ impl TempoMap {
     /// # Returns
     ///
     /// * `f64` - Timestamp in seconds.
-    pub fn get_timestamp(&self, measure: u16, position: f64) -> f64 {
+    pub fn get_timestamp(&self, measure: MeasureIndex, position: f64) -> f64 {
         if measure < self.base_measure {
             return 0.0;
         }
@@ -96,7 +128,8 @@ impl TempoMap {
         let mult_from = self.mult_vec.get(idx_from).copied().unwrap_or(1.0);
         let mult_to = self.mult_vec.get(idx_to).copied().unwrap_or(1.0);
         let span_between = if idx_to > idx_from + 1 {
-            self.cum_mult[idx_to] - self.cum_mult[idx_from + 1]
+            measures_before(&self.mult_vec, &self.cum_mult, idx_to)
+                - measures_before(&self.mult_vec, &self.cum_mult, idx_from + 1)
         } else {
             0.0
         };
@@ -104,6 +137,31 @@ impl TempoMap {
         event.timestamp_sec + delta_measures * base_measure_sec
     }
 
+    /// Last measure covered by this map (including measures that only change length).
+    ///
+    /// # Returns
+    ///
+    /// * `MeasureIndex` - Index of the last measure.
+    pub fn last_measure(&self) -> MeasureIndex {
+        self.base_measure + self.mult_vec.len().saturating_sub(1) as MeasureIndex
+    }
+
+    /// Length multiplier of a measure (1.0 = 4/4).
+    ///
+    /// # Arguments
+    ///
+    /// * `measure` - Measure index.
+    ///
+    /// # Returns
+    ///
+    /// * `f64` - Measure length multiplier.
+    pub fn measure_multiplier(&self, measure: MeasureIndex) -> f64 {
+        self.measure_multipliers
+            .get(&measure)
+            .copied()
+            .unwrap_or(1.0)
+    }
+
     /// Convert a musical position to an absolute timestamp in samples.
     ///
     /// # Arguments
@@ -115,23 +173,193 @@ impl TempoMap {
     /// # Returns
     ///
     /// * `usize` - Timestamp in samples at the given sample rate.
-    pub fn get_timestamp_samples(&self, measure: u16, position: f64, sample_rate: u32) -> usize {
+    pub fn get_timestamp_samples(
+        &self,
+        measure: MeasureIndex,
+        position: f64,
+        sample_rate: u32,
+    ) -> usize {
         (self.get_timestamp(measure, position) * sample_rate as f64).round() as usize
     }
 }
 
 #[derive(Debug, Clone)]
 struct RawTempoChange {
-    measure: u16,
+    measure: MeasureIndex,
     position: f64,
     bpm: f64,
 }
 
 #[derive(Debug, Clone)]
 struct StopEvent {
-    measure: u16,
+    measure: MeasureIndex,
     position: f64,
     duration_192nds: f64,
+    duration_ms: f64,
+}
+
+impl StopEvent {
+    /// Length of the stop in seconds at the given tempo.
+    fn duration_sec(&self, bpm: f64) -> f64 {
+        (self.duration_192nds / 48.0) * (60.0 / bpm) + self.duration_ms / 1000.0
+    }
+}
+
+/// Smallest tempo used when clamping non-positive BPM values.
+pub const MIN_BPM: f64 = 1.0;
+
+/// How zero and negative BPM values are handled when building a tempo map.
+///
+/// Players freeze the chart at a zero tempo until the next tempo change. The
+/// default `Clamp` comes closest while keeping every timestamp finite: at
+/// `MIN_BPM` a beat lasts a minute, so the section plays as a long pause.
+/// `TreatAsStop` instead skips the section, for charts that use a zero tempo
+/// to stack objects.
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, TryFromPrimitive, Serialize)]
+pub enum BpmPolicy {
+    /// Raise the tempo to `MIN_BPM`, pausing the chart for a minute per beat.
+    #[default]
+    Clamp,
+    /// Let the section up to the next valid tempo take no time, so its
+    /// objects sound together at the tempo change.
+    TreatAsStop,
+    /// Refuse to build the tempo map.
+    Error,
+}
+
+impl<'de> Deserialize<'de> for BpmPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct BpmPolicyVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BpmPolicyVisitor {
+            type Value = BpmPolicy;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an i64")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                BpmPolicy::try_from(value as u8).map_err(|_| E::custom("Invalid BpmPolicy"))
+            }
+        }
+
+        deserializer.deserialize_any(BpmPolicyVisitor)
+    }
+}
+
+impl BpmPolicy {
+    /// Apply the policy to a tempo value.
+    ///
+    /// # Arguments
+    ///
+    /// * `bpm` - Tempo from the chart.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<f64>` - Tempo to use, or `None` if the policy rejects it.
+    fn resolve(self, bpm: f64) -> Option<f64> {
+        if bpm.is_finite() && bpm > 0.0 {
+            return Some(bpm);
+        }
+        match self {
+            BpmPolicy::Clamp => Some(MIN_BPM),
+            // Zero seconds per beat: positions advance without time passing.
+            BpmPolicy::TreatAsStop => Some(f64::INFINITY),
+            BpmPolicy::Error => None,
+        }
+    }
+}
+
+/// Order of a tempo change and a stop placed at the same position.
+///
+/// The order decides which tempo the stop's length (channel 09, in 192nds of
+/// a whole note) is measured in. `#STP` stops are given in milliseconds and
+/// last the same either way.
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, TryFromPrimitive, Serialize)]
+pub enum StopOrder {
+    /// The tempo changes first, so the stop lasts as long as at the new tempo,
+    /// as in LR2 and beatoraja.
+    #[default]
+    BpmFirst,
+    /// The stop runs first at the old tempo, then the tempo changes.
+    StopFirst,
+}
+
+impl<'de> Deserialize<'de> for StopOrder {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct StopOrderVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for StopOrderVisitor {
+            type Value = StopOrder;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an i64")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                StopOrder::try_from(value as u8).map_err(|_| E::custom("Invalid StopOrder"))
+            }
+        }
+
+        deserializer.deserialize_any(StopOrderVisitor)
+    }
+}
+
+/// A zero or negative tempo rejected by `BpmPolicy::Error`.
+#[derive(Debug, Clone, Copy)]
+pub struct InvalidBpm {
+    /// Measure of the tempo change.
+    pub measure: MeasureIndex,
+    /// Position within the measure.
+    pub position: f64,
+    /// Rejected tempo.
+    pub bpm: f64,
+}
+
+impl core::fmt::Display for InvalidBpm {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "invalid BPM {} at measure {} (position {})",
+            self.bpm, self.measure, self.position
+        )
+    }
+}
+
+impl std::error::Error for InvalidBpm {}
+
+/// Options controlling how a `TempoMap` is built.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TempoMapOptions {
+    /// Override for the chart's `#BPM`. Every tempo change is scaled by the same
+    /// ratio, so relative changes are preserved.
+    pub base_bpm: Option<f64>,
+    /// Handling of zero and negative tempos.
+    pub bpm_policy: BpmPolicy,
+    /// Start the timeline at measure 0 rather than at the first measure with data.
+    ///
+    /// Reference players keep the empty measures before a chart's first
+    /// message as silence, timed with the initial tempo and any measure length
+    /// changes; by default that lead-in is trimmed.
+    pub keep_leading_measures: bool,
+    /// Order of a tempo change and a stop at the same position.
+    pub stop_order: StopOrder,
 }
 
 /// Build a `TempoMap` from a parsed BMS chart.
@@ -144,9 +372,37 @@ struct StopEvent {
 ///
 /// * `TempoMap` - Precomputed tempo timeline with helpers.
 pub fn build_tempo_map(bms: &Bms) -> TempoMap {
-    let base_bpm = bms.header.bpm;
-    let base_measure = bms.messages.iter().map(|m| m.measure).min().unwrap_or(0);
-    let measure_multipliers: AHashMap<u16, f64> = bms.measure_multipliers.clone();
+    build_tempo_map_with_options(bms, &TempoMapOptions::default())
+        .expect("clamping BPM values never fails")
+}
+
+/// Build a `TempoMap` from a parsed BMS chart with custom options.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `options` - Tempo map options.
+///
+/// # Returns
+///
+/// * `Result<TempoMap, InvalidBpm>` - Precomputed tempo timeline, or the first
+///   non-positive tempo when the policy is `BpmPolicy::Error`.
+#[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+pub fn build_tempo_map_with_options(
+    bms: &Bms,
+    options: &TempoMapOptions,
+) -> Result<TempoMap, InvalidBpm> {
+    let bpm_scale = match options.base_bpm {
+        Some(bpm) if bpm.is_finite() && bpm > 0.0 && bms.header.bpm > 0.0 => bpm / bms.header.bpm,
+        _ => 1.0,
+    };
+    let base_bpm = bms.header.bpm * bpm_scale;
+    let base_measure = if options.keep_leading_measures {
+        0
+    } else {
+        bms.messages.iter().map(|m| m.measure).min().unwrap_or(0)
+    };
+    let measure_multipliers: AHashMap<MeasureIndex, f64> = bms.measure_multipliers.clone();
 
     let max_measure = bms
         .messages
@@ -189,23 +445,22 @@ pub fn build_tempo_map(bms: &Bms) -> TempoMap {
             }
 
             match message.channel {
-                3 => {
+                Channel::Bpm => {
                     // Channel 03: hex BPM (01-FF)
-                    let val = *object;
-                    let hex_val = (val / 36) * 16 + (val % 36);
+                    let hex_val = hex_object(*object, bms.header.object_base());
                     tempo_changes.push(RawTempoChange {
                         measure: message.measure,
                         position,
-                        bpm: hex_val as f64,
+                        bpm: hex_val as f64 * bpm_scale,
                     });
                 }
-                8 => {
+                Channel::ExBpm => {
                     // Channel 08: BPM table reference
                     if let Some(&bpm) = bms.header.bpm_table.get(object) {
                         tempo_changes.push(RawTempoChange {
                             measure: message.measure,
                             position,
-                            bpm,
+                            bpm: bpm * bpm_scale,
                         });
                     }
                 }
@@ -222,10 +477,18 @@ pub fn build_tempo_map(bms: &Bms) -> TempoMap {
         )
     });
 
+    for change in &mut tempo_changes {
+        change.bpm = options.bpm_policy.resolve(change.bpm).ok_or(InvalidBpm {
+            measure: change.measure,
+            position: change.position,
+            bpm: change.bpm,
+        })?;
+    }
+
     let mut stops: Vec<StopEvent> = Vec::with_capacity(bms.messages.len());
 
     for message in &bms.messages {
-        if message.channel != 9 {
+        if message.channel != Channel::Stop {
             continue;
         }
         let num_objects = message.objects.len() as f64;
@@ -243,11 +506,22 @@ pub fn build_tempo_map(bms: &Bms) -> TempoMap {
                     measure: message.measure,
                     position: (i as f64) / num_objects,
                     duration_192nds: stop_val,
+                    duration_ms: 0.0,
                 });
             }
         }
     }
 
+    // `#STP` stops are given in milliseconds and do not depend on the tempo.
+    for stp in &bms.header.ms_stops {
+        stops.push(StopEvent {
+            measure: stp.measure,
+            position: stp.position,
+            duration_192nds: 0.0,
+            duration_ms: stp.duration_ms,
+        });
+    }
+
     stops.sort_by(|a, b| {
         a.measure.cmp(&b.measure).then(
             a.position
@@ -263,15 +537,16 @@ pub fn build_tempo_map(bms: &Bms) -> TempoMap {
         base_measure,
         &mult_vec,
         &cum_mult,
+        options.stop_order,
     );
 
-    TempoMap {
+    Ok(TempoMap {
         base_measure,
         events,
         measure_multipliers,
         mult_vec,
         cum_mult,
-    }
+    })
 }
 
 /// Integrate tempo changes and stop events into a single ordered tempo timeline.
@@ -284,6 +559,7 @@ pub fn build_tempo_map(bms: &Bms) -> TempoMap {
 /// * `base_measure` - First measure covered.
 /// * `mult_vec` - Dense multipliers vector from `base_measure`.
 /// * `cum_mult` - Cumulative multipliers for fast span calculation.
+/// * `stop_order` - Order of a tempo change and a stop at the same position.
 ///
 /// # Returns
 ///
@@ -291,10 +567,11 @@ pub fn build_tempo_map(bms: &Bms) -> TempoMap {
 fn integrate_timeline(
     tempo_changes: &[RawTempoChange],
     stops: &[StopEvent],
-    measure_multipliers: &AHashMap<u16, f64>,
-    base_measure: u16,
+    measure_multipliers: &AHashMap<MeasureIndex, f64>,
+    base_measure: MeasureIndex,
     mult_vec: &[f64],
     cum_mult: &[f64],
+    stop_order: StopOrder,
 ) -> Vec<TempoEvent> {
     if tempo_changes.is_empty() {
         return Vec::new();
@@ -308,6 +585,20 @@ fn integrate_timeline(
 
     let mut stop_idx = 0;
 
+    let time_through_stop = |measure: MeasureIndex, position: f64, bpm: f64, stop: &StopEvent| {
+        calculate_time_between(
+            measure,
+            position,
+            stop.measure,
+            stop.position,
+            bpm,
+            measure_multipliers,
+            base_measure,
+            mult_vec,
+            cum_mult,
+        ) + stop.duration_sec(bpm)
+    };
+
     for tempo_change in tempo_changes {
         if tempo_change.measure > current_measure
             || (tempo_change.measure == current_measure && tempo_change.position > current_position)
@@ -315,25 +606,14 @@ fn integrate_timeline(
             while stop_idx < stops.len() {
                 let stop = &stops[stop_idx];
 
-                if stop.measure < tempo_change.measure
+                let before = stop.measure < tempo_change.measure
                     || (stop.measure == tempo_change.measure
-                        && stop.position < tempo_change.position)
-                {
-                    let time_to_stop = calculate_time_between(
-                        current_measure,
-                        current_position,
-                        stop.measure,
-                        stop.position,
-                        current_bpm,
-                        measure_multipliers,
-                        base_measure,
-                        mult_vec,
-                        cum_mult,
-                    );
-                    current_time += time_to_stop;
-
-                    let stop_duration_sec = (stop.duration_192nds / 48.0) * (60.0 / current_bpm);
-                    current_time += stop_duration_sec;
+                        && (stop.position < tempo_change.position
+                            || (stop_order == StopOrder::StopFirst
+                                && stop.position == tempo_change.position)));
+                if before {
+                    current_time +=
+                        time_through_stop(current_measure, current_position, current_bpm, stop);
 
                     current_measure = stop.measure;
                     current_position = stop.position;
@@ -377,6 +657,24 @@ fn integrate_timeline(
         current_bpm = tempo_change.bpm;
     }
 
+    // Stops after the last tempo change still delay everything that follows.
+    for stop in &stops[stop_idx..] {
+        if stop.measure < current_measure
+            || (stop.measure == current_measure && stop.position < current_position)
+        {
+            continue;
+        }
+        current_time += time_through_stop(current_measure, current_position, current_bpm, stop);
+        current_measure = stop.measure;
+        current_position = stop.position;
+        events.push(TempoEvent {
+            measure: current_measure,
+            position: current_position,
+            bpm: current_bpm,
+            timestamp_sec: current_time,
+        });
+    }
+
     events
 }
 
@@ -399,13 +697,13 @@ fn integrate_timeline(
 /// * `f64` - Time in seconds between the two positions.
 #[allow(clippy::too_many_arguments)]
 fn calculate_time_between(
-    from_measure: u16,
+    from_measure: MeasureIndex,
     from_position: f64,
-    to_measure: u16,
+    to_measure: MeasureIndex,
     to_position: f64,
     bpm: f64,
-    measure_multipliers: &AHashMap<u16, f64>,
-    base_measure: u16,
+    measure_multipliers: &AHashMap<MeasureIndex, f64>,
+    base_measure: MeasureIndex,
     mult_vec: &[f64],
     cum_mult: &[f64],
 ) -> f64 {
@@ -425,7 +723,8 @@ fn calculate_time_between(
     let mult_from = mult_vec.get(idx_from).copied().unwrap_or(1.0);
     let mult_to = mult_vec.get(idx_to).copied().unwrap_or(1.0);
     let span_between = if idx_to > idx_from + 1 {
-        cum_mult[idx_to] - cum_mult[idx_from + 1]
+        measures_before(mult_vec, cum_mult, idx_to)
+            - measures_before(mult_vec, cum_mult, idx_from + 1)
     } else {
         0.0
     };
@@ -433,6 +732,205 @@ fn calculate_time_between(
     delta_measures * base_measure_sec
 }
 
+/// Length in whole measures of everything before measure `idx` (counted from the base measure).
+///
+/// Measures past the end of the table, such as those only named by a `#STP`
+/// stop, have the default length.
+fn measures_before(mult_vec: &[f64], cum_mult: &[f64], idx: usize) -> f64 {
+    match cum_mult.get(idx) {
+        Some(&sum) => sum,
+        None => {
+            let total =
+                cum_mult.last().copied().unwrap_or(0.0) + mult_vec.last().copied().unwrap_or(0.0);
+            total + (idx - cum_mult.len()) as f64
+        }
+    }
+}
+
+/// Assign dense buffer ids to the distinct audio filenames of a chart.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+///
+/// # Returns
+///
+/// * `(Vec<String>, AHashMap<String, usize>)` - Sorted unique filenames and the
+///   mapping from filename to its index in that list.
+pub fn index_audio_files(bms: &Bms) -> (Vec<String>, AHashMap<String, usize>) {
+    let mut filenames: Vec<String> = bms.header.audio_files.values().cloned().collect();
+    filenames.sort();
+    filenames.dedup();
+    let filename_to_id: AHashMap<String, usize> = filenames
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (f.clone(), i))
+        .collect();
+    (filenames, filename_to_id)
+}
+
+/// Resolve every `#WAV` slot to its decoded buffer id up front.
+///
+/// Objects are looked up by indexing the returned table instead of going
+/// through the filename, which keeps per-object work free of string hashing.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `filename_to_id` - Mapping from audio filename to decoded buffer id.
+///
+/// # Returns
+///
+/// * `Vec<Option<usize>>` - Buffer id indexed by object id, `None` for undefined slots.
+pub fn object_key_ids(bms: &Bms, filename_to_id: &AHashMap<String, usize>) -> Vec<Option<usize>> {
+    let audio = &bms.header.audio_files;
+    let len = audio.keys().max().map_or(0, |&max| max as usize + 1);
+    let mut key_ids = vec![None; len];
+    for (&object, filename) in audio {
+        key_ids[object as usize] = filename_to_id.get(filename).copied();
+    }
+    key_ids
+}
+
+/// Value of an object on a channel that holds hexadecimal numbers (03, 97 and 98).
+///
+/// Objects are decoded in the chart's base, so the two digits are split
+/// again in that base and reread as hexadecimal.
+///
+/// # Arguments
+///
+/// * `object` - Object decoded with the chart's base.
+/// * `base` - Object id base of the chart.
+///
+/// # Returns
+///
+/// * `u32` - Hexadecimal value of the two characters.
+fn hex_object(object: ObjectId, base: u32) -> u32 {
+    // Under `#BASE 62` lowercase letters follow the uppercase ones, but are the same hex digits.
+    let digit = |d: u32| if d >= 36 { d - 26 } else { d };
+    digit(object as u32 / base) * 16 + digit(object as u32 % base)
+}
+
+/// Volume automation from `#VOLWAV` and the BGM (97) and key (98) volume channels.
+struct VolumeTrack {
+    /// Global gain from `#VOLWAV`.
+    base: f32,
+    /// BGM volume changes as (measure, position, gain), sorted.
+    bgm: Vec<(MeasureIndex, f64, f32)>,
+    /// Key volume changes as (measure, position, gain), sorted.
+    keys: Vec<(MeasureIndex, f64, f32)>,
+}
+
+impl VolumeTrack {
+    fn new(bms: &Bms) -> Self {
+        let base = bms.header.object_base();
+        let mut bgm = Vec::new();
+        let mut keys = Vec::new();
+        for message in &bms.messages {
+            let target = match message.channel {
+                Channel::BgmVolume => &mut bgm,
+                Channel::KeyVolume => &mut keys,
+                _ => continue,
+            };
+            let len = message.objects.len() as f64;
+            for (i, &object) in message.objects.iter().enumerate() {
+                if object == 0 {
+                    continue;
+                }
+                // Values are hexadecimal 01-FF like channel 03, FF being full volume.
+                let level = hex_object(object, base);
+                target.push((message.measure, i as f64 / len, level as f32 / 255.0));
+            }
+        }
+        for changes in [&mut bgm, &mut keys] {
+            changes.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        }
+        Self {
+            base: bms.header.vol_wav.unwrap_or(100.0) as f32 / 100.0,
+            bgm,
+            keys,
+        }
+    }
+
+    /// Gain of an object on `channel` at the given position.
+    fn gain(&self, channel: Channel, measure: MeasureIndex, position: f64) -> f32 {
+        let changes = if channel == Channel::Bgm {
+            &self.bgm
+        } else {
+            &self.keys
+        };
+        let idx = changes.partition_point(|&(m, p, _)| (m, p) <= (measure, position));
+        let level = if idx == 0 { 1.0 } else { changes[idx - 1].2 };
+        self.base * level
+    }
+}
+
+/// Maximum number of example timestamps kept per drop reason.
+const MAX_DROP_EXAMPLES: usize = 5;
+
+/// Why a note could not be rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DropReason {
+    /// The object id has no `#WAVxx` entry.
+    UndefinedId,
+    /// The referenced file was not provided.
+    MissingFile,
+    /// The referenced file could not be decoded.
+    DecodeFailure,
+}
+
+/// Number of notes dropped for one reason, with a few of their times.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DropStats {
+    /// Number of dropped notes.
+    pub count: usize,
+    /// Chart times (in seconds) of the earliest dropped notes, in time order.
+    pub example_times: Vec<f64>,
+}
+
+/// Notes that were skipped while building the render, by reason.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DroppedObjects {
+    /// Notes whose object id is missing from the `#WAV` table.
+    pub undefined_id: DropStats,
+    /// Notes whose file was not provided.
+    pub missing_file: DropStats,
+    /// Notes whose file failed to decode.
+    pub decode_failure: DropStats,
+}
+
+impl DroppedObjects {
+    /// Record a dropped note.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the note was dropped.
+    /// * `time_sec` - Chart time of the note in seconds.
+    pub fn record(&mut self, reason: DropReason, time_sec: f64) {
+        let stats = match reason {
+            DropReason::UndefinedId => &mut self.undefined_id,
+            DropReason::MissingFile => &mut self.missing_file,
+            DropReason::DecodeFailure => &mut self.decode_failure,
+        };
+        stats.count += 1;
+        // Messages are not in time order, so keep the earliest times seen so far.
+        let at = stats.example_times.partition_point(|&t| t <= time_sec);
+        if at < MAX_DROP_EXAMPLES {
+            stats.example_times.insert(at, time_sec);
+            stats.example_times.truncate(MAX_DROP_EXAMPLES);
+        }
+    }
+
+    /// Total number of dropped notes.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - Sum over all reasons.
+    pub fn total(&self) -> usize {
+        self.undefined_id.count + self.missing_file.count + self.decode_failure.count
+    }
+}
+
 /// Extract timeline `SoundEvent`s from a BMS chart and a tempo map.
 ///
 /// # Arguments
@@ -453,23 +951,361 @@ pub fn extract_sound_events(
     sample_rate: u32,
     channels: usize,
 ) -> Vec<SoundEvent> {
+    extract_sound_events_with_drops(
+        bms,
+        tempo_map,
+        filename_to_id,
+        sample_rate,
+        channels,
+        &SoundEventOptions::default(),
+    )
+    .0
+}
+
+/// How `#LNTYPE 1` objects on a long-note channel pair into heads and tails.
+///
+/// Either way, objects pair in time order across messages and measures, and
+/// `00` slots are empty rather than ending a note.
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, TryFromPrimitive, Serialize)]
+pub enum LnPairing {
+    /// Non-zero objects alternately start and release a note on their
+    /// channel, whatever their ids, so a tail with its own id still ends
+    /// the note.
+    #[default]
+    Tolerant,
+    /// A note is only released by the next object with its head's id; an
+    /// object with another id starts a note of its own.
+    Strict,
+}
+
+impl<'de> Deserialize<'de> for LnPairing {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct LnPairingVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for LnPairingVisitor {
+            type Value = LnPairing;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an i64")
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                LnPairing::try_from(value as u8).map_err(|_| E::custom("Invalid LnPairing"))
+            }
+        }
+
+        deserializer.deserialize_any(LnPairingVisitor)
+    }
+}
+
+/// Options controlling which channels produce `SoundEvent`s.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SoundEventOptions {
+    /// Also play the keysounds of invisible notes (channels 31-39 and 41-49).
+    pub include_invisible_notes: bool,
+    /// Play the `#WAV00` sound at every mine (channels D1-D9 and E1-E9), as if
+    /// each one were hit.
+    pub mine_hit_sound: bool,
+    /// End the keysound of a long note at its release instead of letting it ring out.
+    pub cut_long_notes: bool,
+    /// How `#LNTYPE 1` heads and tails are paired.
+    pub ln_pairing: LnPairing,
+    /// End sounds still playing when the volume of their channel (97 for BGM,
+    /// 98 for keys) changes, instead of letting them ring out at the volume
+    /// they started with.
+    pub cut_at_volume_changes: bool,
+    /// Render only up to the start of this measure: later objects are skipped
+    /// and sounds still playing there are cut.
+    pub end_measure: Option<MeasureIndex>,
+    /// Leave `#LNOBJ` tail objects silent, as releases of their long notes.
+    /// By default a tail with a `#WAV` entry plays like any other note.
+    pub silent_ln_tails: bool,
+}
+
+/// A long note on a long-note channel, from an `#LNTYPE 1` pair or an `#LNTYPE 2` hold.
+struct LnHold {
+    channel: Channel,
+    measure: MeasureIndex,
+    position: f64,
+    /// Object that starts the hold and provides its keysound.
+    object: ObjectId,
+    /// Measure and position of the release, or `None` if the chart ends first.
+    release: Option<(MeasureIndex, f64)>,
+}
+
+/// Collect the `#LNTYPE 2` (MGQ) holds of a chart.
+///
+/// Consecutive non-zero objects on a long-note channel form one hold, even
+/// across messages and measures. The first object starts the hold and plays;
+/// the others only fill it. The hold is released at the first empty slot,
+/// `#LNOBJ` object or measure without data on its channel. Lines on the same
+/// channel and measure overlay each other, so an empty slot only counts where
+/// no other line has an object at the same time.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+///
+/// # Returns
+///
+/// * `Vec<LnHold>` - Holds in order of their start.
+fn mgq_holds(bms: &Bms) -> Vec<LnHold> {
+    let last_measure = bms.messages.iter().map(|m| m.measure).max().unwrap_or(0);
+    let mut holds: Vec<LnHold> = Vec::new();
+    // Index of the open hold on each channel and the last measure it was filled in.
+    let mut open: AHashMap<Channel, (usize, MeasureIndex)> = AHashMap::new();
+    let mut objects = long_note_objects(bms);
+    // Objects come first among slots at the same time on the same channel,
+    // and only the first slot is kept.
+    objects.sort_by(|a, b| {
+        (a.0, a.2.code())
+            .cmp(&(b.0, b.2.code()))
+            .then(a.1.total_cmp(&b.1))
+            .then((a.3 == 0).cmp(&(b.3 == 0)))
+    });
+    objects.dedup_by(|slot, kept| (slot.0, slot.1, slot.2) == (kept.0, kept.1, kept.2));
+    objects.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    for (measure, position, channel, object) in objects {
+        if let Some(&(idx, filled)) = open.get(&channel)
+            && measure > filled + 1
+        {
+            open.remove(&channel);
+            holds[idx].release = Some((filled + 1, 0.0));
+        }
+        let ends = object == 0 || Some(object) == bms.header.ln_obj;
+        match (open.get(&channel).copied(), ends) {
+            (Some((idx, _)), true) => {
+                open.remove(&channel);
+                holds[idx].release = Some((measure, position));
+            }
+            (Some((idx, _)), false) => {
+                open.insert(channel, (idx, measure));
+            }
+            (None, false) => {
+                open.insert(channel, (holds.len(), measure));
+                holds.push(LnHold {
+                    channel,
+                    measure,
+                    position,
+                    object,
+                    release: None,
+                });
+            }
+            (None, true) => {}
+        }
+    }
+    // A hold still open is released by the next measure, if the chart has one.
+    for (idx, filled) in open.into_values() {
+        if filled < last_measure {
+            holds[idx].release = Some((filled + 1, 0.0));
+        }
+    }
+    holds
+}
+
+/// Objects of the long-note channels in time order, including `00` slots.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+///
+/// # Returns
+///
+/// * `Vec<(MeasureIndex, f64, Channel, ObjectId)>` - Measure, position, channel and
+///   object of every slot, stable for slots at the same position.
+fn long_note_objects(bms: &Bms) -> Vec<(MeasureIndex, f64, Channel, ObjectId)> {
+    let mut objects: Vec<(MeasureIndex, f64, Channel, ObjectId)> = Vec::new();
+    for message in &bms.messages {
+        if !matches!(message.channel, Channel::LongNote { .. }) {
+            continue;
+        }
+        let len = message.objects.len() as f64;
+        for (i, &object) in message.objects.iter().enumerate() {
+            objects.push((message.measure, i as f64 / len, message.channel, object));
+        }
+    }
+    objects.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    objects
+}
+
+/// Collect the `#LNTYPE 1` long notes of a chart.
+///
+/// Non-zero objects on a long-note channel pair into a head, which plays,
+/// and a tail, which releases the note. `00` slots are skipped, so a note
+/// stays open across empty slots, messages and measures until its tail.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `pairing` - Whether a tail must carry its head's id.
+///
+/// # Returns
+///
+/// * `Vec<LnHold>` - Notes in order of their start; a note without a tail has no release.
+fn ln_pairs(bms: &Bms, pairing: LnPairing) -> Vec<LnHold> {
+    let mut holds: Vec<LnHold> = Vec::new();
+    // Open note per channel, and per head object under `LnPairing::Strict`.
+    let mut open: AHashMap<(Channel, ObjectId), usize> = AHashMap::new();
+    for (measure, position, channel, object) in long_note_objects(bms) {
+        if object == 0 {
+            continue;
+        }
+        let key = match pairing {
+            LnPairing::Tolerant => (channel, 0),
+            LnPairing::Strict => (channel, object),
+        };
+        match open.remove(&key) {
+            Some(idx) => holds[idx].release = Some((measure, position)),
+            None => {
+                open.insert(key, holds.len());
+                holds.push(LnHold {
+                    channel,
+                    measure,
+                    position,
+                    object,
+                    release: None,
+                });
+            }
+        }
+    }
+    holds
+}
+
+/// Extract timeline `SoundEvent`s and count notes whose object id is undefined.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `tempo_map` - Precomputed tempo map for time conversion.
+/// * `filename_to_id` - Mapping from audio filename to decoded buffer id.
+/// * `sample_rate` - Target sample rate.
+/// * `channels` - Target number of channels.
+/// * `options` - Which optional channels to render.
+///
+/// # Returns
+///
+/// * `(Vec<SoundEvent>, DroppedObjects)` - Scheduled events and the notes skipped for lacking a `#WAV` entry.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(messages = bms.messages.len()))
+)]
+pub fn extract_sound_events_with_drops(
+    bms: &Bms,
+    tempo_map: &TempoMap,
+    filename_to_id: &AHashMap<String, usize>,
+    sample_rate: u32,
+    channels: usize,
+    options: &SoundEventOptions,
+) -> (Vec<SoundEvent>, DroppedObjects) {
     let mut sound_events: Vec<SoundEvent> = vec![];
-    let mut ln_active: AHashMap<u16, (String, f64)> = AHashMap::new();
-    let mut ln_open: AHashMap<u16, HashSet<&u16>> = AHashMap::new();
-    let mut max_ev_measure: u16 = 0;
+    let mut dropped = DroppedObjects::default();
+    let mut max_ev_measure: MeasureIndex = 0;
     let ln_end_id: Option<&u16> = bms.header.ln_obj.as_ref();
     let audio = &bms.header.audio_files;
+    let key_ids = object_key_ids(bms, filename_to_id);
+    let key_of = |object: &ObjectId| key_ids.get(*object as usize).copied().flatten();
+    let volume = VolumeTrack::new(bms);
+    let mine_hit_id = key_of(&0).filter(|_| options.mine_hit_sound);
+    let pitch_of = |object: &u16| bms.header.pitch_shifts.get(object).copied().unwrap_or(0);
+    let pan_of = |object: &u16| {
+        bms.header
+            .wav_pans
+            .get(object)
+            .map_or(0, |pan| pan.clamp(-100.0, 100.0).round() as i8)
+    };
+    let volume_of = |object: &u16| {
+        bms.header
+            .wav_volumes
+            .get(object)
+            .map_or(1.0, |percent| (percent.max(0.0) / 100.0) as f32)
+    };
+    let to_sample = |measure: MeasureIndex, position: f64| {
+        tempo_map.get_timestamp_samples(measure, position, sample_rate) * channels
+    };
+    let ln_type = bms.header.ln_type.unwrap_or(1);
+    // Channel 01 lines seen so far in each measure.
+    let mut bgm_lines: AHashMap<MeasureIndex, u16> = AHashMap::new();
 
-    for message in &bms.messages {
-        let ch = message.channel as u16;
-        let allowed_channel = ch == 1
-            || (37..=45).contains(&ch)
-            || (73..=81).contains(&ch)
-            || (181..=189).contains(&ch)
-            || (217..=225).contains(&ch);
-        if !allowed_channel {
+    let holds = if ln_type == 2 {
+        mgq_holds(bms)
+    } else {
+        ln_pairs(bms, options.ln_pairing)
+    };
+    let in_range = |measure: MeasureIndex| options.end_measure.is_none_or(|end| measure < end);
+    for hold in holds.into_iter().filter(|hold| in_range(hold.measure)) {
+        let (m, position, object) = (hold.measure, hold.position, &hold.object);
+        if !audio.contains_key(object) {
+            dropped.record(
+                DropReason::UndefinedId,
+                tempo_map.get_timestamp(m, position),
+            );
+        }
+        if let Some(kid) = key_of(object) {
+            sound_events.push(SoundEvent {
+                key_id: kid,
+                start: to_sample(m, position),
+                end: hold
+                    .release
+                    .filter(|_| options.cut_long_notes)
+                    .map(|(measure, position)| to_sample(measure, position)),
+                gain: volume.gain(hold.channel, m, position) * volume_of(object),
+                channel: hold.channel,
+                semitones: pitch_of(object),
+                pan: pan_of(object),
+                bgm_lane: 0,
+            });
+        }
+    }
+
+    for message in bms.messages.iter().filter(|m| in_range(m.measure)) {
+        let ch = message.channel;
+        if let Channel::Mine { .. } = ch {
+            // Mine objects carry damage, so every non-zero one plays the hit sound.
+            if let Some(kid) = mine_hit_id {
+                let num_objects = message.objects.len() as f64;
+                for (i, object) in message.objects.iter().enumerate() {
+                    if *object == 0 {
+                        continue;
+                    }
+                    let position = i as f64 / num_objects;
+                    sound_events.push(SoundEvent {
+                        key_id: kid,
+                        start: tempo_map.get_timestamp_samples(
+                            message.measure,
+                            position,
+                            sample_rate,
+                        ) * channels,
+                        end: None,
+                        gain: volume.gain(ch, message.measure, position) * volume_of(&0),
+                        channel: ch,
+                        semitones: pitch_of(&0),
+                        pan: pan_of(&0),
+                        bgm_lane: 0,
+                    });
+                }
+            }
+            continue;
+        }
+        let audible = ch.is_sound()
+            || (options.include_invisible_notes && matches!(ch, Channel::Invisible { .. }));
+        if !audible {
             continue;
         }
+        let bgm_lane = if ch == Channel::Bgm {
+            let lines = bgm_lines.entry(message.measure).or_default();
+            *lines += 1;
+            *lines - 1
+        } else {
+            0
+        };
 
         let num_objects = message.objects.len() as f64;
         if num_objects == 0.0 {
@@ -480,90 +1316,710 @@ pub fn extract_sound_events(
             let m = message.measure;
             let position = i as f64 / num_objects;
             let object_time = tempo_map.get_timestamp(m, position);
-            let start_sample = tempo_map.get_timestamp_samples(m, position, sample_rate) * channels;
-            if (181..=189).contains(&ch) || (217..=225).contains(&ch) {
-                let ln_type = bms.header.ln_type.unwrap_or(1);
-                let is_zero = *object == 0;
-
-                match ln_type {
-                    2 => {
-                        if let Some(ln_end_id) = ln_end_id
-                            && object == ln_end_id
-                        {
-                            ln_active.remove(&ch);
-                            if message.measure > max_ev_measure {
-                                max_ev_measure = message.measure;
-                            }
-                            continue;
-                        }
-
-                        if !is_zero {
-                            let filename_opt = audio.get(object).cloned();
-                            if !ln_active.contains_key(&ch)
-                                && let Some(ref filename) = filename_opt
-                            {
-                                ln_active.insert(ch, (filename.clone(), object_time));
-                            }
-                            if let Some(filename) = filename_opt
-                                && let Some(&kid) = filename_to_id.get(&filename)
-                            {
-                                sound_events.push(SoundEvent {
-                                    key_id: kid,
-                                    start: start_sample,
-                                    end: None,
-                                });
-                            }
-                        } else {
-                            ln_active.remove(&ch);
-                        }
-                    }
-                    _ => {
-                        if is_zero {
-                            if message.measure > max_ev_measure {
-                                max_ev_measure = message.measure;
-                            }
-                            continue;
-                        }
-
-                        let entry = ln_open.entry(ch).or_default();
-
-                        if entry.contains(object) {
-                            entry.remove(object);
-                        } else {
-                            if let Some(filename) = audio.get(object)
-                                && let Some(&kid) = filename_to_id.get(filename)
-                            {
-                                sound_events.push(SoundEvent {
-                                    key_id: kid,
-                                    start: start_sample,
-                                    end: None,
-                                });
-                            }
-                            entry.insert(object);
-                        }
-                    }
-                }
-
-                if message.measure > max_ev_measure {
-                    max_ev_measure = message.measure;
-                }
+            let start_sample = to_sample(m, position);
+            if let Channel::LongNote { .. } = ch {
+                // Long notes were scheduled above.
                 continue;
             }
-            if let Some(filename) = audio.get(object)
-                && let Some(&kid) = filename_to_id.get(filename)
-            {
+            if *object != 0 && Some(object) != ln_end_id && !audio.contains_key(object) {
+                dropped.record(DropReason::UndefinedId, object_time);
+            }
+            let silent_tail = options.silent_ln_tails && Some(object) == ln_end_id;
+            if let Some(kid) = key_of(object).filter(|_| !silent_tail) {
                 sound_events.push(SoundEvent {
                     key_id: kid,
                     start: start_sample,
                     end: None,
+                    gain: volume.gain(ch, m, position) * volume_of(object),
+                    channel: ch,
+                    semitones: pitch_of(object),
+                    pan: pan_of(object),
+                    bgm_lane,
                 });
             }
-            if let Some(_filename) = audio.get(object)
-                && message.measure > max_ev_measure
-            {
+            if audio.contains_key(object) && message.measure > max_ev_measure {
                 max_ev_measure = message.measure;
             }
         }
     }
-    sound_events
+
+    // Sounds end at the earliest of their release, the next volume change of
+    // their channel and the end of the range.
+    let range_end = options.end_measure.map(|measure| to_sample(measure, 0.0));
+    let [bgm_changes, key_changes] = [&volume.bgm, &volume.keys].map(|changes| {
+        changes
+            .iter()
+            .filter(|_| options.cut_at_volume_changes)
+            .map(|&(measure, position, _)| to_sample(measure, position))
+            .collect::<Vec<usize>>()
+    });
+    for ev in &mut sound_events {
+        let changes = if ev.channel == Channel::Bgm {
+            &bgm_changes
+        } else {
+            &key_changes
+        };
+        let next_change = changes
+            .get(changes.partition_point(|&change| change <= ev.start))
+            .copied();
+        ev.end = [ev.end, next_change, range_end].into_iter().flatten().min();
+    }
+    #[cfg(feature = "tracing")]
+    tracing::debug!(events = sound_events.len(), "sound events extracted");
+    (sound_events, dropped)
+}
+
+/// An image or video change on one BGA layer.
+#[derive(Debug, Clone, Serialize)]
+pub struct BgaEvent {
+    /// Time of the change in seconds.
+    pub time_sec: f64,
+    /// Layer the change applies to.
+    pub layer: BgaLayer,
+    /// `#BMPxx` slot as a two-character token.
+    pub object_id: String,
+    /// Image or video filename of the slot, or `None` if the slot is undefined.
+    pub filename: Option<String>,
+}
+
+/// Extract timestamped BGA changes from channels 04, 06, 07 and 0A.
+///
+/// Times use the same origin as the rendered audio, so the events can be
+/// muxed as a video track alongside it. `00` objects leave the layer as is
+/// and are skipped.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `tempo_map` - Precomputed tempo map for time conversion.
+///
+/// # Returns
+///
+/// * `Vec<BgaEvent>` - Layer changes sorted by time.
+pub fn extract_bga_events(bms: &Bms, tempo_map: &TempoMap) -> Vec<BgaEvent> {
+    let base = bms.header.object_base();
+    let mut events: Vec<BgaEvent> = Vec::new();
+    for message in &bms.messages {
+        let Channel::Bga(layer) = message.channel else {
+            continue;
+        };
+        let num_objects = message.objects.len() as f64;
+        for (i, &object) in message.objects.iter().enumerate() {
+            if object == 0 {
+                continue;
+            }
+            events.push(BgaEvent {
+                time_sec: tempo_map.get_timestamp(message.measure, i as f64 / num_objects),
+                layer,
+                object_id: format_object_id_with_base(object, base),
+                filename: bms.header.bmp_files.get(&object).cloned(),
+            });
+        }
+    }
+    events.sort_by(|a, b| a.time_sec.total_cmp(&b.time_sec));
+    events
+}
+
+/// Which visual speed a `ScrollEvent` changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ScrollKind {
+    /// Channel SC with `#SCROLLxx`: scroll speed of the whole lane field.
+    Scroll,
+    /// Channel SP with `#SPEEDxx`: note speed, interpolated towards the next change.
+    Speed,
+}
+
+/// A visual speed change. Scroll and speed changes never move notes in time.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScrollEvent {
+    /// Measure index of the change.
+    pub measure: MeasureIndex,
+    /// Position within the measure (0.0..1.0).
+    pub position: f64,
+    /// Time of the change in seconds.
+    pub time_sec: f64,
+    /// Which speed changes.
+    pub kind: ScrollKind,
+    /// New multiplier from the `#SCROLLxx` or `#SPEEDxx` table.
+    pub value: f64,
+}
+
+/// Extract timestamped `#SCROLL` and `#SPEED` changes from channels SC and SP.
+///
+/// Objects referencing undefined table entries are skipped.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `tempo_map` - Precomputed tempo map for time conversion.
+///
+/// # Returns
+///
+/// * `Vec<ScrollEvent>` - Changes sorted by time.
+pub fn extract_scroll_events(bms: &Bms, tempo_map: &TempoMap) -> Vec<ScrollEvent> {
+    let mut events: Vec<ScrollEvent> = Vec::new();
+    for message in &bms.messages {
+        let (kind, table) = match message.channel {
+            Channel::Scroll => (ScrollKind::Scroll, &bms.header.scroll_table),
+            Channel::Speed => (ScrollKind::Speed, &bms.header.speed_table),
+            _ => continue,
+        };
+        let num_objects = message.objects.len() as f64;
+        for (i, object) in message.objects.iter().enumerate() {
+            let Some(&value) = table.get(object) else {
+                continue;
+            };
+            let position = i as f64 / num_objects;
+            events.push(ScrollEvent {
+                measure: message.measure,
+                position,
+                time_sec: tempo_map.get_timestamp(message.measure, position),
+                kind,
+                value,
+            });
+        }
+    }
+    events.sort_by(|a, b| a.time_sec.total_cmp(&b.time_sec));
+    events
+}
+
+/// A judge rank change from channel A0.
+#[derive(Debug, Clone, Serialize)]
+pub struct RankEvent {
+    /// Measure index of the change.
+    pub measure: MeasureIndex,
+    /// Position within the measure (0.0..1.0).
+    pub position: f64,
+    /// Time of the change in seconds.
+    pub time_sec: f64,
+    /// New judge window in percent, from the `#EXRANKxx` table.
+    pub judge_rank: f64,
+}
+
+/// Extract timestamped judge rank changes from channel A0.
+///
+/// Objects referencing undefined `#EXRANKxx` entries are skipped.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `tempo_map` - Precomputed tempo map for time conversion.
+///
+/// # Returns
+///
+/// * `Vec<RankEvent>` - Changes sorted by time.
+pub fn extract_rank_events(bms: &Bms, tempo_map: &TempoMap) -> Vec<RankEvent> {
+    let mut events: Vec<RankEvent> = Vec::new();
+    for message in bms.messages.iter().filter(|m| m.channel == Channel::ExRank) {
+        let num_objects = message.objects.len() as f64;
+        for (i, object) in message.objects.iter().enumerate() {
+            let Some(&judge_rank) = bms.header.ex_rank_table.get(object) else {
+                continue;
+            };
+            let position = i as f64 / num_objects;
+            events.push(RankEvent {
+                measure: message.measure,
+                position,
+                time_sec: tempo_map.get_timestamp(message.measure, position),
+                judge_rank,
+            });
+        }
+    }
+    events.sort_by(|a, b| a.time_sec.total_cmp(&b.time_sec));
+    events
+}
+
+/// Order audio sources by the time they are first needed.
+///
+/// # Arguments
+///
+/// * `sound_events` - Scheduled audio events.
+///
+/// # Returns
+///
+/// * `Vec<usize>` - Distinct key ids, earliest first use first (ties by id).
+pub fn first_use_order(sound_events: &[SoundEvent]) -> Vec<usize> {
+    let mut first_use: AHashMap<usize, usize> = AHashMap::new();
+    for ev in sound_events {
+        first_use
+            .entry(ev.key_id)
+            .and_modify(|start| *start = (*start).min(ev.start))
+            .or_insert(ev.start);
+    }
+    let mut ids: Vec<usize> = first_use.keys().copied().collect();
+    ids.sort_unstable_by_key(|id| (first_use[id], *id));
+    ids
+}
+
+/// A long note with the keysound that starts it.
+#[derive(Debug, Clone, Copy)]
+pub struct LongNoteSpan {
+    /// Index of the audio source in decoded buffer.
+    pub key_id: usize,
+    /// Start position in the output buffer.
+    pub start: usize,
+    /// Exclusive end position (release) in the output buffer.
+    pub end: usize,
+}
+
+/// Collect long notes with their start and release positions.
+///
+/// Covers `#LNTYPE 1` pairs and `#LNTYPE 2` runs on the long-note channels,
+/// and `#LNOBJ` terminators on the visible note channels.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `tempo_map` - Precomputed tempo map for time conversion.
+/// * `filename_to_id` - Mapping from audio filename to decoded buffer id.
+/// * `sample_rate` - Target sample rate.
+/// * `channels` - Target number of channels.
+/// * `pairing` - How `#LNTYPE 1` heads and tails are paired.
+///
+/// # Returns
+///
+/// * `Vec<LongNoteSpan>` - Long notes whose start keysound is known, sorted by start.
+pub fn long_note_spans(
+    bms: &Bms,
+    tempo_map: &TempoMap,
+    filename_to_id: &AHashMap<String, usize>,
+    sample_rate: u32,
+    channels: usize,
+    pairing: LnPairing,
+) -> Vec<LongNoteSpan> {
+    let ln_type = bms.header.ln_type.unwrap_or(1);
+    // (measure, position, channel, object) of every visible note object.
+    let mut objects: Vec<(MeasureIndex, f64, Channel, ObjectId)> = Vec::new();
+    for message in &bms.messages {
+        let ch = message.channel;
+        if !matches!(ch, Channel::Note { .. }) {
+            continue;
+        }
+        let len = message.objects.len() as f64;
+        for (i, &object) in message.objects.iter().enumerate() {
+            objects.push((message.measure, i as f64 / len, ch, object));
+        }
+    }
+    objects.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+
+    let key_ids = object_key_ids(bms, filename_to_id);
+    let key_of = |object: u16| key_ids.get(object as usize).copied().flatten();
+    let to_sample = |measure: MeasureIndex, position: f64| {
+        tempo_map.get_timestamp_samples(measure, position, sample_rate) * channels
+    };
+
+    let holds = if ln_type == 2 {
+        mgq_holds(bms)
+    } else {
+        ln_pairs(bms, pairing)
+    };
+    let mut spans: Vec<LongNoteSpan> = holds
+        .into_iter()
+        .filter_map(|hold| {
+            let (measure, position) = hold.release?;
+            Some(LongNoteSpan {
+                key_id: key_of(hold.object)?,
+                start: to_sample(hold.measure, hold.position),
+                end: to_sample(measure, position),
+            })
+        })
+        .collect();
+    let mut last_note: AHashMap<Channel, (usize, u16)> = AHashMap::new();
+    for (measure, position, ch, object) in objects {
+        let sample = to_sample(measure, position);
+        if object == 0 {
+            continue;
+        } else if Some(object) == bms.header.ln_obj {
+            if let Some((start, start_object)) = last_note.remove(&ch)
+                && let Some(key_id) = key_of(start_object)
+            {
+                spans.push(LongNoteSpan {
+                    key_id,
+                    start,
+                    end: sample,
+                });
+            }
+        } else {
+            last_note.insert(ch, (sample, object));
+        }
+    }
+    spans.sort_by_key(|span| span.start);
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bms::Message;
+
+    fn timestamp(text: &str, measure: MeasureIndex, position: f64) -> f64 {
+        let bms = Bms::parse(text).unwrap();
+        build_tempo_map(&bms).get_timestamp(measure, position)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn stp_matches_channel_09_stop() {
+        // At 120 BPM, 96/192 of a whole note is two beats, or one second.
+        let channel_09 = "#BPM 120\n#STOP01 96\n#00111:01\n#00109:0001\n#00211:01\n";
+        let stp = "#BPM 120\n#STP 001.500 1000\n#00111:01\n#00211:01\n";
+        for (measure, position) in [(1, 0.25), (1, 0.75), (2, 0.0), (2, 0.5)] {
+            assert_close(
+                timestamp(stp, measure, position),
+                timestamp(channel_09, measure, position),
+            );
+        }
+        assert_close(timestamp(stp, 2, 0.0), 3.0);
+    }
+
+    #[test]
+    fn stp_matches_channel_09_stop_before_bpm_change() {
+        let channel_09 = "#BPM 120\n#STOP01 96\n#00111:01\n#00109:0001\n#00203:F0\n#00311:01\n";
+        let stp = "#BPM 120\n#STP 001.500 1000\n#00111:01\n#00203:F0\n#00311:01\n";
+        assert_close(timestamp(stp, 3, 0.0), timestamp(channel_09, 3, 0.0));
+        // 1 s stop + 2 s for measure 1 + 1 s for measure 2 at 240 BPM.
+        assert_close(timestamp(stp, 3, 0.0), 4.0);
+    }
+
+    #[test]
+    fn stp_duration_does_not_depend_on_bpm() {
+        let channel_09 = "#BPM 240\n#STOP01 96\n#00111:01\n#00109:0001\n#00211:01\n";
+        let stp = "#BPM 240\n#STP 001.500 1000\n#00111:01\n#00211:01\n";
+        assert_close(timestamp(channel_09, 2, 0.0), 1.5);
+        assert_close(timestamp(stp, 2, 0.0), 2.0);
+    }
+
+    #[test]
+    fn stp_and_channel_09_stops_at_the_same_position_add_up() {
+        let text = "#BPM 120\n#STOP01 96\n#STP 001.500 500\n#00111:01\n#00109:0001\n#00211:01\n";
+        assert_close(timestamp(text, 1, 0.25), 0.5);
+        assert_close(timestamp(text, 2, 0.0), 3.5);
+    }
+
+    fn timestamp_with_order(text: &str, stop_order: StopOrder, measure: MeasureIndex) -> f64 {
+        let bms = Bms::parse(text).unwrap();
+        let options = TempoMapOptions {
+            stop_order,
+            ..TempoMapOptions::default()
+        };
+        build_tempo_map_with_options(&bms, &options)
+            .unwrap()
+            .get_timestamp(measure, 0.0)
+    }
+
+    #[test]
+    fn stop_at_a_bpm_change_follows_the_stop_order() {
+        // Half-note stop where 120 BPM doubles to 240 BPM.
+        let text = "#BPM 120\n#STOP01 96\n#00111:01\n#00203:F0\n#00209:01\n#00311:01\n";
+        // 2 s for measure 1, a 0.5 s stop at 240 BPM, 1 s for measure 2.
+        assert_close(timestamp_with_order(text, StopOrder::BpmFirst, 3), 3.5);
+        assert_close(timestamp(text, 3, 0.0), 3.5);
+        // The stop runs at 120 BPM and lasts 1 s.
+        assert_close(timestamp_with_order(text, StopOrder::StopFirst, 3), 4.0);
+    }
+
+    #[test]
+    fn stop_order_only_affects_stops_at_the_bpm_change() {
+        // Stops half a measure before and after the change.
+        let text =
+            "#BPM 120\n#STOP01 96\n#00111:01\n#00109:0001\n#00203:F0\n#00209:0001\n#00311:01\n";
+        for order in [StopOrder::BpmFirst, StopOrder::StopFirst] {
+            // 1 s stop at 120 BPM, 2 s, then 0.5 s stop at 240 BPM, 1 s.
+            assert_close(timestamp_with_order(text, order, 3), 4.5);
+        }
+    }
+
+    #[test]
+    fn stp_at_a_bpm_change_ignores_the_stop_order() {
+        let text = "#BPM 120\n#STP 002.000 1000\n#00111:01\n#00203:F0\n#00311:01\n";
+        for order in [StopOrder::BpmFirst, StopOrder::StopFirst] {
+            assert_close(timestamp_with_order(text, order, 3), 4.0);
+        }
+    }
+
+    fn timestamp_with_policy(bpm_policy: BpmPolicy) -> Result<f64, InvalidBpm> {
+        // The tempo drops to 0 halfway through measure 0, two beats before measure 1.
+        let bms = Bms::parse("#BPM 120\n#BPM01 0\n#00008:0001\n#00111:01\n").unwrap();
+        let options = TempoMapOptions {
+            bpm_policy,
+            ..TempoMapOptions::default()
+        };
+        build_tempo_map_with_options(&bms, &options).map(|map| map.get_timestamp(1, 0.0))
+    }
+
+    #[test]
+    fn zero_bpm_follows_the_policy() {
+        assert_eq!(BpmPolicy::default(), BpmPolicy::Clamp);
+        assert_close(
+            timestamp_with_policy(BpmPolicy::Clamp).unwrap(),
+            1.0 + 120.0,
+        );
+        assert_close(timestamp_with_policy(BpmPolicy::TreatAsStop).unwrap(), 1.0);
+        assert!(timestamp_with_policy(BpmPolicy::Error).is_err());
+    }
+
+    #[test]
+    fn kept_leading_measures_follow_measure_lengths() {
+        let bms = Bms::parse("#BPM 120\n#00102:0.5\n#00311:01\n").unwrap();
+        let options = TempoMapOptions {
+            keep_leading_measures: true,
+            ..TempoMapOptions::default()
+        };
+        let tempo_map = build_tempo_map_with_options(&bms, &options).unwrap();
+        // Measures 0 and 2 take 2 s each at 120 BPM, measure 1 is half as long.
+        assert_close(tempo_map.get_timestamp(3, 0.0), 5.0);
+        assert_close(build_tempo_map(&bms).get_timestamp(3, 0.0), 0.0);
+    }
+
+    /// Events of a chart rendered in mono at 1 kHz, with long notes cut at their release.
+    fn sound_events_with(text: &str, ln_pairing: LnPairing) -> Vec<SoundEvent> {
+        let bms = Bms::parse(text).unwrap();
+        let tempo_map = build_tempo_map(&bms);
+        let (_, filename_to_id) = index_audio_files(&bms);
+        let options = SoundEventOptions {
+            cut_long_notes: true,
+            ln_pairing,
+            ..SoundEventOptions::default()
+        };
+        extract_sound_events_with_drops(&bms, &tempo_map, &filename_to_id, 1000, 1, &options).0
+    }
+
+    fn sound_events(text: &str) -> Vec<SoundEvent> {
+        sound_events_with(text, LnPairing::default())
+    }
+
+    /// Key id, start and end of each event, by start.
+    fn spans(mut events: Vec<SoundEvent>) -> Vec<(usize, usize, Option<usize>)> {
+        events.sort_by_key(|ev| ev.start);
+        events
+            .iter()
+            .map(|ev| (ev.key_id, ev.start, ev.end))
+            .collect()
+    }
+
+    #[test]
+    fn second_player_long_notes_pair_and_play_once() {
+        // Channel 61 pairs within a measure, channel 69 across measures.
+        let text = "#BPM 120\n#WAV01 a.wav\n#WAV02 b.wav\n\
+                    #00161:01000001\n#00269:02\n#00369:02\n";
+        let events = sound_events(text);
+        let summary: Vec<_> = events
+            .iter()
+            .map(|ev| (ev.channel, ev.key_id, ev.start, ev.end))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Channel::LongNote { player: 2, lane: 1 }, 0, 0, Some(1500)),
+                (
+                    Channel::LongNote { player: 2, lane: 9 },
+                    1,
+                    2000,
+                    Some(4000)
+                ),
+            ]
+        );
+        assert_eq!(Channel::LongNote { player: 2, lane: 9 }.code(), 6 * 36 + 9);
+    }
+
+    #[test]
+    fn sounds_are_cut_at_volume_changes_and_the_range_end() {
+        let text = "#BPM 120\n#WAV01 a.wav\n#WAV02 b.wav\n\
+                    #00101:0102\n#00197:00FF\n#00111:01\n#00311:02\n";
+        let bms = Bms::parse(text).unwrap();
+        let tempo_map = build_tempo_map(&bms);
+        let (_, filename_to_id) = index_audio_files(&bms);
+        let events = |options: SoundEventOptions| {
+            let (events, _) = extract_sound_events_with_drops(
+                &bms,
+                &tempo_map,
+                &filename_to_id,
+                1000,
+                1,
+                &options,
+            );
+            spans(events)
+        };
+        // Measures last 2 s; the BGM volume changes halfway through measure 1.
+        assert_eq!(
+            events(SoundEventOptions::default()),
+            [(0, 0, None), (0, 0, None), (1, 1000, None), (1, 4000, None)]
+        );
+        let cut = SoundEventOptions {
+            cut_at_volume_changes: true,
+            ..SoundEventOptions::default()
+        };
+        // The first BGM sound is cut; the key sound follows channel 98 and plays on.
+        let mut cut_events = events(cut);
+        cut_events.sort();
+        assert_eq!(
+            cut_events,
+            [
+                (0, 0, None),
+                (0, 0, Some(1000)),
+                (1, 1000, None),
+                (1, 4000, None)
+            ]
+        );
+        let ranged = SoundEventOptions {
+            end_measure: Some(2),
+            ..cut
+        };
+        let mut ranged_events = events(ranged);
+        ranged_events.sort();
+        assert_eq!(
+            ranged_events,
+            [
+                (0, 0, Some(1000)),
+                (0, 0, Some(2000)),
+                (1, 1000, Some(2000))
+            ]
+        );
+    }
+
+    #[test]
+    fn mgq_holds_end_at_measures_without_fill() {
+        // Measures 2 and 4 have no data on channel 51, so each hold ends there.
+        let text = "#BPM 120\n#LNTYPE 2\n#WAV01 a.wav\n#WAV02 b.wav\n\
+                    #00151:0101\n#00351:0202\n#00551:00\n";
+        let bms = Bms::parse(text).unwrap();
+        let tempo_map = build_tempo_map(&bms);
+        let at = |measure| (tempo_map.get_timestamp(measure, 0.0) * 1000.0).round() as usize;
+        assert_eq!(
+            spans(sound_events(text)),
+            [(0, at(1), Some(at(2))), (1, at(3), Some(at(4)))]
+        );
+    }
+
+    #[test]
+    fn mgq_lines_on_one_channel_overlay() {
+        // The second line's empty first slot does not end the hold started
+        // by the first line; both lines are empty at the half measure.
+        let text = "#BPM 120\n#LNTYPE 2\n#WAV01 a.wav\n#00151:0100\n#00151:00010000\n";
+        assert_eq!(spans(sound_events(text)), [(0, 0, Some(1000))]);
+    }
+
+    #[test]
+    fn volume_channels_are_hex_in_any_base() {
+        for base in ["", "#BASE 62\n"] {
+            let text = format!("{base}#BPM 120\n#WAV01 a.wav\n#00197:80ff\n#00101:0101\n");
+            let mut events = sound_events(&text);
+            events.sort_by_key(|ev| ev.start);
+            let gains: Vec<f32> = events.iter().map(|ev| ev.gain).collect();
+            assert_eq!(gains, [128.0 / 255.0, 1.0], "{base:?}");
+        }
+    }
+
+    #[test]
+    fn high_channels_do_not_wrap_onto_note_lanes() {
+        // 85 decodes to 293 and Z9 to 1269; narrowed to a byte, 293 would become 11.
+        for (line, code) in [("#00185:01", 293), ("#001Z9:01", 1269)] {
+            let message = Message::parse(line).unwrap();
+            assert_eq!(message.channel, Channel::Unknown(code));
+            assert_eq!(message.channel.code(), code);
+        }
+        let text = "#BPM 120\n#WAV01 a.wav\n#00111:01\n#00185:0101\n#001Z9:01\n";
+        let events = sound_events(text);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].channel, Channel::Note { player: 1, lane: 1 });
+    }
+
+    #[test]
+    fn ln_tail_with_another_id_ends_the_note() {
+        let text = "#BPM 120\n#LNTYPE 1\n#WAV01 a.wav\n#WAV02 b.wav\n\
+                    #00151:0102\n#00251:01\n#00351:01\n";
+        assert_eq!(
+            spans(sound_events(text)),
+            [(0, 0, Some(1000)), (0, 2000, Some(4000))]
+        );
+        // Strict pairing waits for a matching id, so 02 and the last 01 stay open.
+        assert_eq!(
+            spans(sound_events_with(text, LnPairing::Strict)),
+            [(0, 0, Some(2000)), (1, 1000, None), (0, 4000, None)]
+        );
+    }
+
+    #[test]
+    fn ln_pairs_in_time_order_across_lines_and_measures() {
+        // The tail's measure comes first in the file, and a line of zeros
+        // on the same channel sits between head and tail.
+        let text = "#BPM 120\n#LNTYPE 1\n#WAV01 a.wav\n#WAV02 b.wav\n\
+                    #00251:0200\n#00151:0001\n#00151:00000000\n#00351:01000001\n";
+        assert_eq!(
+            spans(sound_events(text)),
+            [(0, 1000, Some(2000)), (0, 4000, Some(5500))]
+        );
+        assert_eq!(
+            spans(sound_events_with(text, LnPairing::Strict)),
+            [(0, 1000, Some(4000)), (1, 2000, None), (0, 5500, None)]
+        );
+    }
+
+    #[test]
+    fn drop_examples_are_the_earliest_times() {
+        let mut dropped = DroppedObjects::default();
+        for time in [9.0, 3.0, 7.0, 1.0, 8.0, 5.0, 2.0] {
+            dropped.record(DropReason::UndefinedId, time);
+        }
+        assert_eq!(dropped.undefined_id.count, 7);
+        assert_eq!(
+            dropped.undefined_id.example_times,
+            [1.0, 2.0, 3.0, 5.0, 7.0]
+        );
+    }
+
+    #[test]
+    fn lnobj_tails_play_unless_silenced() {
+        let bms =
+            Bms::parse("#BPM 120\n#WAV01 a.wav\n#WAV02 b.wav\n#LNOBJ 02\n#00111:0102\n").unwrap();
+        let tempo_map = build_tempo_map(&bms);
+        let (_, filename_to_id) = index_audio_files(&bms);
+        let render = |silent_ln_tails| {
+            let options = SoundEventOptions {
+                silent_ln_tails,
+                ..SoundEventOptions::default()
+            };
+            let (events, dropped) = extract_sound_events_with_drops(
+                &bms,
+                &tempo_map,
+                &filename_to_id,
+                1000,
+                1,
+                &options,
+            );
+            assert_eq!(dropped.total(), 0);
+            spans(events)
+        };
+        assert_eq!(render(false), [(0, 0, None), (1, 1000, None)]);
+        assert_eq!(render(true), [(0, 0, None)]);
+    }
+
+    #[test]
+    fn bgm_lines_get_a_lane_per_measure() {
+        // Two BGM lines in measure 1 and one in measure 2, which starts again
+        // at lane 0; the note line between them does not count.
+        let text = "#BPM 120\n#WAV01 a.wav\n#WAV02 b.wav\n#WAV03 c.wav\n\
+                    #00101:01\n#00111:02\n#00101:0003\n#00201:01\n";
+        let mut events = sound_events(text);
+        events.sort_by_key(|ev| ev.start);
+        let lanes: Vec<_> = events
+            .iter()
+            .map(|ev| (ev.key_id, ev.channel, ev.bgm_lane))
+            .collect();
+        assert_eq!(
+            lanes,
+            [
+                (0, Channel::Bgm, 0),
+                (1, Channel::Note { player: 1, lane: 1 }, 0),
+                (2, Channel::Bgm, 1),
+                (0, Channel::Bgm, 0),
+            ]
+        );
+    }
 }
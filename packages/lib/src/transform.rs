@@ -0,0 +1,243 @@
+use crate::bms::{Bms, Channel, MeasureIndex, Message, ObjectId};
+use crate::timeline::build_tempo_map;
+use ahash::AHashMap;
+use std::collections::HashSet;
+
+/// Key lanes (excluding scratch and free zone) of a 7-key chart, left to right.
+const LANES_7K: [u8; 7] = [1, 2, 3, 4, 5, 8, 9];
+/// Key lanes of a 5-key chart, left to right.
+const LANES_5K: [u8; 5] = [1, 2, 3, 4, 5];
+
+/// Playstyle modification applied to the key lanes of a chart.
+#[derive(Debug, Clone)]
+pub enum LaneTransform {
+    /// Reverse the key lanes. Scratch stays in place.
+    Mirror,
+    /// Rotate the key lanes by the given number of columns (positive shifts right).
+    Rotate(i32),
+    /// Explicit permutation where `map[i]` is the destination column of key lane `i`.
+    Permutation(Vec<usize>),
+}
+
+/// Errors that can occur while transforming a chart.
+#[derive(Debug)]
+pub enum TransformError {
+    /// The permutation is not a bijection over the chart's key lanes.
+    InvalidPermutation {
+        /// Number of key lanes the chart has.
+        lanes: usize,
+    },
+}
+
+impl core::fmt::Display for TransformError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TransformError::InvalidPermutation { lanes } => {
+                write!(
+                    f,
+                    "invalid lane permutation (expected a permutation of 0..{})",
+                    lanes
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+/// Lane digit of a visible, invisible or long-note channel.
+///
+/// # Arguments
+///
+/// * `channel` - Message channel.
+///
+/// # Returns
+///
+/// * `Option<u8>` - Lane digit, or `None` for other channels.
+fn note_lane(channel: Channel) -> Option<u8> {
+    match channel {
+        Channel::Note { lane, .. }
+        | Channel::Invisible { lane, .. }
+        | Channel::LongNote { lane, .. } => Some(lane),
+        _ => None,
+    }
+}
+
+/// Move a note channel to another lane of the same player and kind.
+///
+/// # Arguments
+///
+/// * `channel` - Visible, invisible or long-note channel.
+/// * `lane` - Target lane digit.
+///
+/// # Returns
+///
+/// * `Channel` - Channel on the target lane (other channels are returned unchanged).
+fn with_lane(channel: Channel, lane: u8) -> Channel {
+    match channel {
+        Channel::Note { player, .. } => Channel::Note { player, lane },
+        Channel::Invisible { player, .. } => Channel::Invisible { player, lane },
+        Channel::LongNote { player, .. } => Channel::LongNote { player, lane },
+        other => other,
+    }
+}
+
+/// Number of key lanes used by the chart (7 if lanes 6/7 of the 7-key layout are used, otherwise 5).
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+///
+/// # Returns
+///
+/// * `usize` - Key lane count, excluding scratch.
+pub fn key_lane_count(bms: &Bms) -> usize {
+    let uses_7k = bms
+        .messages
+        .iter()
+        .filter_map(|m| note_lane(m.channel))
+        .any(|lane| lane == 8 || lane == 9);
+    if uses_7k {
+        LANES_7K.len()
+    } else {
+        LANES_5K.len()
+    }
+}
+
+/// Remap note channels of both players according to a lane transform.
+///
+/// Visible, invisible and long-note channels of the same lane move together,
+/// so long-note pairing is preserved.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data to modify in place.
+/// * `transform` - Lane transform to apply.
+///
+/// # Returns
+///
+/// * `Result<(), TransformError>` - Ok on success, or an error for invalid permutations.
+pub fn apply_lane_transform(
+    bms: &mut Bms,
+    transform: &LaneTransform,
+) -> Result<(), TransformError> {
+    let lanes: &[u8] = if key_lane_count(bms) == LANES_7K.len() {
+        &LANES_7K
+    } else {
+        &LANES_5K
+    };
+    let n = lanes.len();
+
+    let map: Vec<usize> = match transform {
+        LaneTransform::Mirror => (0..n).rev().collect(),
+        LaneTransform::Rotate(k) => (0..n)
+            .map(|i| (i as i64 + *k as i64).rem_euclid(n as i64) as usize)
+            .collect(),
+        LaneTransform::Permutation(p) => {
+            let mut seen = vec![false; n];
+            if p.len() != n {
+                return Err(TransformError::InvalidPermutation { lanes: n });
+            }
+            for &dst in p {
+                if dst >= n || seen[dst] {
+                    return Err(TransformError::InvalidPermutation { lanes: n });
+                }
+                seen[dst] = true;
+            }
+            p.clone()
+        }
+    };
+
+    for message in &mut bms.messages {
+        if let Some(lane) = note_lane(message.channel)
+            && let Some(idx) = lanes.iter().position(|&l| l == lane)
+        {
+            message.channel = with_lane(message.channel, lanes[map[idx]]);
+        }
+    }
+    Ok(())
+}
+
+/// Extract a measure range into a standalone chart.
+///
+/// Measures are rebased so `first_measure` becomes measure 0, the base BPM is
+/// set to the tempo in effect at the start of the range, and only the audio,
+/// BPM and STOP table entries referenced by the kept messages are retained.
+///
+/// # Arguments
+///
+/// * `bms` - Parsed BMS data.
+/// * `first_measure` - First measure to keep (inclusive).
+/// * `last_measure` - Last measure to keep (inclusive).
+///
+/// # Returns
+///
+/// * `Bms` - New chart containing only the requested section.
+pub fn extract_section(bms: &Bms, first_measure: MeasureIndex, last_measure: MeasureIndex) -> Bms {
+    let tempo_map = build_tempo_map(bms);
+    let start_bpm = tempo_map
+        .events
+        .iter()
+        .take_while(|e| e.measure < first_measure)
+        .last()
+        .map(|e| e.bpm)
+        .unwrap_or(bms.header.bpm);
+
+    let messages: Vec<Message> = bms
+        .messages
+        .iter()
+        .filter(|m| (first_measure..=last_measure).contains(&m.measure))
+        .map(|m| Message {
+            measure: m.measure - first_measure,
+            ..m.clone()
+        })
+        .collect();
+
+    let measure_multipliers: AHashMap<MeasureIndex, f64> = bms
+        .measure_multipliers
+        .iter()
+        .filter(|(m, _)| (first_measure..=last_measure).contains(*m))
+        .map(|(&m, &mult)| (m - first_measure, mult))
+        .collect();
+
+    let mut used_audio: HashSet<ObjectId> = HashSet::new();
+    let mut used_bpm: HashSet<ObjectId> = HashSet::new();
+    let mut used_stop: HashSet<ObjectId> = HashSet::new();
+    let mut used_ex_rank: HashSet<ObjectId> = HashSet::new();
+    let mut used_scroll: HashSet<ObjectId> = HashSet::new();
+    let mut used_speed: HashSet<ObjectId> = HashSet::new();
+    for message in &messages {
+        let used = match message.channel {
+            Channel::Bpm => continue,
+            Channel::ExBpm => &mut used_bpm,
+            Channel::Stop => &mut used_stop,
+            Channel::ExRank => &mut used_ex_rank,
+            Channel::Scroll => &mut used_scroll,
+            Channel::Speed => &mut used_speed,
+            _ => &mut used_audio,
+        };
+        used.extend(message.objects.iter().copied().filter(|&o| o != 0));
+    }
+
+    let mut header = bms.header.clone();
+    header.bpm = start_bpm;
+    header.audio_files.retain(|id, _| used_audio.contains(id));
+    header.bpm_table.retain(|id, _| used_bpm.contains(id));
+    header.stop_table.retain(|id, _| used_stop.contains(id));
+    header
+        .ex_rank_table
+        .retain(|id, _| used_ex_rank.contains(id));
+    header.scroll_table.retain(|id, _| used_scroll.contains(id));
+    header.speed_table.retain(|id, _| used_speed.contains(id));
+    header.pitch_shifts.retain(|id, _| used_audio.contains(id));
+    header.wav_volumes.retain(|id, _| used_audio.contains(id));
+    header.wav_pans.retain(|id, _| used_audio.contains(id));
+
+    Bms {
+        header,
+        messages,
+        measure_multipliers,
+        mode: bms.mode,
+        random_choices: bms.random_choices.clone(),
+    }
+}
@@ -3,18 +3,44 @@ use wasm_bindgen::JsCast;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 
-use crate::bms::Bms;
-use crate::mixer::{bucketize_events, mix_chunk, precompute_overlaps, prepare_events};
-use crate::timeline::{build_tempo_map, extract_sound_events};
-use ahash::AHashMap;
+pub use crate::audio::ResampleMethod;
+
+use crate::audio::{DecodeLimits, estimate_source};
+
+use crate::alignment::{AlignmentOptions, DEFAULT_ALIGNMENT_TOLERANCE_MS, verify_alignment};
+use crate::assets::{
+    KeysoundUsage, chart_assets, chart_mode_for_path, discover_song, keysound_usage,
+    required_audio_union,
+};
+use crate::base64::Base64Chunker;
+use crate::bms::{
+    Bms, ChartMode, DuplicatePolicy, MeasureParser, ParseError, ParseOptions, extended_measure,
+};
+use crate::cache::{CacheKey, DecodeCache, MemoryCache};
+use crate::compare::{DEFAULT_MAX_OFFSET_MS, compare_renders, measure_starts};
+use crate::control::{RandomChoice, RandomVariants, random_variants};
+use crate::diff::diff_charts;
+use crate::encoding::{TextEncoding, decode_text};
+use crate::hash::chart_hash;
+use crate::limits::{ResourceLimitExceeded, ResourceLimits};
+use crate::loader::{AudioLoader, LoadedFiles};
+use crate::mixer::{ChunkLevels, prepare_events};
+use crate::pcm::PcmFormat;
+use crate::pitch::{PitchEstimate, detect_pitch};
+use crate::random::{RandomSource, fraction_to_bits};
+use crate::render::{
+    Analysis, ByteSink, RenderCallbacks, RenderError, RenderOptions, check_file_size, render,
+};
+use crate::sfz::{export_sfz, export_sfz_from_usage};
+use crate::stream::StreamingParser;
+use crate::timeline::{
+    BpmPolicy, LnPairing, SoundEventOptions, StopOrder, TempoMap, TempoMapOptions,
+    build_tempo_map_with_options, extract_bga_events, extract_rank_events, extract_scroll_events,
+};
 use num_enum::TryFromPrimitive;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::sync::{Arc, mpsc};
-use wide::f32x8;
-
-type DecodeResult = Result<(usize, (Vec<f32>, usize)), String>;
+use std::sync::Arc;
 
 #[wasm_bindgen]
 #[repr(u8)]
@@ -50,49 +76,119 @@ impl<'de> Deserialize<'de> for SampleFormat {
     }
 }
 
+/// Named option sets for common use cases.
+///
+/// The output is always WAV; presets pick the closest WAV settings until
+/// compressed encoders are available. There is no separate limiter: samples
+/// outside full scale are clipped when written, and loudness normalization
+/// is approximated with [`AudioOptions::auto_gain`].
 #[wasm_bindgen]
 #[repr(u8)]
-#[derive(Copy, Clone, TryFromPrimitive, Serialize)]
-pub enum ResampleMethod {
-    Linear,
-    Sinc,
-}
-
-impl<'de> Deserialize<'de> for ResampleMethod {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        struct ResampleQualityVisitor;
-
-        impl<'de> serde::de::Visitor<'de> for ResampleQualityVisitor {
-            type Value = ResampleMethod;
-
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("an i64")
-            }
-
-            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                ResampleMethod::try_from(value as u8)
-                    .map_err(|_| E::custom("Invalid ResampleQuality"))
-            }
-        }
-
-        deserializer.deserialize_any(ResampleQualityVisitor)
-    }
+#[derive(Copy, Clone)]
+pub enum ConversionPreset {
+    /// 48 kHz stereo 32-bit float with sinc resampling, no processing and
+    /// auto gain off.
+    Archival,
+    /// 44.1 kHz stereo 16-bit with linear resampling and auto gain, plus
+    /// silence fallback and missing-keysound substitution so previews always
+    /// play.
+    WebPreview,
 }
 
 #[wasm_bindgen]
-#[derive(Copy, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AudioOptions {
     channels: u16,
     sample_rate: u32,
     bits_per_sample: u16,
     sample_format: SampleFormat,
     resample_quality: ResampleMethod,
+    #[serde(default)]
+    max_messages: Option<u32>,
+    #[serde(default)]
+    max_events: Option<u32>,
+    #[serde(default)]
+    max_total_len: Option<u32>,
+    #[serde(default)]
+    max_decoded_bytes: Option<u32>,
+    #[serde(default)]
+    base_bpm: Option<f64>,
+    #[serde(default)]
+    coalesce_threshold_ms: Option<f64>,
+    #[serde(default)]
+    silent_fallback: bool,
+    #[serde(default)]
+    substitute_missing: bool,
+    #[serde(default)]
+    detect_loop: bool,
+    #[serde(default)]
+    min_silence_gap_sec: Option<f64>,
+    #[serde(default)]
+    prioritize_decode: bool,
+    #[serde(default)]
+    base64_output: bool,
+    #[serde(default)]
+    text_encoding: Option<TextEncoding>,
+    #[serde(default)]
+    sustain_long_notes: bool,
+    #[serde(default)]
+    cut_long_notes: bool,
+    #[serde(default)]
+    jitter_ms: Option<f64>,
+    #[serde(default)]
+    jitter_seed: Option<u32>,
+    #[serde(default)]
+    auto_gain: bool,
+    #[serde(default)]
+    multichannel_stems: bool,
+    #[serde(default)]
+    bpm_policy: Option<BpmPolicy>,
+    #[serde(default)]
+    stop_order: Option<StopOrder>,
+    #[serde(default)]
+    progress_interval_ms: Option<u32>,
+    #[serde(default)]
+    include_invisible_notes: bool,
+    #[serde(default)]
+    low_memory: bool,
+    #[serde(default)]
+    mine_hit_sound: bool,
+    #[serde(default)]
+    chart_mode: Option<ChartMode>,
+    #[serde(default)]
+    keep_leading_measures: bool,
+    #[serde(default)]
+    random_seed: Option<u32>,
+    #[serde(default)]
+    extended_measures: bool,
+    #[serde(default)]
+    duplicate_policy: Option<DuplicatePolicy>,
+    #[serde(default)]
+    memory_budget_bytes: Option<u32>,
+    #[serde(default)]
+    max_file_bytes: Option<u32>,
+    #[serde(default)]
+    max_decode_ms: Option<u32>,
+    #[serde(default)]
+    fail_on_file_limit: bool,
+    #[serde(default)]
+    correct_rate_drift: bool,
+    #[serde(default)]
+    gapless_slices: bool,
+    #[serde(default)]
+    rate: Option<f64>,
+    #[serde(default)]
+    pitch_semitones: Option<f64>,
+    #[serde(default)]
+    forced_random: Vec<RandomChoice>,
+    #[serde(default)]
+    ln_pairing: Option<LnPairing>,
+    #[serde(default)]
+    cut_at_volume_changes: bool,
+    #[serde(default)]
+    end_measure: Option<u32>,
+    #[serde(default)]
+    silent_ln_tails: bool,
 }
 
 #[wasm_bindgen]
@@ -111,6 +207,67 @@ impl AudioOptions {
             bits_per_sample,
             sample_format,
             resample_quality,
+            max_messages: None,
+            max_events: None,
+            max_total_len: None,
+            max_decoded_bytes: None,
+            base_bpm: None,
+            coalesce_threshold_ms: None,
+            silent_fallback: false,
+            substitute_missing: false,
+            detect_loop: false,
+            min_silence_gap_sec: None,
+            prioritize_decode: false,
+            base64_output: false,
+            text_encoding: None,
+            sustain_long_notes: false,
+            cut_long_notes: false,
+            jitter_ms: None,
+            jitter_seed: None,
+            auto_gain: false,
+            multichannel_stems: false,
+            bpm_policy: None,
+            stop_order: None,
+            progress_interval_ms: None,
+            include_invisible_notes: false,
+            low_memory: false,
+            mine_hit_sound: false,
+            chart_mode: None,
+            keep_leading_measures: false,
+            random_seed: None,
+            extended_measures: false,
+            duplicate_policy: None,
+            memory_budget_bytes: None,
+            max_file_bytes: None,
+            max_decode_ms: None,
+            fail_on_file_limit: false,
+            correct_rate_drift: false,
+            gapless_slices: false,
+            rate: None,
+            pitch_semitones: None,
+            forced_random: Vec::new(),
+            ln_pairing: None,
+            cut_at_volume_changes: false,
+            end_measure: None,
+            silent_ln_tails: false,
+        }
+    }
+
+    /// Create options from a named preset. Individual fields can still be
+    /// adjusted through their setters afterwards.
+    pub fn from_preset(preset: ConversionPreset) -> Self {
+        match preset {
+            ConversionPreset::Archival => {
+                Self::new(2, 48000, 32, SampleFormat::Float, ResampleMethod::Sinc)
+            }
+            ConversionPreset::WebPreview => {
+                let mut options =
+                    Self::new(2, 44100, 16, SampleFormat::Int, ResampleMethod::Linear);
+                options.silent_fallback = true;
+                options.substitute_missing = true;
+                options.auto_gain = true;
+                options
+            }
         }
     }
 
@@ -138,286 +295,1726 @@ impl AudioOptions {
     pub fn resample_quality(&self) -> ResampleMethod {
         self.resample_quality
     }
-}
 
-#[inline]
-fn convert_to_i16(samples: &[f32], buf_bytes: &mut Vec<u8>) {
-    buf_bytes.clear();
-    buf_bytes.reserve(samples.len() * 2);
-    let n = samples.len();
-    let n8 = n & !7;
-    let maxf = i16::MAX as f32;
-    let minf = i16::MIN as f32;
-    let mut i = 0;
-    while i < n8 {
-        let v = f32x8::from(&samples[i..i + 8]);
-        let mut q = (v * f32x8::splat(maxf)).round();
-        q = q.max(f32x8::splat(minf)).min(f32x8::splat(maxf));
-        let arr: [f32; 8] = q.into();
-        for &f in &arr {
-            let s = f as i16;
-            buf_bytes.extend_from_slice(&s.to_le_bytes());
-        }
-        i += 8;
-    }
-    for &s in &samples[n8..] {
-        let q = (s * i16::MAX as f32).round();
-        let q = if q < i16::MIN as f32 {
-            i16::MIN
-        } else if q > i16::MAX as f32 {
-            i16::MAX
-        } else {
-            q as i16
-        };
-        buf_bytes.extend_from_slice(&q.to_le_bytes());
+    #[wasm_bindgen(getter)]
+    pub fn max_messages(&self) -> Option<u32> {
+        self.max_messages
     }
-}
 
-#[inline]
-fn js_value_to_bytes(val: &JsValue, rel_path: &str) -> Result<Arc<[u8]>, JsValue> {
-    if let Some(u8a) = val.dyn_ref::<Uint8Array>() {
-        let mut v = vec![0u8; u8a.length() as usize];
-        u8a.copy_to(&mut v[..]);
-        Ok(Arc::from(v))
-    } else {
-        Err(JsValue::from_str(&format!(
-            "Value for {} is not Uint8Array",
-            rel_path
-        )))
+    #[wasm_bindgen(setter)]
+    pub fn set_max_messages(&mut self, value: Option<u32>) {
+        self.max_messages = value;
     }
-}
 
-#[inline]
-fn call_chunk(cb: &js_sys::Function, data: &[u8]) -> Result<(), JsValue> {
-    let u8a = Uint8Array::new_with_length(data.len() as u32);
-    u8a.copy_from(data);
-    cb.call1(&JsValue::NULL, &u8a)?;
-    Ok(())
-}
+    #[wasm_bindgen(getter)]
+    pub fn max_events(&self) -> Option<u32> {
+        self.max_events
+    }
 
-#[inline]
-fn report_progress(on_progress: &js_sys::Function, progress: u32, stage: &str) {
-    let _ = on_progress.call2(
-        &JsValue::NULL,
-        &JsValue::from(progress),
-        &JsValue::from_str(stage),
-    );
-}
+    #[wasm_bindgen(setter)]
+    pub fn set_max_events(&mut self, value: Option<u32>) {
+        self.max_events = value;
+    }
 
-#[wasm_bindgen]
-pub async fn convert_bms_to_wav(
-    bms_text: String,
-    audio_options: JsValue,
-    on_progress: js_sys::Function,
-    on_chunk: js_sys::Function,
-    get_many_bytes: js_sys::Function,
-) -> Result<(), JsValue> {
-    let audio_options: AudioOptions = serde_wasm_bindgen::from_value(audio_options)?;
+    #[wasm_bindgen(getter)]
+    pub fn max_total_len(&self) -> Option<u32> {
+        self.max_total_len
+    }
 
-    report_progress(&on_progress, 5, "Parsing BMS");
-    let bms =
-        Bms::parse(&bms_text).map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
-    let tempo_map = build_tempo_map(&bms);
-    report_progress(&on_progress, 10, "Building tempo map");
+    #[wasm_bindgen(setter)]
+    pub fn set_max_total_len(&mut self, value: Option<u32>) {
+        self.max_total_len = value;
+    }
 
-    let mut filenames: Vec<String> = {
-        let mut v = Vec::with_capacity(bms.header.audio_files.len());
-        v.extend(bms.header.audio_files.values().cloned());
-        v
-    };
-    filenames.sort();
-    filenames.dedup();
-    let mut filename_to_id: AHashMap<String, usize> = AHashMap::new();
-    for (i, f) in filenames.iter().enumerate() {
-        filename_to_id.insert(f.clone(), i);
+    #[wasm_bindgen(getter)]
+    pub fn max_decoded_bytes(&self) -> Option<u32> {
+        self.max_decoded_bytes
     }
 
-    let channels = audio_options.channels() as usize;
-    let sample_rate = audio_options.sample_rate();
-    let resample_quality = audio_options.resample_quality();
-    let sound_events =
-        extract_sound_events(&bms, &tempo_map, &filename_to_id, sample_rate, channels);
-    if sound_events.is_empty() {
-        return Err(JsValue::from_str("No sound events found"));
-    }
-
-    let used_ids: HashSet<usize> = sound_events.iter().map(|ev| ev.key_id).collect();
-    let mut ordered_ids: Vec<usize> = used_ids.iter().copied().collect();
-    ordered_ids.sort_unstable();
-    let mut paths: Vec<String> = Vec::with_capacity(ordered_ids.len());
-    for &id in &ordered_ids {
-        paths.push(filenames[id].clone());
-    }
-
-    let js_paths = Array::new();
-    for p in &paths {
-        js_paths.push(&JsValue::from_str(p));
-    }
-    let promise_val = get_many_bytes
-        .call1(&JsValue::NULL, &js_paths)
-        .map_err(|e| JsValue::from_str(&format!("get_many_bytes call failed: {:?}", e)))?;
-    let promise: js_sys::Promise = promise_val
-        .dyn_into()
-        .map_err(|_| JsValue::from_str("get_many_bytes did not return a Promise"))?;
-    report_progress(&on_progress, 15, "Loading audio files");
-    let resolved = JsFuture::from(promise).await?;
-
-    let arr: Array = if let Some(a) = resolved.dyn_ref::<Array>() {
-        a.clone()
-    } else {
-        return Err(JsValue::from_str(
-            "get_many_bytes did not resolve to an Array",
-        ));
-    };
+    #[wasm_bindgen(setter)]
+    pub fn set_max_decoded_bytes(&mut self, value: Option<u32>) {
+        self.max_decoded_bytes = value;
+    }
 
-    let mut inputs: Vec<(usize, Arc<[u8]>)> = Vec::with_capacity(ordered_ids.len());
-    for (i, &id) in ordered_ids.iter().enumerate() {
-        let rel_path = &paths[i];
-        let val = arr.get(i as u32);
-        if val.is_undefined() || val.is_null() {
-            // Audio is missing so skip it.
-            continue;
-        }
-        match js_value_to_bytes(&val, rel_path) {
-            Ok(bytes_arc) => inputs.push((id, bytes_arc)),
-            Err(_) => {
-                // Audio is not a Uint8Array so skip it.
-                continue;
-            }
-        }
+    #[wasm_bindgen(getter)]
+    pub fn base_bpm(&self) -> Option<f64> {
+        self.base_bpm
     }
 
-    report_progress(&on_progress, 20, "Decoding audio files");
-    let results: Vec<DecodeResult> = inputs
-        .into_par_iter()
-        .map(|(id, bytes)| {
-            crate::audio::decode_audio(bytes, sample_rate, channels, resample_quality)
-                .map_err(|e| format!("Error while decoding {}: {}", filenames[id], e))
-                .map(|r| (id, r))
-        })
-        .collect();
-    report_progress(&on_progress, 50, "Audio decoded");
-    let mut decoded_pairs: Vec<(usize, (Vec<f32>, usize))> = Vec::with_capacity(results.len());
-    for r in results {
-        match r {
-            Ok(p) => decoded_pairs.push(p),
-            Err(_e) => {
-                // Ignore decode errors to continue rendering without this audio
-            }
-        }
+    #[wasm_bindgen(setter)]
+    pub fn set_base_bpm(&mut self, value: Option<f64>) {
+        self.base_bpm = value;
     }
 
-    let mut decoded_vec: Vec<(Vec<f32>, usize)> = vec![(Vec::new(), 0); filenames.len()];
-    for (id, (buf, frames)) in decoded_pairs.into_iter() {
-        decoded_vec[id] = (buf, frames);
+    #[wasm_bindgen(getter)]
+    pub fn coalesce_threshold_ms(&self) -> Option<f64> {
+        self.coalesce_threshold_ms
     }
 
-    report_progress(&on_progress, 55, "Preparing events");
-    let prepared = prepare_events(&sound_events, &decoded_vec, channels);
-    if prepared.total_len == 0 {
-        return Err(JsValue::from_str("Nothing to mix"));
+    #[wasm_bindgen(setter)]
+    pub fn set_coalesce_threshold_ms(&mut self, value: Option<f64>) {
+        self.coalesce_threshold_ms = value;
     }
-    let (chunk_count, buckets) =
-        bucketize_events(&prepared.events, prepared.total_len, sample_rate, channels);
-    let pre = precompute_overlaps(
-        &prepared.events,
-        &decoded_vec,
-        &buckets,
-        prepared.total_len,
-        sample_rate,
-        channels,
-    );
-    report_progress(&on_progress, 60, "Mixing audio");
-
-    let out_channels = audio_options.channels();
-    let out_sample_rate = audio_options.sample_rate();
-    let bits_per_sample = audio_options.bits_per_sample();
-    let use_float = matches!(audio_options.sample_format(), SampleFormat::Float);
-    let audio_format: u16 = if use_float { 3 } else { 1 };
-    let block_align: u16 = out_channels * (bits_per_sample / 8);
-    let byte_rate: u32 = out_sample_rate * block_align as u32;
-
-    let bytes_per_sample: u32 = (bits_per_sample as u32) / 8;
-    let total_bytes_64 = (prepared.total_len as u64) * (bytes_per_sample as u64);
-    if total_bytes_64 > (u32::MAX as u64) {
-        return Err(JsValue::from_str("Output exceeds WAV 4GB limit"));
-    }
-    let data_len: u32 = total_bytes_64 as u32;
-    let file_size_minus_8: u32 = 36 + data_len;
-    let mut header: Vec<u8> = Vec::with_capacity(44);
-    header.extend_from_slice(b"RIFF");
-    header.extend_from_slice(&file_size_minus_8.to_le_bytes());
-    header.extend_from_slice(b"WAVE");
-    header.extend_from_slice(b"fmt ");
-    header.extend_from_slice(&16u32.to_le_bytes());
-    header.extend_from_slice(&audio_format.to_le_bytes());
-    header.extend_from_slice(&out_channels.to_le_bytes());
-    header.extend_from_slice(&out_sample_rate.to_le_bytes());
-    header.extend_from_slice(&byte_rate.to_le_bytes());
-    header.extend_from_slice(&block_align.to_le_bytes());
-    header.extend_from_slice(&bits_per_sample.to_le_bytes());
-    header.extend_from_slice(b"data");
-    header.extend_from_slice(&data_len.to_le_bytes());
-    call_chunk(&on_chunk, &header)?;
-    report_progress(&on_progress, 65, "Writing WAV header");
-
-    let (tx, rx) = mpsc::channel::<(usize, Vec<f32>)>();
-    (0..chunk_count)
-        .into_par_iter()
-        .for_each_with(tx.clone(), |s, ci| {
-            let buf = mix_chunk(
-                ci,
-                &prepared.events,
-                &decoded_vec,
-                &pre,
-                prepared.total_len,
-                sample_rate,
-                channels,
-            );
-            let _ = s.send((ci, buf));
-        });
-    drop(tx);
-
-    let mut pending: AHashMap<usize, Vec<f32>> = AHashMap::new();
-    let mut next_ci: usize = 0;
-    let mut emitted: usize = 0;
-    let mut buf_bytes: Vec<u8> = Vec::new();
-    while emitted < chunk_count {
-        if let Ok((ci, samples)) = rx.recv() {
-            if ci == next_ci {
-                if use_float {
-                    let bytes: &[u8] = bytemuck::cast_slice(&samples);
-                    call_chunk(&on_chunk, bytes)?;
-                } else {
-                    convert_to_i16(&samples, &mut buf_bytes);
-                    call_chunk(&on_chunk, &buf_bytes)?;
-                }
-                next_ci += 1;
-                emitted += 1;
 
-                // Report progress every 10 chunks
-                if emitted.is_multiple_of(10) || emitted == chunk_count {
-                    let progress = 65 + ((emitted as f32 / chunk_count as f32) * 30.0) as u32;
-                    report_progress(&on_progress, progress, "Mixing audio");
-                }
+    #[wasm_bindgen(getter)]
+    pub fn silent_fallback(&self) -> bool {
+        self.silent_fallback
+    }
 
-                while let Some(samples2) = pending.remove(&next_ci) {
-                    if use_float {
-                        let bytes: &[u8] = bytemuck::cast_slice(&samples2);
-                        call_chunk(&on_chunk, bytes)?;
-                    } else {
-                        convert_to_i16(&samples2, &mut buf_bytes);
-                        call_chunk(&on_chunk, &buf_bytes)?;
-                    }
-                    next_ci += 1;
-                    emitted += 1;
-                }
-            } else {
-                pending.insert(ci, samples);
-            }
-        } else {
-            break;
-        }
+    #[wasm_bindgen(setter)]
+    pub fn set_silent_fallback(&mut self, value: bool) {
+        self.silent_fallback = value;
     }
-    Ok(())
+
+    #[wasm_bindgen(getter)]
+    pub fn substitute_missing(&self) -> bool {
+        self.substitute_missing
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_substitute_missing(&mut self, value: bool) {
+        self.substitute_missing = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn detect_loop(&self) -> bool {
+        self.detect_loop
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_detect_loop(&mut self, value: bool) {
+        self.detect_loop = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn min_silence_gap_sec(&self) -> Option<f64> {
+        self.min_silence_gap_sec
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_min_silence_gap_sec(&mut self, value: Option<f64>) {
+        self.min_silence_gap_sec = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn prioritize_decode(&self) -> bool {
+        self.prioritize_decode
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_prioritize_decode(&mut self, value: bool) {
+        self.prioritize_decode = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn base64_output(&self) -> bool {
+        self.base64_output
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_base64_output(&mut self, value: bool) {
+        self.base64_output = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn text_encoding(&self) -> Option<TextEncoding> {
+        self.text_encoding
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_text_encoding(&mut self, value: Option<TextEncoding>) {
+        self.text_encoding = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn sustain_long_notes(&self) -> bool {
+        self.sustain_long_notes
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_sustain_long_notes(&mut self, value: bool) {
+        self.sustain_long_notes = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn cut_long_notes(&self) -> bool {
+        self.cut_long_notes
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_cut_long_notes(&mut self, value: bool) {
+        self.cut_long_notes = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn jitter_ms(&self) -> Option<f64> {
+        self.jitter_ms
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_jitter_ms(&mut self, value: Option<f64>) {
+        self.jitter_ms = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn jitter_seed(&self) -> Option<u32> {
+        self.jitter_seed
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_jitter_seed(&mut self, value: Option<u32>) {
+        self.jitter_seed = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn auto_gain(&self) -> bool {
+        self.auto_gain
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_auto_gain(&mut self, value: bool) {
+        self.auto_gain = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn multichannel_stems(&self) -> bool {
+        self.multichannel_stems
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_multichannel_stems(&mut self, value: bool) {
+        self.multichannel_stems = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn bpm_policy(&self) -> Option<BpmPolicy> {
+        self.bpm_policy
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_bpm_policy(&mut self, value: Option<BpmPolicy>) {
+        self.bpm_policy = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn stop_order(&self) -> Option<StopOrder> {
+        self.stop_order
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_stop_order(&mut self, value: Option<StopOrder>) {
+        self.stop_order = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn progress_interval_ms(&self) -> Option<u32> {
+        self.progress_interval_ms
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_progress_interval_ms(&mut self, value: Option<u32>) {
+        self.progress_interval_ms = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn include_invisible_notes(&self) -> bool {
+        self.include_invisible_notes
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_include_invisible_notes(&mut self, value: bool) {
+        self.include_invisible_notes = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn low_memory(&self) -> bool {
+        self.low_memory
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_low_memory(&mut self, value: bool) {
+        self.low_memory = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn mine_hit_sound(&self) -> bool {
+        self.mine_hit_sound
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_mine_hit_sound(&mut self, value: bool) {
+        self.mine_hit_sound = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn chart_mode(&self) -> Option<ChartMode> {
+        self.chart_mode
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_chart_mode(&mut self, value: Option<ChartMode>) {
+        self.chart_mode = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn keep_leading_measures(&self) -> bool {
+        self.keep_leading_measures
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_keep_leading_measures(&mut self, value: bool) {
+        self.keep_leading_measures = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn random_seed(&self) -> Option<u32> {
+        self.random_seed
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_random_seed(&mut self, value: Option<u32>) {
+        self.random_seed = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn extended_measures(&self) -> bool {
+        self.extended_measures
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_extended_measures(&mut self, value: bool) {
+        self.extended_measures = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn duplicate_policy(&self) -> Option<DuplicatePolicy> {
+        self.duplicate_policy
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_duplicate_policy(&mut self, value: Option<DuplicatePolicy>) {
+        self.duplicate_policy = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn memory_budget_bytes(&self) -> Option<u32> {
+        self.memory_budget_bytes
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_memory_budget_bytes(&mut self, value: Option<u32>) {
+        self.memory_budget_bytes = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn max_file_bytes(&self) -> Option<u32> {
+        self.max_file_bytes
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_max_file_bytes(&mut self, value: Option<u32>) {
+        self.max_file_bytes = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn max_decode_ms(&self) -> Option<u32> {
+        self.max_decode_ms
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_max_decode_ms(&mut self, value: Option<u32>) {
+        self.max_decode_ms = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn fail_on_file_limit(&self) -> bool {
+        self.fail_on_file_limit
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_fail_on_file_limit(&mut self, value: bool) {
+        self.fail_on_file_limit = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn correct_rate_drift(&self) -> bool {
+        self.correct_rate_drift
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_correct_rate_drift(&mut self, value: bool) {
+        self.correct_rate_drift = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn gapless_slices(&self) -> bool {
+        self.gapless_slices
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_gapless_slices(&mut self, value: bool) {
+        self.gapless_slices = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_rate(&mut self, value: Option<f64>) {
+        self.rate = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn pitch_semitones(&self) -> Option<f64> {
+        self.pitch_semitones
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_pitch_semitones(&mut self, value: Option<f64>) {
+        self.pitch_semitones = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn forced_random(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.forced_random)?)
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_forced_random(&mut self, value: JsValue) -> Result<(), JsValue> {
+        self.forced_random = serde_wasm_bindgen::from_value(value)?;
+        Ok(())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn ln_pairing(&self) -> Option<LnPairing> {
+        self.ln_pairing
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_ln_pairing(&mut self, value: Option<LnPairing>) {
+        self.ln_pairing = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn cut_at_volume_changes(&self) -> bool {
+        self.cut_at_volume_changes
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_cut_at_volume_changes(&mut self, value: bool) {
+        self.cut_at_volume_changes = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn end_measure(&self) -> Option<u32> {
+        self.end_measure
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_end_measure(&mut self, value: Option<u32>) {
+        self.end_measure = value;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn silent_ln_tails(&self) -> bool {
+        self.silent_ln_tails
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_silent_ln_tails(&mut self, value: bool) {
+        self.silent_ln_tails = value;
+    }
+}
+
+impl AudioOptions {
+    fn resource_limits(&self) -> ResourceLimits {
+        ResourceLimits {
+            max_messages: self.max_messages.map(|v| v as usize),
+            max_events: self.max_events.map(|v| v as usize),
+            max_total_len: self.max_total_len.map(|v| v as usize),
+            max_decoded_bytes: self.max_decoded_bytes.map(|v| v as usize),
+        }
+    }
+
+    fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_file_bytes: self.max_file_bytes.map(|v| v as usize),
+            max_decode_ms: self.max_decode_ms.map(f64::from),
+            clock: js_sys::Date::now,
+        }
+    }
+
+    fn pcm_format(&self) -> Result<PcmFormat, JsValue> {
+        let float = matches!(self.sample_format, SampleFormat::Float);
+        PcmFormat::from_bits(self.bits_per_sample, float).ok_or_else(|| {
+            JsValue::from_str(&format!(
+                "Unsupported sample format: {}-bit {}",
+                self.bits_per_sample,
+                if float { "float" } else { "integer" }
+            ))
+        })
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            mode: self.chart_mode.unwrap_or_default(),
+            random_seed: self.random_seed.unwrap_or(0) as u64,
+            forced_random: self.forced_random.clone(),
+            measure_parser: self
+                .extended_measures
+                .then_some(extended_measure as MeasureParser),
+            duplicate_policy: self.duplicate_policy.unwrap_or_default(),
+            ..ParseOptions::default()
+        }
+    }
+
+    fn tempo_map_options(&self) -> TempoMapOptions {
+        TempoMapOptions {
+            base_bpm: self.base_bpm,
+            bpm_policy: self.bpm_policy.unwrap_or_default(),
+            keep_leading_measures: self.keep_leading_measures,
+            stop_order: self.stop_order.unwrap_or_default(),
+        }
+    }
+
+    fn sound_event_options(&self) -> SoundEventOptions {
+        SoundEventOptions {
+            include_invisible_notes: self.include_invisible_notes,
+            mine_hit_sound: self.mine_hit_sound,
+            cut_long_notes: self.cut_long_notes,
+            ln_pairing: self.ln_pairing.unwrap_or_default(),
+            cut_at_volume_changes: self.cut_at_volume_changes,
+            end_measure: self.end_measure,
+            silent_ln_tails: self.silent_ln_tails,
+        }
+    }
+
+    fn render_options(&self) -> Result<RenderOptions, JsValue> {
+        Ok(RenderOptions {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            format: self.pcm_format()?,
+            resample_quality: self.resample_quality,
+            limits: self.resource_limits(),
+            decode_limits: self.decode_limits(),
+            tempo_map: self.tempo_map_options(),
+            sound_events: self.sound_event_options(),
+            coalesce_threshold_ms: self.coalesce_threshold_ms,
+            silent_fallback: self.silent_fallback,
+            substitute_missing: self.substitute_missing,
+            detect_loop: self.detect_loop,
+            min_silence_gap_sec: self.min_silence_gap_sec,
+            prioritize_decode: self.prioritize_decode,
+            sustain_long_notes: self.sustain_long_notes,
+            jitter_ms: self.jitter_ms,
+            jitter_seed: self.jitter_seed.unwrap_or(0) as u64,
+            auto_gain: self.auto_gain,
+            multichannel_stems: self.multichannel_stems,
+            low_memory: self.low_memory,
+            memory_budget_bytes: self.memory_budget_bytes.map(u64::from),
+            fail_on_file_limit: self.fail_on_file_limit,
+            correct_rate_drift: self.correct_rate_drift,
+            gapless_slices: self.gapless_slices,
+            rate: self.rate,
+            pitch_semitones: self.pitch_semitones,
+            progress_interval_ms: self
+                .progress_interval_ms
+                .map_or(RenderOptions::default().progress_interval_ms, f64::from),
+        })
+    }
+}
+
+/// Loader calling the host's `get_many_bytes(paths)`, which resolves to an
+/// array of `Uint8Array` (`null` or `undefined` for missing files).
+struct JsLoader<'a> {
+    callback: &'a js_sys::Function,
+}
+
+/// Decode cache backed by a host object with `get(key)` and `put(key, samples)`
+/// methods, either of which may return a Promise.
+struct JsDecodeCache {
+    target: js_sys::Object,
+}
+
+impl JsDecodeCache {
+    /// Call a method of the host object and wait for its result.
+    async fn call(&self, method: &str, args: &Array) -> Option<JsValue> {
+        let function: js_sys::Function =
+            js_sys::Reflect::get(&self.target, &JsValue::from_str(method))
+                .ok()?
+                .dyn_into()
+                .ok()?;
+        let value = function.apply(&self.target, args).ok()?;
+        match value.dyn_into::<js_sys::Promise>() {
+            Ok(promise) => JsFuture::from(promise).await.ok(),
+            Err(value) => Some(value),
+        }
+    }
+}
+
+impl DecodeCache for JsDecodeCache {
+    async fn get(&self, key: &CacheKey) -> Option<Vec<f32>> {
+        self.call("get", &Array::of1(&JsValue::from_str(&key.to_string())))
+            .await?
+            .dyn_into::<js_sys::Float32Array>()
+            .ok()
+            .map(|samples| samples.to_vec())
+    }
+
+    async fn put(&self, key: &CacheKey, samples: &[f32]) {
+        let samples = js_sys::Float32Array::from(samples);
+        self.call(
+            "put",
+            &Array::of2(&JsValue::from_str(&key.to_string()), &samples),
+        )
+        .await;
+    }
+}
+
+/// Files resolved by `get_many_bytes`, copied out of JavaScript one at a time.
+struct JsFiles {
+    array: Array,
+}
+
+impl JsFiles {
+    fn file(&self, index: usize) -> Option<Uint8Array> {
+        self.array.get(index as u32).dyn_into::<Uint8Array>().ok()
+    }
+}
+
+impl LoadedFiles for JsFiles {
+    fn size(&self, index: usize) -> Option<u64> {
+        self.file(index).map(|file| file.length() as u64)
+    }
+
+    fn header(&self, index: usize, len: usize) -> Option<Vec<u8>> {
+        let file = self.file(index)?;
+        let len = (file.length() as usize).min(len) as u32;
+        Some(file.subarray(0, len).to_vec())
+    }
+
+    fn bytes(&self, index: usize) -> Option<Arc<[u8]>> {
+        let file = self.file(index)?;
+        let mut bytes = vec![0u8; file.length() as usize];
+        file.copy_to(&mut bytes);
+        Some(Arc::from(bytes))
+    }
+}
+
+impl AudioLoader for JsLoader<'_> {
+    type Files = JsFiles;
+
+    async fn load(&self, paths: &[String]) -> Result<JsFiles, String> {
+        let js_paths = Array::new();
+        for path in paths {
+            js_paths.push(&JsValue::from_str(path));
+        }
+        let promise: js_sys::Promise = self
+            .callback
+            .call1(&JsValue::NULL, &js_paths)
+            .map_err(|e| format!("get_many_bytes call failed: {:?}", e))?
+            .dyn_into()
+            .map_err(|_| "get_many_bytes did not return a Promise".to_string())?;
+        let array = JsFuture::from(promise)
+            .await
+            .map_err(|e| format!("get_many_bytes failed: {:?}", e))?
+            .dyn_into()
+            .map_err(|_| "get_many_bytes did not resolve to an Array".to_string())?;
+        Ok(JsFiles { array })
+    }
+}
+
+#[inline]
+fn call_chunk(cb: &js_sys::Function, data: &[u8]) -> Result<(), JsValue> {
+    let u8a = Uint8Array::new_with_length(data.len() as u32);
+    u8a.copy_from(data);
+    cb.call1(&JsValue::NULL, &u8a)?;
+    Ok(())
+}
+
+/// Output stream passed to a chunk callback, either as raw bytes or as base64 text.
+struct ChunkSink<'a> {
+    callback: &'a js_sys::Function,
+    base64: Option<Base64Chunker>,
+    /// Exception thrown by the callback, returned instead of the render error.
+    error: Option<JsValue>,
+}
+
+impl<'a> ChunkSink<'a> {
+    fn new(callback: &'a js_sys::Function, base64: bool) -> Self {
+        Self {
+            callback,
+            base64: base64.then(Base64Chunker::new),
+            error: None,
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        match &mut self.base64 {
+            Some(chunker) => {
+                let text = chunker.push(data);
+                if !text.is_empty() {
+                    self.callback
+                        .call1(&JsValue::NULL, &JsValue::from_str(&text))?;
+                }
+                Ok(())
+            }
+            None => call_chunk(self.callback, data),
+        }
+    }
+
+    /// Flush the base64 remainder (with padding); no-op for raw output.
+    fn flush(&mut self) -> Result<(), JsValue> {
+        if let Some(chunker) = self.base64.take() {
+            let text = chunker.finish();
+            if !text.is_empty() {
+                self.callback
+                    .call1(&JsValue::NULL, &JsValue::from_str(&text))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep a thrown exception so it reaches the host unchanged.
+    fn keep(&mut self, result: Result<(), JsValue>) -> Result<(), String> {
+        result.map_err(|e| {
+            let message = format!("Chunk callback failed: {:?}", e);
+            self.error = Some(e);
+            message
+        })
+    }
+}
+
+impl ByteSink for ChunkSink<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        let result = self.send(data);
+        self.keep(result)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        let result = self.flush();
+        self.keep(result)
+    }
+}
+
+/// Read a chart passed either as a string or as raw bytes (`Uint8Array`).
+fn chart_text(input: &JsValue, encoding: Option<TextEncoding>) -> Result<String, JsValue> {
+    if let Some(text) = input.as_string() {
+        Ok(text)
+    } else if let Some(bytes) = input.dyn_ref::<Uint8Array>() {
+        Ok(decode_text(&bytes.to_vec(), encoding).0)
+    } else {
+        Err(JsValue::from_str("BMS data must be a string or Uint8Array"))
+    }
+}
+
+/// Parse a chart passed either as a string or as raw bytes (`Uint8Array`).
+fn parse_bms_input(
+    input: &JsValue,
+    encoding: Option<TextEncoding>,
+    options: &ParseOptions,
+) -> Result<Bms, JsValue> {
+    parse_bms_text(&chart_text(input, encoding)?, options)
+}
+
+fn parse_bms_text(text: &str, options: &ParseOptions) -> Result<Bms, JsValue> {
+    Bms::parse_with_options(text, options)
+        .map(|(bms, _)| bms)
+        .map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))
+}
+
+/// Convert a render failure, raising limit errors as `ResourceLimitExceeded`.
+fn render_error(err: RenderError) -> JsValue {
+    match err {
+        RenderError::Limit(err) => limit_error(err),
+        RenderError::Failed(message) => JsValue::from_str(&message),
+    }
+}
+
+fn limit_error(err: ResourceLimitExceeded) -> JsValue {
+    let js_err = js_sys::Error::new(&err.to_string());
+    js_err.set_name("ResourceLimitExceeded");
+    let _ = js_sys::Reflect::set(
+        &js_err,
+        &JsValue::from_str("kind"),
+        &JsValue::from_str(&format!("{:?}", err.kind)),
+    );
+    let _ = js_sys::Reflect::set(
+        &js_err,
+        &JsValue::from_str("limit"),
+        &JsValue::from(err.limit as f64),
+    );
+    let _ = js_sys::Reflect::set(
+        &js_err,
+        &JsValue::from_str("actual"),
+        &JsValue::from(err.actual as f64),
+    );
+    js_err.into()
+}
+
+/// Forward a progress update to the host's callback.
+fn report_progress(on_progress: &js_sys::Function, progress: f64, stage: &str) {
+    let _ = on_progress.call2(
+        &JsValue::NULL,
+        &JsValue::from(progress),
+        &JsValue::from_str(stage),
+    );
+}
+
+/// Random source backed by a host callback returning values in `0.0..1.0`.
+///
+/// The callback has the shape of `Math.random`; a throwing callback or a
+/// non-numeric result counts as `0.0`.
+struct JsRandom<'a> {
+    callback: &'a js_sys::Function,
+}
+
+impl RandomSource for JsRandom<'_> {
+    fn next_u64(&mut self) -> u64 {
+        let value = self
+            .callback
+            .call0(&JsValue::NULL)
+            .ok()
+            .and_then(|v| v.as_f64())
+            .unwrap_or(0.0);
+        fraction_to_bits(value)
+    }
+}
+
+/// Send the library's tracing spans and events to the browser console.
+///
+/// Spans cover parsing, tempo mapping, event extraction, decoding (one span
+/// per keysound file) and mixing, and are also recorded as performance marks
+/// and measures, so they show up in the browser's performance timeline.
+/// Events log per-file decode results and problems. Call once, before the
+/// first conversion.
+///
+/// # Arguments
+///
+/// * `max_level` - Most verbose level to report (`"error"`, `"warn"`, `"info"`,
+///   `"debug"` or `"trace"`); `"debug"` if omitted. `"trace"` adds a span per
+///   mixed chunk.
+///
+/// # Returns
+///
+/// * `Result<(), JsValue>` - Error if the level is unknown or tracing is already enabled.
+#[cfg(all(feature = "tracing", target_arch = "wasm32"))]
+#[wasm_bindgen]
+pub fn enable_tracing(max_level: Option<String>) -> Result<(), JsValue> {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let level = match max_level {
+        Some(level) => level
+            .parse::<tracing::Level>()
+            .map_err(|_| JsValue::from_str(&format!("Unknown tracing level: {}", level)))?,
+        None => tracing::Level::DEBUG,
+    };
+    let config = tracing_wasm::WASMLayerConfigBuilder::new()
+        .set_max_level(level)
+        .build();
+    let subscriber = tracing_subscriber::registry().with(tracing_wasm::WASMLayer::new(config));
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|_| JsValue::from_str("Tracing is already enabled"))
+}
+
+/// Read the display metadata of a chart and its score-tracker hashes.
+///
+/// Pass the file as a `Uint8Array` for hashes that match LR2IR and
+/// beatoraja; a string is hashed as UTF-8, which only matches UTF-8 files.
+#[wasm_bindgen]
+pub fn read_bms_metadata(bms_data: JsValue) -> Result<JsValue, JsValue> {
+    let (text, hash) = if let Some(text) = bms_data.as_string() {
+        let hash = chart_hash(text.as_bytes());
+        (text, hash)
+    } else if let Some(bytes) = bms_data.dyn_ref::<Uint8Array>() {
+        let bytes = bytes.to_vec();
+        (decode_text(&bytes, None).0, chart_hash(&bytes))
+    } else {
+        return Err(JsValue::from_str("BMS data must be a string or Uint8Array"));
+    };
+    let header = Bms::parse_header(&text)
+        .map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    let mut metadata = header.metadata();
+    metadata.hash = Some(hash);
+    Ok(serde_wasm_bindgen::to_value(&metadata)?)
+}
+
+/// Compute the MD5 and SHA-256 that identify a chart file in LR2IR and beatoraja.
+#[wasm_bindgen]
+pub fn chart_fingerprint(bms_bytes: Uint8Array) -> Result<JsValue, JsValue> {
+    Ok(serde_wasm_bindgen::to_value(&chart_hash(
+        &bms_bytes.to_vec(),
+    ))?)
+}
+
+#[wasm_bindgen]
+pub fn check_bms(bms_text: String, strict: bool) -> Result<JsValue, JsValue> {
+    let report = match Bms::parse_with_options(
+        &bms_text,
+        &ParseOptions {
+            strict,
+            ..ParseOptions::default()
+        },
+    ) {
+        Ok((_, report)) | Err(ParseError::Strict(report)) => report,
+        Err(e) => return Err(JsValue::from_str(&format!("BMS parse error: {}", e))),
+    };
+    Ok(serde_wasm_bindgen::to_value(&report)?)
+}
+
+#[wasm_bindgen]
+pub fn list_keysounds(bms_text: String) -> Result<JsValue, JsValue> {
+    let bms =
+        Bms::parse(&bms_text).map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    Ok(serde_wasm_bindgen::to_value(&keysound_usage(&bms))?)
+}
+
+#[wasm_bindgen]
+pub fn list_assets(bms_text: String) -> Result<JsValue, JsValue> {
+    let header = Bms::parse_header(&bms_text)
+        .map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    Ok(serde_wasm_bindgen::to_value(&chart_assets(&header))?)
+}
+
+#[wasm_bindgen]
+pub fn export_sound_bank(bms_text: String, first_key: u8) -> Result<JsValue, JsValue> {
+    let bms =
+        Bms::parse(&bms_text).map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    Ok(serde_wasm_bindgen::to_value(&export_sfz(
+        &bms,
+        first_key.min(127),
+    ))?)
+}
+
+/// Sample rate keysounds are decoded at for pitch detection.
+const PITCH_SAMPLE_RATE: u32 = 44100;
+
+/// Fetch and decode every keysound of a chart and estimate its pitch.
+///
+/// Files that are missing or fail to decode keep `pitch: None`.
+async fn keysound_usage_with_pitch(
+    bms: &Bms,
+    get_many_bytes: &js_sys::Function,
+) -> Result<Vec<KeysoundUsage>, JsValue> {
+    let mut usage = keysound_usage(bms);
+    let paths: Vec<String> = usage.iter().map(|u| u.filename.clone()).collect();
+    let files = JsLoader {
+        callback: get_many_bytes,
+    }
+    .load(&paths)
+    .await
+    .map_err(|e| JsValue::from_str(&e))?;
+    let inputs: Vec<(usize, Arc<[u8]>)> = (0..paths.len())
+        .filter_map(|i| files.bytes(i).map(|bytes| (i, bytes)))
+        .collect();
+
+    let pitches: Vec<(usize, Option<PitchEstimate>)> = inputs
+        .into_par_iter()
+        .map(|(i, bytes)| {
+            let pitch =
+                crate::audio::decode_audio(bytes, PITCH_SAMPLE_RATE, 1, ResampleMethod::Linear)
+                    .ok()
+                    .and_then(|(samples, _)| detect_pitch(&samples, 1, PITCH_SAMPLE_RATE));
+            (i, pitch)
+        })
+        .collect();
+    for (i, pitch) in pitches {
+        usage[i].pitch = pitch;
+    }
+    Ok(usage)
+}
+
+#[wasm_bindgen]
+pub async fn list_keysound_pitches(
+    bms_text: String,
+    get_many_bytes: js_sys::Function,
+) -> Result<JsValue, JsValue> {
+    let bms =
+        Bms::parse(&bms_text).map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    let usage = keysound_usage_with_pitch(&bms, &get_many_bytes).await?;
+    Ok(serde_wasm_bindgen::to_value(&usage)?)
+}
+
+#[wasm_bindgen]
+pub async fn export_pitched_sound_bank(
+    bms_text: String,
+    first_key: u8,
+    get_many_bytes: js_sys::Function,
+) -> Result<JsValue, JsValue> {
+    let bms =
+        Bms::parse(&bms_text).map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    let usage = keysound_usage_with_pitch(&bms, &get_many_bytes).await?;
+    Ok(serde_wasm_bindgen::to_value(&export_sfz_from_usage(
+        &bms,
+        usage,
+        first_key.min(127),
+    ))?)
+}
+
+/// Parse a chart for the timed event lists.
+///
+/// # Arguments
+///
+/// * `bms_data` - Chart text or bytes.
+/// * `audio_options` - Render options, as a JavaScript object.
+///
+/// # Returns
+///
+/// * `Result<(Bms, TempoMap, f64), JsValue>` - Parsed chart, its tempo map and
+///   the playback rate that event times are divided by to match the render.
+fn parse_timed_chart(
+    bms_data: &JsValue,
+    audio_options: JsValue,
+) -> Result<(Bms, TempoMap, f64), JsValue> {
+    let audio_options: AudioOptions = serde_wasm_bindgen::from_value(audio_options)?;
+    let bms = parse_bms_input(
+        bms_data,
+        audio_options.text_encoding,
+        &audio_options.parse_options(),
+    )?;
+    let tempo_map = build_tempo_map_with_options(&bms, &audio_options.tempo_map_options())
+        .map_err(|e| JsValue::from_str(&format!("Tempo error: {}", e)))?;
+    let rate = RenderOptions {
+        rate: audio_options.rate,
+        ..RenderOptions::default()
+    }
+    .playback_rate()
+    .map_err(render_error)?
+    .unwrap_or(1.0);
+    Ok((bms, tempo_map, rate))
+}
+
+/// List the BGA image and video changes of a chart with their times.
+///
+/// Times follow the tempo options and playback rate of `audio_options`,
+/// matching the rendered audio.
+#[wasm_bindgen]
+pub fn list_bga_events(bms_data: JsValue, audio_options: JsValue) -> Result<JsValue, JsValue> {
+    let (bms, tempo_map, rate) = parse_timed_chart(&bms_data, audio_options)?;
+    let mut events = extract_bga_events(&bms, &tempo_map);
+    for event in &mut events {
+        event.time_sec /= rate;
+    }
+    Ok(serde_wasm_bindgen::to_value(&events)?)
+}
+
+/// List the `#SCROLL` and `#SPEED` changes of a chart with their times.
+///
+/// Times are scaled by the playback rate like those of `list_bga_events`.
+#[wasm_bindgen]
+pub fn list_scroll_events(bms_data: JsValue, audio_options: JsValue) -> Result<JsValue, JsValue> {
+    let (bms, tempo_map, rate) = parse_timed_chart(&bms_data, audio_options)?;
+    let mut events = extract_scroll_events(&bms, &tempo_map);
+    for event in &mut events {
+        event.time_sec /= rate;
+    }
+    Ok(serde_wasm_bindgen::to_value(&events)?)
+}
+
+/// List the judge rank changes (channel A0) of a chart with their times.
+///
+/// Times are scaled by the playback rate like those of `list_bga_events`.
+#[wasm_bindgen]
+pub fn list_rank_events(bms_data: JsValue, audio_options: JsValue) -> Result<JsValue, JsValue> {
+    let (bms, tempo_map, rate) = parse_timed_chart(&bms_data, audio_options)?;
+    let mut events = extract_rank_events(&bms, &tempo_map);
+    for event in &mut events {
+        event.time_sec /= rate;
+    }
+    Ok(serde_wasm_bindgen::to_value(&events)?)
+}
+
+#[wasm_bindgen]
+pub fn verify_bms_alignment(
+    bms_text: String,
+    audio_options: JsValue,
+    tolerance_ms: Option<f64>,
+) -> Result<JsValue, JsValue> {
+    let audio_options: AudioOptions = serde_wasm_bindgen::from_value(audio_options)?;
+    let bms =
+        Bms::parse(&bms_text).map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    let options = AlignmentOptions {
+        sample_rate: audio_options.sample_rate(),
+        tolerance_ms: tolerance_ms.unwrap_or(DEFAULT_ALIGNMENT_TOLERANCE_MS),
+        tempo_map: audio_options.tempo_map_options(),
+    };
+    let report = verify_alignment(&bms, &options)
+        .map_err(|e| JsValue::from_str(&format!("Tempo error: {}", e)))?;
+    Ok(serde_wasm_bindgen::to_value(&report)?)
+}
+
+#[wasm_bindgen]
+pub fn diff_bms(old_text: String, new_text: String) -> Result<JsValue, JsValue> {
+    let old =
+        Bms::parse(&old_text).map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    let new =
+        Bms::parse(&new_text).map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    Ok(serde_wasm_bindgen::to_value(&diff_charts(&old, &new))?)
+}
+
+#[wasm_bindgen]
+pub fn required_audio_files(bms_texts: Vec<String>) -> Result<Vec<String>, JsValue> {
+    let charts = bms_texts
+        .iter()
+        .map(|text| Bms::parse(text))
+        .collect::<Result<Vec<Bms>, _>>()
+        .map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    Ok(required_audio_union(&charts))
+}
+
+/// A single audio file decoded by `decode_audio_to_f32`.
+#[wasm_bindgen]
+pub struct DecodedAudio {
+    samples: Vec<f32>,
+    sample_rate: u32,
+    channels: u16,
+    frames: usize,
+    warnings: Vec<String>,
+}
+
+#[wasm_bindgen]
+impl DecodedAudio {
+    /// Interleaved samples.
+    #[wasm_bindgen(getter)]
+    pub fn samples(&self) -> js_sys::Float32Array {
+        js_sys::Float32Array::from(&self.samples[..])
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Problems that did not stop decoding, such as a truncated file.
+    #[wasm_bindgen(getter)]
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+}
+
+/// Decode one audio file the way keysounds are decoded for a render.
+///
+/// Uses the same probing and fallbacks (MP3 inside WAV, ID3 sniffing, chained
+/// Ogg) and converts to the channel count and sample rate of `audio_options`,
+/// so hosts can preview individual keysounds. The per-file size and time
+/// limits of `audio_options` apply.
+#[wasm_bindgen]
+pub fn decode_audio_to_f32(
+    bytes: Uint8Array,
+    audio_options: JsValue,
+) -> Result<DecodedAudio, JsValue> {
+    let audio_options: AudioOptions = serde_wasm_bindgen::from_value(audio_options)?;
+    let limits = audio_options.decode_limits();
+    check_file_size(&limits, Some(bytes.length() as u64))
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let sample_rate = audio_options.sample_rate();
+    let channels = audio_options.channels();
+    let ((samples, frames), info) = crate::audio::decode_audio_with_limits(
+        Arc::from(bytes.to_vec()),
+        sample_rate,
+        channels as usize,
+        audio_options.resample_quality(),
+        None,
+        &limits,
+    )
+    .map_err(|e| JsValue::from_str(&format!("Error while decoding: {}", e)))?;
+    Ok(DecodedAudio {
+        samples,
+        sample_rate,
+        channels,
+        frames,
+        warnings: [info.rate_mismatch(), info.truncation()]
+            .into_iter()
+            .flatten()
+            .collect(),
+    })
+}
+
+/// Find the charts in a song folder and group them as one song.
+///
+/// `filenames` may list the whole folder (or zip); only chart files are
+/// fetched through `get_many_bytes`, and their encoding is detected.
+#[wasm_bindgen]
+pub async fn discover_song_folder(
+    filenames: Vec<String>,
+    get_many_bytes: js_sys::Function,
+) -> Result<JsValue, JsValue> {
+    let charts: Vec<String> = filenames
+        .into_iter()
+        .filter(|name| chart_mode_for_path(name).is_some())
+        .collect();
+    let files = JsLoader {
+        callback: &get_many_bytes,
+    }
+    .load(&charts)
+    .await
+    .map_err(|e| JsValue::from_str(&e))?;
+
+    let mut texts: Vec<(&str, String)> = Vec::with_capacity(charts.len());
+    let mut missing: Vec<String> = Vec::new();
+    for (i, name) in charts.iter().enumerate() {
+        match files.bytes(i) {
+            Some(bytes) => texts.push((name, decode_text(&bytes, None).0)),
+            None => missing.push(name.clone()),
+        }
+    }
+    let mut song = discover_song(texts.iter().map(|(name, text)| (*name, text.as_str())));
+    song.unreadable.extend(missing);
+    Ok(serde_wasm_bindgen::to_value(&song)?)
+}
+
+/// Decoded keysound size predicted from encoded sizes and container headers.
+#[derive(Debug, Clone, Serialize)]
+pub struct DecodeSizeEstimate {
+    /// Decoded size of the required files with f32 storage, in bytes.
+    pub total_bytes: f64,
+    /// Decoded size with the i16 storage of the low-memory profile, in bytes.
+    pub low_memory_bytes: f64,
+    /// Whether `total_bytes` exceeds the `max_decoded_bytes` limit.
+    pub exceeds_limit: bool,
+    /// Files whose length was derived from a bitrate or guessed, not read from a header.
+    pub approximate_files: Vec<String>,
+}
+
+/// A keysound sounding inside a window inspected with `events_between`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveSound {
+    /// Keysound file name.
+    pub filename: String,
+    /// Start of the sound (or of its latest retrigger) in seconds.
+    pub start_sec: f64,
+    /// End of the sound in seconds (exclusive).
+    pub end_sec: f64,
+    /// Frame of the file playing at the start of the window.
+    pub source_frame: usize,
+}
+
+/// A parsed and scheduled chart, ready to be rendered with `render_bms_analysis`.
+#[wasm_bindgen]
+pub struct BmsAnalysis {
+    analysis: Analysis,
+    base64_output: bool,
+}
+
+#[wasm_bindgen]
+impl BmsAnalysis {
+    pub fn summary(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(self.analysis.summary())?)
+    }
+
+    /// Decode a keysound as if it were recorded at `sample_rate`, for files
+    /// whose headers declare the wrong rate. Returns `false` if no keysound
+    /// uses `filename`.
+    pub fn override_source_rate(&mut self, filename: &str, sample_rate: u32) -> bool {
+        self.analysis.override_source_rate(filename, sample_rate)
+    }
+
+    /// Estimate the decoded size of the required keysounds before loading them.
+    ///
+    /// Both arrays follow the order of `summary().required_files`. Each header
+    /// holds the first bytes of the file (4 KiB covers most files); a missing
+    /// header counts as an unrecognized format.
+    pub fn estimate_decoded_size(
+        &self,
+        encoded_sizes: Vec<f64>,
+        headers: Array,
+    ) -> Result<JsValue, JsValue> {
+        let required = &self.analysis.summary.required_files;
+        if encoded_sizes.len() != required.len() {
+            return Err(JsValue::from_str(&format!(
+                "Expected {} encoded sizes, got {}",
+                required.len(),
+                encoded_sizes.len()
+            )));
+        }
+        let sample_rate = self.analysis.options.sample_rate;
+        let channels = self.analysis.options.channels as usize;
+        let mut total_bytes = 0.0;
+        let mut low_memory_bytes = 0.0;
+        let mut approximate_files = Vec::new();
+        for (i, (name, &size)) in required.iter().zip(&encoded_sizes).enumerate() {
+            let header = headers
+                .get(i as u32)
+                .dyn_into::<Uint8Array>()
+                .map(|bytes| bytes.to_vec())
+                .unwrap_or_default();
+            let source = estimate_source(&header, size.max(0.0) as u64);
+            if !source.exact {
+                approximate_files.push(name.clone());
+            }
+            total_bytes += source.decoded_bytes(sample_rate, channels, size_of::<f32>()) as f64;
+            low_memory_bytes +=
+                source.decoded_bytes(sample_rate, channels, size_of::<i16>()) as f64;
+        }
+        let exceeds_limit = self
+            .analysis
+            .options
+            .limits
+            .max_decoded_bytes
+            .is_some_and(|max| total_bytes > max as f64);
+        Ok(serde_wasm_bindgen::to_value(&DecodeSizeEstimate {
+            total_bytes,
+            low_memory_bytes,
+            exceeds_limit,
+            approximate_files,
+        })?)
+    }
+
+    /// List the keysounds sounding between two times without rendering.
+    ///
+    /// `source_frames` follows the order of `summary().required_files` and
+    /// holds each file's decoded length in output frames. Sounds are cut where
+    /// the same keysound retriggers, as in the mix; long-note sustain and
+    /// gapless joining are not applied.
+    pub fn events_between(
+        &self,
+        start_sec: f64,
+        end_sec: f64,
+        source_frames: Vec<f64>,
+    ) -> Result<JsValue, JsValue> {
+        let analysis = &self.analysis;
+        let required = &analysis.summary.required_files;
+        if source_frames.len() != required.len() {
+            return Err(JsValue::from_str(&format!(
+                "Expected {} source lengths, got {}",
+                required.len(),
+                source_frames.len()
+            )));
+        }
+        let mut lengths: Vec<(Vec<f32>, usize)> = vec![(Vec::new(), 0); analysis.filenames.len()];
+        for (name, &frames) in required.iter().zip(&source_frames) {
+            if let Some(&id) = analysis.filename_to_id.get(name) {
+                lengths[id].1 = frames.max(0.0) as usize;
+            }
+        }
+        let channels = analysis.options.channels as usize;
+        let prepared = prepare_events(&analysis.sound_events, &lengths, channels);
+        let active: Vec<ActiveSound> = prepared
+            .events_between(start_sec, end_sec, analysis.options.sample_rate, channels)
+            .into_iter()
+            .map(|ev| ActiveSound {
+                filename: analysis.filenames[ev.key_id].clone(),
+                start_sec: ev.start_sec,
+                end_sec: ev.end_sec,
+                source_frame: ev.source_frame,
+            })
+            .collect();
+        Ok(serde_wasm_bindgen::to_value(&active)?)
+    }
+}
+
+/// Parse and schedule a chart, drawing its `#RANDOM` and `#SWITCH` values
+/// from `random_source` when given.
+fn analyze(
+    bms_data: &JsValue,
+    audio_options: AudioOptions,
+    random_source: Option<&js_sys::Function>,
+) -> Result<BmsAnalysis, JsValue> {
+    let text = chart_text(bms_data, audio_options.text_encoding)?;
+    let options = audio_options.parse_options();
+    let parsed = match random_source {
+        Some(callback) => Bms::parse_with_random(&text, &options, &mut JsRandom { callback }),
+        None => Bms::parse_with_options(&text, &options),
+    };
+    let (bms, _) = parsed.map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+    analyze_parsed(bms, audio_options)
+}
+
+fn analyze_parsed(bms: Bms, audio_options: AudioOptions) -> Result<BmsAnalysis, JsValue> {
+    Ok(BmsAnalysis {
+        analysis: Analysis::new(bms, audio_options.render_options()?).map_err(render_error)?,
+        base64_output: audio_options.base64_output,
+    })
+}
+
+/// Parse and schedule a chart without loading any audio.
+///
+/// The returned analysis carries estimates (duration, output size, required
+/// files) that hosts can show before calling `render_bms_analysis`.
+///
+/// `random_source`, when given, is called like `Math.random` for the values
+/// of `#RANDOM` and `#SWITCH` commands instead of `random_seed`.
+#[wasm_bindgen]
+pub fn analyze_bms(
+    bms_data: JsValue,
+    audio_options: JsValue,
+    random_source: Option<js_sys::Function>,
+) -> Result<BmsAnalysis, JsValue> {
+    let audio_options: AudioOptions = serde_wasm_bindgen::from_value(audio_options)?;
+    analyze(&bms_data, audio_options, random_source.as_ref())
+}
+
+/// Chart parser fed in chunks, such as the body of a streaming fetch.
+///
+/// Only the current incomplete line is buffered, so the file is never held
+/// as one string. `finish` returns the same analysis as `analyze_bms`.
+#[wasm_bindgen]
+pub struct BmsStreamParser {
+    audio_options: AudioOptions,
+    parser: StreamingParser,
+}
+
+#[wasm_bindgen]
+impl BmsStreamParser {
+    #[wasm_bindgen(constructor)]
+    pub fn new(audio_options: JsValue) -> Result<BmsStreamParser, JsValue> {
+        let audio_options: AudioOptions = serde_wasm_bindgen::from_value(audio_options)?;
+        let parser =
+            StreamingParser::new(audio_options.parse_options(), audio_options.text_encoding);
+        Ok(BmsStreamParser {
+            audio_options,
+            parser,
+        })
+    }
+
+    /// Feed the next bytes; chunks may end anywhere.
+    pub fn push(&mut self, chunk: &Uint8Array) {
+        self.parser.push_bytes(&chunk.to_vec());
+    }
+
+    /// Feed the next decoded text.
+    pub fn push_text(&mut self, chunk: &str) {
+        self.parser.push_str(chunk);
+    }
+
+    /// Parse the final line and schedule the chart.
+    pub fn finish(self) -> Result<BmsAnalysis, JsValue> {
+        let (bms, _) = self
+            .parser
+            .finish()
+            .map_err(|e| JsValue::from_str(&format!("BMS parse error: {}", e)))?;
+        analyze_parsed(bms, self.audio_options)
+    }
+}
+
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub async fn convert_bms_to_wav(
+    bms_data: JsValue,
+    audio_options: JsValue,
+    on_progress: js_sys::Function,
+    on_chunk: js_sys::Function,
+    get_many_bytes: js_sys::Function,
+    on_guide_chunk: Option<js_sys::Function>,
+    on_meter: Option<js_sys::Function>,
+    placeholder: Option<Uint8Array>,
+    random_source: Option<js_sys::Function>,
+    decode_cache: Option<js_sys::Object>,
+) -> Result<JsValue, JsValue> {
+    let audio_options: AudioOptions = serde_wasm_bindgen::from_value(audio_options)?;
+    report_progress(&on_progress, 5.0, "Parsing BMS");
+    let analysis = analyze(&bms_data, audio_options, random_source.as_ref())?;
+    report_progress(&on_progress, 10.0, "Building tempo map");
+    render_bms_analysis(
+        analysis,
+        on_progress,
+        on_chunk,
+        get_many_bytes,
+        on_guide_chunk,
+        on_meter,
+        placeholder,
+        random_source,
+        decode_cache,
+    )
+    .await
+}
+
+/// Render a chart analyzed with `analyze_bms`.
+///
+/// `random_source`, when given, is called like `Math.random` for the timing
+/// jitter instead of `jitter_seed`, so hosts can apply their own policy or
+/// replay recorded values. `#RANDOM` and `#SWITCH` values are drawn when the
+/// chart is analyzed, so pass the same source to `analyze_bms` for those;
+/// `convert_bms_to_wav` uses it for both.
+///
+/// `decode_cache`, when given, is an object with `get(key)` and
+/// `put(key, samples)` methods (either may return a Promise) that stores
+/// decoded keysounds as `Float32Array`s across renders.
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub async fn render_bms_analysis(
+    analysis: BmsAnalysis,
+    on_progress: js_sys::Function,
+    on_chunk: js_sys::Function,
+    get_many_bytes: js_sys::Function,
+    on_guide_chunk: Option<js_sys::Function>,
+    on_meter: Option<js_sys::Function>,
+    placeholder: Option<Uint8Array>,
+    random_source: Option<js_sys::Function>,
+    decode_cache: Option<js_sys::Object>,
+) -> Result<JsValue, JsValue> {
+    render_with_loader(
+        analysis,
+        &JsLoader {
+            callback: &get_many_bytes,
+        },
+        decode_cache.map(|target| JsDecodeCache { target }).as_ref(),
+        &on_progress,
+        &on_chunk,
+        on_guide_chunk.as_ref(),
+        on_meter.as_ref(),
+        placeholder,
+        random_source.as_ref(),
+    )
+    .await
+}
+
+/// Render an analysis through `render::render`, forwarding its output and
+/// progress to host callbacks.
+#[allow(clippy::too_many_arguments)]
+async fn render_with_loader<L: AudioLoader, C: DecodeCache>(
+    analysis: BmsAnalysis,
+    loader: &L,
+    cache: Option<&C>,
+    on_progress: &js_sys::Function,
+    on_chunk: &js_sys::Function,
+    on_guide_chunk: Option<&js_sys::Function>,
+    on_meter: Option<&js_sys::Function>,
+    placeholder: Option<Uint8Array>,
+    random_source: Option<&js_sys::Function>,
+) -> Result<JsValue, JsValue> {
+    let BmsAnalysis {
+        analysis,
+        base64_output,
+    } = analysis;
+    let mut output = ChunkSink::new(on_chunk, base64_output);
+    let mut guide = on_guide_chunk.map(|callback| ChunkSink::new(callback, base64_output));
+    let mut progress = |progress: f64, stage: &str| report_progress(on_progress, progress, stage);
+    let mut meter = on_meter.map(|callback| {
+        move |chunk_index: usize, levels: ChunkLevels| {
+            let _ = callback.call3(
+                &JsValue::NULL,
+                &JsValue::from(chunk_index as u32),
+                &JsValue::from(levels.peak),
+                &JsValue::from(levels.rms),
+            );
+        }
+    });
+    let mut random = random_source.map(|callback| JsRandom { callback });
+    let placeholder = placeholder.map(|bytes| bytes.to_vec());
+    let callbacks = RenderCallbacks {
+        output: &mut output,
+        guide: guide.as_mut().map(|sink| sink as &mut dyn ByteSink),
+        progress: &mut progress,
+        meter: meter
+            .as_mut()
+            .map(|meter| meter as &mut dyn FnMut(usize, ChunkLevels)),
+        random: random.as_mut().map(|rng| rng as &mut dyn RandomSource),
+    };
+    let result = render(analysis, loader, cache, placeholder.as_deref(), callbacks).await;
+    match result {
+        Ok(report) => Ok(serde_wasm_bindgen::to_value(&report)?),
+        // An exception thrown by a chunk callback is more useful than its message.
+        Err(e) => Err(output
+            .error
+            .take()
+            .or_else(|| guide.as_mut().and_then(|sink| sink.error.take()))
+            .unwrap_or_else(|| render_error(e))),
+    }
+}
+
+/// Render every distinct variant of a chart's `#RANDOM` and `#SWITCH` blocks.
+///
+/// Branch values are enumerated in ascending order, skipping combinations
+/// that select the same lines as an earlier variant, until `max_variants`
+/// variants were found. Keysounds are decoded once and reused by every
+/// variant, through `decode_cache` when given and an in-memory cache
+/// otherwise.
+///
+/// # Arguments
+///
+/// * `bms_data` - Chart text, as for `convert_bms_to_wav`.
+/// * `audio_options` - Render options; `forced_random` is set for each variant.
+/// * `max_variants` - Largest number of variants rendered.
+/// * `on_progress` - Progress callback, restarting for each variant.
+/// * `on_variant` - Called with the index and `random_choices` of each variant
+///   before it renders; returns the function receiving that variant's WAV chunks.
+/// * `get_many_bytes` - Keysound loader, as for `convert_bms_to_wav`.
+/// * `decode_cache` - Decode cache, as for `render_bms_analysis`.
+///
+/// # Returns
+///
+/// * `Result<JsValue, JsValue>` - `{ reports, truncated }`: the `ConversionReport` of each
+///   variant, and whether variants were left out.
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub async fn convert_bms_random_variants(
+    bms_data: JsValue,
+    audio_options: JsValue,
+    max_variants: u32,
+    on_progress: js_sys::Function,
+    on_variant: js_sys::Function,
+    get_many_bytes: js_sys::Function,
+    decode_cache: Option<js_sys::Object>,
+) -> Result<JsValue, JsValue> {
+    let audio_options: AudioOptions = serde_wasm_bindgen::from_value(audio_options)?;
+    let text = chart_text(&bms_data, audio_options.text_encoding)?;
+    let RandomVariants {
+        variants,
+        truncated,
+    } = if audio_options.parse_options().mode == ChartMode::Dtx {
+        // DTX charts have no control flow to enumerate.
+        RandomVariants {
+            variants: vec![Vec::new()],
+            truncated: false,
+        }
+    } else {
+        random_variants(&text, max_variants as usize)
+    };
+
+    let loader = JsLoader {
+        callback: &get_many_bytes,
+    };
+    let host_cache = decode_cache.map(|target| JsDecodeCache { target });
+    let memory_cache = MemoryCache::new();
+    let reports = Array::new();
+    for (index, choices) in variants.into_iter().enumerate() {
+        let mut options = audio_options.clone();
+        options.forced_random = choices;
+        let bms = parse_bms_text(&text, &options.parse_options())?;
+        let analysis = analyze_parsed(bms, options)?;
+        let on_chunk: js_sys::Function = on_variant
+            .call2(
+                &JsValue::NULL,
+                &JsValue::from(index as u32),
+                &serde_wasm_bindgen::to_value(&analysis.analysis.chart().random_choices)?,
+            )?
+            .dyn_into()
+            .map_err(|_| JsValue::from_str("on_variant did not return a function"))?;
+        let report = match &host_cache {
+            Some(cache) => {
+                render_with_loader(
+                    analysis,
+                    &loader,
+                    Some(cache),
+                    &on_progress,
+                    &on_chunk,
+                    None,
+                    None,
+                    None,
+                    None,
+                )
+                .await?
+            }
+            None => {
+                render_with_loader(
+                    analysis,
+                    &loader,
+                    Some(&memory_cache),
+                    &on_progress,
+                    &on_chunk,
+                    None,
+                    None,
+                    None,
+                    None,
+                )
+                .await?
+            }
+        };
+        reports.push(&report);
+    }
+
+    let result = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&result, &JsValue::from_str("reports"), &reports);
+    let _ = js_sys::Reflect::set(
+        &result,
+        &JsValue::from_str("truncated"),
+        &JsValue::from_bool(truncated),
+    );
+    Ok(result.into())
+}
+
+/// Render a chart and compare it with a reference rendering of the same chart.
+///
+/// The reference (typically a WAV exported by another player's renderer) is
+/// decoded to the render's sample rate and channel count, aligned to the
+/// render, and compared measure by measure. Output options that change the
+/// channel layout, speed, pitch or encoding of the render are ignored.
+///
+/// # Arguments
+///
+/// * `bms_data` - Chart text, as for `convert_bms_to_wav`.
+/// * `audio_options` - Render options.
+/// * `reference` - Encoded reference audio.
+/// * `on_progress` - Progress callback of the render.
+/// * `get_many_bytes` - Keysound loader, as for `convert_bms_to_wav`.
+/// * `max_offset_ms` - Largest offset searched, 500 ms by default.
+///
+/// # Returns
+///
+/// * `ComparisonReport` - Offset of the reference and per-measure level differences.
+#[wasm_bindgen]
+pub async fn compare_with_reference(
+    bms_data: JsValue,
+    audio_options: JsValue,
+    reference: Uint8Array,
+    on_progress: js_sys::Function,
+    get_many_bytes: js_sys::Function,
+    max_offset_ms: Option<f64>,
+) -> Result<JsValue, JsValue> {
+    let mut audio_options: AudioOptions = serde_wasm_bindgen::from_value(audio_options)?;
+    audio_options.base64_output = false;
+    audio_options.multichannel_stems = false;
+    audio_options.rate = None;
+    audio_options.pitch_semitones = None;
+    let channels = audio_options.channels() as usize;
+    let sample_rate = audio_options.sample_rate();
+    let resample_quality = audio_options.resample_quality();
+    let analysis = analyze(&bms_data, audio_options, None)?.analysis;
+    let sections = measure_starts(&analysis.tempo_map);
+
+    let mut rendered_bytes: Vec<u8> = Vec::new();
+    render(
+        analysis,
+        &JsLoader {
+            callback: &get_many_bytes,
+        },
+        None::<&MemoryCache>,
+        None,
+        RenderCallbacks {
+            output: &mut rendered_bytes,
+            guide: None,
+            progress: &mut |progress, stage| report_progress(&on_progress, progress, stage),
+            meter: None,
+            random: None,
+        },
+    )
+    .await
+    .map_err(render_error)?;
+
+    let (rendered, _) = crate::audio::decode_audio(
+        Arc::from(rendered_bytes),
+        sample_rate,
+        channels,
+        resample_quality,
+    )
+    .map_err(|e| JsValue::from_str(&format!("Error while decoding render: {}", e)))?;
+    let (reference, _) = crate::audio::decode_audio(
+        Arc::from(reference.to_vec()),
+        sample_rate,
+        channels,
+        resample_quality,
+    )
+    .map_err(|e| JsValue::from_str(&format!("Error while decoding reference: {}", e)))?;
+    let report = compare_renders(
+        &rendered,
+        &reference,
+        channels,
+        sample_rate,
+        &sections,
+        max_offset_ms.unwrap_or(DEFAULT_MAX_OFFSET_MS),
+    );
+    Ok(serde_wasm_bindgen::to_value(&report)?)
 }
@@ -0,0 +1,197 @@
+//! Golden renders of tiny synthetic charts.
+//!
+//! Each chart is rendered through the timeline and mixer with generated
+//! keysounds and compared against the PCM checked in under `tests/golden`.
+//! After an intended change to the output, regenerate the files with
+//! `BMXTRACT_BLESS=1 cargo test --test golden` and review the difference.
+
+use bmxtract::bms::{Bms, ParseOptions};
+use bmxtract::cache::{samples_from_le_bytes, samples_to_le_bytes};
+use bmxtract::mixer::{bucketize_events, mix_chunk, precompute_overlaps, prepare_events};
+use bmxtract::stream::StreamingParser;
+use bmxtract::timeline::{build_tempo_map, extract_sound_events, index_audio_files};
+use std::path::PathBuf;
+
+/// Sample rate of the golden renders, kept low so the files stay small.
+const SAMPLE_RATE: u32 = 8000;
+
+/// Chunk length, short enough that every render spans several chunks.
+const CHUNK_FRAMES: usize = 256;
+
+/// Largest per-sample difference accepted against a golden render.
+const TOLERANCE: f32 = 1e-5;
+
+/// Generate the keysound for the `index`-th `#WAV` file of a chart.
+///
+/// Keysounds alternate between decaying tones of rising pitch and noise
+/// bursts, and their lengths differ so truncation and overlaps are audible.
+fn keysound(index: usize, channels: usize) -> (Vec<f32>, usize) {
+    let frames = SAMPLE_RATE as usize / 4 + index * 331;
+    let mut seed = 0x9E37_79B9_u32.wrapping_mul(index as u32 + 1);
+    let mut samples = Vec::with_capacity(frames * channels);
+    for frame in 0..frames {
+        let t = frame as f32 / SAMPLE_RATE as f32;
+        let envelope = 1.0 - frame as f32 / frames as f32;
+        let value = if index.is_multiple_of(2) {
+            let freq = 220.0 * (1 + index) as f32;
+            (std::f32::consts::TAU * freq * t).sin()
+        } else {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32 * 2.0 - 1.0
+        };
+        for ch in 0..channels {
+            // Channels differ slightly so interleaving mistakes show up.
+            samples.push(value * envelope * 0.4 / (1 + ch) as f32);
+        }
+    }
+    (samples, frames)
+}
+
+/// Render a chart with generated keysounds.
+fn render(chart: &str, channels: usize) -> Vec<f32> {
+    render_bms(&Bms::parse(chart).unwrap(), channels)
+}
+
+/// Render a parsed chart with generated keysounds.
+fn render_bms(bms: &Bms, channels: usize) -> Vec<f32> {
+    let tempo_map = build_tempo_map(bms);
+    let (filenames, filename_to_id) = index_audio_files(bms);
+    let decoded: Vec<(Vec<f32>, usize)> = (0..filenames.len())
+        .map(|i| keysound(i, channels))
+        .collect();
+    let sound_events =
+        extract_sound_events(bms, &tempo_map, &filename_to_id, SAMPLE_RATE, channels);
+    let prepared = prepare_events(&sound_events, &decoded, channels);
+    let (chunk_count, buckets) =
+        bucketize_events(&prepared.events, prepared.total_len, CHUNK_FRAMES, channels);
+    let overlaps = precompute_overlaps(
+        &prepared.events,
+        &decoded,
+        &buckets,
+        prepared.total_len,
+        CHUNK_FRAMES,
+        channels,
+    );
+    (0..chunk_count)
+        .flat_map(|ci| {
+            mix_chunk(
+                ci,
+                &prepared.events,
+                &decoded,
+                &overlaps,
+                prepared.total_len,
+                CHUNK_FRAMES,
+                channels,
+            )
+        })
+        .collect()
+}
+
+/// Compare a render with its golden file, or rewrite the file when blessing.
+fn check_golden(name: &str, rendered: &[f32], channels: usize) {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", name]
+        .iter()
+        .collect::<PathBuf>()
+        .with_extension("pcm");
+    if std::env::var_os("BMXTRACT_BLESS").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, samples_to_le_bytes(rendered)).unwrap();
+        return;
+    }
+    let bytes = std::fs::read(&path)
+        .unwrap_or_else(|e| panic!("{}: {e} (run with BMXTRACT_BLESS=1)", path.display()));
+    let golden = samples_from_le_bytes(&bytes).expect("truncated golden file");
+    assert_eq!(
+        rendered.len(),
+        golden.len(),
+        "{name}: rendered {} samples, golden has {}",
+        rendered.len(),
+        golden.len()
+    );
+    if let Some((i, (a, b))) = rendered
+        .iter()
+        .zip(&golden)
+        .enumerate()
+        .find(|(_, (a, b))| (*a - *b).abs() > TOLERANCE)
+    {
+        panic!(
+            "{name}: sample {i} (frame {}, {:.4} s) is {a}, golden has {b}",
+            i / channels,
+            (i / channels) as f64 / SAMPLE_RATE as f64
+        );
+    }
+}
+
+/// BGM and key lanes overlap; the same keysound retriggering cuts itself off.
+const OVERLAPPING_KEYSOUNDS: &str = "#BPM 240\n#WAV01 a.wav\n#WAV02 b.wav\n#WAV03 c.wav\n\
+                                     #00101:01000200\n#00111:0303\n#00112:00000003\n#00201:02\n";
+
+const TEMPO_CHANGES_AND_STOPS: &str = "#BPM 180\n#BPM01 90\n#STOP01 48\n#WAV01 a.wav\n#WAV02 b.wav\n\
+                                       #00101:0101\n#00108:0001\n#00109:01\n#00211:02020202\n#00311:01\n";
+
+const SHORT_MEASURES_AND_DENSE_NOTES: &str = "#BPM 200\n#WAV01 a.wav\n#WAV02 b.wav\n#WAV03 c.wav\n#WAV04 d.wav\n\
+                                              #00102:0.75\n#00111:0102030401020304\n#00112:04\n#00201:0000000003\n";
+
+#[test]
+fn overlapping_keysounds() {
+    check_golden(
+        "overlapping_keysounds",
+        &render(OVERLAPPING_KEYSOUNDS, 2),
+        2,
+    );
+}
+
+#[test]
+fn tempo_changes_and_stops() {
+    check_golden(
+        "tempo_changes_and_stops",
+        &render(TEMPO_CHANGES_AND_STOPS, 1),
+        1,
+    );
+}
+
+#[test]
+fn short_measures_and_dense_notes() {
+    check_golden(
+        "short_measures_and_dense_notes",
+        &render(SHORT_MEASURES_AND_DENSE_NOTES, 2),
+        2,
+    );
+}
+
+#[test]
+fn streaming_parse_matches_full_parse() {
+    for chart in [
+        OVERLAPPING_KEYSOUNDS,
+        TEMPO_CHANGES_AND_STOPS,
+        SHORT_MEASURES_AND_DENSE_NOTES,
+    ] {
+        let (full, full_report) = Bms::parse_with_options(chart, &ParseOptions::default()).unwrap();
+        let mut parser = StreamingParser::new(ParseOptions::default(), None);
+        // Chunks split lines at arbitrary points.
+        for chunk in chart.as_bytes().chunks(7) {
+            parser.push_bytes(chunk);
+        }
+        let (streamed, streamed_report) = parser.finish().unwrap();
+
+        let messages = |bms: &Bms| {
+            bms.messages
+                .iter()
+                .map(|m| (m.measure, m.channel, m.objects.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(messages(&streamed), messages(&full));
+        assert_eq!(streamed.measure_multipliers, full.measure_multipliers);
+        assert_eq!(streamed.header.audio_files, full.header.audio_files);
+        assert_eq!(streamed.header.bpm, full.header.bpm);
+        assert_eq!(streamed.header.bpm_table, full.header.bpm_table);
+        assert_eq!(streamed.header.stop_table, full.header.stop_table);
+        assert_eq!(
+            format!("{:?}", streamed_report.diagnostics),
+            format!("{:?}", full_report.diagnostics)
+        );
+        assert_eq!(render_bms(&streamed, 2), render_bms(&full, 2));
+    }
+}
@@ -0,0 +1,114 @@
+//! Inputs found by the fuzz targets in `fuzz/` that used to panic.
+
+use bmxtract::audio::{estimate_source, parse_wave};
+use bmxtract::bms::{Bms, Message, ParseError, ParseOptions, extended_measure};
+use bmxtract::dtx::parse_dtx;
+use bmxtract::timeline::build_tempo_map;
+
+#[test]
+fn multi_byte_measure_is_an_error() {
+    assert!(matches!(
+        Message::parse("#12é:0101"),
+        Err(ParseError::InvalidFormat)
+    ));
+    assert!(Message::parse_with_measure_parser("#é011:01", 36, extended_measure).is_err());
+}
+
+#[test]
+fn multi_byte_lines_are_skipped() {
+    let chart = "#BPM 120\n#0é11:01\n#00é:01\n#é\n#WAV01 a.wav\n#00111:01\n";
+    let (bms, _) = Bms::parse_with_options(chart, &ParseOptions::default()).unwrap();
+    assert_eq!(bms.messages.len(), 1);
+    let options = ParseOptions {
+        measure_parser: Some(extended_measure),
+        ..ParseOptions::default()
+    };
+    assert!(Bms::parse_with_options(chart, &options).is_ok());
+}
+
+#[test]
+fn huge_extended_measure_is_rejected() {
+    assert!(Message::parse_with_measure_parser("#429496729511:01", 36, extended_measure).is_err());
+    let chart = "#BPM 120\n#WAV01 a.wav\n#00111:01\n#429496729511:01\n";
+    let options = ParseOptions {
+        measure_parser: Some(extended_measure),
+        ..ParseOptions::default()
+    };
+    let (bms, _) = Bms::parse_with_options(chart, &options).unwrap();
+    assert_eq!(bms.messages.len(), 1);
+    assert_eq!(build_tempo_map(&bms).last_measure(), 1);
+}
+
+#[test]
+fn multi_byte_dtx_command_is_skipped() {
+    let (bms, _) = parse_dtx("#12é4: 01\n#00111: 01\n");
+    assert_eq!(bms.messages.len(), 1);
+}
+
+#[test]
+fn stop_past_the_last_measure() {
+    let bms = Bms::parse("#BPM 120\n#STP 010.000 1000\n#00111:01\n").unwrap();
+    let tempo_map = build_tempo_map(&bms);
+    // The chart starts at measure 1; measures past it last 2 s at 120 BPM
+    // and the stop adds 1 s.
+    assert_eq!(tempo_map.get_timestamp(12, 0.0), 23.0);
+}
+
+#[test]
+fn truncated_wave_chunks() {
+    assert_eq!(parse_wave(b"RIFF"), None);
+    assert_eq!(parse_wave(b"RIFF\0\0\0\0WAVEfmt \x01\0\0\0\x01"), None);
+    // A data chunk claiming more bytes than the file holds is not returned.
+    assert_eq!(
+        parse_wave(b"RIFF\0\0\0\0WAVEdata\xff\xff\xff\xff\0\0"),
+        None
+    );
+    let mut wave = b"RIFF\0\0\0\0WAVEfmt \x02\0\0\0\x55\0data\x02\0\0\0".to_vec();
+    wave.extend_from_slice(&[1, 2]);
+    assert_eq!(parse_wave(&wave), Some((30, 2, true, 0x55)));
+}
+
+#[test]
+fn oversized_wav_chunk_ends_the_walk() {
+    let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+    file.extend_from_slice(b"fmt \x10\0\0\0");
+    file.extend_from_slice(&[1, 0, 2, 0, 0x44, 0xAC, 0, 0, 0x10, 0xB1, 2, 0, 4, 0, 16, 0]);
+    file.extend_from_slice(b"junk");
+    file.extend_from_slice(&0xFFFF_FFF8u32.to_le_bytes());
+    file.extend_from_slice(&[0; 16]);
+    let estimate = estimate_source(&file, file.len() as u64);
+    assert_eq!((estimate.sample_rate, estimate.channels), (44100, 2));
+    assert!(!estimate.exact);
+}
+
+#[test]
+fn huge_vorbis_file_does_not_overflow() {
+    let mut file = b"OggS".to_vec();
+    file.extend_from_slice(&[0; 22]);
+    file.extend_from_slice(&[1, 30]);
+    file.extend_from_slice(b"\x01vorbis");
+    file.extend_from_slice(&[0; 4]);
+    file.push(2);
+    file.extend_from_slice(&u32::MAX.to_le_bytes());
+    file.extend_from_slice(&[0; 4]);
+    file.extend_from_slice(&8u32.to_le_bytes());
+    file.extend_from_slice(&[0; 6]);
+    let estimate = estimate_source(&file, u64::MAX);
+    assert_eq!(estimate.sample_rate, u32::MAX);
+    assert_eq!(estimate.frames, u64::MAX);
+}
+
+#[test]
+fn oversized_aiff_chunk_ends_the_walk() {
+    let mut file = b"FORM\0\0\0\0AIFF".to_vec();
+    file.extend_from_slice(b"junk");
+    file.extend_from_slice(&0xFFFF_FFF8u32.to_be_bytes());
+    file.extend_from_slice(b"COMM\0\0\0\x12\0\x02");
+    let estimate = estimate_source(&file, file.len() as u64);
+    assert!(!estimate.exact);
+
+    // A COMM chunk cut off by the end of the header is not read either.
+    file.truncate(12);
+    file.extend_from_slice(b"COMM\0\0\0\x12\0\x02");
+    assert!(!estimate_source(&file, file.len() as u64).exact);
+}
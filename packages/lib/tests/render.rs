@@ -0,0 +1,188 @@
+//! Native renders of a song folder through `render::render`.
+
+use bmxtract::bms::Bms;
+use bmxtract::cache::{DecodeCache, FsCache, MemoryCache, samples_to_le_bytes};
+use bmxtract::loader::FsLoader;
+use bmxtract::render::{Analysis, ConversionReport, RenderCallbacks, RenderOptions, render};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+const SAMPLE_RATE: u32 = 44100;
+
+/// Frames of the keysound, a tenth of a second.
+const KICK_FRAMES: usize = 4410;
+
+/// Level of every keysound sample.
+const KICK_LEVEL: i16 = 8000;
+
+/// One keysound at the start of each of the first two measures, plus a
+/// note whose file is not in the folder.
+const CHART: &str = "#BPM 120
+#WAV01 kick.wav
+#WAV02 missing.wav
+#00001:01
+#00101:01
+#00101:02
+";
+
+/// Build a mono 16-bit WAV file holding a constant level.
+fn kick_wav() -> Vec<u8> {
+    let data_len = (KICK_FRAMES * 2) as u32;
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for _ in 0..KICK_FRAMES {
+        wav.extend_from_slice(&KICK_LEVEL.to_le_bytes());
+    }
+    wav
+}
+
+/// Create an empty folder under the system's temporary directory.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bmxtract-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write the chart's keysound into a new song folder.
+fn song_folder(name: &str) -> PathBuf {
+    let dir = temp_dir(name);
+    std::fs::write(dir.join("kick.wav"), kick_wav()).unwrap();
+    dir
+}
+
+/// Render `CHART` with keysounds from `dir`, returning the WAV file and the report.
+fn render_song<C: DecodeCache>(dir: &Path, cache: Option<&C>) -> (Vec<u8>, ConversionReport) {
+    let (bms, _) = Bms::parse_with_options(CHART, &Default::default()).unwrap();
+    let options = RenderOptions {
+        sample_rate: SAMPLE_RATE,
+        ..RenderOptions::default()
+    };
+    let analysis = Analysis::new(bms, options).unwrap();
+    let loader = FsLoader::new(dir);
+    let mut output = Vec::new();
+    let mut stages = Vec::new();
+    let mut progress = |percent: f64, stage: &str| stages.push((percent, stage.to_string()));
+    // The filesystem loader and caches never wait, so one poll completes the render.
+    let report = {
+        let future = pin!(render(
+            analysis,
+            &loader,
+            cache,
+            None,
+            RenderCallbacks {
+                output: &mut output,
+                guide: None,
+                progress: &mut progress,
+                meter: None,
+                random: None,
+            },
+        ));
+        let Poll::Ready(report) = future.poll(&mut Context::from_waker(Waker::noop())) else {
+            panic!("render future is pending");
+        };
+        report.unwrap()
+    };
+    assert_eq!(stages.last(), Some(&(100.0, "Done".to_string())));
+    (output, report)
+}
+
+/// Left-channel samples of a 16-bit stereo WAV written by a render.
+fn left_channel(wav: &[u8]) -> Vec<i16> {
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(&wav[8..12], b"WAVE");
+    assert_eq!(u16::from_le_bytes([wav[22], wav[23]]), 2);
+    assert_eq!(
+        u32::from_le_bytes(wav[24..28].try_into().unwrap()),
+        SAMPLE_RATE
+    );
+    assert_eq!(u16::from_le_bytes([wav[34], wav[35]]), 16);
+    assert_eq!(&wav[36..40], b"data");
+    let data_len = u32::from_le_bytes(wav[40..44].try_into().unwrap()) as usize;
+    assert_eq!(wav.len(), 44 + data_len);
+    wav[44..]
+        .as_chunks::<4>()
+        .0
+        .iter()
+        .map(|frame| i16::from_le_bytes([frame[0], frame[1]]))
+        .collect()
+}
+
+#[test]
+fn renders_a_song_folder_through_the_filesystem_loader() {
+    let dir = song_folder("render-fs");
+    let (wav, report) = render_song(&dir, None::<&MemoryCache>);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let left = left_channel(&wav);
+    // Measure 1 starts two seconds in at 120 BPM.
+    let second_note = 2 * SAMPLE_RATE as usize;
+    assert!(left.len() >= second_note + KICK_FRAMES);
+    assert!(left[..KICK_FRAMES].iter().all(|&s| s != 0));
+    assert!(left[KICK_FRAMES..second_note].iter().all(|&s| s == 0));
+    assert!(
+        left[second_note..second_note + KICK_FRAMES]
+            .iter()
+            .all(|&s| s != 0)
+    );
+    assert_eq!(report.dropped.missing_file.count, 1);
+}
+
+/// Paths of the entries stored in a cache folder.
+fn cache_entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    entries.sort();
+    entries
+}
+
+#[test]
+fn filesystem_cache_stores_on_a_miss_and_is_read_on_a_hit() {
+    let dir = song_folder("render-cache-song");
+    let cache_dir = temp_dir("render-cache-entries");
+    let cache = FsCache::new(&cache_dir);
+
+    // Miss: the keysound is decoded and stored, and the render is unchanged.
+    let (uncached, _) = render_song(&dir, None::<&MemoryCache>);
+    let (missed, _) = render_song(&dir, Some(&cache));
+    assert_eq!(missed, uncached);
+    let entries = cache_entries(&cache_dir);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries[0].extension().and_then(|ext| ext.to_str()),
+        Some("pcm")
+    );
+
+    // Hit: the stored samples are mixed instead of decoding the file again.
+    let level = 0.1f32;
+    std::fs::write(
+        &entries[0],
+        samples_to_le_bytes(&vec![level; KICK_FRAMES * 2]),
+    )
+    .unwrap();
+    let (hit, _) = render_song(&dir, Some(&cache));
+    let _ = std::fs::remove_dir_all(&dir);
+    let _ = std::fs::remove_dir_all(&cache_dir);
+
+    let left = left_channel(&hit);
+    let second_note = 2 * SAMPLE_RATE as usize;
+    for &sample in left[..KICK_FRAMES]
+        .iter()
+        .chain(&left[second_note..second_note + KICK_FRAMES])
+    {
+        assert!((sample as f32 / 32768.0 - level).abs() < 1e-3);
+    }
+}
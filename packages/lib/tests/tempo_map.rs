@@ -0,0 +1,238 @@
+//! Properties of `TempoMap` on generated charts.
+//!
+//! Timestamps are checked against a reference that walks the chart one
+//! breakpoint at a time, so the cumulative-multiplier shortcuts of
+//! `get_timestamp` and the accumulated event times of the map are both
+//! compared with a plain integration of the tempo.
+
+use bmxtract::bms::{Bms, MeasureIndex};
+use bmxtract::timeline::{TempoMap, build_tempo_map};
+use proptest::prelude::*;
+
+/// Object slots per measure for tempo changes and stops.
+const SLOTS: usize = 8;
+
+/// Allowed difference between two timestamps, in seconds.
+const EPSILON: f64 = 1e-6;
+
+/// One measure of a generated chart.
+#[derive(Debug, Clone)]
+struct MeasureSpec {
+    /// Length multiplier (channel 02).
+    length: f64,
+    /// Tempo change at each slot `i`, at position `i / SLOTS` (channel 08).
+    bpm: [Option<f64>; SLOTS],
+    /// Stop in 192nds at each slot `i`, at position `(2i + 1) / (2 * SLOTS)`
+    /// so stops never coincide with tempo changes (channel 09).
+    stop: [Option<u32>; SLOTS],
+}
+
+/// A generated chart.
+#[derive(Debug, Clone)]
+struct ChartSpec {
+    bpm: f64,
+    measures: Vec<MeasureSpec>,
+}
+
+impl ChartSpec {
+    fn stop_position(slot: usize) -> f64 {
+        (2 * slot + 1) as f64 / (2 * SLOTS) as f64
+    }
+
+    /// Write the chart as BMS text.
+    fn to_bms(&self) -> String {
+        let mut text = format!("#BPM {}\n#00001:01\n", self.bpm);
+        let mut next_id = 1u32;
+        let mut id = |text: &mut String, command: &str, value: String| {
+            let code = format!(
+                "{}{}",
+                char::from_digit(next_id / 36, 36).unwrap(),
+                char::from_digit(next_id % 36, 36).unwrap()
+            )
+            .to_uppercase();
+            next_id += 1;
+            text.push_str(&format!("#{}{} {}\n", command, code, value));
+            code
+        };
+        for (m, measure) in self.measures.iter().enumerate() {
+            if measure.length != 1.0 {
+                text.push_str(&format!("#{:03}02:{}\n", m, measure.length));
+            }
+            let mut bpm_line = format!("#{:03}08:", m);
+            for bpm in measure.bpm {
+                match bpm {
+                    Some(bpm) => bpm_line.push_str(&id(&mut text, "BPM", bpm.to_string())),
+                    None => bpm_line.push_str("00"),
+                }
+            }
+            let mut stop_line = format!("#{:03}09:", m);
+            for stop in measure.stop {
+                stop_line.push_str("00");
+                match stop {
+                    Some(stop) => stop_line.push_str(&id(&mut text, "STOP", stop.to_string())),
+                    None => stop_line.push_str("00"),
+                }
+            }
+            text.push_str(&bpm_line);
+            text.push('\n');
+            text.push_str(&stop_line);
+            text.push('\n');
+        }
+        text
+    }
+
+    fn length(&self, measure: usize) -> f64 {
+        self.measures.get(measure).map_or(1.0, |m| m.length)
+    }
+
+    /// Tempo in effect at a point, ignoring stops.
+    fn bpm_at(&self, measure: usize, position: f64) -> f64 {
+        let mut bpm = self.bpm;
+        for (m, spec) in self.measures.iter().enumerate().take(measure + 1) {
+            for (slot, change) in spec.bpm.iter().enumerate() {
+                let at = slot as f64 / SLOTS as f64;
+                if let Some(change) = change
+                    && (m < measure || at <= position)
+                {
+                    bpm = *change;
+                }
+            }
+        }
+        bpm
+    }
+
+    /// Reference timestamp: integrate the tempo between consecutive breakpoints.
+    fn timestamp(&self, measure: usize, position: f64) -> f64 {
+        let mut time = 0.0;
+        let mut bpm = self.bpm;
+        let mut prev = (0usize, 0.0f64);
+        // Breakpoints in order: (measure, position, tempo change, stop).
+        let mut points: Vec<(usize, f64, Option<f64>, Option<u32>)> = Vec::new();
+        for (m, spec) in self.measures.iter().enumerate() {
+            for slot in 0..SLOTS {
+                points.push((m, slot as f64 / SLOTS as f64, spec.bpm[slot], None));
+                points.push((m, Self::stop_position(slot), None, spec.stop[slot]));
+            }
+        }
+        points.push((measure, position, None, None));
+        points.sort_by(|a, b| (a.0, a.1).partial_cmp(&(b.0, b.1)).unwrap());
+        for (m, p, change, stop) in points {
+            if (m, p) > (measure, position) {
+                break;
+            }
+            let measures = if m == prev.0 {
+                (p - prev.1) * self.length(m)
+            } else {
+                (1.0 - prev.1) * self.length(prev.0)
+                    + (prev.0 + 1..m).map(|i| self.length(i)).sum::<f64>()
+                    + p * self.length(m)
+            };
+            time += measures * 4.0 * 60.0 / bpm;
+            prev = (m, p);
+            if (m, p) == (measure, position) {
+                break;
+            }
+            if let Some(change) = change {
+                bpm = change;
+            }
+            if let Some(stop) = stop {
+                time += stop as f64 / 48.0 * 60.0 / bpm;
+            }
+        }
+        time
+    }
+
+    fn tempo_map(&self) -> TempoMap {
+        build_tempo_map(&Bms::parse(&self.to_bms()).unwrap())
+    }
+}
+
+fn measure_spec() -> impl Strategy<Value = MeasureSpec> {
+    (
+        prop::sample::select(vec![1.0, 0.25, 0.5, 0.75, 1.5, 2.0, 3.5]),
+        prop::array::uniform8(prop::option::weighted(0.2, 30.0f64..400.0)),
+        prop::array::uniform8(prop::option::weighted(0.15, 1u32..400)),
+    )
+        .prop_map(|(length, bpm, stop)| MeasureSpec { length, bpm, stop })
+}
+
+fn chart_spec() -> impl Strategy<Value = ChartSpec> {
+    (30.0f64..400.0, prop::collection::vec(measure_spec(), 1..8))
+        .prop_map(|(bpm, measures)| ChartSpec { bpm, measures })
+}
+
+/// A point on the chart, possibly in the measures after its last one.
+fn point(measures: usize) -> impl Strategy<Value = (usize, f64)> {
+    (0..measures + 3, 0.0f64..1.0)
+}
+
+proptest! {
+    #[test]
+    fn timestamps_never_decrease(
+        chart in chart_spec(),
+        a in point(8),
+        b in point(8),
+    ) {
+        let tempo_map = chart.tempo_map();
+        let (early, late) = if a <= b { (a, b) } else { (b, a) };
+        let t_early = tempo_map.get_timestamp(early.0 as MeasureIndex, early.1);
+        let t_late = tempo_map.get_timestamp(late.0 as MeasureIndex, late.1);
+        prop_assert!(t_early <= t_late + EPSILON, "{early:?} at {t_early}, {late:?} at {t_late}");
+    }
+
+    #[test]
+    fn timestamps_match_reference(chart in chart_spec(), at in point(8)) {
+        let tempo_map = chart.tempo_map();
+        let actual = tempo_map.get_timestamp(at.0 as MeasureIndex, at.1);
+        let expected = chart.timestamp(at.0, at.1);
+        prop_assert!((actual - expected).abs() < EPSILON, "{at:?}: {actual} != {expected}");
+    }
+
+    #[test]
+    fn events_match_reference(chart in chart_spec()) {
+        let tempo_map = chart.tempo_map();
+        for event in &tempo_map.events {
+            let at = (event.measure as usize, event.position);
+            let expected = chart.timestamp(at.0, at.1);
+            // Stop events carry the time the stop ends.
+            let stop = chart
+                .measures
+                .get(at.0)
+                .and_then(|m| (0..SLOTS).find(|&s| ChartSpec::stop_position(s) == at.1).and_then(|s| m.stop[s]))
+                .map_or(0.0, |d| d as f64 / 48.0 * 60.0 / chart.bpm_at(at.0, at.1));
+            prop_assert!(
+                (event.timestamp_sec - expected - stop).abs() < EPSILON,
+                "{at:?}: {} != {}", event.timestamp_sec, expected + stop
+            );
+            prop_assert!(
+                (tempo_map.get_timestamp(event.measure, event.position) - event.timestamp_sec).abs()
+                    < EPSILON
+            );
+        }
+    }
+
+    #[test]
+    fn stop_adds_its_duration_after_it(
+        chart in chart_spec(),
+        measure in 0usize..8,
+        slot in 0..SLOTS,
+        duration in 1u32..400,
+        at in point(8),
+    ) {
+        let measure = measure % chart.measures.len();
+        let mut without = chart.clone();
+        without.measures[measure].stop[slot] = None;
+        let mut with = without.clone();
+        with.measures[measure].stop[slot] = Some(duration);
+
+        let stop_at = (measure, ChartSpec::stop_position(slot));
+        let added = if at > stop_at {
+            duration as f64 / 48.0 * 60.0 / chart.bpm_at(stop_at.0, stop_at.1)
+        } else {
+            0.0
+        };
+        let before = without.tempo_map().get_timestamp(at.0 as MeasureIndex, at.1);
+        let after = with.tempo_map().get_timestamp(at.0 as MeasureIndex, at.1);
+        prop_assert!((after - before - added).abs() < EPSILON, "{at:?}: {after} - {before} != {added}");
+    }
+}